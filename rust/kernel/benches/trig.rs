@@ -0,0 +1,43 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+// The cost of the kernel's libm sin and cos against std's, over the phases of the
+// accuracy sweep ([-4 pi, 4 pi]). Run with cargo bench -p gridless-kernel; the
+// results are printed as nanoseconds per sin, cos pair.
+//
+extern crate libm;
+
+use std::f64::consts::PI;
+use std::hint::black_box;
+use std::time::Instant;
+
+const PHASES: usize = 4096;
+const ROUNDS: usize = 2000;
+
+fn time_per_call<F: Fn(f64) -> (f64, f64)>(phases: &[f64], f: F) -> f64 {
+    let mut sum = 0.0;
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for &x in phases {
+            let (s, c) = f(black_box(x));
+            sum += s + c;
+        }
+    }
+    let elapsed = start.elapsed();
+    black_box(sum);
+    elapsed.as_secs_f64()*1.0e9/((ROUNDS*phases.len()) as f64)
+}
+
+fn main() {
+    let phases: Vec<f64> = (0..PHASES).map(|i| -4.0*PI + 8.0*PI*(i as f64)/(PHASES as f64)).collect();
+
+    // Warm up, so that neither is timed on a cold cache
+    time_per_call(&phases, |x| (libm::sin(x), libm::cos(x)));
+
+    let libm_ns = time_per_call(&phases, |x| (libm::sin(x), libm::cos(x)));
+    let std_ns = time_per_call(&phases, |x| x.sin_cos());
+    println!("{:<16}{:>10}", "trig", "ns/call");
+    println!("{:<16}{:>10.2}", "libm sin, cos", libm_ns);
+    println!("{:<16}{:>10.2}", "std sin_cos", std_ns);
+    println!("{:<16}{:>10.2}", "libm / std", libm_ns/std_ns);
+}
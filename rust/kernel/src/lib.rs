@@ -0,0 +1,156 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+// The core of gridless imaging, the harmonic sum over baselines, with no dependence
+// on std, ndarray or serde so that it can run on an embedded device beside the
+// array. Everything is on plain slices and the output buffers are supplied by
+// the caller, so no allocation is needed.
+//
+#![no_std]
+
+extern crate libm;
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+const PI: f64 = core::f64::consts::PI;
+
+// Largest absolute difference between the libm and std phase factors, checked by test
+pub const TRIG_TOLERANCE: f64 = 1.0e-15;
+
+/*
+*   Baseline coordinates u, v, w (in wavelengths), all of the same length.
+*/
+pub struct Baselines<'a> {
+    pub u: &'a [f64],
+    pub v: &'a [f64],
+    pub w: &'a [f64],
+}
+
+/*
+*   Direction cosines l, m, n of the points to evaluate, all of the same length.
+*/
+pub struct Directions<'a> {
+    pub l: &'a [f64],
+    pub m: &'a [f64],
+    pub n: &'a [f64],
+}
+
+/*
+*   The phase convention of the sum. Each harmonic is exp(i sign 2 pi (u l + v m + w (n - w_reference))),
+*   so sign is -1 or +1, and w_reference is 1 to measure the w term from the phase centre
+*   (the zenith) or 0 for the plain n. None drops the w term, for coplanar arrays.
+*/
+pub struct Convention {
+    pub sign: f64,
+    pub w_reference: Option<f64>,
+}
+
+// exp(-2 pi i (u l + v m + w (n - 1))), the convention of harmonic_sum
+pub const STANDARD: Convention = Convention { sign: -1.0, w_reference: Some(1.0) };
+
+/*
+*   Accumulate the complex image sum_k vis_k exp(-2 pi i (u l + v m + w (n - 1))) / sqrt(N)
+*   at each of the N directions into (out_re, out_im), which must be zeroed by the caller.
+*   The w term can be dropped for coplanar arrays.
+*
+*   There is no fast trig approximation here: the phase factors use libm's sin and cos,
+*   which agree with std to within TRIG_TOLERANCE (a few ulp) for phases of any size
+*   that occur in practice, so the kernel adds no approximation error of its own.
+*/
+pub fn harmonic_sum(vis_re: &[f64],
+                    vis_im: &[f64],
+                    baselines: &Baselines,
+                    directions: &Directions,
+                    include_w_term: bool,
+                    out_re: &mut [f64],
+                    out_im: &mut [f64]) {
+    let convention = Convention {
+        w_reference: if include_w_term { STANDARD.w_reference } else { None },
+        ..STANDARD
+    };
+    harmonic_sum_with(vis_re, vis_im, baselines, directions, &convention, out_re, out_im);
+}
+
+/*
+*   As harmonic_sum, with the sign and w term given by convention.
+*/
+pub fn harmonic_sum_with(vis_re: &[f64],
+                         vis_im: &[f64],
+                         baselines: &Baselines,
+                         directions: &Directions,
+                         convention: &Convention,
+                         out_re: &mut [f64],
+                         out_im: &mut [f64]) {
+    let norm = libm::sqrt(directions.l.len() as f64);
+
+    for i in 0..vis_re.len() {
+        let (u, v, w) = (baselines.u[i], baselines.v[i], baselines.w[i]);
+        for k in 0..directions.l.len() {
+            let mut theta = u*directions.l[k] + v*directions.m[k];
+            if let Some(reference) = convention.w_reference {
+                theta += w*(directions.n[k] - reference);
+            }
+            let phase = convention.sign*2.0*PI*theta;
+            let h_re = libm::cos(phase) / norm;
+            let h_im = libm::sin(phase) / norm;
+
+            out_re[k] += vis_re[i]*h_re - vis_im[i]*h_im;
+            out_im[k] += vis_re[i]*h_im + vis_im[i]*h_re;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Worst absolute difference from std's sin and cos over n + 1 phases in [-range, range]
+    fn max_trig_error(range: f64, n: usize) -> f64 {
+        (0..=n).map(|i| -range + 2.0*range*(i as f64)/(n as f64))
+            .map(|x| (libm::sin(x) - x.sin()).abs().max((libm::cos(x) - x.cos()).abs()))
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn test_trig_accuracy() {
+        // A fine sweep of [-4 pi, 4 pi], covering every argument reduction boundary
+        assert!(max_trig_error(4.0*PI, 1_000_000) < TRIG_TOLERANCE);
+
+        // The phase is -2 pi (u l + v m), so baselines of thousands of wavelengths
+        // give arguments in the tens of thousands of radians
+        assert!(max_trig_error(2.0*PI*1.0e4, 1_000_003) < TRIG_TOLERANCE);
+
+        // The quadrant boundaries themselves
+        for k in -8..=8 {
+            let x = (k as f64)*PI/2.0;
+            assert!((libm::sin(x) - x.sin()).abs() < TRIG_TOLERANCE);
+            assert!((libm::cos(x) - x.cos()).abs() < TRIG_TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn test_point_source_at_phase_center() {
+        // A source at the zenith has unit visibilities on every baseline
+        let u = [1.0, -2.0, 0.5];
+        let v = [0.5, 1.5, -2.5];
+        let w = [0.0, 0.1, -0.1];
+        let l = [0.0, 0.3, -0.2, 0.1];
+        let m = [0.0, 0.1, 0.4, -0.5];
+        let n: std::vec::Vec<f64> = l.iter().zip(m.iter()).map(|(l, m)| (1.0f64 - l*l - m*m).sqrt()).collect();
+
+        let mut re = [0.0; 4];
+        let mut im = [0.0; 4];
+        harmonic_sum(&[1.0; 3], &[0.0; 3], &Baselines { u: &u, v: &v, w: &w },
+                     &Directions { l: &l, m: &m, n: &n }, true, &mut re, &mut im);
+
+        // Every baseline adds in phase at the zenith, giving 3/sqrt(4)
+        assert!((re[0] - 1.5).abs() < 1.0e-12 && im[0].abs() < 1.0e-12);
+        for k in 1..4 {
+            assert!(re[k]*re[k] + im[k]*im[k] < 1.5*1.5);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+use chrono::{DateTime, Utc, TimeZone};
+
+use sphere::{ElAz, Hemisphere};
+use utils::PI;
+
+// Rotation rate of the Earth relative to the stars (radians per SI second)
+pub const SIDEREAL_RATE: f64 = 7.292_115_9e-5;
+
+/*
+*   Greenwich mean sidereal time (radians, in [0, 2pi)) at utc, from the IAU 1982
+*   expression in days since J2000.0. Good to well under a second over this century.
+*/
+pub fn greenwich_sidereal_time(utc: &DateTime<Utc>) -> f64 {
+    let j2000 = Utc.with_ymd_and_hms(2000, 1, 1, 12, 0, 0).unwrap();
+    let d = (*utc - j2000).num_milliseconds() as f64 / 86_400_000.0;
+    let t = d / 36525.0;
+
+    let gmst_deg = 280.46061837 + 360.98564736629*d + 0.000387933*t*t - t*t*t/38_710_000.0;
+    gmst_deg.to_radians().rem_euclid(2.0*PI)
+}
+
+/*
+*   Local sidereal time (radians) at east longitude lon (radians).
+*/
+pub fn local_sidereal_time(lon: f64, utc: &DateTime<Utc>) -> f64 {
+    (greenwich_sidereal_time(utc) + lon).rem_euclid(2.0*PI)
+}
+
+/*
+*   Convert equatorial coordinates (ra, dec) to elevation and azimuth (north through
+*   east) for an observer at latitude lat and east longitude lon. All angles in radians.
+*/
+pub fn equatorial_to_horizontal(ra: f64, dec: f64, lat: f64, lon: f64, utc: &DateTime<Utc>) -> ElAz {
+    let ha = local_sidereal_time(lon, utc) - ra;
+
+    let sin_el = dec.sin()*lat.sin() + dec.cos()*lat.cos()*ha.cos();
+    let el = sin_el.clamp(-1.0, 1.0).asin();
+
+    // Components of the direction towards east and north
+    let east = -dec.cos()*ha.sin();
+    let north = dec.sin()*lat.cos() - dec.cos()*lat.sin()*ha.cos();
+    let az = east.atan2(north).rem_euclid(2.0*PI);
+
+    ElAz::new(el, az)
+}
+
+/*
+*   The sky with each pixel's direction cosines moved to where the sky at that pixel
+*   at a reference time has rotated (about the celestial pole, for a site at latitude
+*   lat radians) dt seconds later. Imaging a snapshot taken dt after the reference
+*   onto it gives an image in the reference frame, so fixed sources stay put from
+*   frame to frame and snapshots can be stacked. Pixels rotated below the horizon are
+*   not seen in the snapshot: hide them after imaging with Hemisphere::hide_below_horizon.
+*/
+pub fn rotate_sky_to_reference(sky: &Hemisphere, lat: f64, dt: f64) -> Hemisphere {
+    let (sin_lat, cos_lat) = lat.sin_cos();
+    let (sin_h, cos_h) = (SIDEREAL_RATE*dt).sin_cos();
+
+    let mut rotated = sky.clone();
+    for i in 0..sky.npix {
+        let (l, m, n) = (sky.l[i], sky.m[i], sky.n[i]);
+        // To equatorial axes: x towards the meridian on the equator, y = l (east), z the pole
+        let x = cos_lat*n - sin_lat*m;
+        let z = sin_lat*n + cos_lat*m;
+        // The hour angle grows by SIDEREAL_RATE*dt
+        let (x, y) = (x*cos_h + l*sin_h, l*cos_h - x*sin_h);
+        rotated.l[i] = y;
+        rotated.m[i] = cos_lat*z - sin_lat*x;
+        rotated.n[i] = sin_lat*z + cos_lat*x;
+    }
+    rotated
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // M13 from Birmingham UK at 1998-08-10 23:10 UT, the worked example from
+    // http://www.stargazing.net/kepler/altaz.html: LST 304.80762, alt 49.169122, az 269.14634
+    #[test]
+    fn test_m13_example() {
+        let utc = Utc.with_ymd_and_hms(1998, 8, 10, 23, 10, 0).unwrap();
+        let (lat, lon) = (52.5f64.to_radians(), (-1.9166667f64).to_radians());
+
+        let lst = local_sidereal_time(lon, &utc).to_degrees();
+        assert!((lst - 304.80762).abs() < 0.01);
+
+        let elaz = equatorial_to_horizontal(250.425f64.to_radians(), 36.466667f64.to_radians(), lat, lon, &utc);
+        assert!((elaz.el.to_degrees() - 49.169122).abs() < 0.01);
+        assert!((elaz.az.to_degrees() - 269.14634).abs() < 0.01);
+    }
+
+    // An object transits (crosses the meridian) when the local sidereal time equals its RA
+    #[test]
+    fn test_transit() {
+        let utc = Utc.with_ymd_and_hms(2021, 11, 4, 9, 31, 5).unwrap();
+        let (lat, lon) = ((-45.85177f64).to_radians(), 170.5456f64.to_radians());
+        let ra = local_sidereal_time(lon, &utc);
+
+        // South of the zenith from the southern hemisphere, culminating at 90 - |lat - dec|
+        let dec = (-70.0f64).to_radians();
+        let elaz = equatorial_to_horizontal(ra, dec, lat, lon, &utc);
+        assert!((elaz.az.to_degrees() - 180.0).abs() < 1.0e-6);
+        assert!((elaz.el.to_degrees() - (90.0 - (45.85177 - 70.0f64).abs())).abs() < 1.0e-6);
+
+        let dec = 10.0f64.to_radians();
+        let elaz = equatorial_to_horizontal(ra, dec, lat, lon, &utc);
+        assert!(elaz.az.to_degrees().abs() < 1.0e-6 || (elaz.az.to_degrees() - 360.0).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn test_rotate_sky_to_reference() {
+        use gridless;
+        use img::AntPositionUnit;
+        use tart_api;
+        use tart_obs;
+
+        let obs = tart_obs::get_full(&tart_api::file_to_dataset("data.json")).unwrap();
+        let (u, v, w) = obs.get_uvw(AntPositionUnit::Meters);
+        let (lat, lon) = ((-45.85177f64).to_radians(), 170.5456f64.to_radians());
+        let t0 = Utc.with_ymd_and_hms(2021, 11, 4, 9, 31, 5).unwrap();
+        let (ra, dec) = (local_sidereal_time(lon, &t0) - 0.1, (-30.0f64).to_radians());
+
+        // A fixed source imaged at t0 and half an hour later
+        let peak = |dt: i64, derotate: bool| {
+            let utc = t0 + chrono::Duration::seconds(dt);
+            let elaz = equatorial_to_horizontal(ra, dec, lat, lon, &utc);
+            let vis = gridless::point_source_vis(&u, &v, &w, elaz.el, elaz.az, 1.0);
+            let sky = Hemisphere::new(32);
+            let mut sky = if derotate { rotate_sky_to_reference(&sky, lat, dt as f64) } else { sky };
+            gridless::image_visibilities(&vis, &u, &v, &w, &mut sky, false);
+            sky.hide_below_horizon();
+            sky.peak().unwrap().healpix_index
+        };
+        assert_eq!(peak(1800, true), peak(0, true));
+        assert_ne!(peak(1800, false), peak(0, false));
+
+        // The celestial pole doesn't move, and a rotation back undoes it
+        let sky = Hemisphere::new(8);
+        let there = rotate_sky_to_reference(&sky, lat, 3600.0);
+        let back = rotate_sky_to_reference(&there, lat, -3600.0);
+        for i in 0..sky.npix {
+            assert!((back.l[i] - sky.l[i]).abs() < 1.0e-12);
+            assert!((back.m[i] - sky.m[i]).abs() < 1.0e-12);
+            assert!((back.n[i] - sky.n[i]).abs() < 1.0e-12);
+            let pole = (sky.m[i]*lat.cos() + sky.n[i]*lat.sin(), there.m[i]*lat.cos() + there.n[i]*lat.sin());
+            assert!((pole.0 - pole.1).abs() < 1.0e-12);
+        }
+    }
+}
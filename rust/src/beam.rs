@@ -0,0 +1,149 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+use std::str::FromStr;
+
+use sphere::Hemisphere;
+use utils::{C, PI, parse_spec};
+
+// Below this beam gain a pixel is outside the useful field of view. Correcting it
+// would amplify noise without bound near the beam nulls, so it is set to zero.
+pub const BEAM_FLOOR: f64 = 0.01;
+
+/*
+*   Power pattern of the antenna primary beam, pointing at the zenith, as a
+*   function of the angle from boresight.
+*
+*   CosinePower(n) is cos^n, a reasonable model for dipoles. Airy is the far
+*   field of a uniformly illuminated dish, (2 J1(x)/x)^2 with x = pi D sin(angle) / lambda.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum BeamModel {
+    CosinePower(f32),
+    Airy { dish_diameter_m: f32, frequency_hz: f32 },
+}
+
+/*
+*   Parse a beam, ignoring case: cosine:N for CosinePower(N), or airy:D,F for a dish
+*   of diameter D metres at F Hz.
+*/
+impl FromStr for BeamModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BeamModel, String> {
+        match parse_spec(s)? {
+            (ref name, ref args) if name == "cosine" && args.len() == 1 => Ok(BeamModel::CosinePower(args[0] as f32)),
+            (ref name, ref args) if name == "airy" && args.len() == 2 && args[0] > 0.0 && args[1] > 0.0 =>
+                Ok(BeamModel::Airy { dish_diameter_m: args[0] as f32, frequency_hz: args[1] as f32 }),
+            _ => Err(format!("unknown beam '{}', expected cosine:N or airy:DIAMETER_M,FREQUENCY_HZ", s)),
+        }
+    }
+}
+
+impl BeamModel {
+    // Gain (1 at boresight) at an angle offset (radians) from boresight
+    pub fn gain(&self, offset: f64) -> f64 {
+        match *self {
+            BeamModel::CosinePower(n) => offset.cos().max(0.0).powf(n as f64),
+            BeamModel::Airy { dish_diameter_m, frequency_hz } => {
+                let wavelength = C / (frequency_hz as f64);
+                let x = PI * (dish_diameter_m as f64) * offset.sin() / wavelength;
+                if x.abs() < 1.0e-8 {
+                    return 1.0;
+                }
+                let a = 2.0 * bessel_j1(x) / x;
+                a*a
+            }
+        }
+    }
+
+    // Divide each visible pixel by the beam gain, zeroing pixels below BEAM_FLOOR
+    pub fn correct(&self, sky: &mut Hemisphere) {
+        for i in 0..sky.npix {
+            let offset = sky.n[i].min(1.0).acos(); // zenith angle
+            let g = self.gain(offset);
+            if g < BEAM_FLOOR {
+                sky.visible_pix[i] = 0.0;
+            } else {
+                sky.visible_pix[i] /= g;
+            }
+        }
+    }
+}
+
+/*
+*   Bessel function of the first kind, order one. Rational approximation from
+*   Numerical Recipes (bessj1), accurate to about 1e-8.
+*/
+fn bessel_j1(x: f64) -> f64 {
+    let ax = x.abs();
+    if ax < 8.0 {
+        let y = x*x;
+        let ans1 = x*(72362614232.0 + y*(-7895059235.0 + y*(242396853.1
+            + y*(-2972611.439 + y*(15704.48260 + y*(-30.16036606))))));
+        let ans2 = 144725228442.0 + y*(2300535178.0 + y*(18583304.74
+            + y*(99447.43394 + y*(376.9991397 + y*1.0))));
+        ans1/ans2
+    } else {
+        let z = 8.0/ax;
+        let y = z*z;
+        let xx = ax - 0.75*PI;
+        let ans1 = 1.0 + y*(0.183105e-2 + y*(-0.3516396496e-4
+            + y*(0.2457520174e-5 + y*(-0.240337019e-6))));
+        let ans2 = 0.04687499995 + y*(-0.2002690873e-3
+            + y*(0.8449199096e-5 + y*(-0.88228987e-6 + y*0.105787412e-6)));
+        let ans = (2.0/(PI*ax)).sqrt()*(xx.cos()*ans1 - z*xx.sin()*ans2);
+        if x < 0.0 { -ans } else { ans }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("Cosine:2".parse(), Ok(BeamModel::CosinePower(2.0)));
+        assert_eq!("airy:0.5,1.57542e9".parse(), Ok(BeamModel::Airy { dish_diameter_m: 0.5, frequency_hz: 1.57542e9 }));
+        assert!("airy:0.5".parse::<BeamModel>().is_err());
+        assert!("gaussian:3".parse::<BeamModel>().unwrap_err().contains("cosine:N"));
+    }
+
+    #[test]
+    fn test_bessel_j1() {
+        assert!((bessel_j1(1.0) - 0.4400505857).abs() < 1.0e-7);
+        assert!((bessel_j1(10.0) - 0.0434727462).abs() < 1.0e-7);
+        assert!(bessel_j1(3.8317060).abs() < 1.0e-6); // first zero
+    }
+
+    #[test]
+    fn test_airy_beam() {
+        let beam = BeamModel::Airy { dish_diameter_m: 2.0, frequency_hz: 1.5e9 };
+        assert_eq!(beam.gain(0.0), 1.0);
+
+        // Falls off monotonically to the first null at sin(angle) = 1.22 lambda / D
+        let null = (3.8317060 * (C / 1.5e9) / (PI * 2.0)).asin();
+        let mut last = 1.0;
+        for k in 1..20 {
+            let g = beam.gain(null * (k as f64) / 20.0);
+            assert!(g < last);
+            last = g;
+        }
+        assert!(beam.gain(null) < 1.0e-10);
+        assert!((beam.gain(0.5*null) - 0.4).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_correct_guards_nulls() {
+        let beam = BeamModel::Airy { dish_diameter_m: 2.0, frequency_hz: 1.5e9 };
+        let mut sky = Hemisphere::new(8);
+        sky.visible_pix.fill(1.0);
+        beam.correct(&mut sky);
+
+        assert!(sky.visible_pix.iter().all(|p| p.is_finite() && *p <= 1.0/BEAM_FLOOR));
+        assert!(sky.visible_pix.iter().any(|p| *p == 0.0));
+    }
+}
@@ -0,0 +1,47 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+use chrono::{DateTime, Utc};
+use serde::Serializer;
+
+use config::ProcessingConfig;
+use error::Warning;
+use sphere::HemisphereStats;
+use utils::base64_encode;
+
+/*
+*   The image of a RenderBundle, serialized as {"format": "svg" or "png", "data": ...}
+*   with the SVG text, or the PNG bytes in base64.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "format", content = "data", rename_all = "lowercase")]
+pub enum BundleImage {
+    Svg(String),
+    Png(#[serde(serialize_with = "as_base64")] Vec<u8>),
+}
+
+/*
+*   Everything about one render in a single record for archiving: the image, the
+*   statistics of the sky behind it, the config that produced it, when it was
+*   observed and any warnings about the data.
+*/
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderBundle {
+    pub image: BundleImage,
+    pub nside: u32,
+    pub stats: HemisphereStats,
+    pub config: ProcessingConfig,
+    pub timestamp: DateTime<Utc>,
+    pub warnings: Vec<Warning>,
+}
+
+impl RenderBundle {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("RenderBundle always serializes")
+    }
+}
+
+fn as_base64<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&base64_encode(bytes))
+}
@@ -0,0 +1,280 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+use std::str::FromStr;
+
+use num::clamp;
+use utils::{PI, parse_spec};
+use wasm_bindgen::prelude::*;
+
+/*
+*   Mapping from a normalized pixel value in [0, 1] to a colour.
+*/
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub enum Colormap {
+    #[default]
+    Cubehelix,
+    Grayscale,
+}
+
+/*
+*   How the range of pixel values mapped onto the colormap is chosen.
+*
+*   MinMax uses the full range, so a single bright outlier (e.g. RFI) compresses
+*   everything else into the bottom of the colormap. Percentile(lo, hi) maps the lo-th
+*   to hi-th percentile of the visible pixels onto the colormap and clamps beyond.
+*   HistEq spans the full range but maps each value through the cumulative histogram
+*   of the pixels, so every colour is used by about as many pixels and faint structure
+*   across a large dynamic range shows. The colormap is then non-linear in value.
+*   The default is Percentile(1.0, 99.0).
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum AutoScale {
+    MinMax,
+    Percentile(f64, f64),
+    HistEq,
+}
+
+impl Default for AutoScale {
+    fn default() -> AutoScale {
+        AutoScale::Percentile(1.0, 99.0)
+    }
+}
+
+/*
+*   Parse a colormap name, ignoring case: cubehelix, grayscale (or greyscale).
+*/
+impl FromStr for Colormap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Colormap, String> {
+        match s.trim().to_lowercase().as_str() {
+            "cubehelix" => Ok(Colormap::Cubehelix),
+            "grayscale" | "greyscale" => Ok(Colormap::Grayscale),
+            _ => Err(format!("unknown colormap '{}', expected cubehelix or grayscale", s)),
+        }
+    }
+}
+
+/*
+*   Parse a scaling, ignoring case: minmax, percentile (1 to 99), percentile:LO,HI or
+*   histeq.
+*/
+impl FromStr for AutoScale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AutoScale, String> {
+        match parse_spec(s)? {
+            (ref name, ref args) if name == "minmax" && args.is_empty() => Ok(AutoScale::MinMax),
+            (ref name, ref args) if name == "percentile" && args.is_empty() => Ok(AutoScale::default()),
+            (ref name, ref args) if name == "percentile" && args.len() == 2 && args[0] < args[1] =>
+                Ok(AutoScale::Percentile(args[0], args[1])),
+            (ref name, ref args) if name == "histeq" && args.is_empty() => Ok(AutoScale::HistEq),
+            _ => Err(format!("unknown scaling '{}', expected minmax, percentile, percentile:LO,HI with LO < HI, or histeq", s)),
+        }
+    }
+}
+
+impl Colormap {
+    // Returns (red, green, blue) in the range [0, 255]. Values outside [0, 1] are
+    // clamped (NaN is treated as 0) so fixed scaling bounds can't wrap the colormap.
+    pub fn rgb(&self, fract: f64) -> (f64, f64, f64) {
+        let fract = if fract.is_nan() { 0.0 } else { clamp(fract, 0.0, 1.0) };
+        match self {
+            Colormap::Cubehelix => cubehelix(fract),
+            Colormap::Grayscale => {
+                let g = fract*255.0;
+                (g, g, g)
+            }
+        }
+    }
+
+    pub fn rgb_u8(&self, fract: f64) -> (u8, u8, u8) {
+        let (r, g, b) = self.rgb(fract);
+        (r.round() as u8, g.round() as u8, b.round() as u8)
+    }
+
+    /*
+    *   n RGB triplets (3*n bytes) sampling the colormap evenly from 0 to 1 inclusive,
+    *   for a client colouring values itself. Looking up round(fract*(n - 1)) gives
+    *   exactly rgb_u8(fract) at the samples, and the nearest sample in between, so use
+    *   n = 256 or more to match the server to within rounding.
+    */
+    pub fn lut(&self, n: usize) -> Vec<u8> {
+        let last = n.saturating_sub(1).max(1) as f64;
+        let mut bytes = Vec::with_capacity(3*n);
+        for k in 0..n {
+            let (r, g, b) = self.rgb_u8((k as f64)/last);
+            bytes.extend_from_slice(&[r, g, b]);
+        }
+        bytes
+    }
+
+    // As rgb_u8, adding offset (from dither_offset) to each channel before rounding
+    pub fn rgb_u8_dithered(&self, fract: f64, offset: f64) -> (u8, u8, u8) {
+        let (r, g, b) = self.rgb(fract);
+        let quantize = |c: f64| clamp((c + offset).round(), 0.0, 255.0) as u8;
+        (quantize(r), quantize(g), quantize(b))
+    }
+}
+
+/*
+*   A colormap lookup table with, optionally, the pixel values at its ends: a value v
+*   is coloured by entry round(clamp((v - min)/(max - min), 0, 1)*(n - 1)).
+*/
+#[derive(Debug, Clone, Serialize)]
+pub struct ColormapLut {
+    pub colormap: Colormap,
+    pub n: usize,
+    pub rgb: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+}
+
+impl ColormapLut {
+    pub fn new(colormap: Colormap, n: usize, scale: Option<(f64, f64)>) -> ColormapLut {
+        ColormapLut {
+            colormap,
+            n,
+            rgb: colormap.lut(n),
+            min: scale.map(|s| s.0),
+            max: scale.map(|s| s.1),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("a colormap LUT always serializes")
+    }
+}
+
+// 4 x 4 Bayer matrix: every threshold 0..16 once, spread as evenly as possible
+const BAYER_4: [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+/*
+*   Ordered dithering offset in (-0.5, 0.5) for position (x, y). Added before rounding
+*   to 8 bits, it breaks the bands of a smooth gradient into a fine, fixed pattern whose
+*   average over each 4 x 4 block is the unrounded colour. It depends only on the
+*   position, so dithered output is reproducible.
+*/
+pub fn dither_offset(x: usize, y: usize) -> f64 {
+    (BAYER_4[y % 4][x % 4] as f64 + 0.5)/16.0 - 0.5
+}
+
+/* Generate a colour Map
+// http://inversed.ru/Blog_2.htm
+// THese are CUbeHelix values
+*/
+fn cubehelix(fract: f64) -> (f64, f64, f64) {
+    let start = 1.0;
+    let rot = -1.5;
+    let sat = 1.5; 
+    let _gamma = 1.0;
+    
+    let pi = PI;
+    
+    let angle = 2.0 * pi * (start / 3.0 + rot * fract + 1.);
+
+    let amp = sat * fract * (1. - fract) / 2.;
+
+    // compute the RGB vectors according to main equations
+    let mut red = fract + amp * (-0.14861 * angle.cos() + 1.78277 * angle.sin());
+    let mut grn = fract + amp * (-0.29227 * angle.cos() - 0.90649 * angle.sin());
+    let mut blu = fract + amp * (1.97294 * angle.cos());
+
+    // find where RBB are outside the range [0,1], clip
+    red = clamp(red, 0.0, 1.0);
+    grn = clamp(grn, 0.0, 1.0);
+    blu = clamp(blu, 0.0, 1.0);
+
+    (red*255.0, grn*255.0, blu*255.0)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_dither() {
+        let offsets: Vec<f64> = (0..16).map(|k| dither_offset(k % 4, k / 4)).collect();
+        assert!(offsets.iter().all(|o| o.abs() < 0.5));
+        assert!(offsets.iter().sum::<f64>().abs() < 1.0e-12);
+        assert_eq!(dither_offset(1, 2), dither_offset(5, 6));
+
+        // Averaged over a block, the dithered level is close to the unrounded one
+        let fract = 100.3/255.0;
+        let mean = offsets.iter()
+            .map(|o| Colormap::Grayscale.rgb_u8_dithered(fract, *o).0 as f64)
+            .sum::<f64>()/16.0;
+        assert_eq!(Colormap::Grayscale.rgb_u8(fract).0, 100);
+        assert!((mean - 100.3).abs() < 1.0/16.0 + 1.0e-9);
+
+        // Never more than one level from the undithered colour, and clamped at the ends
+        for o in &offsets {
+            let (r, _, _) = Colormap::Grayscale.rgb_u8_dithered(fract, *o);
+            assert!(r == 100 || r == 101);
+        }
+        assert_eq!(Colormap::Grayscale.rgb_u8_dithered(1.0, 0.49), (255, 255, 255));
+        assert_eq!(Colormap::Grayscale.rgb_u8_dithered(0.0, -0.49), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("CubeHelix".parse(), Ok(Colormap::Cubehelix));
+        assert_eq!(" greyscale".parse(), Ok(Colormap::Grayscale));
+        assert!("viridis".parse::<Colormap>().unwrap_err().contains("cubehelix or grayscale"));
+
+        assert_eq!("MinMax".parse(), Ok(AutoScale::MinMax));
+        assert_eq!("percentile".parse(), Ok(AutoScale::Percentile(1.0, 99.0)));
+        assert_eq!("percentile:5,95".parse(), Ok(AutoScale::Percentile(5.0, 95.0)));
+        assert!("percentile:95,5".parse::<AutoScale>().is_err());
+        assert!("minmax:1".parse::<AutoScale>().is_err());
+        assert_eq!("HistEq".parse(), Ok(AutoScale::HistEq));
+        assert!("log".parse::<AutoScale>().unwrap_err().contains("expected minmax"));
+    }
+
+    #[test]
+    fn test_out_of_range_clamps() {
+        for cmap in [Colormap::Cubehelix, Colormap::Grayscale].iter() {
+            assert_eq!(cmap.rgb(1.7), cmap.rgb(1.0));
+            assert_eq!(cmap.rgb(-0.3), cmap.rgb(0.0));
+            assert_eq!(cmap.rgb(f64::INFINITY), cmap.rgb(1.0));
+            assert_eq!(cmap.rgb(f64::NAN), cmap.rgb(0.0));
+        }
+    }
+
+    #[test]
+    fn test_lut() {
+        for &cmap in &[Colormap::Cubehelix, Colormap::Grayscale] {
+            let lut = cmap.lut(256);
+            assert_eq!(lut.len(), 3*256);
+            for &k in &[0, 1, 100, 255] {
+                let (r, g, b) = cmap.rgb_u8((k as f64)/255.0);
+                assert_eq!(&lut[3*k..3*k + 3], &[r, g, b]);
+            }
+        }
+        assert_eq!(Colormap::Grayscale.lut(2), vec![0, 0, 0, 255, 255, 255]);
+
+        let json = ColormapLut::new(Colormap::Grayscale, 2, Some((-1.0, 3.5))).to_json();
+        assert_eq!(json, "{\"colormap\":\"Grayscale\",\"n\":2,\"rgb\":[0,0,0,255,255,255],\"min\":-1.0,\"max\":3.5}");
+        let json = ColormapLut::new(Colormap::Grayscale, 2, None).to_json();
+        assert!(!json.contains("min"));
+    }
+
+    #[test]
+    fn test_endpoints() {
+        assert_eq!(Colormap::Grayscale.rgb_u8(1.0), (255, 255, 255));
+        assert_eq!(Colormap::Cubehelix.rgb_u8(0.0), (0, 0, 0));
+        assert_eq!(Colormap::Cubehelix.rgb_u8(1.0), (255, 255, 255));
+    }
+}
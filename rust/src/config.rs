@@ -0,0 +1,355 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+use beam::BeamModel;
+use gridless::FourierConvention;
+use img::AntPositionUnit;
+use colormap::{Colormap, AutoScale};
+use error::ProcessingError;
+use sphere::Hemisphere;
+use theme::Theme;
+use utils::parse_spec;
+
+use serde_json::{self, Value};
+use std::str::FromStr;
+
+// nside cap for the WASM bindings unless GRIDLESS_MAX_NSIDE is set when building.
+// This is the top of the viewer's nside slider.
+const BINDING_MAX_NSIDE: u32 = 128;
+
+// Largest supersample factor, which bounds the raster buffer at 64 times the output
+pub const MAX_SUPERSAMPLE: u32 = 8;
+
+/*
+*   How each pixel is drawn in the SVG: as its HEALPix outline, or as a filled circle
+*   of radius (in units of the 4000 wide view) at its centre. Points make a much
+*   smaller SVG at high nside.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub enum PixelStyle {
+    #[default]
+    Polygon,
+    Point { radius: u32 },
+}
+
+// Parse a pixel style, ignoring case: polygon, or point:RADIUS
+impl FromStr for PixelStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PixelStyle, String> {
+        match parse_spec(s)? {
+            (ref name, ref args) if name == "polygon" && args.is_empty() => Ok(PixelStyle::Polygon),
+            (ref name, ref args) if name == "point" && args.len() == 1 && args[0] >= 1.0 =>
+                Ok(PixelStyle::Point { radius: args[0].round() as u32 }),
+            _ => Err(format!("unknown pixel style '{}', expected polygon or point:RADIUS", s)),
+        }
+    }
+}
+
+/*
+*   A horizontal colorbar in the lower right corner, running from the low to the high
+*   end of the color scale. show_histogram draws the distribution of pixel values
+*   above it, so you can see where the data falls on the scale. label (e.g. the units)
+*   is written beneath the middle of the bar.
+*/
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Colorbar {
+    pub show_histogram: bool,
+    pub label: Option<String>,
+}
+
+/*
+*   A box listing the image statistics. Its size and text scale with the view, and
+*   x, y place its top left corner as fractions of the view width (0 to 1).
+*/
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsOverlay {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Default for StatsOverlay {
+    fn default() -> StatsOverlay {
+        StatsOverlay {
+            x: 0.025,
+            y: 0.025,
+        }
+    }
+}
+
+/*
+*   Options controlling how a hemisphere is imaged and rendered.
+*/
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessingConfig {
+    // The graticule: circles of constant elevation (including the horizon) and
+    // lines of constant azimuth every 30 degrees. set_show_grid sets both.
+    pub show_elevation_circles: bool,
+    pub show_azimuth_lines: bool,
+    // Opacity in [0, 1] of the grid lines and of the source markers, so that
+    // they don't hide faint structure. 1.0 draws them solid.
+    pub grid_opacity: f32,
+    pub source_opacity: f32,
+    // Draw a wider dark stroke under each source marker so that it stays visible
+    // against bright parts of the image (e.g. the sun).
+    pub source_outline: bool,
+    // Only draw pixels whose value lies at or above this fraction of the [min, max]
+    // range. Pixels below it are left out of the SVG (transparent).
+    pub render_threshold: Option<f32>,
+    // Show negative pixels (sidelobes of the dirty image) as zero. Otherwise they take
+    // the low end of the colormap.
+    pub clip_negatives: bool,
+    // Label the horizon with azimuth ticks every 30 degrees.
+    pub show_azimuth_ticks: bool,
+    // Draw a bar of scalebar_deg degrees in the lower left corner. The projection
+    // compresses towards the horizon, so the length is true at the zenith.
+    pub show_scalebar: bool,
+    pub scalebar_deg: f32,
+    pub colorbar: Option<Colorbar>,
+    pub stats_overlay: Option<StatsOverlay>,
+    // Per-pixel confidence in [0, 1], in the same order as Hemisphere::visible_pix.
+    // When present each polygon is drawn with this opacity so that poorly sampled
+    // regions fade into the background.
+    pub pixel_opacity: Option<Vec<f32>>,
+    // Include the w*(n-1) term in the harmonics. This is exact for non-coplanar arrays.
+    // Dropping it saves one vector operation per baseline and is accurate only when all
+    // |w| are small (a coplanar array), with errors growing towards the horizon.
+    pub include_w_term: bool,
+    // Sign of the exponent and the form of the w term. See FourierConvention.
+    pub fourier_convention: FourierConvention,
+    pub ant_position_unit: AntPositionUnit,
+    // Only image baselines with min <= |uv| <= max (wavelengths), e.g. just the short
+    // baselines to see extended emission. None uses every baseline.
+    pub baseline_filter: Option<(f32, f32)>,
+    // Image only every Nth baseline (after baseline_filter), for a quick look at very
+    // dense datasets: about N times faster, with higher sidelobes. None, 0 and 1 use
+    // every baseline.
+    pub decimate: Option<usize>,
+    // Image a sub-array: only the baselines with both antennas in this list (e.g. the
+    // inner ring). None uses every antenna. See Observation::with_antennas.
+    pub antenna_subset: Option<Vec<u32>>,
+    // Add the conjugate of every visibility on the reversed baseline before imaging,
+    // for datasets storing only one half of each conjugate pair. The image is then real.
+    pub assume_hermitian: bool,
+    pub colormap: Colormap,
+    pub auto_scale: AutoScale,
+    // Ordered dithering when quantizing colours to 8 bits, breaking up banding in
+    // smooth gradients. Deterministic, so output is reproducible.
+    pub dither: bool,
+    // Extra points interpolated along each pixel edge, giving smoother curved cell
+    // boundaries at low nside. Zero draws just the four corners.
+    pub edge_subdivisions: u32,
+    pub pixel_style: PixelStyle,
+    // Draw pixels sorted by projected position rather than in HEALPix order, so that
+    // overlapping outlines stack the same way everywhere and zoomed seams are consistent.
+    // Off by default, as it reorders the SVG.
+    pub deterministic_pixel_order: bool,
+    // Pixels whose highest corner is at or below this elevation (radians) are not drawn,
+    // hiding the ragged row of cells that only just clear the horizon. The default 0.07
+    // is about 4.0 degrees; 0 draws every visible pixel. The SVG and the WebGL corner
+    // bindings (get_hemisphere_pixel_corners) use the same test, Hemisphere::is_drawn.
+    pub min_draw_lat: f64,
+    // Mark the brightest pixel, labelled with its value and el/az
+    pub annotate_peak: bool,
+    // Raster export: render at supersample times the output size and average each
+    // supersample x supersample block, antialiasing the pixel boundaries. The
+    // intermediate buffer is 4*(size*supersample)^2 bytes, so keep this small (2-4);
+    // at most MAX_SUPERSAMPLE.
+    pub supersample: u32,
+    // Raster export: 8 for the colour image, or 16 for a 16 bit grayscale data product
+    // of the scaled values (see Hemisphere::to_png16).
+    pub png_bit_depth: u32,
+    // Primary beam to divide out of the image after reconstruction. None leaves
+    // the image uncorrected.
+    pub beam: Option<BeamModel>,
+    // Smooth the image with a Gaussian of this FWHM (degrees) after reconstruction,
+    // hiding pixel-scale noise. See Hemisphere::smooth. None leaves it unsmoothed.
+    pub smooth_fwhm: Option<f32>,
+    // Largest nside accepted, guarding a server against requests that would exhaust
+    // memory. None (the default, used by the CLI) is unbounded.
+    pub max_nside: Option<u32>,
+    // Image at this lower nside and resample onto the requested one (nearest neighbour),
+    // for a fast first paint. Imaging cost grows with the pixel count, so this is about
+    // (nside/preview_nside)^2 times quicker, but each preview pixel shows as a block of
+    // requested pixels and detail finer than the preview pixels is lost. Pixels near the
+    // horizon whose centres fall below the preview's horizon are left unseen (NaN).
+    pub preview_nside: Option<u32>,
+    pub theme: Theme,
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> ProcessingConfig {
+        ProcessingConfig {
+            show_elevation_circles: true,
+            show_azimuth_lines: true,
+            grid_opacity: 1.0,
+            source_opacity: 1.0,
+            source_outline: false,
+            render_threshold: None,
+            clip_negatives: false,
+            show_azimuth_ticks: false,
+            show_scalebar: false,
+            scalebar_deg: 10.0,
+            colorbar: None,
+            stats_overlay: None,
+            pixel_opacity: None,
+            include_w_term: true,
+            fourier_convention: FourierConvention::default(),
+            ant_position_unit: AntPositionUnit::default(),
+            baseline_filter: None,
+            decimate: None,
+            antenna_subset: None,
+            assume_hermitian: false,
+            colormap: Colormap::default(),
+            auto_scale: AutoScale::default(),
+            dither: false,
+            edge_subdivisions: 0,
+            pixel_style: PixelStyle::default(),
+            deterministic_pixel_order: false,
+            min_draw_lat: 0.07,
+            annotate_peak: false,
+            supersample: 1,
+            png_bit_depth: 8,
+            beam: None,
+            smooth_fwhm: None,
+            max_nside: None,
+            preview_nside: None,
+            theme: Theme::default(),
+        }
+    }
+}
+
+impl ProcessingConfig {
+    pub fn set_show_grid(&mut self, show_grid: bool) {
+        self.show_elevation_circles = show_grid;
+        self.show_azimuth_lines = show_grid;
+    }
+
+    /*
+    *   The defaults used by the WASM bindings: nside is capped at GRIDLESS_MAX_NSIDE
+    *   from the build environment, or 128.
+    */
+    pub fn for_bindings() -> ProcessingConfig {
+        let max_nside = option_env!("GRIDLESS_MAX_NSIDE")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(BINDING_MAX_NSIDE);
+        ProcessingConfig {
+            max_nside: Some(max_nside),
+            ..ProcessingConfig::default()
+        }
+    }
+
+    // The nside actually imaged for an image at nside: preview_nside when it is lower
+    pub fn imaging_nside(&self, nside: u32) -> u32 {
+        match self.preview_nside {
+            Some(preview) if preview < nside => preview,
+            _ => nside,
+        }
+    }
+
+    /*
+    *   The settings of this config that differ from other, as name=value, e.g.
+    *   colormap=Grayscale. The names and values are those of the JSON form. Used to
+    *   label the images in a comparison.
+    */
+    pub fn differences(&self, other: &ProcessingConfig) -> Vec<String> {
+        let (a, b) = (serde_json::to_value(self), serde_json::to_value(other));
+        let (a, b) = match (a, b) {
+            (Ok(Value::Object(a)), Ok(Value::Object(b))) => (a, b),
+            _ => unreachable!("a config serializes to a JSON object"),
+        };
+        a.iter()
+            .filter(|&(name, value)| b.get(name) != Some(value))
+            .map(|(name, value)| match value {
+                Value::String(s) => format!("{}={}", name, s),
+                _ => format!("{}={}", name, value),
+            })
+            .collect()
+    }
+
+    // Check that supersample is between 1 and MAX_SUPERSAMPLE
+    pub fn check_supersample(&self) -> Result<(), ProcessingError> {
+        if self.supersample == 0 || self.supersample > MAX_SUPERSAMPLE {
+            return Err(ProcessingError::InvalidFormat(format!(
+                "supersample {} is outside 1 to {}", self.supersample, MAX_SUPERSAMPLE)));
+        }
+        Ok(())
+    }
+
+    // Check nside against max_nside, and that it is a power of two when smoothing (which
+    // uses the NESTED scheme). Imaging alone works at any nside.
+    pub fn check_nside(&self, nside: u32) -> Result<(), ProcessingError> {
+        match self.max_nside {
+            Some(max) if nside > max => return Err(ProcessingError::InvalidFormat(format!(
+                "nside {} exceeds the maximum of {}", nside, max))),
+            _ => {},
+        }
+        if self.smooth_fwhm.is_some() {
+            Hemisphere::check_nside(nside)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_max_nside() {
+        assert!(ProcessingConfig::default().check_nside(1024).is_ok());
+
+        let config = ProcessingConfig {
+            max_nside: Some(64),
+            ..ProcessingConfig::default()
+        };
+        assert!(config.check_nside(64).is_ok());
+        assert!(config.check_nside(36).is_ok());
+        match config.check_nside(512) {
+            Err(ProcessingError::InvalidFormat(msg)) => assert!(msg.contains("maximum of 64")),
+            _ => panic!("Expected an InvalidFormat error"),
+        }
+        assert!(ProcessingConfig::for_bindings().max_nside.is_some());
+
+        // Any nside images, but smoothing needs a power of two
+        assert!(ProcessingConfig::default().check_nside(36).is_ok());
+        let smooth = ProcessingConfig { smooth_fwhm: Some(5.0), ..ProcessingConfig::default() };
+        assert!(smooth.check_nside(32).is_ok());
+        assert!(smooth.check_nside(36).is_err());
+    }
+
+    #[test]
+    fn test_imaging_nside() {
+        assert_eq!(ProcessingConfig::default().imaging_nside(64), 64);
+        let config = ProcessingConfig {
+            preview_nside: Some(16),
+            ..ProcessingConfig::default()
+        };
+        assert_eq!(config.imaging_nside(64), 16);
+        assert_eq!(config.imaging_nside(8), 8);
+    }
+
+    #[test]
+    fn test_differences() {
+        let a = ProcessingConfig::default();
+        let b = ProcessingConfig {
+            colormap: Colormap::Grayscale,
+            decimate: Some(4),
+            ..ProcessingConfig::default()
+        };
+        assert!(a.differences(&a.clone()).is_empty());
+        assert_eq!(b.differences(&a), vec!["colormap=Grayscale", "decimate=4"]);
+        assert_eq!(a.differences(&b), vec!["colormap=Cubehelix", "decimate=null"]);
+    }
+
+    #[test]
+    fn test_pixel_style_from_str() {
+        assert_eq!("Polygon".parse(), Ok(PixelStyle::Polygon));
+        assert_eq!("point:6".parse(), Ok(PixelStyle::Point { radius: 6 }));
+        assert!("point:0".parse::<PixelStyle>().is_err());
+        assert!("square".parse::<PixelStyle>().unwrap_err().contains("point:RADIUS"));
+    }
+}
@@ -0,0 +1,65 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+use std::fmt;
+
+/*
+*   Errors reported when a dataset cannot be processed.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProcessingError {
+    // A file could not be read or written
+    Io(String),
+    // The input is not a valid dataset
+    Parse(String),
+    // The dataset parsed, but its contents are inconsistent
+    InvalidFormat(String),
+}
+
+impl fmt::Display for ProcessingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessingError::Io(msg) => write!(f, "I/O error: {}", msg),
+            ProcessingError::Parse(msg) => write!(f, "Parse error: {}", msg),
+            ProcessingError::InvalidFormat(msg) => write!(f, "Invalid dataset: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ProcessingError {}
+
+/*
+*   Things done to the data that did not stop processing, but that the user should
+*   know about, e.g. visibilities that were left out of the image.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum Warning {
+    // Visibilities marked as flagged (e.g. RFI) and left out
+    FlaggedVisibilities { count: usize },
+    // Cross hand (XY, YX, RL, LR) visibilities dropped when forming Stokes I
+    CrossHandsDropped { count: usize },
+    // Visibilities with zero weight, which contribute nothing
+    ZeroWeight { count: usize },
+    // Baselines (as i < j) given more than once; every copy is imaged
+    DuplicateBaselines { baselines: Vec<(u32, u32)> },
+    // An nside that images, but that smoothing and NESTED maps can't use
+    NsideNotPowerOfTwo { nside: u32 },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::FlaggedVisibilities { count } => write!(f, "{} flagged visibilities were left out", count),
+            Warning::CrossHandsDropped { count } => write!(f, "{} cross hand visibilities were dropped forming Stokes I", count),
+            Warning::ZeroWeight { count } => write!(f, "{} visibilities have zero weight", count),
+            Warning::DuplicateBaselines { baselines } => {
+                let list: Vec<String> = baselines.iter().map(|(i, j)| format!("({}, {})", i, j)).collect();
+                write!(f, "baselines given more than once: {}", list.join(", "))
+            },
+            Warning::NsideNotPowerOfTwo { nside } =>
+                write!(f, "nside {} is not a power of two, so it can't be smoothed or written as a NESTED map", nside),
+        }
+    }
+}
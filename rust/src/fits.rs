@@ -0,0 +1,232 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+// Full sky HEALPix maps as FITS files, in the layout healpy and the HEALPix tools
+// use: an empty primary HDU followed by a BINTABLE with the map in its first column,
+// and the keywords PIXTYPE, ORDERING, NSIDE and INDXSCHM = 'IMPLICIT'.
+//
+
+use error::ProcessingError;
+use sphere::HealpixOrdering;
+
+use std::convert::TryInto;
+
+const BLOCK: usize = 2880;
+const CARD: usize = 80;
+
+/*
+*   A FITS file holding map (one f64 per pixel, 12*nside^2 of them, in ordering).
+*/
+pub fn write_healpix_map(map: &[f64], nside: u32, ordering: HealpixOrdering) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    let primary = [
+        card("SIMPLE", "T"),
+        card("BITPIX", "8"),
+        card("NAXIS", "0"),
+        card("EXTEND", "T"),
+    ];
+    push_header(&mut bytes, &primary);
+
+    let table = [
+        card("XTENSION", &quoted("BINTABLE")),
+        card("BITPIX", "8"),
+        card("NAXIS", "2"),
+        card("NAXIS1", "8"),
+        card("NAXIS2", &map.len().to_string()),
+        card("PCOUNT", "0"),
+        card("GCOUNT", "1"),
+        card("TFIELDS", "1"),
+        card("TTYPE1", &quoted("SIGNAL")),
+        card("TFORM1", &quoted("D")),
+        card("PIXTYPE", &quoted("HEALPIX")),
+        card("ORDERING", &quoted(ordering.name())),
+        card("NSIDE", &nside.to_string()),
+        card("FIRSTPIX", "0"),
+        card("LASTPIX", &(map.len() as i64 - 1).to_string()),
+        card("INDXSCHM", &quoted("IMPLICIT")),
+        card("OBJECT", &quoted("FULLSKY")),
+    ];
+    push_header(&mut bytes, &table);
+
+    for x in map {
+        bytes.extend_from_slice(&x.to_be_bytes());
+    }
+    pad(&mut bytes, 0);
+    bytes
+}
+
+/*
+*   Read the first HEALPix BINTABLE in a FITS file, returning nside, the ordering and
+*   the map from its first column. The column may be D (f64) or E (f32), with any
+*   repeat count (healpy writes 1024 pixels per row).
+*/
+pub fn read_healpix_map(bytes: &[u8]) -> Result<(u32, HealpixOrdering, Vec<f64>), ProcessingError> {
+    let invalid = |msg: String| ProcessingError::InvalidFormat(format!("FITS: {}", msg));
+
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let (header, data_start) = read_header(bytes, offset).ok_or_else(|| invalid("truncated header".to_string()))?;
+        let keyword = |key: &str| header.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        let integer = |key: &str| keyword(key).and_then(|v| v.parse::<i64>().ok());
+
+        let naxis = integer("NAXIS").unwrap_or(0);
+        let data_len = if naxis == 0 {
+            0
+        } else {
+            let bitpix = integer("BITPIX").ok_or_else(|| invalid("missing BITPIX".to_string()))?;
+            let elements: i64 = (1..=naxis).map(|n| integer(&format!("NAXIS{}", n)).unwrap_or(0)).product();
+            ((bitpix.abs()/8)*integer("GCOUNT").unwrap_or(1)*(integer("PCOUNT").unwrap_or(0) + elements)) as usize
+        };
+
+        if keyword("XTENSION") == Some("BINTABLE") {
+            let nside = integer("NSIDE").ok_or_else(|| invalid("missing NSIDE".to_string()))? as u32;
+            let ordering = match keyword("ORDERING") {
+                Some("RING") => HealpixOrdering::Ring,
+                Some("NESTED") | Some("NEST") => HealpixOrdering::Nested,
+                other => return Err(invalid(format!("unknown ORDERING {:?}", other))),
+            };
+            if keyword("INDXSCHM").is_some_and(|s| s != "IMPLICIT") {
+                return Err(invalid("only IMPLICIT (full sky) maps are supported".to_string()));
+            }
+            let tform = keyword("TFORM1").ok_or_else(|| invalid("missing TFORM1".to_string()))?;
+            let (repeat, size) = match tform.split_at(tform.len().saturating_sub(1)) {
+                (n, "D") => (n.parse::<usize>().unwrap_or(1), 8),
+                (n, "E") => (n.parse::<usize>().unwrap_or(1), 4),
+                _ => return Err(invalid(format!("unsupported column format '{}'", tform))),
+            };
+            let row = integer("NAXIS1").unwrap_or(0) as usize;
+            let rows = integer("NAXIS2").unwrap_or(0) as usize;
+            let npix = 12*(nside as usize)*(nside as usize);
+            if repeat*rows != npix || row < repeat*size {
+                return Err(invalid(format!("{} values for nside {}", repeat*rows, nside)));
+            }
+            if bytes.len() < data_start + row*rows {
+                return Err(invalid("truncated data".to_string()));
+            }
+
+            let mut map = Vec::with_capacity(npix);
+            for r in 0..rows {
+                for k in 0..repeat {
+                    let at = data_start + r*row + k*size;
+                    map.push(match size {
+                        8 => f64::from_be_bytes(bytes[at..at + 8].try_into().unwrap()),
+                        _ => f32::from_be_bytes(bytes[at..at + 4].try_into().unwrap()) as f64,
+                    });
+                }
+            }
+            return Ok((nside, ordering, map));
+        }
+        offset = data_start + data_len.div_ceil(BLOCK)*BLOCK;
+    }
+    Err(invalid("no BINTABLE extension".to_string()))
+}
+
+// A header card: KEYWORD = value, with numbers and logicals right aligned in column 30
+fn card(key: &str, value: &str) -> String {
+    let value = if value.starts_with('\'') {
+        format!("{:<20}", value)
+    } else {
+        format!("{:>20}", value)
+    };
+    format!("{:<8}= {:<70}", key, value)
+}
+
+fn quoted(s: &str) -> String {
+    format!("'{:<8}'", s)
+}
+
+fn push_header(bytes: &mut Vec<u8>, cards: &[String]) {
+    for c in cards.iter().chain(std::iter::once(&format!("{:<80}", "END"))) {
+        bytes.extend_from_slice(c.as_bytes());
+    }
+    pad(bytes, b' ');
+}
+
+fn pad(bytes: &mut Vec<u8>, fill: u8) {
+    let len = bytes.len().div_ceil(BLOCK)*BLOCK;
+    bytes.resize(len, fill);
+}
+
+/*
+*   The (keyword, value) pairs of the header at offset, with string values unquoted,
+*   and the offset of its data.
+*/
+fn read_header(bytes: &[u8], offset: usize) -> Option<(Vec<(String, String)>, usize)> {
+    let mut header = Vec::new();
+    let mut at = offset;
+    loop {
+        let card = std::str::from_utf8(bytes.get(at..at + CARD)?).ok()?;
+        at += CARD;
+        let key = card.get(0..8)?.trim();
+        if key == "END" {
+            break;
+        }
+        if card.get(8..10) != Some("= ") {
+            continue;   // COMMENT, HISTORY and blank cards
+        }
+        let value = card.get(10..)?.trim();
+        let value = if let Some(quoted) = value.strip_prefix('\'') {
+            quoted.split('\'').next().unwrap_or("").trim_end().to_string()
+        } else {
+            value.split('/').next().unwrap_or("").trim().to_string()
+        };
+        header.push((key.to_string(), value));
+    }
+    Some((header, at.div_ceil(BLOCK)*BLOCK))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let nside = 4;
+        let map: Vec<f64> = (0..192).map(|i| (i as f64)*0.5 - 3.0).collect();
+        for &ordering in &[HealpixOrdering::Ring, HealpixOrdering::Nested] {
+            let bytes = write_healpix_map(&map, nside, ordering);
+            assert_eq!(bytes.len() % BLOCK, 0);
+            assert_eq!(read_healpix_map(&bytes), Ok((nside, ordering, map.clone())));
+        }
+
+        let bytes = write_healpix_map(&map, nside, HealpixOrdering::Nested);
+        let text = String::from_utf8_lossy(&bytes[BLOCK..2*BLOCK]).to_string();
+        assert!(text.contains("ORDERING= 'NESTED  '"));
+        assert!(text.contains("NSIDE   =                    4"));
+    }
+
+    #[test]
+    fn test_float_rows() {
+        // healpy style: 1024E rows of f32, here 48E for nside 4
+        let mut bytes = Vec::new();
+        push_header(&mut bytes, &[card("SIMPLE", "T"), card("BITPIX", "8"), card("NAXIS", "0")]);
+        push_header(&mut bytes, &[
+            card("XTENSION", &quoted("BINTABLE")), card("BITPIX", "8"), card("NAXIS", "2"),
+            card("NAXIS1", "192"), card("NAXIS2", "4"), card("TFIELDS", "1"),
+            card("TFORM1", &quoted("48E")), card("ORDERING", &quoted("RING")), card("NSIDE", "4"),
+        ]);
+        for i in 0..192 {
+            bytes.extend_from_slice(&(i as f32).to_be_bytes());
+        }
+        pad(&mut bytes, 0);
+
+        let (nside, ordering, map) = read_healpix_map(&bytes).unwrap();
+        assert_eq!((nside, ordering), (4, HealpixOrdering::Ring));
+        assert_eq!(map, (0..192).map(|i| i as f64).collect::<Vec<f64>>());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(read_healpix_map(b"not a fits file").is_err());
+        let mut bytes = write_healpix_map(&[0.0; 192], 4, HealpixOrdering::Ring);
+        bytes.truncate(2*BLOCK + 100);
+        match read_healpix_map(&bytes) {
+            Err(ProcessingError::InvalidFormat(msg)) => assert!(msg.contains("truncated")),
+            _ => panic!("Expected an InvalidFormat error"),
+        }
+        assert!(read_healpix_map(&write_healpix_map(&[0.0; 100], 4, HealpixOrdering::Ring)).is_err());
+    }
+}
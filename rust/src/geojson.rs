@@ -0,0 +1,114 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+// The projected pixels of a hemisphere as a GeoJSON-style FeatureCollection, for
+// loading an image into GIS and analysis tools as polygons with values.
+//
+
+use sphere::Hemisphere;
+
+/*
+*   A closed ring of the four projected corners of a pixel.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Geometry {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub coordinates: Vec<Vec<[f32; 2]>>,
+}
+
+/*
+*   The value of a pixel (null where unseen) and its centre, el and az in degrees.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PixelProperties {
+    pub healpix_index: u64,
+    pub value: f64,
+    pub el: f64,
+    pub az: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Feature {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub geometry: Geometry,
+    pub properties: PixelProperties,
+}
+
+/*
+*   One Polygon feature per visible pixel, in visible_pix order. The coordinates are
+*   those of Hemisphere::corners_normalized: the horizon is the circle inscribed in the
+*   unit square with (0, 0) at the top left, so north is up and y increases southwards.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FeatureCollection {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub features: Vec<Feature>,
+}
+
+impl FeatureCollection {
+    pub fn from_hemisphere(sky: &Hemisphere) -> FeatureCollection {
+        let corners = sky.corners_normalized();
+        let features = sky.iter_pixels().zip(corners.chunks(8)).map(|(pixel, c)| {
+            let mut ring: Vec<[f32; 2]> = c.chunks(2).map(|xy| [xy[0], xy[1]]).collect();
+            ring.push(ring[0]);
+            Feature {
+                kind: "Feature",
+                geometry: Geometry {
+                    kind: "Polygon",
+                    coordinates: vec![ring],
+                },
+                properties: PixelProperties {
+                    healpix_index: pixel.healpix_index,
+                    value: pixel.value,
+                    el: pixel.el.to_degrees(),
+                    az: pixel.az.to_degrees(),
+                },
+            }
+        }).collect();
+
+        FeatureCollection {
+            kind: "FeatureCollection",
+            features,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("a FeatureCollection always serializes")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_from_hemisphere() {
+        let mut sky = Hemisphere::new(4);
+        sky.visible_pix[3] = 2.5;
+        sky.visible_pix[5] = f64::NAN;
+        let collection = FeatureCollection::from_hemisphere(&sky);
+        assert_eq!(collection.features.len(), sky.npix);
+
+        let corners = sky.corners_normalized();
+        let feature = &collection.features[3];
+        assert_eq!(feature.properties.value, 2.5);
+        assert_eq!(feature.properties.healpix_index, sky.visible_indices[3]);
+        let ring = &feature.geometry.coordinates[0];
+        assert_eq!(ring.len(), 5);
+        assert_eq!(ring[0], ring[4]);
+        assert_eq!(ring[1], [corners[3*8 + 2], corners[3*8 + 3]]);
+
+        let json: Value = serde_json::from_str(&collection.to_json()).unwrap();
+        assert_eq!(json["type"], "FeatureCollection");
+        assert_eq!(json["features"][3]["type"], "Feature");
+        assert_eq!(json["features"][3]["geometry"]["type"], "Polygon");
+        assert_eq!(json["features"][3]["properties"]["value"], 2.5);
+        assert!(json["features"][5]["properties"]["value"].is_null());
+    }
+}
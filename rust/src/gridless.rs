@@ -2,36 +2,223 @@
 // Copyright (c) 2019-2021 Tim Molteno tim@elec.ac.nz
 //
 use utils::{VectorReal, VectorComplex, C64, PI};
-use sphere::Hemisphere;
+use sphere::{Hemisphere, ElAz};
+use config::ProcessingConfig;
+use iteration::IterationReport;
+use error::ProcessingError;
 
 use ndarray::{Ix1};
+use gridless_kernel::{self, Baselines, Directions};
+
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/*
+*   The sign of the exponent in each harmonic exp(+/-2 pi i theta). Negative is the
+*   usual imaging convention for visibilities V = sum I exp(+2 pi i theta). Datasets
+*   from correlators using the opposite sign image mirrored through the zenith
+*   ((l, m) -> (-l, -m)) unless Positive is chosen.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub enum ExponentSign {
+    #[default]
+    Negative,
+    Positive,
+}
+
+/*
+*   What w multiplies in theta = u l + v m + w (...). NMinusOne, w (n - 1), measures it
+*   from the phase centre at the zenith, so zenith sources have zero phase. N, w n, adds
+*   a fixed phase 2 pi w to each baseline, for visibilities that were not phase-rotated
+*   to the zenith.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub enum WReference {
+    #[default]
+    NMinusOne,
+    N,
+}
+
+/*
+*   The phase convention of the transform: each harmonic is exp(sign 2 pi i theta) with
+*   theta = u l + v m + w (n - 1) or u l + v m + w n. The default is the usual
+*   exp(-2 pi i (u l + v m + w (n - 1))). The w term is left out entirely when
+*   include_w_term is false, whatever w_reference is.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct FourierConvention {
+    pub sign: ExponentSign,
+    pub w_reference: WReference,
+}
+
+impl FourierConvention {
+    fn kernel(&self, include_w_term: bool) -> gridless_kernel::Convention {
+        gridless_kernel::Convention {
+            sign: match self.sign {
+                ExponentSign::Negative => -1.0,
+                ExponentSign::Positive => 1.0,
+            },
+            w_reference: match self.w_reference {
+                _ if !include_w_term => None,
+                WReference::NMinusOne => Some(1.0),
+                WReference::N => Some(0.0),
+            },
+        }
+    }
+}
+
+// Parse a convention, ignoring case: the sign (negative or -, positive or +), optionally
+// followed by :n-1 or :n for the w term, e.g. positive:n. The w term defaults to n-1.
+impl FromStr for FourierConvention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FourierConvention, String> {
+        let lower = s.trim().to_lowercase();
+        let mut parts = lower.splitn(2, ':');
+        let sign = match parts.next().unwrap_or("").trim() {
+            "negative" | "-" => ExponentSign::Negative,
+            "positive" | "+" => ExponentSign::Positive,
+            _ => return Err(format!("unknown convention '{}', expected SIGN or SIGN:n-1 or SIGN:n", s)),
+        };
+        let w_reference = match parts.next().map(|w| w.trim()) {
+            None | Some("n-1") => WReference::NMinusOne,
+            Some("n") => WReference::N,
+            Some(w) => return Err(format!("unknown w term '{}' in '{}', expected n-1 or n", w, s)),
+        };
+        Ok(FourierConvention {
+            sign,
+            w_reference,
+        })
+    }
+}
 
 pub fn get_harmonics(
             sky: &Hemisphere,
             u_arr: &VectorReal, 
             v_arr: &VectorReal, 
             w_arr: &VectorReal) -> Vec::<VectorComplex> {
+    get_harmonics_w(sky, u_arr, v_arr, w_arr, true)
+}
+
+
+/*
+*   As get_harmonics, but the w*(n-1) term can be dropped for (near) coplanar arrays.
+*/
+pub fn get_harmonics_w(
+            sky: &Hemisphere,
+            u_arr: &VectorReal, 
+            v_arr: &VectorReal, 
+            w_arr: &VectorReal,
+            include_w_term: bool) -> Vec::<VectorComplex> {
+    get_harmonics_with(sky, u_arr, v_arr, w_arr, include_w_term, &FourierConvention::default())
+}
+
+
+/*
+*   As get_harmonics_w, in the given phase convention.
+*/
+pub fn get_harmonics_with(
+            sky: &Hemisphere,
+            u_arr: &VectorReal, 
+            v_arr: &VectorReal, 
+            w_arr: &VectorReal,
+            include_w_term: bool,
+            convention: &FourierConvention) -> Vec::<VectorComplex> {
                         
     let mut harmonics = Vec::new();
     
-    let n_arr_minus_1 = &sky.n - 1.0;
+    let phase = convention.kernel(include_w_term);
+    let n_arr_minus_ref = &sky.n - phase.w_reference.unwrap_or(0.0);
 
-    let p2j = C64::new(0.0, 2.0*PI);
+    let p2j = C64::new(0.0, phase.sign*2.0*PI);
     
     for i in 0..u_arr.len() {
         let u = u_arr[i];
         let v = v_arr[i];
-        let w = w_arr[i];
         
-        let theta = u*&sky.l + v*&sky.m + w*&n_arr_minus_1;
-        let harmonic = theta.mapv(|x| (-p2j*x).exp() / (sky.npix as f64).sqrt());
+        let mut theta = u*&sky.l + v*&sky.m;
+        if phase.w_reference.is_some() {
+            theta = theta + w_arr[i]*&n_arr_minus_ref;
+        }
+        let harmonic = theta.mapv(|x| (p2j*x).exp() / (sky.npix as f64).sqrt());
         harmonics.push(harmonic);
     }
 
-    return harmonics
+    harmonics
 }
 
 
+/*
+*   Harmonics for a fixed array geometry and hemisphere, kept so that repeated
+*   snapshots from the same array skip recomputing them. Holds one complex vector
+*   of npix per baseline, so the memory cost is 16*npix*n_baselines bytes.
+*/
+pub struct HarmonicCache {
+    nside: u32,
+    u: VectorReal,
+    v: VectorReal,
+    w: VectorReal,
+    include_w_term: bool,
+    convention: FourierConvention,
+    pub harmonics: Vec<VectorComplex>,
+}
+
+impl HarmonicCache {
+    pub fn new(sky: &Hemisphere,
+               u: &VectorReal,
+               v: &VectorReal,
+               w: &VectorReal,
+               include_w_term: bool,
+               convention: &FourierConvention) -> HarmonicCache {
+        HarmonicCache {
+            nside: sky.nside,
+            u: u.clone(),
+            v: v.clone(),
+            w: w.clone(),
+            include_w_term,
+            convention: *convention,
+            harmonics: get_harmonics_with(sky, u, v, w, include_w_term, convention),
+        }
+    }
+
+    // True if these harmonics are valid for imaging onto sky with these baselines
+    pub fn matches(&self,
+                   sky: &Hemisphere,
+                   u: &VectorReal,
+                   v: &VectorReal,
+                   w: &VectorReal,
+                   include_w_term: bool,
+                   convention: &FourierConvention) -> bool {
+        self.nside == sky.nside && self.include_w_term == include_w_term &&
+            self.convention == *convention && self.u == u && self.v == v && self.w == w
+    }
+}
+
+
+/*
+*   Visibilities of a point source of flux jy at (el, az) in radians, using the same
+*   (default) phase convention as get_harmonics so that the source images at (el, az).
+*/
+pub fn point_source_vis(
+            u_arr: &VectorReal, 
+            v_arr: &VectorReal, 
+            w_arr: &VectorReal,
+            el: f64,
+            az: f64,
+            jy: f64) -> VectorComplex {
+
+    let (l, m, n) = ElAz::new(el, az).to_lmn();
+    let p2j = C64::new(0.0, 2.0*PI);
+
+    let theta = u_arr*l + v_arr*m + w_arr*(n - 1.0);
+    theta.mapv(|x| jy*(p2j*x).exp())
+}
+
+
+/*
+*   Image the visibilities onto the sky. Any existing values in sky.visible_pix
+*   are replaced, not accumulated.
+*/
 pub fn image_visibilities( 
             vis: &VectorComplex,
             u: &VectorReal,
@@ -39,13 +226,222 @@ pub fn image_visibilities(
             w: &VectorReal,
             sky: &mut Hemisphere,
             real_only: bool)
+{
+    image(vis, u, v, w, sky, real_only, true, &FourierConvention::default());
+}
+
+
+/*
+*   Image the visibilities using the options in config.
+*/
+pub fn image_visibilities_with_config( 
+            vis: &VectorComplex,
+            u: &VectorReal,
+            v: &VectorReal,
+            w: &VectorReal,
+            sky: &mut Hemisphere,
+            config: &ProcessingConfig)
+{
+    match configured_baselines(vis, u, v, w, config) {
+        Some((vis, u, v, w)) => image(&vis, &u, &v, &w, sky, false, config.include_w_term, &config.fourier_convention),
+        None => image(vis, u, v, w, sky, false, config.include_w_term, &config.fourier_convention),
+    }
+}
+
+
+/*
+*   The visibilities to image under config: those within baseline_filter, thinned by
+*   decimate, completed with their conjugates if assume_hermitian. None when config
+*   changes nothing, so the caller can use its own arrays without a copy.
+*/
+pub fn configured_baselines(
+            vis: &VectorComplex,
+            u: &VectorReal,
+            v: &VectorReal,
+            w: &VectorReal,
+            config: &ProcessingConfig) -> Option<(VectorComplex, VectorReal, VectorReal, VectorReal)>
+{
+    let filtered = config.baseline_filter.map(|range| filter_baselines(vis, u, v, w, range));
+    let filtered = match (config.decimate, filtered) {
+        (Some(n), Some((vis, u, v, w))) if n > 1 => Some(decimate_baselines(&vis, &u, &v, &w, n)),
+        (Some(n), None) if n > 1 => Some(decimate_baselines(vis, u, v, w, n)),
+        (_, filtered) => filtered,
+    };
+    if !config.assume_hermitian {
+        return filtered;
+    }
+    Some(match filtered {
+        Some((vis, u, v, w)) => hermitian_complete(&vis, &u, &v, &w),
+        None => hermitian_complete(vis, u, v, w),
+    })
+}
+
+
+/*
+*   Append the conjugate of each visibility on the reversed baseline (-u, -v, -w).
+*   For datasets that hold only one of each conjugate pair, this makes the image real:
+*   the imaginary parts of each pair cancel. Datasets already holding both halves
+*   should not be completed, as every pair would be counted twice.
+*/
+pub fn hermitian_complete(
+            vis: &VectorComplex,
+            u: &VectorReal,
+            v: &VectorReal,
+            w: &VectorReal) -> (VectorComplex, VectorReal, VectorReal, VectorReal)
+{
+    let n = vis.len();
+    (VectorComplex::from_shape_fn(2*n, |k| if k < n { vis[k] } else { vis[k - n].conj() }),
+     VectorReal::from_shape_fn(2*n, |k| if k < n { u[k] } else { -u[k - n] }),
+     VectorReal::from_shape_fn(2*n, |k| if k < n { v[k] } else { -v[k - n] }),
+     VectorReal::from_shape_fn(2*n, |k| if k < n { w[k] } else { -w[k - n] }))
+}
+
+
+/*
+*   Keep only the baselines with min <= |uv| <= max (in wavelengths).
+*/
+pub fn filter_baselines(
+            vis: &VectorComplex,
+            u: &VectorReal,
+            v: &VectorReal,
+            w: &VectorReal,
+            (min, max): (f32, f32)) -> (VectorComplex, VectorReal, VectorReal, VectorReal)
+{
+    let keep: Vec<usize> = (0..u.len())
+        .filter(|&i| {
+            let uv = (u[i]*u[i] + v[i]*v[i]).sqrt();
+            uv >= min as f64 && uv <= max as f64
+        })
+        .collect();
+    select_baselines(vis, u, v, w, &keep)
+}
+
+
+/*
+*   Keep every nth baseline, starting with the first. Deterministic, and as the
+*   baselines are usually ordered by antenna pair this samples the whole array.
+*/
+pub fn decimate_baselines(
+            vis: &VectorComplex,
+            u: &VectorReal,
+            v: &VectorReal,
+            w: &VectorReal,
+            n: usize) -> (VectorComplex, VectorReal, VectorReal, VectorReal)
+{
+    let keep: Vec<usize> = (0..vis.len()).step_by(n.max(1)).collect();
+    select_baselines(vis, u, v, w, &keep)
+}
+
+
+fn select_baselines(
+            vis: &VectorComplex,
+            u: &VectorReal,
+            v: &VectorReal,
+            w: &VectorReal,
+            keep: &[usize]) -> (VectorComplex, VectorReal, VectorReal, VectorReal)
+{
+    (VectorComplex::from_shape_fn(keep.len(), |k| vis[keep[k]]),
+     VectorReal::from_shape_fn(keep.len(), |k| u[keep[k]]),
+     VectorReal::from_shape_fn(keep.len(), |k| v[keep[k]]),
+     VectorReal::from_shape_fn(keep.len(), |k| w[keep[k]]))
+}
+
+
+#[allow(clippy::too_many_arguments)]
+fn image( 
+            vis: &VectorComplex,
+            u: &VectorReal,
+            v: &VectorReal,
+            w: &VectorReal,
+            sky: &mut Hemisphere,
+            real_only: bool,
+            include_w_term: bool,
+            convention: &FourierConvention)
+{
+    sky.visible_pix = image_at_directions_with(vis, u, v, w, &sky.l, &sky.m, &sky.n, real_only, include_w_term, convention);
+}
+
+
+/*
+*   The gridless transform evaluated at arbitrary direction cosines (l, m, n), e.g. a
+*   1D slice or a custom grid, independent of Hemisphere. The normalization is as for
+*   get_harmonics with npix = l.len(). The sum itself is gridless_kernel::harmonic_sum,
+*   which needs no std (for embedded use) and no memory beyond the output.
+*/
+#[allow(clippy::too_many_arguments)]
+pub fn image_at_directions(
+            vis: &VectorComplex,
+            u: &VectorReal,
+            v: &VectorReal,
+            w: &VectorReal,
+            l: &VectorReal,
+            m: &VectorReal,
+            n: &VectorReal,
+            real_only: bool,
+            include_w_term: bool) -> VectorReal
+{
+    image_at_directions_with(vis, u, v, w, l, m, n, real_only, include_w_term, &FourierConvention::default())
+}
+
+
+/*
+*   As image_at_directions, in the given phase convention.
+*/
+#[allow(clippy::too_many_arguments)]
+pub fn image_at_directions_with(
+            vis: &VectorComplex,
+            u: &VectorReal,
+            v: &VectorReal,
+            w: &VectorReal,
+            l: &VectorReal,
+            m: &VectorReal,
+            n: &VectorReal,
+            real_only: bool,
+            include_w_term: bool,
+            convention: &FourierConvention) -> VectorReal
+{
+    let vis_re: Vec<f64> = vis.iter().map(|v| v.re).collect();
+    let vis_im: Vec<f64> = vis.iter().map(|v| v.im).collect();
+    let (u, v, w) = (contiguous(u), contiguous(v), contiguous(w));
+    let (l, m, n) = (contiguous(l), contiguous(m), contiguous(n));
+
+    let mut re = vec![0.0; l.len()];
+    let mut im = vec![0.0; l.len()];
+    gridless_kernel::harmonic_sum_with(&vis_re, &vis_im,
+                                       &Baselines { u: &u, v: &v, w: &w },
+                                       &Directions { l: &l, m: &m, n: &n },
+                                       &convention.kernel(include_w_term), &mut re, &mut im);
+
+    if real_only {
+        VectorReal::from_vec(re)
+    } else {
+        VectorReal::from_shape_fn(re.len(), |k| C64::new(re[k], im[k]).norm())
+    }
+}
+
+
+// The elements of a as a slice, copying only if it is not laid out contiguously
+fn contiguous(a: &VectorReal) -> Cow<'_, [f64]> {
+    match a.as_slice() {
+        Some(s) => Cow::Borrowed(s),
+        None => Cow::Owned(a.to_vec()),
+    }
+}
+
+
+/*
+*   Image the visibilities using precomputed harmonics (one per visibility).
+*/
+pub fn image_with_harmonics(
+            vis: &VectorComplex,
+            harmonics: &[VectorComplex],
+            sky: &mut Hemisphere,
+            real_only: bool)
 {
     let n_s = &sky.visible_pix.len();
 
     let mut pixels = VectorComplex::zeros(Ix1(*n_s));
 
-    let harmonics = get_harmonics(sky, u, v, w);
-    
     for i in 0..vis.len() {
         let v = vis[i];
         pixels = pixels + v * &harmonics[i];
@@ -57,3 +453,387 @@ pub fn image_visibilities(
         sky.visible_pix = pixels.mapv(|p| p.norm());
     }
 }
+
+
+/*
+*   The dirty beam (point spread function) of an array on a hemisphere. It varies
+*   across the sky, so the response to a unit source at any pixel is formed from the
+*   harmonics as needed. Memory as for HarmonicCache. An array with no baselines (e.g.
+*   all of them removed by baseline_filter) has no beam, and new() refuses it.
+*/
+pub struct DirtyBeam {
+    harmonics: Vec<VectorComplex>,
+    // FWHM (radians) of the Gaussian clean beam, about 1/(longest baseline in wavelengths)
+    pub clean_beam_fwhm: f64,
+}
+
+impl DirtyBeam {
+    pub fn new(sky: &Hemisphere,
+               u: &VectorReal,
+               v: &VectorReal,
+               w: &VectorReal,
+               include_w_term: bool) -> Result<DirtyBeam, ProcessingError> {
+        if u.is_empty() {
+            return Err(ProcessingError::InvalidFormat("no baselines to form a dirty beam".to_string()));
+        }
+        let mut max_uv: f64 = 0.0;
+        for i in 0..u.len() {
+            max_uv = max_uv.max((u[i]*u[i] + v[i]*v[i]).sqrt());
+        }
+        Ok(DirtyBeam {
+            harmonics: get_harmonics_w(sky, u, v, w, include_w_term),
+            clean_beam_fwhm: if max_uv > 0.0 { 1.0 / max_uv } else { PI },
+        })
+    }
+
+    // The real-only image of a unit point source at visible pixel k, normalized to 1 at k.
+    // A beam with no response at k is returned as it is (all zero there) rather than as NaN.
+    pub fn centered_at(&self, k: usize) -> VectorReal {
+        let mut beam = VectorComplex::zeros(Ix1(self.harmonics[0].len()));
+        for h in &self.harmonics {
+            beam.scaled_add(h[k].conj(), h);
+        }
+        let beam = beam.mapv(|b| b.re);
+        let peak = beam[k];
+        if peak == 0.0 {
+            return beam;
+        }
+        beam / peak
+    }
+}
+
+/*
+*   A point source found by clean(), with its flux in image units.
+*/
+#[derive(Debug, Clone)]
+pub struct CleanComponent {
+    pub index: usize, // into visible_pix
+    pub healpix_index: u64,
+    pub el: f64,
+    pub az: f64,
+    pub flux: f64,
+}
+
+/*
+*   Högbom CLEAN. The sky must be a real-only image (image_visibilities(.., true)) so
+*   that it is linear in the visibilities. Repeatedly subtracts gain times the dirty beam
+*   at the brightest (absolute) residual pixel, stopping after n_iter components or when
+*   the peak falls below threshold. Returns the residual plus the components restored
+*   with a Gaussian clean beam, the components themselves, and the peak residual and
+*   whether it reached threshold.
+*/
+pub fn clean(sky: &Hemisphere,
+             dirty_beam: &DirtyBeam,
+             gain: f64,
+             n_iter: usize,
+             threshold: f64) -> (Hemisphere, Vec<CleanComponent>, IterationReport) {
+    let mut residual = sky.visible_pix.clone();
+    let mut components = Vec::new();
+
+    let peak = |residual: &VectorReal| {
+        let mut k = 0;
+        for i in 0..residual.len() {
+            if residual[i].abs() > residual[k].abs() {
+                k = i;
+            }
+        }
+        k
+    };
+    let peak_residual = |residual: &VectorReal| residual.get(peak(residual)).map_or(0.0, |r| r.abs());
+
+    for _ in 0..n_iter {
+        let k = peak(&residual);
+        if sky.npix == 0 || residual[k].abs() < threshold {
+            break;
+        }
+
+        let flux = gain * residual[k];
+        residual.scaled_add(-flux, &dirty_beam.centered_at(k));
+        components.push(CleanComponent {
+            index: k,
+            healpix_index: sky.visible_indices[k],
+            el: sky.n[k].min(1.0).asin(),
+            az: sky.l[k].atan2(sky.m[k]),
+            flux,
+        });
+    }
+
+    let report = IterationReport::new(components.len(), n_iter, peak_residual(&residual), threshold);
+
+    // Restore
+    let sigma = dirty_beam.clean_beam_fwhm / (8.0 * 2.0_f64.ln()).sqrt();
+    for c in &components {
+        let k = c.index;
+        let cos_sep = (&sky.l*sky.l[k] + &sky.m*sky.m[k] + &sky.n*sky.n[k]).mapv(|x| x.min(1.0).acos());
+        let gaussian = cos_sep.mapv(|s| (-s*s / (2.0*sigma*sigma)).exp());
+        residual.scaled_add(c.flux, &gaussian);
+    }
+
+    let mut restored = sky.clone();
+    restored.visible_pix = residual;
+    (restored, components, report)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use img;
+
+    // uvw for a ring of 8 antennas of growing radius, in wavelengths
+    fn ring_uvw() -> (VectorReal, VectorReal, VectorReal) {
+        let num_ant = 8;
+        let mut baselines = Vec::new();
+        let mut x = Vec::new();
+        let mut y = Vec::new();
+        for i in 0..num_ant {
+            let angle = 2.0*PI*(i as f64)/(num_ant as f64);
+            let r = 0.5 + 0.1*(i as f64);
+            x.push(r*angle.cos());
+            y.push(r*angle.sin());
+            for j in (i+1)..num_ant {
+                baselines.push((i as u32, j as u32));
+            }
+        }
+        img::get_uvw(&baselines, &VectorReal::from_vec(x), &VectorReal::from_vec(y), &VectorReal::zeros(num_ant))
+    }
+
+    fn peak_index(pix: &VectorReal) -> usize {
+        let mut k = 0;
+        for i in 0..pix.len() {
+            if pix[i] > pix[k] {
+                k = i;
+            }
+        }
+        k
+    }
+
+    #[test]
+    fn test_clean_point_sources() {
+        let (u, v, w) = ring_uvw();
+        let vis = point_source_vis(&u, &v, &w, 1.0, 0.5, 1.0)
+            + point_source_vis(&u, &v, &w, 0.6, 3.0, 0.5);
+
+        let mut sky = Hemisphere::new(16);
+        image_visibilities(&vis, &u, &v, &w, &mut sky, true);
+        let dirty_peak = sky.visible_pix[peak_index(&sky.visible_pix)];
+
+        let beam = DirtyBeam::new(&sky, &u, &v, &w, true).unwrap();
+        let (restored, components, report) = clean(&sky, &beam, 0.1, 200, 0.02*dirty_peak);
+
+        assert!(!components.is_empty() && components.len() <= 200);
+        assert_eq!(report.iterations, components.len());
+        assert_eq!(report.converged, report.iterations < 200);
+        assert_eq!(report.converged, report.residual < 0.02*dirty_peak);
+
+        // Too few iterations to get there
+        let (_, capped, report) = clean(&sky, &beam, 0.1, 3, 0.02*dirty_peak);
+        assert_eq!(capped.len(), 3);
+        assert!(!report.converged && report.residual >= 0.02*dirty_peak);
+
+        // The first component is the brighter source
+        let first = ElAz::new(components[0].el, components[0].az);
+        assert!(first.angular_separation(&ElAz::new(1.0, 0.5)) < 0.2);
+
+        // Both sources appear as components
+        let near = |el: f64, az: f64| components.iter()
+            .filter(|c| ElAz::new(c.el, c.az).angular_separation(&ElAz::new(el, az)) < 0.2)
+            .map(|c| c.flux).sum::<f64>();
+        assert!(near(0.6, 3.0) > 0.0);
+        assert!(near(1.0, 0.5) > near(0.6, 3.0));
+
+        assert_eq!(restored.npix, sky.npix);
+    }
+
+    // Number of pixels at or above half the peak, a measure of the main lobe area
+    fn half_max_area(pix: &VectorReal) -> usize {
+        let max = pix.iter().cloned().fold(f64::MIN, f64::max);
+        pix.iter().filter(|&&p| p >= 0.5*max).count()
+    }
+
+    #[test]
+    fn test_image_at_directions() {
+        let (u, v, w) = ring_uvw();
+        let vis = point_source_vis(&u, &v, &w, 1.0, 0.5, 1.0);
+
+        // Matches imaging via stored harmonics on the hemisphere's own directions
+        let mut sky = Hemisphere::new(8);
+        let harmonics = get_harmonics(&sky, &u, &v, &w);
+        image_with_harmonics(&vis, &harmonics, &mut sky, false);
+        let direct = image_at_directions(&vis, &u, &v, &w, &sky.l, &sky.m, &sky.n, false, true);
+        assert!(direct.iter().zip(sky.visible_pix.iter()).all(|(a, b)| (a - b).abs() < 1.0e-12));
+
+        // A slice through the source in azimuth peaks at the source
+        let n_slice = 91;
+        let el = VectorReal::linspace(0.1, PI/2.0, n_slice);
+        let dirs: Vec<(f64, f64, f64)> = el.iter().map(|e| ElAz::new(*e, 0.5).to_lmn()).collect();
+        let l = VectorReal::from_shape_fn(n_slice, |i| dirs[i].0);
+        let m = VectorReal::from_shape_fn(n_slice, |i| dirs[i].1);
+        let n = VectorReal::from_shape_fn(n_slice, |i| dirs[i].2);
+        let slice = image_at_directions(&vis, &u, &v, &w, &l, &m, &n, false, true);
+        assert!((el[peak_index(&slice)] - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_fourier_convention() {
+        let (u, v, w) = ring_uvw();
+        let vis = point_source_vis(&u, &v, &w, 1.0, 0.5, 1.0)
+            + point_source_vis(&u, &v, &w, 0.6, 2.5, 0.4);
+        let sky = Hemisphere::new(8);
+        let positive = FourierConvention {
+            sign: ExponentSign::Positive,
+            ..FourierConvention::default()
+        };
+
+        // The default is the original convention
+        let standard = image_at_directions(&vis, &u, &v, &w, &sky.l, &sky.m, &sky.n, false, true);
+        let default = image_at_directions_with(&vis, &u, &v, &w, &sky.l, &sky.m, &sky.n, false, true, &FourierConvention::default());
+        assert_eq!(standard, default);
+
+        // Flipping the sign mirrors the image through the zenith, (l, m) -> (-l, -m)
+        let flipped = image_at_directions_with(&vis, &u, &v, &w, &sky.l, &sky.m, &sky.n, false, true, &positive);
+        let mirrored = image_at_directions(&vis, &u, &v, &w, &(-&sky.l), &(-&sky.m), &sky.n, false, true);
+        assert!(flipped.iter().zip(mirrored.iter()).all(|(a, b)| (a - b).abs() < 1.0e-12));
+        assert!(flipped.iter().zip(standard.iter()).any(|(a, b)| (a - b).abs() > 1.0e-3));
+
+        // The cached harmonics agree with the kernel in every convention
+        for convention in [positive, "-:n".parse().unwrap(), "+:n".parse().unwrap()].iter() {
+            let mut cached = Hemisphere::new(8);
+            let harmonics = get_harmonics_with(&cached, &u, &v, &(&w + 0.3), true, convention);
+            image_with_harmonics(&vis, &harmonics, &mut cached, false);
+            let direct = image_at_directions_with(&vis, &u, &v, &(&w + 0.3), &sky.l, &sky.m, &sky.n, false, true, convention);
+            assert!(direct.iter().zip(cached.visible_pix.iter()).all(|(a, b)| (a - b).abs() < 1.0e-12));
+        }
+    }
+
+    #[test]
+    fn test_fourier_convention_from_str() {
+        assert_eq!("negative".parse(), Ok(FourierConvention::default()));
+        assert_eq!("Positive:N".parse(), Ok(FourierConvention { sign: ExponentSign::Positive, w_reference: WReference::N }));
+        assert_eq!("+:n-1".parse::<FourierConvention>().map(|c| c.w_reference), Ok(WReference::NMinusOne));
+        assert!("up".parse::<FourierConvention>().is_err());
+        assert!("-:m".parse::<FourierConvention>().unwrap_err().contains("n-1 or n"));
+    }
+
+    #[test]
+    fn test_short_baselines_smoother() {
+        let (u, v, w) = ring_uvw();
+        let vis = point_source_vis(&u, &v, &w, 1.2, 0.5, 1.0);
+
+        let uv: Vec<f64> = (0..u.len()).map(|i| (u[i]*u[i] + v[i]*v[i]).sqrt()).collect();
+        let median_uv = ::utils::median(&uv).unwrap() as f32;
+
+        let (_, u_short, _, _) = filter_baselines(&vis, &u, &v, &w, (0.0, median_uv));
+        assert!(!u_short.is_empty() && u_short.len() < u.len());
+
+        let mut all = Hemisphere::new(16);
+        image_visibilities_with_config(&vis, &u, &v, &w, &mut all, &ProcessingConfig::default());
+
+        let config = ProcessingConfig {
+            baseline_filter: Some((0.0, median_uv)),
+            ..ProcessingConfig::default()
+        };
+        let mut short = Hemisphere::new(16);
+        image_visibilities_with_config(&vis, &u, &v, &w, &mut short, &config);
+
+        // Without the long baselines the source is imaged with a broader, smoother beam
+        assert!(half_max_area(&short.visible_pix) > half_max_area(&all.visible_pix));
+    }
+
+    #[test]
+    fn test_decimate() {
+        let (u, v, w) = ring_uvw();
+        let vis = point_source_vis(&u, &v, &w, 1.2, 0.5, 1.0)
+            + point_source_vis(&u, &v, &w, 0.6, 2.5, 0.4);
+
+        let (vis2, u2, _, _) = decimate_baselines(&vis, &u, &v, &w, 3);
+        assert_eq!(vis2.len(), 10);
+        assert_eq!((vis2[1], u2[1]), (vis[3], u[3]));
+
+        let config = ProcessingConfig { decimate: Some(3), ..ProcessingConfig::default() };
+        let (vis3, _, _, _) = configured_baselines(&vis, &u, &v, &w, &config).unwrap();
+        assert_eq!(vis3, vis2);
+
+        for n in &[None, Some(0), Some(1)] {
+            let config = ProcessingConfig { decimate: *n, ..ProcessingConfig::default() };
+            assert!(configured_baselines(&vis, &u, &v, &w, &config).is_none());
+        }
+    }
+
+    #[test]
+    fn test_hermitian_complete() {
+        let (u, v, w) = ring_uvw();
+        let vis = point_source_vis(&u, &v, &w, 1.2, 0.5, 1.0)
+            + point_source_vis(&u, &v, &w, 0.6, 2.5, 0.4);
+        let sky = Hemisphere::new(8);
+        let image = |vis: &VectorComplex, u: &VectorReal, v: &VectorReal, w: &VectorReal, real_only| {
+            image_at_directions(vis, u, v, w, &sky.l, &sky.m, &sky.n, real_only, true)
+        };
+
+        // One half of each pair leaves a complex image
+        let re = image(&vis, &u, &v, &w, true);
+        let norm = image(&vis, &u, &v, &w, false);
+        assert!(re.iter().zip(norm.iter()).any(|(r, n)| n - r.abs() > 1.0e-3));
+
+        // Completed, the imaginary part vanishes and |image| is its real part
+        let (vis2, u2, v2, w2) = hermitian_complete(&vis, &u, &v, &w);
+        assert_eq!(vis2.len(), 2*vis.len());
+        let re = image(&vis2, &u2, &v2, &w2, true);
+        let norm = image(&vis2, &u2, &v2, &w2, false);
+        assert!(re.iter().zip(norm.iter()).all(|(r, n)| (n - r.abs()).abs() < 1.0e-9));
+
+        let config = ProcessingConfig { assume_hermitian: true, ..ProcessingConfig::default() };
+        let mut completed = Hemisphere::new(8);
+        image_visibilities_with_config(&vis, &u, &v, &w, &mut completed, &config);
+        assert_eq!(completed.visible_pix, norm);
+        assert!(configured_baselines(&vis, &u, &v, &w, &ProcessingConfig::default()).is_none());
+    }
+
+    #[test]
+    fn test_dirty_beam_peak() {
+        let (u, v, w) = ring_uvw();
+        let sky = Hemisphere::new(4);
+        let beam = DirtyBeam::new(&sky, &u, &v, &w, true).unwrap();
+        let b = beam.centered_at(10);
+        assert_eq!(peak_index(&b), 10);
+        assert!((b[10] - 1.0).abs() < 1.0e-12);
+    }
+
+    #[test]
+    fn test_dirty_beam_no_baselines() {
+        let sky = Hemisphere::new(4);
+        let none = VectorReal::zeros(0);
+        match DirtyBeam::new(&sky, &none, &none, &none, true) {
+            Err(ProcessingError::InvalidFormat(_)) => (),
+            _ => panic!("Expected an InvalidFormat error"),
+        }
+
+        // No response at the pixel: the beam stays zero instead of dividing by it
+        let silent = DirtyBeam { harmonics: vec![VectorComplex::zeros(sky.npix)], clean_beam_fwhm: PI };
+        let b = silent.centered_at(10);
+        assert!(b.iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_w_term_coplanar() {
+        let u = VectorReal::from_vec(vec![1.0, -2.5, 3.0, 0.5]);
+        let v = VectorReal::from_vec(vec![0.5, 1.5, -2.0, 4.0]);
+        let w = VectorReal::from_vec(vec![1.0e-9, -1.0e-9, 0.0, 0.0]);
+        let vis = point_source_vis(&u, &v, &w, 1.0, 0.5, 1.0);
+
+        let mut with_w = Hemisphere::new(8);
+        image_visibilities_with_config(&vis, &u, &v, &w, &mut with_w, &ProcessingConfig::default());
+
+        let config = ProcessingConfig {
+            include_w_term: false,
+            ..ProcessingConfig::default()
+        };
+        let mut without_w = Hemisphere::new(8);
+        image_visibilities_with_config(&vis, &u, &v, &w, &mut without_w, &config);
+
+        for i in 0..with_w.npix {
+            assert!((with_w.visible_pix[i] - without_w.visible_pix[i]).abs() < 1.0e-6);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+use config::ProcessingConfig;
+use gridless::{self, HarmonicCache};
+use sphere::Hemisphere;
+use logging;
+use tart_obs::Observation;
+use utils::{VectorReal, VectorComplex};
+
+/*
+*   The imaging workflow (uvw from the antenna positions, reconstruction onto a
+*   hemisphere, rendering) in one place. With caching enabled, the harmonics are
+*   kept between calls and reused while the array geometry stays the same.
+*/
+pub struct Imager {
+    pub sky: Hemisphere,
+    pub config: ProcessingConfig,
+    cache: Option<HarmonicCache>,
+    use_cache: bool,
+}
+
+impl Imager {
+    pub fn new(nside: u32, config: ProcessingConfig) -> Imager {
+        Imager {
+            sky: Hemisphere::new(nside),
+            config,
+            cache: None,
+            use_cache: false,
+        }
+    }
+
+    // Keep the harmonics between calls to image(). See HarmonicCache for the memory cost.
+    pub fn with_cache(mut self) -> Imager {
+        self.use_cache = true;
+        self
+    }
+
+    pub fn image(&mut self, obs: &Observation) -> &VectorReal {
+        let (u, v, w) = obs.get_uvw(self.config.ant_position_unit);
+        self.image_vis(&obs.vis_arr, &u, &v, &w)
+    }
+
+    pub fn image_vis(&mut self,
+                     vis: &VectorComplex,
+                     u: &VectorReal,
+                     v: &VectorReal,
+                     w: &VectorReal) -> &VectorReal {
+        let include_w_term = self.config.include_w_term;
+        let convention = self.config.fourier_convention;
+        if self.use_cache {
+            let configured;
+            let (vis, u, v, w) = match gridless::configured_baselines(vis, u, v, w, &self.config) {
+                Some(baselines) => {
+                    configured = baselines;
+                    (&configured.0, &configured.1, &configured.2, &configured.3)
+                },
+                None => (vis, u, v, w),
+            };
+            let stale = match self.cache {
+                Some(ref cache) => !cache.matches(&self.sky, u, v, w, include_w_term, &convention),
+                None => true,
+            };
+            if stale {
+                self.cache = Some(HarmonicCache::new(&self.sky, u, v, w, include_w_term, &convention));
+            }
+            let harmonics = &self.cache.as_ref().unwrap().harmonics;
+            gridless::image_with_harmonics(vis, harmonics, &mut self.sky, false);
+        } else {
+            gridless::image_visibilities_with_config(vis, u, v, w, &mut self.sky, &self.config);
+        }
+        if let Some(beam) = self.config.beam {
+            beam.correct(&mut self.sky);
+        }
+        if let Some(fwhm) = self.config.smooth_fwhm {
+            // config.check_nside refuses smoothing at an nside where it fails
+            match self.sky.smooth(fwhm) {
+                Ok(sky) => self.sky = sky,
+                Err(e) => logging::log(&format!("Not smoothed: {}", e)),
+            }
+        }
+        &self.sky.visible_pix
+    }
+
+    pub fn to_svg(&self) -> String {
+        self.sky.to_svg_with_config(&self.config, None).to_string()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn baselines() -> (VectorReal, VectorReal, VectorReal) {
+        let u = VectorReal::from_vec(vec![1.0, -2.0, 3.5, 0.5]);
+        let v = VectorReal::from_vec(vec![0.5, 1.5, -1.0, 2.0]);
+        let w = VectorReal::from_vec(vec![0.1, 0.0, -0.2, 0.05]);
+        (u, v, w)
+    }
+
+    #[test]
+    fn test_cached_matches_uncached() {
+        let (u, v, w) = baselines();
+        let (el, az) = (1.0, 0.5);
+        let vis = gridless::point_source_vis(&u, &v, &w, el, az, 1.0);
+
+        let mut plain = Imager::new(4, ProcessingConfig::default());
+        let mut cached = Imager::new(4, ProcessingConfig::default()).with_cache();
+        for _ in 0..2 {
+            let a = plain.image_vis(&vis, &u, &v, &w).clone();
+            let b = cached.image_vis(&vis, &u, &v, &w);
+            assert!(a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1.0e-12));
+        }
+
+        // A different geometry must not reuse the old harmonics
+        let u2 = &u*2.0;
+        let a = plain.image_vis(&vis, &u2, &v, &w).clone();
+        let b = cached.image_vis(&vis, &u2, &v, &w);
+        assert!(a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1.0e-12));
+    }
+}
@@ -3,15 +3,57 @@
 //
 use utils::{VectorReal, L1_WAVELENGTH};
 
+use std::str::FromStr;
+
+/*
+*   The units of the antenna positions in a dataset. TART datasets are in metres;
+*   simulators often give positions in wavelengths already. Getting this wrong scales
+*   the whole image, so it has to be stated rather than guessed.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub enum AntPositionUnit {
+    #[default]
+    Meters,
+    Wavelengths,
+}
+
+// Parse a unit, ignoring case: meters (or metres, m) or wavelengths
+impl FromStr for AntPositionUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AntPositionUnit, String> {
+        match s.trim().to_lowercase().as_str() {
+            "meters" | "metres" | "m" => Ok(AntPositionUnit::Meters),
+            "wavelengths" => Ok(AntPositionUnit::Wavelengths),
+            _ => Err(format!("unknown antenna position unit '{}', expected meters or wavelengths", s)),
+        }
+    }
+}
+
 fn spatial_frequency(a: f64, b: f64) -> f64 {
     (a - b) / L1_WAVELENGTH
 }
 
+// Baseline coordinates (in wavelengths) from antenna positions in metres
 pub fn get_uvw( baselines: &Vec<(u32, u32)>, 
                 x: &VectorReal, 
                 y: &VectorReal,
                 z: &VectorReal) -> (VectorReal, VectorReal, VectorReal)
 {
+    get_uvw_in(baselines, x, y, z, AntPositionUnit::Meters)
+}
+
+// Baseline coordinates (in wavelengths) from antenna positions in the given unit
+pub fn get_uvw_in(baselines: &Vec<(u32, u32)>,
+                  x: &VectorReal,
+                  y: &VectorReal,
+                  z: &VectorReal,
+                  unit: AntPositionUnit) -> (VectorReal, VectorReal, VectorReal)
+{
+    let frequency = match unit {
+        AntPositionUnit::Meters => spatial_frequency,
+        AntPositionUnit::Wavelengths => |a: f64, b: f64| a - b,
+    };
     let mut uu_a = Vec::new();
     let mut vv_a = Vec::new();
     let mut ww_a = Vec::new();
@@ -19,12 +61,45 @@ pub fn get_uvw( baselines: &Vec<(u32, u32)>,
     for bl in baselines {
         let i = bl.0 as usize;
         let j = bl.1 as usize;
-        uu_a.push(spatial_frequency(x[i], x[j]));
-        vv_a.push(spatial_frequency(y[i], y[j]));
-        ww_a.push(spatial_frequency(z[i], z[j]));
+        uu_a.push(frequency(x[i], x[j]));
+        vv_a.push(frequency(y[i], y[j]));
+        ww_a.push(frequency(z[i], z[j]));
     }
     (   VectorReal::from_vec(uu_a),
         VectorReal::from_vec(vv_a),
         VectorReal::from_vec(ww_a)
     )
 }
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_ant_position_units() {
+        let baselines = vec![(0, 1), (1, 2)];
+        let x = VectorReal::from_vec(vec![0.0, 2.0, 5.0]);
+        let y = VectorReal::from_vec(vec![1.0, 0.0, -1.0]);
+        let z = VectorReal::from_vec(vec![0.0, 0.5, 0.0]);
+
+        let (u, v, w) = get_uvw_in(&baselines, &x, &y, &z, AntPositionUnit::Wavelengths);
+        assert_eq!(u.to_vec(), vec![-2.0, -3.0]);
+        assert_eq!(v.to_vec(), vec![1.0, 1.0]);
+        assert_eq!(w.to_vec(), vec![-0.5, 0.5]);
+
+        // Metres are divided by the L1 wavelength (about 19 cm)
+        let (um, vm, wm) = get_uvw(&baselines, &x, &y, &z);
+        assert_eq!((um, vm, wm), get_uvw_in(&baselines, &x, &y, &z, AntPositionUnit::Meters));
+        let (um, _, _) = get_uvw(&baselines, &x, &y, &z);
+        for k in 0..2 {
+            assert!((um[k]*L1_WAVELENGTH - u[k]).abs() < 1.0e-12);
+        }
+        assert!(um[1].abs() > 15.0);
+
+        assert_eq!("Metres".parse(), Ok(AntPositionUnit::Meters));
+        assert_eq!("wavelengths".parse(), Ok(AntPositionUnit::Wavelengths));
+        assert!("feet".parse::<AntPositionUnit>().is_err());
+    }
+}
@@ -0,0 +1,62 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+use std::fmt;
+
+/*
+*   How an iterative algorithm (e.g. gridless::clean) stopped: after how many of the
+*   allowed iterations, and whether the quantity it drives down (the residual) reached
+*   its threshold, or it ran out of iterations first.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct IterationReport {
+    pub iterations: usize,
+    pub max_iterations: usize,
+    pub converged: bool,
+    pub residual: f64,
+    pub threshold: f64,
+}
+
+impl IterationReport {
+    // The report for stopping after iterations, converged if residual is below threshold
+    pub fn new(iterations: usize, max_iterations: usize, residual: f64, threshold: f64) -> IterationReport {
+        IterationReport {
+            iterations,
+            max_iterations,
+            converged: residual < threshold,
+            residual,
+            threshold,
+        }
+    }
+}
+
+impl fmt::Display for IterationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.converged {
+            write!(f, "converged after {} of {} iterations (residual {:.3e} < {:.3e})",
+                self.iterations, self.max_iterations, self.residual, self.threshold)
+        } else {
+            write!(f, "stopped at the {} iteration limit without converging (residual {:.3e} >= {:.3e})",
+                self.max_iterations, self.residual, self.threshold)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let done = IterationReport::new(12, 100, 0.01, 0.05);
+        assert!(done.converged);
+        assert_eq!(done.to_string(), "converged after 12 of 100 iterations (residual 1.000e-2 < 5.000e-2)");
+
+        let capped = IterationReport::new(100, 100, 0.2, 0.05);
+        assert!(!capped.converged);
+        assert!(capped.to_string().starts_with("stopped at the 100 iteration limit"));
+    }
+}
@@ -5,6 +5,7 @@
 // TODO switch to the above model of Linear Memory.
 
 extern crate gloo_utils;
+extern crate js_sys;
 extern crate ndarray;
 extern crate serde;
 extern crate serde_json;
@@ -15,24 +16,59 @@ extern crate serde_derive;
 extern crate cdshealpix;
 extern crate chrono;
 extern crate num;
+extern crate png;
+extern crate gridless_kernel;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate rayon;
 
+#[cfg(test)]
+extern crate rand;
+
+pub mod astro;
+pub mod beam;
+pub mod bundle;
+pub mod colormap;
+pub mod config;
+pub mod error;
+pub mod fits;
+pub mod geojson;
 pub mod gridless;
 pub mod img;
+pub mod imager;
+pub mod iteration;
+pub mod lightcurve;
+pub mod logging;
+pub mod prelude;
+pub mod running_mean;
 
-mod sphere;
+pub mod sphere;
 mod sphere_plot;
+mod sphere_raster;
 mod svg;
-mod tart_api;
-mod tart_obs;
+pub mod theme;
+pub mod tart_api;
+pub mod tart_obs;
 mod utils;
 
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
 use chrono::{DateTime, Utc};
 
-use sphere::Hemisphere;
-use tart_api::FullDataset;
+use bundle::{BundleImage, RenderBundle};
+use colormap::{Colormap, ColormapLut};
+use config::{Colorbar, ProcessingConfig};
+use error::{ProcessingError, Warning};
+use geojson::FeatureCollection;
+use imager::Imager;
+use iteration::IterationReport;
+
+use sphere::{Hemisphere, HemisphereStats};
+use tart_api::{FullDataset, InputLimits};
 
 use tart_api::Source;
-use tart_obs::Observation;
+use tart_obs::{Observation, ResidualReport};
 use utils::{VectorComplex, VectorReal};
 
 use wasm_bindgen::prelude::*;
@@ -42,14 +78,97 @@ use wasm_bindgen::JsValue;
 
 #[wasm_bindgen]
 pub struct SVG {
-    internal: String
+    internal: String,
+    // The last reconstructed sky, keyed on a hash of the dataset JSON and nside
+    cache_key: Option<u64>,
+    sky: Option<Hemisphere>,
+    // When the cached sky was observed, and the local sidereal time (degrees) if the site is known
+    timestamp: Option<DateTime<Utc>>,
+    lst: Option<f32>,
+    // Baseline length range (wavelengths) to image, set with set_uvrange
+    baseline_filter: Option<(f32, f32)>,
+    // Warnings from building the cached observation
+    warnings: Vec<Warning>,
+    // Dither colours when quantizing, set with set_dither
+    dither: bool,
+    // Image at this lower nside for a fast first paint, set with set_preview_nside
+    preview_nside: Option<u32>,
+    // Elevation (radians) pixels must reach to be drawn, set with set_min_draw_lat
+    min_draw_lat: Option<f64>,
+}
+
+fn dataset_hash(json: &str, nside: u32) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    json.hash(&mut hasher);
+    nside.hash(&mut hasher);
+    hasher.finish()
 }
 
 #[wasm_bindgen]
 impl SVG {
+    #[allow(clippy::new_without_default)]
     #[wasm_bindgen(constructor)]
     pub fn new() -> SVG {
-        SVG { internal: "Hello".to_string() }
+        SVG {
+            internal: "Hello".to_string(),
+            cache_key: None,
+            sky: None,
+            timestamp: None,
+            lst: None,
+            baseline_filter: None,
+            warnings: Vec::new(),
+            dither: false,
+            preview_nside: None,
+            min_draw_lat: None,
+        }
+    }
+
+    // Only image baselines with min <= |uv| <= max wavelengths. Forces the next call to re-image.
+    #[wasm_bindgen]
+    pub fn set_uvrange(&mut self, min: f32, max: f32) {
+        self.baseline_filter = Some((min, max));
+        self.cache_key = None;
+    }
+
+    // Dither colours in the SVG and recolor() bytes, breaking up banding in gradients
+    #[wasm_bindgen]
+    pub fn set_dither(&mut self, dither: bool) {
+        self.dither = dither;
+    }
+
+    // Image at this lower nside and upsample to the requested one, for a quick preview
+    // to show while a full resolution render runs. None images at full resolution.
+    // Forces the next call to re-image.
+    #[wasm_bindgen]
+    pub fn set_preview_nside(&mut self, preview_nside: Option<u32>) {
+        self.preview_nside = preview_nside;
+        self.cache_key = None;
+    }
+
+    // Only draw pixels reaching above this elevation (radians), as
+    // get_hemisphere_pixel_corners(nside, min_lat) does. None restores the default
+    // (0.07, about 4 degrees). See ProcessingConfig::min_draw_lat.
+    #[wasm_bindgen]
+    pub fn set_min_draw_lat(&mut self, min_lat: Option<f64>) {
+        self.min_draw_lat = min_lat;
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_uvrange(&mut self) {
+        self.baseline_filter = None;
+        self.cache_key = None;
+    }
+
+    // Drop the cached sky to release its memory, e.g. when the page is hidden or the
+    // browser reports memory pressure. The next json_to_svg_ext call re-images.
+    // Safe to call at any time, including when nothing is cached.
+    #[wasm_bindgen]
+    pub fn clear_caches(&mut self) {
+        self.cache_key = None;
+        self.sky = None;
+        self.timestamp = None;
+        self.lst = None;
+        self.warnings.clear();
     }
 
     #[wasm_bindgen]
@@ -61,49 +180,417 @@ impl SVG {
     //     self.internal = val;
     // }
     #[wasm_bindgen]
-    pub fn json_to_svg_ext(&mut self, json: String, nside: u32, show_sources: bool) {
+    pub fn json_to_svg_ext(&mut self, json: String, nside: u32, show_sources: bool) -> Result<(), JsValue> {
         // https://rustwasm.github.io/wasm-bindgen/reference/arbitrary-data-with-serde.html
         // https://stackoverflow.com/questions/78000437/allocating-deallocating-memory-in-rust-compiled-to-webassembly
-        let data = tart_api::json_to_dataset(&json);
-        let obs = get_obs_from_dataset(&data);
+        let data = tart_api::parse_dataset_limited(&json, &InputLimits::default()).map_err(js_error)?;
+        let config = ProcessingConfig {
+            baseline_filter: self.baseline_filter,
+            dither: self.dither,
+            preview_nside: self.preview_nside,
+            min_draw_lat: self.min_draw_lat.unwrap_or(ProcessingConfig::default().min_draw_lat),
+            ..ProcessingConfig::for_bindings()
+        };
+        config.check_nside(nside).map_err(js_error)?;
 
-        let (u, v, w) = img::get_uvw(&obs.baselines, &obs.ant_x, &obs.ant_y, &obs.ant_z);
+        // Only re-image when the dataset or resolution has changed
+        let key = dataset_hash(&json, nside);
+        if self.cache_key != Some(key) || self.sky.is_none() {
+            let obs = get_obs_from_dataset(&data).map_err(js_error)?;
+            self.sky = Some(image_obs(&obs, nside, &config).map_err(js_error)?);
+            self.cache_key = Some(key);
+            self.timestamp = Some(obs.timestamp);
+            self.lst = obs.site_lst();
+            self.warnings = obs.warnings;
+        }
 
         let sources = if show_sources {
             Some(get_sources_from_dataset(&data))
         } else {
             None
         };
-        let svg = make_svg(&obs.vis_arr, &u, &v, &w, nside, sources);
-        
-        self.internal = svg;
+        if let Some(ref sky) = self.sky {
+            let mut svg = sky.to_svg_with_config(&config, sources);
+            match (self.timestamp, self.lst) {
+                (Some(t), Some(lst)) => svg.title(&format!("Observed {} (LST {:.4} deg)", t.to_rfc3339(), lst)),
+                (Some(t), None) => svg.title(&format!("Observed {}", t.to_rfc3339())),
+                _ => {},
+            }
+            self.internal = svg.to_string();
+        }
         // <JsValue as gloo_utils::format::JsValueSerdeExt>::from_serde(&svg).unwrap()
+        Ok(())
     }  
 
+    // RFC 3339 UTC time of the last imaged observation
+    #[wasm_bindgen]
+    pub fn timestamp(&self) -> Option<String> {
+        self.timestamp.map(|t| t.to_rfc3339())
+    }
+
+    // Warnings about the last imaged dataset, as an array of objects with a "kind" field
+    #[wasm_bindgen]
+    pub fn warnings(&self) -> Result<JsValue, JsValue> {
+        <JsValue as gloo_utils::format::JsValueSerdeExt>::from_serde(&self.warnings)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    // The warnings as readable messages
+    #[wasm_bindgen]
+    pub fn warning_messages(&self) -> Vec<String> {
+        self.warnings.iter().map(|w| w.to_string()).collect()
+    }
+
+    // Local sidereal time (degrees) of the last imaged observation, if the site is known
+    #[wasm_bindgen]
+    pub fn lst(&self) -> Option<f32> {
+        self.lst
+    }
+
+    /*
+    *   RGB bytes for each visible pixel of the cached sky, so that a colormap change
+    *   does not require the visibilities to be imaged again. Empty if nothing has
+    *   been imaged yet.
+    */
+    #[wasm_bindgen]
+    pub fn recolor(&self, colormap: Colormap) -> Vec<u8> {
+        match self.sky {
+            Some(ref sky) => {
+                let config = ProcessingConfig {
+                    colormap,
+                    dither: self.dither,
+                    ..ProcessingConfig::default()
+                };
+                sky.color_bytes(&config)
+            },
+            None => Vec::new(),
+        }
+    }
+
+}
+
+
+
+/*
+*   What this build was compiled with, to tell from a deployed artifact which
+*   features it has.
+*/
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub features: Vec<&'static str>,
+    pub target_arch: &'static str,
+    pub target_os: &'static str,
+    pub debug_assertions: bool,     // true for unoptimized (debug profile) builds
+    pub binding_max_nside: u32,     // from GRIDLESS_MAX_NSIDE when it was built
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "gridless {}", self.version)?;
+        writeln!(f, "target: {}-{}", self.target_arch, self.target_os)?;
+        if self.features.is_empty() {
+            writeln!(f, "features: none")?;
+        } else {
+            writeln!(f, "features: {}", self.features.join(", "))?;
+        }
+        writeln!(f, "debug assertions: {}", self.debug_assertions)?;
+        write!(f, "binding max nside: {}", self.binding_max_nside)
+    }
 }
 
+pub fn build_info() -> BuildInfo {
+    let mut features = Vec::new();
+    if cfg!(feature = "nightly") {
+        features.push("nightly");
+    }
+    // WebAssembly SIMD, so the page can confirm which build its loader picked
+    if cfg!(target_feature = "simd128") {
+        features.push("simd128");
+    }
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        features,
+        target_arch: std::env::consts::ARCH,
+        target_os: std::env::consts::OS,
+        debug_assertions: cfg!(debug_assertions),
+        binding_max_nside: ProcessingConfig::for_bindings().max_nside.unwrap_or(0),
+    }
+}
 
+// build_info as an object, for the browser console
+#[wasm_bindgen(js_name = build_info)]
+pub fn build_info_js() -> Result<JsValue, JsValue> {
+    <JsValue as gloo_utils::format::JsValueSerdeExt>::from_serde(&build_info())
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
 
+fn js_error(e: ProcessingError) -> JsValue {
+    JsValue::from_str(&e.to_string())
+}
+
+/*
+*   Serialized hemisphere geometry for image_onto_geometry.
+*/
+#[wasm_bindgen]
+pub fn hemisphere_geometry(nside: u32) -> Result<Vec<u8>, JsValue> {
+    ProcessingConfig::for_bindings().check_nside(nside).map_err(js_error)?;
+    Ok(Hemisphere::new(nside).to_binary())
+}
+
+/*
+*   Pixel corners for WebGL, as a Float32Array of 8 values (four x, y corners) per
+*   visible pixel, in [0, 1] with the origin top left. See Hemisphere::corners_normalized.
+*/
 #[wasm_bindgen]
-pub fn json_to_svg_ext(json: String, nside: u32, show_sources: bool) -> JsValue {
+pub fn get_pixel_coords_normalized(nside: u32) -> Result<Vec<f32>, JsValue> {
+    ProcessingConfig::for_bindings().check_nside(nside).map_err(js_error)?;
+    Ok(Hemisphere::new(nside).corners_normalized())
+}
+
+// Largest colormap lookup table the bindings will build
+const MAX_LUT_ENTRIES: u32 = 65536;
+
+fn check_lut_size(n: u32) -> Result<(), JsValue> {
+    if !(2..=MAX_LUT_ENTRIES).contains(&n) {
+        return Err(JsValue::from_str(&format!("LUT size {} is not between 2 and {}", n, MAX_LUT_ENTRIES)));
+    }
+    Ok(())
+}
+
+/*
+*   n RGB triplets (a Uint8Array of 3*n bytes) sampling colormap from 0 to 1, so the
+*   client can colour raw values as the server does. See Colormap::lut.
+*/
+#[wasm_bindgen]
+pub fn get_colormap_lut(colormap: Colormap, n: u32) -> Result<Vec<u8>, JsValue> {
+    check_lut_size(n)?;
+    Ok(colormap.lut(n as usize))
+}
+
+/*
+*   As get_colormap_lut, as JSON with the colormap name and, if both are given, the
+*   pixel values min and max mapped to its ends. See ColormapLut.
+*/
+#[wasm_bindgen]
+pub fn get_colormap_lut_json(colormap: Colormap, n: u32, min: Option<f64>, max: Option<f64>) -> Result<String, JsValue> {
+    check_lut_size(n)?;
+    let scale = match (min, max) {
+        (Some(min), Some(max)) => Some((min, max)),
+        _ => None,
+    };
+    Ok(ColormapLut::new(colormap, n as usize, scale).to_json())
+}
+
+/*
+*   As get_pixel_coords_normalized, with NaN corners for the pixels the SVG leaves out
+*   at this min_lat (radians, default 0.07 or about 4 degrees), so that a 3D dome clips
+*   the horizon as the SVG does. See ProcessingConfig::min_draw_lat.
+*/
+#[wasm_bindgen]
+pub fn get_hemisphere_pixel_corners(nside: u32, min_lat: Option<f64>) -> Result<Vec<f32>, JsValue> {
+    let config = ProcessingConfig::for_bindings();
+    config.check_nside(nside).map_err(js_error)?;
+    Ok(Hemisphere::new(nside).drawn_corners_normalized(min_lat.unwrap_or(config.min_draw_lat)))
+}
+
+/*
+*   The sky coverage of a hemisphere at this nside, as a Coverage object.
+*/
+#[wasm_bindgen]
+pub fn hemisphere_coverage(nside: u32) -> Result<JsValue, JsValue> {
+    ProcessingConfig::for_bindings().check_nside(nside).map_err(js_error)?;
+    let coverage = Hemisphere::new(nside).coverage();
+    <JsValue as gloo_utils::format::JsValueSerdeExt>::from_serde(&coverage)
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/*
+*   Image a dataset onto geometry previously produced by hemisphere_geometry (possibly
+*   on a server), returning RGB bytes for each visible pixel.
+*/
+#[wasm_bindgen]
+pub fn image_onto_geometry(geometry: &[u8], json: String, colormap: Colormap) -> Result<Vec<u8>, JsValue> {
+    let mut sky = Hemisphere::from_binary(geometry).map_err(js_error)?;
+    let config = ProcessingConfig {
+        colormap,
+        ..ProcessingConfig::for_bindings()
+    };
+    config.check_nside(sky.nside).map_err(js_error)?;
+
+    let data = tart_api::parse_dataset_limited(&json, &InputLimits::default()).map_err(js_error)?;
+    let obs = get_obs_from_dataset(&data).map_err(js_error)?;
+    let (u, v, w) = obs.get_uvw(config.ant_position_unit);
+
+    gridless::image_visibilities_with_config(&obs.vis_arr, &u, &v, &w, &mut sky, &config);
+    Ok(sky.color_bytes(&config))
+}
+
+#[wasm_bindgen]
+pub fn json_to_svg_ext(json: String, nside: u32, show_sources: bool) -> Result<JsValue, JsValue> {
     // https://rustwasm.github.io/wasm-bindgen/reference/arbitrary-data-with-serde.html
     // https://stackoverflow.com/questions/78000437/allocating-deallocating-memory-in-rust-compiled-to-webassembly
-    let data = tart_api::json_to_dataset(&json);
-    let obs = get_obs_from_dataset(&data);
+    let config = ProcessingConfig::for_bindings();
+    config.check_nside(nside).map_err(js_error)?;
 
-    let (u, v, w) = img::get_uvw(&obs.baselines, &obs.ant_x, &obs.ant_y, &obs.ant_z);
+    let data = tart_api::parse_dataset_limited(&json, &InputLimits::default()).map_err(js_error)?;
+    let obs = get_obs_from_dataset(&data).map_err(js_error)?;
+
+    let (u, v, w) = obs.get_uvw(config.ant_position_unit);
 
     let sources = if show_sources {
         Some(get_sources_from_dataset(&data))
     } else {
         None
     };
-    let svg = make_svg(&obs.vis_arr, &u, &v, &w, nside, sources);
+    let svg = make_svg_with_config(&obs.vis_arr, &u, &v, &w, nside, &config, sources);
 
-    JsValue::from_str(&svg)
+    Ok(JsValue::from_str(&svg))
 
 }  
 
+/*
+*   Entry point for untrusted (e.g. pasted) datasets: input size and array lengths
+*   are bounded by the default InputLimits, and nside by ProcessingConfig::for_bindings.
+*/
+#[wasm_bindgen]
+pub fn untrusted_json_to_svg(json: String, nside: u32, show_sources: bool) -> Result<JsValue, JsValue> {
+    let data = tart_api::parse_dataset_limited(&json, &InputLimits::default()).map_err(js_error)?;
+    let (svg, _timestamp) = dataset_to_svg(&data, nside, show_sources, &ProcessingConfig::for_bindings()).map_err(js_error)?;
+    Ok(JsValue::from_str(&svg))
+}
+
+/*
+*   Per-baseline residuals after subtracting the dataset's own catalog sources, as a
+*   JSON ResidualReport string.
+*/
+#[wasm_bindgen]
+pub fn json_residuals(json: String) -> Result<JsValue, JsValue> {
+    let data = tart_api::parse_dataset_limited(&json, &InputLimits::default()).map_err(js_error)?;
+    let report = dataset_residuals(&data).map_err(js_error)?;
+    let json = serde_json::to_string(&report).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Ok(JsValue::from_str(&json))
+}
+
+/*
+*   An untrusted dataset rendered to SVG, as a JSON RenderBundle string.
+*/
+#[wasm_bindgen]
+pub fn json_to_bundle(json: String, nside: u32, show_sources: bool) -> Result<JsValue, JsValue> {
+    let data = tart_api::parse_dataset_limited(&json, &InputLimits::default()).map_err(js_error)?;
+    let bundle = dataset_to_bundle(&data, nside, show_sources, None, &ProcessingConfig::for_bindings()).map_err(js_error)?;
+    Ok(JsValue::from_str(&bundle.to_json()))
+}
+
+/*
+*   An untrusted dataset imaged to RGBA bytes, 4 per visible pixel in ascending RING
+*   index order (matching hemisphere_geometry and get_pixel_coords_normalized), with
+*   the pixels the SVG would leave out fully transparent. See Hemisphere::color_bytes_rgba.
+*/
+#[wasm_bindgen]
+pub fn get_color_bytes_rgba(json: String, nside: u32) -> Result<Vec<u8>, JsValue> {
+    let data = tart_api::parse_dataset_limited(&json, &InputLimits::default()).map_err(js_error)?;
+    let config = ProcessingConfig::for_bindings();
+    let (sky, _obs) = image_dataset(&data, nside, &config).map_err(js_error)?;
+    Ok(sky.color_bytes_rgba(&config))
+}
+
+/*
+*   An untrusted dataset imaged to a GeoJSON-style FeatureCollection: a polygon in the
+*   normalized coordinates of get_pixel_coords_normalized, with its value, per visible
+*   pixel. See geojson::FeatureCollection.
+*/
+#[wasm_bindgen]
+pub fn get_geojson(json: String, nside: u32) -> Result<String, JsValue> {
+    let data = tart_api::parse_dataset_limited(&json, &InputLimits::default()).map_err(js_error)?;
+    let (sky, _obs) = image_dataset(&data, nside, &ProcessingConfig::for_bindings()).map_err(js_error)?;
+    Ok(FeatureCollection::from_hemisphere(&sky).to_json())
+}
+
+// Width and height of the image from get_png_data_url
+const DATA_URL_PNG_SIZE: u32 = 512;
+// Largest PNG the bindings will render, 64 MB of RGBA
+const MAX_PNG_SIZE: u32 = 4096;
+
+fn check_png_size(size: u32) -> Result<(), JsValue> {
+    if !(1..=MAX_PNG_SIZE).contains(&size) {
+        return Err(JsValue::from_str(&format!("PNG size {} is not between 1 and {}", size, MAX_PNG_SIZE)));
+    }
+    Ok(())
+}
+
+/*
+*   Image an untrusted dataset as a size x size PNG, returning the raw bytes.
+*/
+#[wasm_bindgen]
+pub fn json_to_png(json: String, nside: u32, size: u32) -> Result<Vec<u8>, JsValue> {
+    check_png_size(size)?;
+    let data = tart_api::parse_dataset_limited(&json, &InputLimits::default()).map_err(js_error)?;
+    let (png, _timestamp) = dataset_to_png(&data, nside, size, &ProcessingConfig::for_bindings()).map_err(js_error)?;
+    Ok(png)
+}
+
+/*
+*   As json_to_png, as a data:image/png;base64 URL that can be assigned directly to
+*   an <img> src.
+*/
+#[wasm_bindgen]
+pub fn get_png_data_url(json: String, nside: u32) -> Result<String, JsValue> {
+    let png = json_to_png(json, nside, DATA_URL_PNG_SIZE)?;
+    Ok(png_data_url(&png))
+}
+
+fn png_data_url(png: &[u8]) -> String {
+    format!("data:image/png;base64,{}", utils::base64_encode(png))
+}
+
+/*
+*   A standalone colorbar legend for the scale (min, max), with an optional label
+*   such as the units.
+*/
+#[wasm_bindgen]
+pub fn colorbar_legend_svg(min: f64, max: f64, colormap: Colormap, label: Option<String>) -> String {
+    let config = ProcessingConfig {
+        colormap,
+        colorbar: Some(Colorbar { label, ..Colorbar::default() }),
+        ..ProcessingConfig::for_bindings()
+    };
+    render_colorbar_svg(min, max, &config)
+}
+
+struct CallbackLogger {
+    callback: js_sys::Function,
+}
+
+impl logging::Logger for CallbackLogger {
+    fn log(&self, msg: &str) {
+        let _ = self.callback.call1(&JsValue::NULL, &JsValue::from_str(msg));
+    }
+}
+
+/*
+*   Entry point for Node.js pipelines. The dataset is passed as a byte buffer (e.g. the
+*   contents of a file) and diagnostics are sent to the optional callback rather than
+*   the browser console.
+*/
+#[wasm_bindgen]
+pub fn bytes_to_svg(json: &[u8], nside: u32, show_sources: bool, log: Option<js_sys::Function>) -> Result<JsValue, JsValue> {
+    let json = match std::str::from_utf8(json) {
+        Ok(s) => s.to_string(),
+        Err(e) => return Err(JsValue::from_str(&format!("Dataset is not valid UTF-8: {}", e))),
+    };
+
+    let previous = log.map(|f| logging::set_logger(Box::new(CallbackLogger { callback: f })));
+
+    let result = json_to_svg_with_config(&json, nside, show_sources, &ProcessingConfig::for_bindings());
+
+    if let Some(logger) = previous {
+        logging::set_logger(logger);
+    }
+    let (svg, _timestamp) = result.map_err(js_error)?;
+    Ok(JsValue::from_str(&svg))
+}
+
 pub fn make_svg(
     vis: &VectorComplex,
     u: &VectorReal,
@@ -112,48 +599,499 @@ pub fn make_svg(
     nside: u32,
     sources: Option<&Vec<Source>>,
 ) -> String {
-    let mut sky = Hemisphere::new(nside);
+    make_svg_with_config(vis, u, v, w, nside, &ProcessingConfig::default(), sources)
+}
+
+pub fn make_svg_with_config(
+    vis: &VectorComplex,
+    u: &VectorReal,
+    v: &VectorReal,
+    w: &VectorReal,
+    nside: u32,
+    config: &ProcessingConfig,
+    sources: Option<&Vec<Source>>,
+) -> String {
+    let mut imager = Imager::new(nside, config.clone());
+    imager.image_vis(vis, u, v, w);
+    imager.sky.to_svg_with_config(config, sources).to_string()
+}
+
 
-    gridless::image_visibilities(&vis, &u, &v, &w, &mut sky, false);
-    return sky.to_svg(true, sources).to_string();
+pub fn json_to_svg(json: &str, nside: u32, show_sources: bool) -> Result<(String, DateTime<Utc>), ProcessingError> {
+    json_to_svg_with_config(json, nside, show_sources, &ProcessingConfig::default())
 }
 
+pub fn json_to_svg_with_config(json: &str, nside: u32, show_sources: bool,
+                               config: &ProcessingConfig) -> Result<(String, DateTime<Utc>), ProcessingError> {
+    let data = tart_api::parse_dataset(json)?;
+    dataset_to_svg(&data, nside, show_sources, config)
+}
 
-pub fn json_to_svg(json: &String, nside: u32, show_sources: bool) -> (String, DateTime<Utc>) {
-    let data = tart_api::json_to_dataset(&json);
-    let obs = get_obs_from_dataset(&data);
+pub fn dataset_to_svg(data: &FullDataset, nside: u32, show_sources: bool,
+                      config: &ProcessingConfig) -> Result<(String, DateTime<Utc>), ProcessingError> {
+    let (svg, timestamp, _stats) = dataset_to_svg_with_stats(data, nside, show_sources, config)?;
+    Ok((svg, timestamp))
+}
 
-    let (u, v, w) = img::get_uvw(&obs.baselines, &obs.ant_x, &obs.ant_y, &obs.ant_z);
+/*
+*   As dataset_to_svg, also returning the statistics of the image.
+*/
+pub fn dataset_to_svg_with_stats(data: &FullDataset, nside: u32, show_sources: bool,
+                      config: &ProcessingConfig) -> Result<(String, DateTime<Utc>, HemisphereStats), ProcessingError> {
+    let (svg, sky, obs) = render_dataset(data, nside, show_sources, config)?;
+    Ok((svg, obs.timestamp, sky.stats()))
+}
+
+/*
+*   As dataset_to_svg, also returning the warnings about data left out or suspect.
+*/
+pub fn dataset_to_svg_with_warnings(data: &FullDataset, nside: u32, show_sources: bool,
+                      config: &ProcessingConfig) -> Result<(String, DateTime<Utc>, Vec<Warning>), ProcessingError> {
+    let (svg, _sky, obs) = render_dataset(data, nside, show_sources, config)?;
+    Ok((svg, obs.timestamp, obs.warnings))
+}
+
+fn render_dataset(data: &FullDataset, nside: u32, show_sources: bool,
+                  config: &ProcessingConfig) -> Result<(String, Hemisphere, Observation), ProcessingError> {
+    let (sky, obs) = image_dataset(data, nside, config)?;
 
     let sources = if show_sources {
-        Some(get_sources_from_dataset(&data))
+        Some(get_sources_from_dataset(data))
     } else {
         None
     };
 
-    return (
-        make_svg(&obs.vis_arr, &u, &v, &w, nside, sources),
-        obs.timestamp,
-    );
+    let mut svg = sky.to_svg_with_config(config, sources);
+    svg.title(&obs.describe());
+
+    Ok((svg.to_string(), sky, obs))
+}
+
+/*
+*   Image a dataset as a size x size PNG.
+*/
+pub fn dataset_to_png(data: &FullDataset, nside: u32, size: u32,
+                      config: &ProcessingConfig) -> Result<(Vec<u8>, DateTime<Utc>), ProcessingError> {
+    let (sky, obs) = image_dataset(data, nside, config)?;
+    Ok((sky.to_png(size, config)?, obs.timestamp))
+}
+
+/*
+*   Image a dataset into a RenderBundle holding an SVG, or a png_size x png_size PNG
+*   when png_size is given.
+*/
+pub fn dataset_to_bundle(data: &FullDataset, nside: u32, show_sources: bool, png_size: Option<u32>,
+                         config: &ProcessingConfig) -> Result<RenderBundle, ProcessingError> {
+    let (image, sky, obs) = match png_size {
+        Some(size) => {
+            let (sky, obs) = image_dataset(data, nside, config)?;
+            (BundleImage::Png(sky.to_png(size, config)?), sky, obs)
+        },
+        None => {
+            let (svg, sky, obs) = render_dataset(data, nside, show_sources, config)?;
+            (BundleImage::Svg(svg), sky, obs)
+        },
+    };
+    Ok(RenderBundle {
+        image,
+        nside,
+        stats: sky.stats(),
+        config: config.clone(),
+        timestamp: obs.timestamp,
+        warnings: obs.warnings,
+    })
+}
+
+/*
+*   A false colour SVG of two or three datasets of the same sky (e.g. the L1 and L2
+*   bands), each imaged at nside and driving one colour channel. See
+*   Hemisphere::compose_rgb. The time is that of the red dataset.
+*/
+pub fn datasets_to_rgb_svg(red: &FullDataset, green: Option<&FullDataset>, blue: &FullDataset,
+                           nside: u32, config: &ProcessingConfig) -> Result<(String, DateTime<Utc>), ProcessingError> {
+    let (red_sky, obs) = image_dataset(red, nside, config)?;
+    let green_sky = match green {
+        Some(data) => Some(image_dataset(data, nside, config)?.0),
+        None => None,
+    };
+    let (blue_sky, _) = image_dataset(blue, nside, config)?;
+
+    let mut svg = Hemisphere::compose_rgb(&red_sky, green_sky.as_ref(), &blue_sky, config)?;
+    svg.title(&obs.describe());
+    Ok((svg.to_string(), obs.timestamp))
+}
+
+/*
+*   The observation imaged at nside with each of two configs (e.g. two colormaps or
+*   baseline filters), drawn side by side in one SVG for tuning. Each image is titled
+*   with the settings where its config differs from the other.
+*/
+pub fn make_comparison_svg(obs: &Observation, nside: u32, config_a: &ProcessingConfig, config_b: &ProcessingConfig,
+                           sources: Option<&Vec<Source>>) -> Result<String, ProcessingError> {
+    let title = |differences: Vec<String>| match differences.is_empty() {
+        true => "no differences".to_string(),
+        false => differences.join(", "),
+    };
+    let mut panels = Vec::new();
+    for &(config, other) in &[(config_a, config_b), (config_b, config_a)] {
+        config.check_nside(nside)?;
+        let sky = image_obs(obs, nside, config)?;
+        panels.push((sky.to_svg_with_config(config, sources), title(config.differences(other))));
+    }
+
+    let (a, b) = (&panels[0], &panels[1]);
+    let mut svg = sphere_plot::side_by_side_svg(&a.0, &b.0, (&a.1, &b.1), config_a);
+    svg.title(&obs.describe());
+    Ok(svg.to_string())
+}
+
+/*
+*   A standalone colorbar legend for images drawn with scale (min, max) and config,
+*   e.g. one legend shared by a grid of thumbnails.
+*/
+pub fn render_colorbar_svg(min: f64, max: f64, config: &ProcessingConfig) -> String {
+    sphere_plot::colorbar_svg(min, max, config).to_string()
+}
+
+/// Image a dataset file as a size x size PNG, returning the PNG bytes and the time of
+/// the observation. This is the recommended entry point for embedding: it reads,
+/// parses, validates, images and rasterizes in one call.
+///
+/// ```
+/// use gridlesslib::prelude::*;
+///
+/// let (png, timestamp) = gridlesslib::image_file_to_png("data.json", 16, 256, &ProcessingConfig::default()).unwrap();
+/// assert_eq!(&png[1..4], b"PNG");
+/// println!("Observed {}", timestamp);
+/// ```
+pub fn image_file_to_png(path: &str, nside: u32, size: u32,
+                         config: &ProcessingConfig) -> Result<(Vec<u8>, DateTime<Utc>), ProcessingError> {
+    let json = std::fs::read_to_string(path).map_err(|e| ProcessingError::Io(format!("{}: {}", path, e)))?;
+    let data = tart_api::parse_dataset(&json)?;
+    dataset_to_png(&data, nside, size, config)
+}
+
+/*
+*   Image a dataset onto a new hemisphere, returning it with the observation.
+*/
+pub fn image_dataset(data: &FullDataset, nside: u32,
+                     config: &ProcessingConfig) -> Result<(Hemisphere, Observation), ProcessingError> {
+    config.check_nside(nside)?;
+    let obs = get_obs_from_dataset(data)?;
+    Ok((image_obs(&obs, nside, config)?, obs))
+}
+
+// Image an observation at nside, or at config.preview_nside and resampled onto nside
+fn image_obs(obs: &Observation, nside: u32, config: &ProcessingConfig) -> Result<Hemisphere, ProcessingError> {
+    let imaging_nside = config.imaging_nside(nside);
+    config.check_nside(imaging_nside)?;
+
+    let subset = match config.antenna_subset {
+        Some(ref antennas) => Some(obs.with_antennas(antennas)?),
+        None => None,
+    };
+    let mut imager = Imager::new(imaging_nside, config.clone());
+    imager.image(subset.as_ref().unwrap_or(obs));
+    if imaging_nside == nside {
+        Ok(imager.sky)
+    } else {
+        Ok(imager.sky.resample_to(nside))
+    }
+}
+
+// Loop gain of clean_dataset: the fraction of the peak residual removed per component
+const CLEAN_GAIN: f64 = 0.1;
+
+/*
+*   Högbom CLEAN a dataset at nside (see gridless::clean). The real-only dirty image of
+*   the configured baselines is cleaned for at most n_iter components, or until the peak
+*   residual falls below threshold times the peak of the dirty image. Returns the restored
+*   image and the report of how CLEAN stopped.
+*/
+pub fn clean_dataset(data: &FullDataset, nside: u32, n_iter: usize, threshold: f64,
+                     config: &ProcessingConfig) -> Result<(Hemisphere, Observation, IterationReport), ProcessingError> {
+    config.check_nside(nside)?;
+    let obs = get_obs_from_dataset(data)?;
+    let subset = match config.antenna_subset {
+        Some(ref antennas) => Some(obs.with_antennas(antennas)?),
+        None => None,
+    };
+    let imaged = subset.as_ref().unwrap_or(&obs);
+
+    let (u, v, w) = imaged.get_uvw(config.ant_position_unit);
+    let (vis, u, v, w) = gridless::configured_baselines(&imaged.vis_arr, &u, &v, &w, config)
+        .unwrap_or_else(|| (imaged.vis_arr.clone(), u, v, w));
+
+    let mut sky = Hemisphere::new(nside);
+    sky.visible_pix = gridless::image_at_directions_with(&vis, &u, &v, &w, &sky.l, &sky.m, &sky.n,
+                                                         true, config.include_w_term, &config.fourier_convention);
+    let dirty_peak = sky.visible_pix.iter().fold(0.0f64, |m, x| m.max(x.abs()));
+
+    let beam = gridless::DirtyBeam::new(&sky, &u, &v, &w, config.include_w_term)?;
+    let (restored, _components, report) = gridless::clean(&sky, &beam, CLEAN_GAIN, n_iter, threshold*dirty_peak);
+    Ok((restored, obs, report))
+}
+
+/*
+*   The visibility residuals of a dataset after subtracting its catalog sources.
+*/
+pub fn dataset_residuals(data: &FullDataset) -> Result<ResidualReport, ProcessingError> {
+    let obs = get_obs_from_dataset(data)?;
+    Ok(tart_obs::residual_report(&obs, get_sources_from_dataset(data)))
+}
+
+/*
+*   Run the checks that imaging would, without imaging. Returns every problem found,
+*   and warnings about things that image but limit what else can be done (an nside
+*   that is not a power of two can't be smoothed or written as a NESTED map).
+*/
+pub fn validate_dataset(data: &FullDataset, nside: u32) -> (Vec<ProcessingError>, Vec<Warning>) {
+    let mut issues = Vec::new();
+    let mut warnings = Vec::new();
+    if Hemisphere::check_nside(nside).is_err() {
+        warnings.push(Warning::NsideNotPowerOfTwo { nside });
+    }
+    if let Err(e) = get_obs_from_dataset(data) {
+        issues.push(e);
+    }
+    (issues, warnings)
 }
 
 pub fn file_to_dataset(fname: &str) -> FullDataset {
-    let data = tart_api::file_to_dataset(&fname);
-    return data;
+    tart_api::file_to_dataset(fname)
 }
 
-pub fn get_obs_from_dataset(data: &FullDataset) -> Observation {
-    let obs = tart_obs::get_full(&data);
-    return obs;
+pub fn get_obs_from_dataset(data: &FullDataset) -> Result<Observation, ProcessingError> {
+    tart_obs::get_full(data)
 }
 
 pub fn get_sources_from_dataset(data: &FullDataset) -> &Vec<Source> {
-    let sources = tart_obs::get_sources(&data);
-    return sources;
+    let sources = tart_obs::get_sources(data);
+    sources
 }
 
 pub fn get_uvw_from_obs(obs: &Observation) -> (VectorReal, VectorReal, VectorReal) {
-    let (u, v, w) = img::get_uvw(&obs.baselines, &obs.ant_x, &obs.ant_y, &obs.ant_z);
+    let (u, v, w) = obs.get_uvw(img::AntPositionUnit::Meters);
+
+    (u, v, w)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_image_onto_geometry() {
+        let json = fs::read_to_string("data.json").unwrap();
+        let geometry = hemisphere_geometry(4).unwrap();
+        let bytes = image_onto_geometry(&geometry, json.clone(), Colormap::Cubehelix).unwrap();
+
+        let mut svg = SVG::new();
+        svg.json_to_svg_ext(json, 4, false).unwrap();
+        assert_eq!(bytes, svg.recolor(Colormap::Cubehelix));
+    }
+
+    #[test]
+    fn test_recolor_uses_cache() {
+        let json = fs::read_to_string("data.json").unwrap();
+        let mut svg = SVG::new();
+        assert!(svg.recolor(Colormap::Grayscale).is_empty());
+
+        svg.json_to_svg_ext(json.clone(), 4, false).unwrap();
+        let npix = svg.sky.as_ref().unwrap().npix;
+        assert_eq!(svg.recolor(Colormap::Grayscale).len(), 3*npix);
+
+        // Overwrite the cached sky. The same request must not re-image it.
+        svg.sky.as_mut().unwrap().visible_pix[0] = 1.0e6;
+        svg.json_to_svg_ext(json.clone(), 4, false).unwrap();
+        assert_eq!(&svg.recolor(Colormap::Grayscale)[0..3], &[255, 255, 255]);
+
+        // A change of nside invalidates the cache
+        svg.json_to_svg_ext(json.clone(), 8, false).unwrap();
+        assert!(svg.sky.as_ref().unwrap().visible_pix[0] < 1.0e6);
+    }
+
+    #[test]
+    fn test_image_file_to_png() {
+        let config = ProcessingConfig::default();
+        let (png, timestamp) = image_file_to_png("data.json", 4, 32, &config).unwrap();
+        let data = tart_api::parse_dataset(&fs::read_to_string("data.json").unwrap()).unwrap();
+        assert_eq!((png, timestamp), dataset_to_png(&data, 4, 32, &config).unwrap());
+
+        match image_file_to_png("missing.json", 4, 32, &config) {
+            Err(ProcessingError::Io(msg)) => assert!(msg.starts_with("missing.json")),
+            _ => panic!("Expected a read error"),
+        }
+        // Any nside images (RING needs no power of two)
+        assert!(image_file_to_png("data.json", 3, 32, &config).is_ok());
+    }
+
+    #[test]
+    fn test_clean_dataset() {
+        let data = tart_api::parse_dataset(&fs::read_to_string("data.json").unwrap()).unwrap();
+        let config = ProcessingConfig::default();
+        let (sky, _obs, report) = clean_dataset(&data, 8, 50, 0.05, &config).unwrap();
+        assert_eq!(sky.nside, 8);
+        assert!(report.iterations <= 50 && report.max_iterations == 50);
+        assert_eq!(report.converged, report.residual < report.threshold);
+
+        // A single iteration cannot bring a real sky down to 5% of its peak
+        let (_, _, report) = clean_dataset(&data, 8, 1, 0.05, &config).unwrap();
+        assert_eq!(report.iterations, 1);
+        assert!(!report.converged);
+    }
+
+    #[test]
+    fn test_preview_nside() {
+        let data = tart_api::parse_dataset(&fs::read_to_string("data.json").unwrap()).unwrap();
+        let (coarse, _) = image_dataset(&data, 8, &ProcessingConfig::default()).unwrap();
 
-    return (u, v, w);
+        // The preview is the coarse image upsampled onto the requested pixels
+        let config = ProcessingConfig {
+            preview_nside: Some(8),
+            ..ProcessingConfig::default()
+        };
+        let (preview, _) = image_dataset(&data, 32, &config).unwrap();
+        assert_eq!(preview.nside, 32);
+        let upsampled = coarse.resample_to(32);
+        assert!(preview.visible_pix.iter().zip(upsampled.visible_pix.iter())
+            .all(|(a, b)| a == b || (a.is_nan() && b.is_nan())));
+
+        // Neither nside needs to be a power of two, unless smoothing
+        let any = ProcessingConfig {
+            preview_nside: Some(6),
+            ..ProcessingConfig::default()
+        };
+        assert_eq!(image_dataset(&data, 36, &any).unwrap().0.nside, 36);
+        let smooth = ProcessingConfig {
+            smooth_fwhm: Some(5.0),
+            ..any
+        };
+        assert!(image_dataset(&data, 32, &smooth).is_err());
+    }
+
+    #[test]
+    fn test_antenna_subset() {
+        let data = tart_api::parse_dataset(&fs::read_to_string("data.json").unwrap()).unwrap();
+        let (all, _) = image_dataset(&data, 8, &ProcessingConfig::default()).unwrap();
+
+        let config = ProcessingConfig {
+            antenna_subset: Some(vec![0, 1, 2, 3, 4, 5]),
+            ..ProcessingConfig::default()
+        };
+        let (inner, _) = image_dataset(&data, 8, &config).unwrap();
+        assert!(inner.visible_pix != all.visible_pix);
+
+        let bad = ProcessingConfig {
+            antenna_subset: Some(vec![0, 1, 99]),
+            ..ProcessingConfig::default()
+        };
+        match image_dataset(&data, 8, &bad) {
+            Err(ProcessingError::InvalidFormat(msg)) => assert!(msg.contains("antenna 99")),
+            _ => panic!("Expected an InvalidFormat error"),
+        }
+    }
+
+    #[test]
+    fn test_decimate() {
+        let data = tart_api::parse_dataset(&fs::read_to_string("data.json").unwrap()).unwrap();
+        let (full, _) = image_dataset(&data, 16, &ProcessingConfig::default()).unwrap();
+        let config = ProcessingConfig { decimate: Some(2), ..ProcessingConfig::default() };
+        let (quick, _) = image_dataset(&data, 16, &config).unwrap();
+
+        // The same sky at lower fidelity: the brightest source is still found, and the
+        // images are correlated but not equal (the sidelobes differ)
+        assert_eq!(quick.peak().unwrap().healpix_index, full.peak().unwrap().healpix_index);
+        let centered = |x: &VectorReal| x - x.mean().unwrap();
+        let (a, b) = (centered(&full.visible_pix), centered(&quick.visible_pix));
+        let correlation = a.dot(&b)/(a.dot(&a)*b.dot(&b)).sqrt();
+        assert!(correlation > 0.5 && correlation < 0.99);
+    }
+
+    #[test]
+    fn test_bundle() {
+        let data = tart_api::parse_dataset(&fs::read_to_string("data.json").unwrap()).unwrap();
+        let config = ProcessingConfig::default();
+
+        let bundle = dataset_to_bundle(&data, 4, true, None, &config).unwrap();
+        let (svg, timestamp) = dataset_to_svg(&data, 4, true, &config).unwrap();
+        assert_eq!(bundle.image, BundleImage::Svg(svg));
+        assert_eq!(bundle.timestamp, timestamp);
+
+        let json: serde_json::Value = serde_json::from_str(&bundle.to_json()).unwrap();
+        assert_eq!(json["image"]["format"], "svg");
+        assert_eq!(json["nside"], 4);
+        assert_eq!(json["stats"]["n_s"], Hemisphere::new(4).npix);
+        assert_eq!(json["config"]["include_w_term"], true);
+        assert!(json["warnings"].is_array());
+
+        // PNG bytes are base64, starting with the encoded signature
+        let png = dataset_to_bundle(&data, 4, true, Some(32), &config).unwrap();
+        assert_eq!(png.image, BundleImage::Png(dataset_to_png(&data, 4, 32, &config).unwrap().0));
+        let json: serde_json::Value = serde_json::from_str(&png.to_json()).unwrap();
+        assert_eq!(json["image"]["format"], "png");
+        assert!(json["image"]["data"].as_str().unwrap().starts_with("iVBORw0KGgo"));
+    }
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.target_arch, std::env::consts::ARCH);
+        assert_eq!(info.features.contains(&"nightly"), cfg!(feature = "nightly"));
+
+        let text = info.to_string();
+        assert!(text.starts_with(&format!("gridless {}\n", info.version)));
+        assert!(text.contains("features: "));
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["binding_max_nside"], info.binding_max_nside);
+    }
+
+    #[test]
+    fn test_rgb_svg() {
+        let data = tart_api::parse_dataset(&fs::read_to_string("data.json").unwrap()).unwrap();
+        let config = ProcessingConfig::default();
+        let (svg, timestamp) = datasets_to_rgb_svg(&data, None, &data, 4, &config).unwrap();
+        assert_eq!(timestamp, dataset_to_svg(&data, 4, false, &config).unwrap().1);
+        // The same image in red and blue is shades of magenta throughout
+        assert!(svg.contains("fill=\"rgb(255,0,255)\""));
+        for fill in svg.split("fill=\"rgb(").skip(1) {
+            let rgb: Vec<&str> = fill.split(')').next().unwrap().split(',').collect();
+            assert!(rgb[0] == rgb[2] && rgb[1] == "0");
+        }
+    }
+
+    #[test]
+    fn test_comparison_svg() {
+        let data = tart_api::parse_dataset(&fs::read_to_string("data.json").unwrap()).unwrap();
+        let obs = get_obs_from_dataset(&data).unwrap();
+        let config_a = ProcessingConfig::default();
+        let config_b = ProcessingConfig { colormap: Colormap::Grayscale, ..ProcessingConfig::default() };
+        let svg = make_comparison_svg(&obs, 4, &config_a, &config_b, None).unwrap();
+
+        assert!(svg.contains("viewBox=\"0 0 8200 4240\""));
+        assert!(svg.contains(">colormap=Cubehelix</text>"));
+        assert!(svg.contains(">colormap=Grayscale</text>"));
+        // Each image is drawn whole, one beside the other
+        let single = Hemisphere::new(4).npix;
+        assert_eq!(svg.matches("<polygon").count(), 2*svg[..svg.find("translate(4200").unwrap()].matches("<polygon").count());
+        assert!(svg.matches("<polygon").count() <= 2*single);
+        assert!(make_comparison_svg(&obs, 3, &config_a, &config_b, None).is_ok());
+        let capped = ProcessingConfig { max_nside: Some(2), ..ProcessingConfig::default() };
+        assert!(make_comparison_svg(&obs, 4, &config_a, &capped, None).is_err());
+    }
+
+    #[test]
+    fn test_png_data_url() {
+        let json = fs::read_to_string("data.json").unwrap();
+        let png = json_to_png(json.clone(), 4, DATA_URL_PNG_SIZE).unwrap();
+        let url = get_png_data_url(json, 4).unwrap();
+        assert_eq!(url, png_data_url(&png));
+        // The PNG signature, base64 encoded
+        assert!(url.starts_with("data:image/png;base64,iVBORw0KGgo"));
+        assert_eq!(url.len(), "data:image/png;base64,".len() + 4*png.len().div_ceil(3));
+    }
 }
@@ -0,0 +1,105 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+use std::borrow::Borrow;
+
+use chrono::{DateTime, Utc};
+
+use config::ProcessingConfig;
+use error::ProcessingError;
+use imager::Imager;
+use sphere::ElAz;
+use tart_api::{FullDataset, RaDecSource};
+use tart_obs::{self, Observation};
+
+/*
+*   A position to follow through a sequence of observations: fixed in the sky of the
+*   site (e.g. a geostationary satellite), or a catalog source that moves with the stars.
+*/
+#[derive(Debug, Clone)]
+pub enum Target {
+    Horizontal(ElAz),
+    Equatorial(RaDecSource),
+}
+
+impl Target {
+    // Where the target is at the time of obs. Equatorial targets need the site location.
+    pub fn position(&self, obs: &Observation) -> Result<ElAz, ProcessingError> {
+        match self {
+            Target::Horizontal(elaz) => Ok(elaz.clone()),
+            Target::Equatorial(src) => match obs.location {
+                Some(ref location) => Ok(src.to_source(location, &obs.timestamp).elaz()),
+                None => Err(ProcessingError::InvalidFormat(
+                    "the dataset has no site location, needed for an RA/Dec target".to_string())),
+            },
+        }
+    }
+}
+
+/*
+*   The image value at the target for each dataset, in order. Epochs when the target
+*   is below the horizon give NaN. The array is assumed fixed, so the harmonics are
+*   computed once and reused for every epoch.
+*/
+pub fn lightcurve<I>(datasets: I,
+                     target: &Target,
+                     nside: u32,
+                     config: &ProcessingConfig) -> Result<Vec<(DateTime<Utc>, f32)>, ProcessingError>
+    where I: IntoIterator,
+          I::Item: Borrow<FullDataset>
+{
+    config.check_nside(nside)?;
+
+    let mut imager = Imager::new(nside, config.clone()).with_cache();
+    let mut points = Vec::new();
+    for data in datasets {
+        let obs = tart_obs::get_full(data.borrow())?;
+        let obs = match config.antenna_subset {
+            Some(ref antennas) => obs.with_antennas(antennas)?,
+            None => obs,
+        };
+        let position = target.position(&obs)?;
+        let value = if position.el > 0.0 {
+            imager.image(&obs);
+            imager.sky.value_at(&position).map_or(f32::NAN, |v| v as f32)
+        } else {
+            f32::NAN
+        };
+        points.push((obs.timestamp, value));
+    }
+    Ok(points)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use tart_api;
+
+    #[test]
+    fn test_lightcurve() {
+        let data = tart_api::file_to_dataset("data.json");
+        let datasets = vec![data];
+
+        let zenith = Target::Horizontal(ElAz::from_degrees(89.0, 0.0));
+        let points = lightcurve(&datasets, &zenith, 8, &ProcessingConfig::default()).unwrap();
+        assert_eq!(points.len(), 1);
+        assert!(points[0].1.is_finite());
+
+        let (sky, _) = ::image_dataset(&datasets[0], 8, &ProcessingConfig::default()).unwrap();
+        let expected = sky.value_at(&ElAz::from_degrees(89.0, 0.0)).unwrap() as f32;
+        assert!((points[0].1 - expected).abs() < 1.0e-6);
+
+        // A position below the horizon is a gap
+        let below = Target::Horizontal(ElAz::from_degrees(-10.0, 180.0));
+        let points = lightcurve(&datasets, &below, 8, &ProcessingConfig::default()).unwrap();
+        assert!(points[0].1.is_nan());
+
+        // The site is in the southern hemisphere, where the south celestial pole is always up
+        let pole = Target::Equatorial(RaDecSource { ra: 0.0, dec: -90.0, name: "SCP".to_string(), jy: 1.0 });
+        let points = lightcurve(&datasets, &pole, 8, &ProcessingConfig::default()).unwrap();
+        assert!(points[0].1.is_finite());
+    }
+}
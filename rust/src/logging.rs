@@ -0,0 +1,43 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+// Diagnostic output. By default messages go to stdout (or the browser console
+// when compiled to wasm). Hosts such as Node.js can install their own Logger.
+
+use std::cell::RefCell;
+
+#[cfg(target_arch = "wasm32")]
+use web_sys;
+
+pub trait Logger {
+    fn log(&self, msg: &str);
+}
+
+pub struct DefaultLogger;
+
+impl Logger for DefaultLogger {
+    #[cfg(target_arch = "wasm32")]
+    fn log(&self, msg: &str) {
+        web_sys::console::log_1(&msg.into());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn log(&self, msg: &str) {
+        println!("{}", msg);
+    }
+}
+
+thread_local! {
+    static LOGGER: RefCell<Box<dyn Logger>> = RefCell::new(Box::new(DefaultLogger));
+}
+
+/*
+*   Install a new logger, returning the one it replaces.
+*/
+pub fn set_logger(logger: Box<dyn Logger>) -> Box<dyn Logger> {
+    LOGGER.with(|l| l.replace(logger))
+}
+
+pub fn log(msg: &str) {
+    LOGGER.with(|l| l.borrow().log(msg));
+}
@@ -1,57 +1,790 @@
 //
 // Copyright (c) 2019-2021 Tim Molteno tim@elec.ac.nz
 //
+extern crate chrono;
 extern crate gridlesslib;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate structopt;
 
 #[cfg(test)]
 extern crate rand;
 
+use chrono::{DateTime, Utc};
 use std::fs::File;
+use std::io::BufReader;
 use std::io::BufWriter;
-use std::io::Read;
 use std::io::Write;
+use std::path::Path;
 use structopt::StructOpt;
 
-use std::time::Instant;
+use gridlesslib::beam::BeamModel;
+use gridlesslib::colormap::{AutoScale, Colormap};
+use gridlesslib::config::{PixelStyle, ProcessingConfig, MAX_SUPERSAMPLE};
+use gridlesslib::error::{ProcessingError, Warning};
+use gridlesslib::geojson::FeatureCollection;
+use gridlesslib::gridless::FourierConvention;
+use gridlesslib::imager::Imager;
+use gridlesslib::img::AntPositionUnit;
+use gridlesslib::lightcurve::{self, Target};
+use gridlesslib::running_mean::RunningMean;
+use gridlesslib::sphere::{ElAz, HealpixOrdering, Hemisphere};
+use gridlesslib::sphere::HemisphereStats;
+use gridlesslib::tart_api::{self, FullDataset};
+use gridlesslib::tart_obs;
+use gridlesslib::theme::Theme;
+
+use std::time::{Duration, Instant, SystemTime};
 
 /// Gridless deconvolution
 ///
 /// Example: ./gridless nside 8
-
 #[derive(StructOpt, Debug)]
 #[structopt(name = "gridless")]
-
 struct Opt {
-    #[structopt(long = "nside")]
-    nside: u32,
+    // Handled in main before parsing; declared so that --help lists it
+    #[allow(dead_code)]
+    #[structopt(long = "verbose", help = "With --version, also print the target and enabled features")]
+    verbose: bool,
+    #[structopt(long = "nside", help = "HEALPix nside, or 'auto' to match the pixel size to the synthesized beam")]
+    nside: String,
     #[structopt(long = "sources")]
     show_sources: bool,
-    #[structopt(long = "file", default_value = "data.json")]
+    #[structopt(long = "file", default_value = "data.json", help = "Dataset (.json), or one dataset per line (.jsonl) for a batch run")]
     file: String,
+    #[structopt(long = "out-dir", default_value = ".")]
+    out_dir: String,
+    #[structopt(long = "threshold", help = "Only draw pixels above this fraction of the data range")]
+    render_threshold: Option<f32>,
+    #[structopt(long = "ticks", help = "Label the horizon with azimuth ticks")]
+    show_azimuth_ticks: bool,
+    #[structopt(long = "no-w-term", help = "Drop the w term (coplanar array approximation)")]
+    no_w_term: bool,
+    #[structopt(long = "difference", help = "Batch runs: show each frame minus a running average with this decay (0-1]")]
+    difference: Option<f64>,
+    #[structopt(long = "validate", help = "Check the input parses and passes validation, without imaging")]
+    validate: bool,
+    #[structopt(long = "png", help = "Write a PNG image of this width and height instead of an SVG")]
+    png: Option<u32>,
+    #[structopt(long = "supersample", default_value = "1", help = "PNG antialiasing: render N times larger and downsample")]
+    supersample: u32,
+    #[structopt(long = "bitdepth", default_value = "8", help = "PNG bit depth: 8 for the colour image, or 16 for a grayscale data product of the scaled values")]
+    bitdepth: u32,
+    #[structopt(long = "max-nside", help = "Refuse larger nside (default: $GRIDLESS_MAX_NSIDE, else unbounded)")]
+    max_nside: Option<u32>,
+    #[structopt(long = "theme", help = "SVG theme: astronomy, light, or a JSON theme file")]
+    theme: Option<String>,
+    #[structopt(long = "uvrange", help = "Only image baselines with MIN <= |uv| <= MAX wavelengths, as MIN,MAX")]
+    uvrange: Option<String>,
+    #[structopt(long = "decimate", help = "Quick look: image only every Nth baseline")]
+    decimate: Option<usize>,
+    #[structopt(long = "antennas", help = "Image the sub-array of these antennas only, e.g. 0,1,2,5")]
+    antennas: Option<String>,
+    #[structopt(long = "lightcurve", help = "Write the image value at EL,AZ (degrees) for each dataset to lightcurve.csv instead of imaging")]
+    lightcurve: Option<String>,
+    #[structopt(long = "residuals", help = "Write per-baseline visibility residuals after subtracting the catalog sources to residuals.json (single dataset only)")]
+    residuals: bool,
+    #[structopt(long = "watch", help = "Re-image the file whenever it changes, writing gridless_latest.svg (or .png)")]
+    watch: bool,
+    #[structopt(long = "bundle", help = "Write the image, its statistics, the config and any warnings to this JSON file instead (single dataset only)")]
+    bundle: Option<String>,
+    #[structopt(long = "compose", help = "False colour image of two (red,blue) or three (red,green,blue) dataset files, e.g. L1.json,L2.json")]
+    compose: Option<String>,
+    #[structopt(long = "compare", raw(allow_hyphen_values = "true"), help = "Image a single dataset again with these extra options, e.g. \"--colormap grayscale\", and write both side by side")]
+    compare: Option<String>,
+    #[structopt(long = "clean", help = "CLEAN the image with at most this many components and print whether it converged (single dataset only)")]
+    clean: Option<usize>,
+    #[structopt(long = "clean-threshold", default_value = "0.05", help = "Stop --clean when the peak residual falls below this fraction of the dirty image peak")]
+    clean_threshold: f64,
+    #[structopt(long = "fits", help = "Write the image as a full sky HEALPix FITS map to this file instead (single dataset only)")]
+    fits: Option<String>,
+    #[structopt(long = "geojson", help = "Write the projected pixel polygons and their values as a GeoJSON-style feature collection to this file instead (single dataset only)")]
+    geojson: Option<String>,
+    #[structopt(long = "ordering", help = "HEALPix ordering of the --fits map: ring (the default) or nested")]
+    ordering: Option<HealpixOrdering>,
+    #[structopt(long = "tiles", help = "Write 256 pixel web map tiles at this zoom level to tiles/z/x/y.png")]
+    tiles: Option<u32>,
+    #[structopt(long = "colormap", help = "cubehelix or grayscale")]
+    colormap: Option<Colormap>,
+    #[structopt(long = "scale", help = "Colormap range: minmax, percentile, percentile:LO,HI, or histeq (histogram equalized)")]
+    scale: Option<AutoScale>,
+    #[structopt(long = "beam", help = "Primary beam to correct for: cosine:N or airy:DIAMETER_M,FREQUENCY_HZ")]
+    beam: Option<BeamModel>,
+    #[structopt(long = "smooth", help = "Smooth the image with a Gaussian of this FWHM in degrees")]
+    smooth: Option<f32>,
+    #[structopt(long = "preview", help = "Image at this lower nside and upsample to --nside, for a quick low quality look")]
+    preview: Option<u32>,
+    #[structopt(long = "peak", help = "Mark the brightest pixel with its value and position")]
+    annotate_peak: bool,
+    #[structopt(long = "dither", help = "Dither colours when quantizing to 8 bits, to break up banding")]
+    dither: bool,
+    #[structopt(long = "hermitian", help = "The dataset holds one half of each conjugate pair: add the other half before imaging")]
+    hermitian: bool,
+    #[structopt(long = "ant-units", help = "Units of the antenna positions: meters (the default) or wavelengths")]
+    ant_units: Option<AntPositionUnit>,
+    #[structopt(long = "convention", help = "Phase convention: negative (the default) or positive exponent, optionally :n-1 (the default) or :n for the w term")]
+    convention: Option<FourierConvention>,
+    #[structopt(long = "pixel-style", help = "Draw pixels as polygon outlines (the default) or point:RADIUS circles")]
+    pixel_style: Option<PixelStyle>,
+    #[structopt(long = "vis", help = "Read the dataset from separate endpoint files: visibilities (with --gains, --ant and --info) instead of --file")]
+    vis: Option<String>,
+    #[structopt(long = "gains", help = "Gains and phase offsets JSON, for --vis")]
+    gains: Option<String>,
+    #[structopt(long = "ant", help = "Antenna positions JSON, for --vis")]
+    ant: Option<String>,
+    #[structopt(long = "info", help = "Telescope info JSON, for --vis")]
+    info: Option<String>,
+    #[structopt(long = "profile", help = "Print the time spent in each stage of imaging a single dataset to stderr")]
+    profile: bool,
+}
+
+const TILE_SIZE: u32 = 256;
+
+// How often --watch checks the input, and how long it must be unchanged before imaging
+const WATCH_POLL: Duration = Duration::from_millis(500);
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(250);
+
+fn parse_elaz(s: &str) -> Result<ElAz, String> {
+    let mut parts = s.split(',').map(|p| p.trim().parse::<f64>());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(el)), Some(Ok(az)), None) => Ok(ElAz::from_degrees(el, az)),
+        _ => Err(format!("expected EL,AZ in degrees, got '{}'", s)),
+    }
+}
+
+fn parse_antennas(s: &str) -> Result<Vec<u32>, String> {
+    s.split(',')
+        .map(|p| p.trim().parse::<u32>().map_err(|_| format!("expected antenna numbers like 0,1,2,5, got '{}'", s)))
+        .collect()
+}
+
+fn parse_uvrange(s: &str) -> Result<(f32, f32), String> {
+    let mut parts = s.split(',').map(|p| p.trim().parse::<f32>());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(min)), Some(Ok(max)), None) if min <= max => Ok((min, max)),
+        _ => Err(format!("expected MIN,MAX with MIN <= MAX, got '{}'", s)),
+    }
+}
+
+/*
+*   Record of a batch run, written to manifest.json in the output directory.
+*/
+#[derive(Serialize)]
+struct ManifestEntry {
+    dataset: usize,
+    timestamp: Option<DateTime<Utc>>,
+    output: Option<String>,
+    stats: Option<HemisphereStats>,
+    error: Option<String>,
+    elapsed_ms: u128,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    input: &'a str,
+    nside: u32,
+    show_sources: bool,
+    config: &'a ProcessingConfig,
+    started: DateTime<Utc>,
+    entries: Vec<ManifestEntry>,
+    total_ms: u128,
+}
+
+fn write_svg(out_dir: &str, svg_data: &str, timestamp: &DateTime<Utc>) -> Result<String, ProcessingError> {
+    let dstring = timestamp.format("%Y_%m_%d_%H_%M_%S_%Z");
+    let fname = Path::new(out_dir).join(format!("gridless_{}.svg", dstring));
+    write_output(&fname, svg_data)?;
+    Ok(fname.to_string_lossy().into_owned())
+}
+
+// Write contents to fname, naming the file in any error
+fn write_output<P: AsRef<Path>, C: AsRef<[u8]>>(fname: P, contents: C) -> Result<(), ProcessingError> {
+    std::fs::write(&fname, contents)
+        .map_err(|e| ProcessingError::Io(format!("{}: {}", fname.as_ref().display(), e)))
+}
+
+// Write value to fname as pretty printed JSON
+fn write_json<P: AsRef<Path>, T: serde::Serialize>(fname: P, value: &T) -> Result<(), ProcessingError> {
+    let failed = |e: String| ProcessingError::Io(format!("{}: {}", fname.as_ref().display(), e));
+    let mut output = BufWriter::new(File::create(&fname).map_err(|e| failed(e.to_string()))?);
+    serde_json::to_writer_pretty(&mut output, value).map_err(|e| failed(e.to_string()))?;
+    output.flush().map_err(|e| failed(e.to_string()))
+}
+
+fn read_input(fname: &str) -> Result<String, ProcessingError> {
+    std::fs::read_to_string(fname).map_err(|e| ProcessingError::Io(format!("{}: {}", fname, e)))
+}
+
+// The value, or report the error and exit non-zero, as a failed --validate does
+fn or_exit<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
+/*
+*   Image one dataset of a live sequence as its difference from the running average
+*   of the frames before it.
+*/
+fn difference_to_svg(data: &FullDataset, nside: u32, show_sources: bool, config: &ProcessingConfig,
+                     running: &mut RunningMean) -> Result<(String, DateTime<Utc>, HemisphereStats), ProcessingError> {
+    let (mut sky, obs) = gridlesslib::image_dataset(data, nside, config)?;
+    sky.visible_pix = running.update(&sky.visible_pix);
+
+    let sources = if show_sources {
+        Some(gridlesslib::get_sources_from_dataset(data))
+    } else {
+        None
+    };
+    let mut svg = sky.to_svg_with_config(config, sources);
+    svg.title(&obs.describe());
+    Ok((svg.to_string(), obs.timestamp, sky.stats()))
+}
+
+// Print a PASS/FAIL line for one dataset with its issues and warnings. Returns true on a pass.
+fn report(label: &str, (issues, warnings): (Vec<ProcessingError>, Vec<Warning>)) -> bool {
+    if issues.is_empty() {
+        println!("PASS {}", label);
+    } else {
+        println!("FAIL {}", label);
+        for issue in &issues {
+            println!("    {}", issue);
+        }
+    }
+    for warning in &warnings {
+        println!("    warning: {}", warning);
+    }
+    issues.is_empty()
+}
+
+fn validate(opt: &Opt, nside: u32) -> bool {
+    let check = |data: Result<FullDataset, ProcessingError>| match data {
+        Ok(d) => gridlesslib::validate_dataset(&d, nside),
+        Err(e) => (vec![e], Vec::new()),
+    };
+
+    if opt.file.ends_with(".jsonl") {
+        let file = match File::open(&opt.file) {
+            Ok(file) => file,
+            Err(e) => return report(&opt.file, (vec![ProcessingError::Io(e.to_string())], Vec::new())),
+        };
+        let mut passed = true;
+        for (n, data) in tart_api::datasets_from_jsonl(BufReader::new(file)).enumerate() {
+            passed &= report(&format!("{} dataset {}", opt.file, n), check(data));
+        }
+        passed
+    } else {
+        let data = std::fs::read_to_string(&opt.file)
+            .map_err(|e| ProcessingError::Io(e.to_string()))
+            .and_then(|json| tart_api::parse_dataset(&json));
+        report(&opt.file, check(data))
+    }
+}
+
+/*
+*   The dataset assembled from separate --vis/--gains/--ant/--info files, or None when
+*   none of them are given. Giving only some of them is an error.
+*/
+fn endpoint_dataset(opt: &Opt) -> Option<Result<FullDataset, ProcessingError>> {
+    match (&opt.vis, &opt.gains, &opt.ant, &opt.info) {
+        (None, None, None, None) => None,
+        (Some(vis), Some(gains), Some(ant), Some(info)) => Some(tart_api::assemble_dataset(vis, gains, ant, info)),
+        _ => Some(Err(ProcessingError::Io("--vis, --gains, --ant and --info must be given together".to_string()))),
+    }
+}
+
+/*
+*   The nside to image with. 'auto' suggests one from the dataset the run images, with
+*   the antenna positions read in unit as they will be when imaging.
+*/
+fn resolve_nside(opt: &Opt, unit: AntPositionUnit) -> Result<u32, String> {
+    if opt.nside != "auto" {
+        return explicit_nside(opt);
+    }
+    suggest_nside(opt, unit).map_err(|e| e.to_string())
+}
+
+fn explicit_nside(opt: &Opt) -> Result<u32, String> {
+    opt.nside.parse()
+        .map_err(|_| format!("--nside must be a number or 'auto', got '{}'", opt.nside))
+}
+
+/*
+*   The dataset the run images: the endpoint files, the first --compose file, or --file.
+*   A batch skips bad lines, so its first readable dataset is used; the array (and so
+*   the beam) is the same for every dataset in a batch.
+*/
+fn imaged_dataset(opt: &Opt) -> Result<FullDataset, ProcessingError> {
+    if let Some(data) = endpoint_dataset(opt) {
+        return data;
+    }
+    let fname = match opt.compose {
+        Some(ref files) => files.split(',').next().unwrap_or("").trim(),
+        None => opt.file.as_str(),
+    };
+    if fname.ends_with(".jsonl") {
+        let file = File::open(fname).map_err(|e| ProcessingError::Io(format!("{}: {}", fname, e)))?;
+        tart_api::datasets_from_jsonl(BufReader::new(file))
+            .find_map(|data| data.ok())
+            .ok_or_else(|| ProcessingError::InvalidFormat("no readable datasets in input".to_string()))
+    } else {
+        tart_api::parse_dataset(&read_input(fname)?)
+    }
+}
+
+fn suggest_nside(opt: &Opt, unit: AntPositionUnit) -> Result<u32, ProcessingError> {
+    let obs = gridlesslib::get_obs_from_dataset(&imaged_dataset(opt)?)?;
+    let nside = tart_obs::suggest_nside(&obs, unit);
+    println!("Using nside {} (longest baseline {:.1} wavelengths)", nside, obs.max_baseline_wavelengths(unit));
+    Ok(nside)
+}
+
+/*
+*   Follow one position through every dataset in the input, writing timestamp,value
+*   rows to lightcurve.csv. Bad datasets are reported and skipped; epochs with the
+*   target below the horizon are written as NaN.
+*/
+fn write_lightcurve(opt: &Opt, target: &Target, nside: u32, config: &ProcessingConfig) -> Result<(), ProcessingError> {
+    let datasets: Vec<FullDataset> = if opt.file.ends_with(".jsonl") {
+        let file = File::open(&opt.file).map_err(|e| ProcessingError::Io(e.to_string()))?;
+        tart_api::datasets_from_jsonl(BufReader::new(file))
+            .enumerate()
+            .filter_map(|(n, data)| data.map_err(|e| eprintln!("dataset {}: {}", n, e)).ok())
+            .collect()
+    } else {
+        let json = std::fs::read_to_string(&opt.file).map_err(|e| ProcessingError::Io(e.to_string()))?;
+        vec![tart_api::parse_dataset(&json)?]
+    };
+
+    let points = lightcurve::lightcurve(&datasets, target, nside, config)?;
+
+    let fname = Path::new(&opt.out_dir).join("lightcurve.csv");
+    let mut output = BufWriter::new(File::create(fname).map_err(|e| ProcessingError::Io(e.to_string()))?);
+    let mut write = |line: String| output.write_all(line.as_bytes()).map_err(|e| ProcessingError::Io(e.to_string()));
+    write("timestamp,value\n".to_string())?;
+    for (timestamp, value) in points {
+        write(format!("{},{}\n", timestamp.to_rfc3339(), value))?;
+    }
+    Ok(())
+}
+
+/*
+*   Image a single dataset file to SVG one stage at a time, as dataset_to_svg does,
+*   returning how long each stage took. The timing lives here rather than in the
+*   library because Instant is unavailable in the browser.
+*/
+fn profile_svg(opt: &Opt, nside: u32, config: &ProcessingConfig) -> Result<Vec<(&'static str, Duration)>, ProcessingError> {
+    let mut stages = Vec::new();
+    let mut t0 = Instant::now();
+    let mut lap = |stage: &'static str| {
+        stages.push((stage, t0.elapsed()));
+        t0 = Instant::now();
+    };
+
+    let json = std::fs::read_to_string(&opt.file).map_err(|e| ProcessingError::Io(e.to_string()))?;
+    lap("read");
+    let data = tart_api::parse_dataset(&json)?;
+    lap("parse");
+    config.check_nside(nside)?;
+    let obs = gridlesslib::get_obs_from_dataset(&data)?;
+    let obs = match config.antenna_subset {
+        Some(ref antennas) => obs.with_antennas(antennas)?,
+        None => obs,
+    };
+    lap("observation");
+    print_warnings(&obs.warnings);
+    let (u, v, w) = obs.get_uvw(config.ant_position_unit);
+    lap("uvw");
+    let imaging_nside = config.imaging_nside(nside);
+    config.check_nside(imaging_nside)?;
+    let mut imager = Imager::new(imaging_nside, config.clone());
+    lap("hemisphere");
+    imager.image_vis(&obs.vis_arr, &u, &v, &w);
+    if imaging_nside != nside {
+        imager.sky = imager.sky.resample_to(nside);
+    }
+    lap("reconstruction");
+    let sources = if opt.show_sources { Some(gridlesslib::get_sources_from_dataset(&data)) } else { None };
+    let mut svg = imager.sky.to_svg_with_config(config, sources);
+    svg.title(&obs.describe());
+    let svg_data = svg.to_string();
+    lap("svg");
+    write_svg(&opt.out_dir, &svg_data, &obs.timestamp)?;
+    lap("write");
+    Ok(stages)
+}
+
+/*
+*   Image two or three comma separated dataset files into one false colour SVG.
+*/
+fn write_composite(opt: &Opt, files: &str, nside: u32, config: &ProcessingConfig) -> Result<(), ProcessingError> {
+    let datasets = files.split(',')
+        .map(|fname| {
+            let fname = fname.trim();
+            let json = std::fs::read_to_string(fname).map_err(|e| ProcessingError::Io(format!("{}: {}", fname, e)))?;
+            tart_api::parse_dataset(&json)
+        })
+        .collect::<Result<Vec<FullDataset>, ProcessingError>>()?;
+    let (svg_data, timestamp) = match datasets.len() {
+        2 => gridlesslib::datasets_to_rgb_svg(&datasets[0], None, &datasets[1], nside, config)?,
+        3 => gridlesslib::datasets_to_rgb_svg(&datasets[0], Some(&datasets[1]), &datasets[2], nside, config)?,
+        n => return Err(ProcessingError::InvalidFormat(format!("--compose needs 2 or 3 files, not {}", n))),
+    };
+    write_svg(&opt.out_dir, &svg_data, &timestamp)?;
+    Ok(())
+}
+
+// The processing config given by the command line options
+fn make_config(opt: &Opt) -> ProcessingConfig {
+    ProcessingConfig {
+        render_threshold: opt.render_threshold,
+        show_azimuth_ticks: opt.show_azimuth_ticks,
+        include_w_term: !opt.no_w_term,
+        ant_position_unit: opt.ant_units.unwrap_or_default(),
+        fourier_convention: opt.convention.unwrap_or_default(),
+        assume_hermitian: opt.hermitian,
+        dither: opt.dither,
+        annotate_peak: opt.annotate_peak,
+        colormap: opt.colormap.unwrap_or_default(),
+        auto_scale: opt.scale.unwrap_or_default(),
+        beam: opt.beam,
+        smooth_fwhm: opt.smooth,
+        preview_nside: opt.preview,
+        pixel_style: opt.pixel_style.unwrap_or_default(),
+        baseline_filter: opt.uvrange.as_deref().map(|s| or_exit(parse_uvrange(s).map_err(|e| format!("--uvrange: {}", e)))),
+        decimate: opt.decimate,
+        antenna_subset: opt.antennas.as_deref().map(|s| or_exit(parse_antennas(s).map_err(|e| format!("--antennas: {}", e)))),
+        supersample: match opt.supersample {
+            n @ 1..=MAX_SUPERSAMPLE => n,
+            n => {
+                eprintln!("--supersample: expected 1 to {}, got {}", MAX_SUPERSAMPLE, n);
+                std::process::exit(1);
+            },
+        },
+        png_bit_depth: match opt.bitdepth {
+            8 | 16 => opt.bitdepth,
+            n => {
+                eprintln!("--bitdepth: expected 8 or 16, got {}", n);
+                std::process::exit(1);
+            },
+        },
+        max_nside: opt.max_nside.or_else(|| std::env::var("GRIDLESS_MAX_NSIDE").ok().and_then(|s| s.parse().ok())),
+        theme: opt.theme.as_deref().map(|name| or_exit(load_theme(name).map_err(|e| format!("--theme: {}", e)))).unwrap_or_default(),
+        ..ProcessingConfig::default()
+    }
+}
+
+/*
+*   Image a single dataset twice, with the command line options and with the options in
+*   compare added to them (e.g. "--colormap grayscale"), and write both side by side.
+*/
+fn write_comparison(opt: &Opt, compare: &str, nside: u32, config: &ProcessingConfig) -> Result<(), ProcessingError> {
+    // The command line without --compare, plus the options to compare
+    let mut args = Vec::new();
+    let mut skip = false;
+    for arg in std::env::args() {
+        if skip {
+            skip = false;
+        } else if arg == "--compare" {
+            skip = true;
+        } else if !arg.starts_with("--compare=") {
+            args.push(arg);
+        }
+    }
+    args.extend(compare.split_whitespace().map(String::from));
+    let opt_b = Opt::from_iter_safe(args)
+        .map_err(|e| ProcessingError::InvalidFormat(format!("--compare: {}", e)))?;
+    let config_b = make_config(&opt_b);
+
+    let json = std::fs::read_to_string(&opt.file).map_err(|e| ProcessingError::Io(format!("{}: {}", opt.file, e)))?;
+    let data = tart_api::parse_dataset(&json)?;
+    let obs = gridlesslib::get_obs_from_dataset(&data)?;
+    let sources = if opt.show_sources {
+        Some(gridlesslib::get_sources_from_dataset(&data))
+    } else {
+        None
+    };
+    let svg_data = gridlesslib::make_comparison_svg(&obs, nside, config, &config_b, sources)?;
+    print_warnings(&obs.warnings);
+    write_svg(&opt.out_dir, &svg_data, &obs.timestamp)?;
+    Ok(())
+}
+
+fn print_warnings(warnings: &[Warning]) {
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+}
+
+fn print_profile(stages: &[(&str, Duration)]) {
+    let total: Duration = stages.iter().map(|s| s.1).sum();
+    eprintln!("{:<16}{:>12}{:>8}", "stage", "ms", "%");
+    for (stage, elapsed) in stages.iter().chain(std::iter::once(&("total", total))) {
+        eprintln!("{:<16}{:>12.3}{:>8.1}", stage, elapsed.as_secs_f64()*1000.0,
+            100.0*elapsed.as_secs_f64()/total.as_secs_f64().max(1.0e-12));
+    }
+}
+
+/*
+*   Write the tiles of one zoom level as out_dir/tiles/z/x/y.png. Tiles entirely
+*   beyond the horizon are skipped; map viewers leave missing tiles blank.
+*/
+fn write_tiles(opt: &Opt, json: &str, nside: u32, zoom: u32, config: &ProcessingConfig) -> Result<usize, ProcessingError> {
+    let tiles = Hemisphere::tiles_per_side(zoom)?;
+    let data = tart_api::parse_dataset(json)?;
+    let (sky, _obs) = gridlesslib::image_dataset(&data, nside, config)?;
+
+    let mut written = 0;
+    for x in 0..tiles {
+        let dir = Path::new(&opt.out_dir).join("tiles").join(zoom.to_string()).join(x.to_string());
+        for y in 0..tiles {
+            if let Some(png) = sky.to_tile_png(zoom, x, y, TILE_SIZE, config)? {
+                std::fs::create_dir_all(&dir)
+                    .and_then(|_| std::fs::write(dir.join(format!("{}.png", y)), png))
+                    .map_err(|e| ProcessingError::Io(e.to_string()))?;
+                written += 1;
+            }
+        }
+    }
+    Ok(written)
+}
+
+fn modified(fname: &str) -> Option<SystemTime> {
+    std::fs::metadata(fname).and_then(|m| m.modified()).ok()
+}
+
+/*
+*   Image the file to gridless_latest.svg (or .png) in the output directory. The image
+*   is written to a temporary file and renamed, so a viewer never sees a partial file.
+*/
+fn image_latest(opt: &Opt, nside: u32, config: &ProcessingConfig) -> Result<(), ProcessingError> {
+    let json = std::fs::read_to_string(&opt.file).map_err(|e| ProcessingError::Io(e.to_string()))?;
+    let (bytes, ext) = match opt.png {
+        Some(size) => {
+            let data = tart_api::parse_dataset(&json)?;
+            (gridlesslib::dataset_to_png(&data, nside, size, config)?.0, "png")
+        },
+        None => (gridlesslib::json_to_svg_with_config(&json, nside, opt.show_sources, config)?.0.into_bytes(), "svg"),
+    };
+    let fname = Path::new(&opt.out_dir).join(format!("gridless_latest.{}", ext));
+    let tmp = Path::new(&opt.out_dir).join(format!(".gridless_latest.{}.tmp", ext));
+    std::fs::write(&tmp, bytes)
+        .and_then(|_| std::fs::rename(&tmp, &fname))
+        .map_err(|e| ProcessingError::Io(e.to_string()))
+}
+
+/*
+*   Poll the input's modification time and re-image when it changes. A change is only
+*   acted on once the file has stopped changing for WATCH_DEBOUNCE, so a file being
+*   written in several chunks is imaged once. Errors (e.g. a half written file) are
+*   reported and the watch continues.
+*/
+fn watch(opt: &Opt, nside: u32, config: &ProcessingConfig) -> ! {
+    let mut last = None;
+    loop {
+        let mtime = modified(&opt.file);
+        if mtime.is_some() && mtime != last {
+            std::thread::sleep(WATCH_DEBOUNCE);
+            if modified(&opt.file) == mtime {
+                last = mtime;
+                let t0 = Instant::now();
+                match image_latest(opt, nside, config) {
+                    Ok(()) => println!("Imaged {} in {} ms", opt.file, t0.elapsed().as_millis()),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            continue;
+        }
+        std::thread::sleep(WATCH_POLL);
+    }
+}
+
+fn load_theme(name: &str) -> Result<Theme, ProcessingError> {
+    match name {
+        "astronomy" => Ok(Theme::astronomy()),
+        "light" => Ok(Theme::light()),
+        fname => {
+            let json = read_input(fname)?;
+            serde_json::from_str(&json).map_err(|e| ProcessingError::Parse(format!("{}: {}", fname, e)))
+        }
+    }
 }
 
 fn main() {
+    // Checked before parsing, as --version alone prints the version and exits
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|a| a == "--verbose") && args.iter().any(|a| a == "--version" || a == "-V") {
+        println!("{}", gridlesslib::build_info());
+        return;
+    }
+
     let opt = Opt::from_args();
-    let nside = opt.nside;
+    let config = make_config(&opt);
+
+    // Before resolving 'auto', which reads the input, so a bad file gets its FAIL line
+    if opt.validate {
+        // A suggested nside is always a power of two, so 'auto' has nothing to warn about
+        let nside = if opt.nside == "auto" { 1 } else { or_exit(explicit_nside(&opt)) };
+        let passed = validate(&opt, nside);
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    let nside = or_exit(resolve_nside(&opt, config.ant_position_unit));
 
     let start = Instant::now();
 
-    let mut file = File::open(&opt.file).unwrap();
-    let mut json = String::new();
-    file.read_to_string(&mut json).unwrap();
+    if let Some(ref compare) = opt.compare {
+        or_exit(write_comparison(&opt, compare, nside, &config));
+        println!("Gridless took {} ms", start.elapsed().as_millis());
+        return;
+    }
+    if let Some(ref files) = opt.compose {
+        or_exit(write_composite(&opt, files, nside, &config));
+        println!("Gridless took {} ms", start.elapsed().as_millis());
+        return;
+    }
 
-    // Main library call. Returns some SVG data
-    let (svg_data, timestamp) = gridlesslib::json_to_svg(&json, nside, opt.show_sources);
+    if let Some(data) = endpoint_dataset(&opt) {
+        or_exit(data.and_then(|d| match opt.png {
+            Some(size) => gridlesslib::dataset_to_png(&d, nside, size, &config)
+                .and_then(|(png_data, timestamp)| {
+                    let dstring = timestamp.format("%Y_%m_%d_%H_%M_%S_%Z");
+                    write_output(Path::new(&opt.out_dir).join(format!("gridless_{}.png", dstring)), png_data)
+                }),
+            None => gridlesslib::dataset_to_svg_with_warnings(&d, nside, opt.show_sources, &config)
+                .and_then(|(svg_data, timestamp, warnings)| {
+                    print_warnings(&warnings);
+                    write_svg(&opt.out_dir, &svg_data, &timestamp).map(|_| ())
+                }),
+        }));
+        println!("Gridless took {} ms", start.elapsed().as_millis());
+        return;
+    }
 
-    let dstring = timestamp.format("%Y_%m_%d_%H_%M_%S_%Z");
-    let fname = format!("gridless_{}.svg", dstring);
+    if opt.profile {
+        if opt.file.ends_with(".jsonl") || opt.png.is_some() {
+            eprintln!("--profile times a single dataset imaged to SVG");
+            std::process::exit(1);
+        }
+        print_profile(&or_exit(profile_svg(&opt, nside, &config)));
+        return;
+    }
+
+    if opt.watch {
+        if opt.file.ends_with(".jsonl") {
+            eprintln!("--watch needs a single dataset, not a .jsonl batch");
+            std::process::exit(1);
+        }
+        watch(&opt, nside, &config);
+    }
+
+    if let Some(ref elaz) = opt.lightcurve {
+        let elaz = or_exit(parse_elaz(elaz).map_err(|e| format!("--lightcurve: {}", e)));
+        or_exit(write_lightcurve(&opt, &Target::Horizontal(elaz), nside, &config));
+    } else if opt.file.ends_with(".jsonl") {
+        // A batch run with one dataset per line. Bad lines are reported and skipped.
+        let mut manifest = Manifest {
+            input: &opt.file,
+            nside,
+            show_sources: opt.show_sources,
+            config: &config,
+            started: Utc::now(),
+            entries: Vec::new(),
+            total_ms: 0,
+        };
+
+        let mut running = opt.difference.map(RunningMean::new);
+
+        let file = or_exit(File::open(&opt.file).map_err(|e| ProcessingError::Io(format!("{}: {}", opt.file, e))));
+        for (n, data) in tart_api::datasets_from_jsonl(BufReader::new(file)).enumerate() {
+            let t0 = Instant::now();
+            let mut entry = ManifestEntry {
+                dataset: n,
+                timestamp: None,
+                output: None,
+                stats: None,
+                error: None,
+                elapsed_ms: 0,
+            };
+            let result = data.and_then(|d| match running {
+                Some(ref mut rm) => difference_to_svg(&d, nside, opt.show_sources, &config, rm),
+                None => gridlesslib::dataset_to_svg_with_stats(&d, nside, opt.show_sources, &config),
+            }).and_then(|(svg_data, timestamp, stats)| {
+                write_svg(&opt.out_dir, &svg_data, &timestamp).map(|output| (output, timestamp, stats))
+            });
+            match result {
+                Ok((output, timestamp, stats)) => {
+                    entry.output = Some(output);
+                    entry.timestamp = Some(timestamp);
+                    entry.stats = Some(stats);
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    entry.error = Some(e.to_string());
+                },
+            }
+            entry.elapsed_ms = t0.elapsed().as_millis();
+            manifest.entries.push(entry);
+        }
+
+        manifest.total_ms = start.elapsed().as_millis();
+        or_exit(write_json(Path::new(&opt.out_dir).join("manifest.json"), &manifest));
+    } else {
+        let json = or_exit(read_input(&opt.file));
 
-    let mut output = BufWriter::new(File::create(fname).unwrap());
-    //svg_data.finalize(&mut output).expect("Writing SVG image failed");
-    (&mut output)
-        .write_all(svg_data.as_bytes())
-        .expect("Writing SVG image failed");
+        if let Some(zoom) = opt.tiles {
+            let n = or_exit(write_tiles(&opt, &json, nside, zoom, &config));
+            println!("Wrote {} tiles at zoom {}", n, zoom);
+        } else if opt.residuals {
+            let report = or_exit(tart_api::parse_dataset(&json)
+                .and_then(|data| gridlesslib::dataset_residuals(&data)));
+            println!("Residual RMS {:.6}", report.rms);
+            or_exit(write_json(Path::new(&opt.out_dir).join("residuals.json"), &report));
+        } else if let Some(n_iter) = opt.clean {
+            let data = or_exit(tart_api::parse_dataset(&json));
+            let (sky, obs, report) = or_exit(gridlesslib::clean_dataset(&data, nside, n_iter, opt.clean_threshold, &config));
+            print_warnings(&obs.warnings);
+            println!("CLEAN {}", report);
+            let sources = if opt.show_sources {
+                Some(gridlesslib::get_sources_from_dataset(&data))
+            } else {
+                None
+            };
+            let mut svg = sky.to_svg_with_config(&config, sources);
+            svg.title(&obs.describe());
+            or_exit(write_svg(&opt.out_dir, &svg.to_string(), &obs.timestamp));
+        } else if let Some(ref fname) = opt.fits {
+            let (sky, obs) = or_exit(tart_api::parse_dataset(&json)
+                .and_then(|data| gridlesslib::image_dataset(&data, nside, &config)));
+            print_warnings(&obs.warnings);
+            let fits = or_exit(sky.to_fits(opt.ordering.unwrap_or_default()));
+            or_exit(write_output(fname, fits));
+        } else if let Some(ref fname) = opt.geojson {
+            let (sky, obs) = or_exit(tart_api::parse_dataset(&json)
+                .and_then(|data| gridlesslib::image_dataset(&data, nside, &config)));
+            print_warnings(&obs.warnings);
+            or_exit(write_output(fname, FeatureCollection::from_hemisphere(&sky).to_json()));
+        } else if let Some(ref fname) = opt.bundle {
+            let bundle = or_exit(tart_api::parse_dataset(&json)
+                .and_then(|data| gridlesslib::dataset_to_bundle(&data, nside, opt.show_sources, opt.png, &config)));
+            print_warnings(&bundle.warnings);
+            or_exit(write_output(fname, bundle.to_json()));
+        } else if let Some(size) = opt.png {
+            let (png_data, timestamp) = or_exit(tart_api::parse_dataset(&json)
+                .and_then(|data| gridlesslib::dataset_to_png(&data, nside, size, &config)));
+            let dstring = timestamp.format("%Y_%m_%d_%H_%M_%S_%Z");
+            or_exit(write_output(Path::new(&opt.out_dir).join(format!("gridless_{}.png", dstring)), png_data));
+        } else {
+            // Main library call. Returns some SVG data
+            let (svg_data, timestamp, warnings) = or_exit(tart_api::parse_dataset(&json)
+                .and_then(|data| gridlesslib::dataset_to_svg_with_warnings(&data, nside, opt.show_sources, &config)));
+            print_warnings(&warnings);
+            or_exit(write_svg(&opt.out_dir, &svg_data, &timestamp));
+        }
+    }
 
     println!("Gridless took {} ms", start.elapsed().as_millis());
 }
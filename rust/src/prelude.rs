@@ -0,0 +1,31 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+//! Commonly needed types, re-exported for library users.
+//!
+//! ```
+//! use gridlesslib::prelude::*;
+//!
+//! let sky = Hemisphere::new(4);
+//! let config = ProcessingConfig::default();
+//! let svg = sky.to_svg_with_config(&config, None).to_string();
+//! assert!(svg.contains("<svg"));
+//! ```
+//!
+//! Everything exported here is considered stable: it will not be renamed or removed
+//! without a version bump. Types reached through other module paths may still move.
+
+pub use beam::BeamModel;
+pub use bundle::{BundleImage, RenderBundle};
+pub use colormap::{Colormap, AutoScale};
+pub use config::ProcessingConfig;
+pub use error::{ProcessingError, Warning};
+pub use imager::Imager;
+pub use iteration::IterationReport;
+pub use logging::Logger;
+pub use running_mean::RunningMean;
+pub use sphere::{Hemisphere, HemisphereStats, HealpixOrdering, PixelView, ElAz};
+pub use tart_api::{FullDataset, Source, RaDecSource, Location, InputLimits};
+pub use tart_obs::Observation;
+pub use utils::{VectorReal, VectorComplex, C64};
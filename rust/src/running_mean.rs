@@ -0,0 +1,80 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+use utils::VectorReal;
+
+/*
+*   Exponential moving average of a sequence of images (visible_pix arrays), used
+*   to show each new frame as its deviation from recent frames so that transients
+*   stand out against steady sources.
+*
+*   decay is the weight given to the newest frame when updating the average, in (0, 1].
+*   A small decay averages over roughly 1/decay frames.
+*/
+pub struct RunningMean {
+    pub decay: f64,
+    mean: Option<VectorReal>,
+}
+
+impl RunningMean {
+    pub fn new(decay: f64) -> RunningMean {
+        RunningMean {
+            decay,
+            mean: None,
+        }
+    }
+
+    /*
+    *   Returns the frame minus the average of the previous frames, then folds the
+    *   frame into the average. The first frame (or one with a different number of
+    *   pixels) restarts the average and gives a zero difference.
+    */
+    pub fn update(&mut self, frame: &VectorReal) -> VectorReal {
+        match self.mean {
+            Some(ref mut mean) if mean.len() == frame.len() => {
+                let diff = frame - &*mean;
+                *mean = &*mean*(1.0 - self.decay) + frame*self.decay;
+                diff
+            },
+            _ => {
+                self.mean = Some(frame.clone());
+                VectorReal::zeros(frame.len())
+            }
+        }
+    }
+
+    pub fn mean(&self) -> Option<&VectorReal> {
+        self.mean.as_ref()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_steady_sky_cancels() {
+        let mut rm = RunningMean::new(0.2);
+        let frame = VectorReal::from_vec(vec![1.0, 2.0, 3.0]);
+        for _ in 0..5 {
+            let diff = rm.update(&frame);
+            assert!(diff.iter().all(|d| d.abs() < 1.0e-12));
+        }
+    }
+
+    #[test]
+    fn test_transient_stands_out() {
+        let mut rm = RunningMean::new(0.5);
+        let steady = VectorReal::from_vec(vec![1.0, 1.0, 1.0]);
+        rm.update(&steady);
+        rm.update(&steady);
+
+        let flare = VectorReal::from_vec(vec![1.0, 5.0, 1.0]);
+        let diff = rm.update(&flare);
+        assert_eq!(diff, VectorReal::from_vec(vec![0.0, 4.0, 0.0]));
+        assert_eq!(rm.mean().unwrap()[1], 3.0);
+    }
+}
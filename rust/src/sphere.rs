@@ -6,15 +6,67 @@
 
 
 
-use cdshealpix::ring::{n_hash, center};
-use utils::{VectorReal, PI_OVER_2};
+use cdshealpix::ring::{n_hash, center, hash};
+use cdshealpix::nested;
+use utils::{VectorReal, PI, PI_OVER_2, median};
+use error::ProcessingError;
+use fits;
+
+use std::convert::TryInto;
+use std::str::FromStr;
+
+// Header of the serialized geometry produced by Hemisphere::to_binary
+const GEOMETRY_MAGIC: &[u8; 4] = b"GLHS";
+const GEOMETRY_HEADER: usize = 12;
+const GEOMETRY_PIXEL: usize = 48;
+// Largest nside from_binary accepts: far beyond any image, and small enough that the
+// pixel count can't overflow a 32 bit usize (wasm32)
+const MAX_GEOMETRY_NSIDE: u32 = 8192;
+
+// The HEALPix convention for pixels without data in a full sky map
+pub const HEALPIX_UNSEEN: f64 = -1.6375e30;
+
+/*
+*   Pixel numbering of full sky HEALPix maps exchanged with other tools. A Hemisphere
+*   is always RING internally, where the pixels above the horizon are the first npix;
+*   the ordering applies when a map is written or read (to_healpix_map, to_fits).
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub enum HealpixOrdering {
+    #[default]
+    Ring,
+    Nested,
+}
+
+impl HealpixOrdering {
+    // The FITS ORDERING keyword value
+    pub fn name(&self) -> &'static str {
+        match self {
+            HealpixOrdering::Ring => "RING",
+            HealpixOrdering::Nested => "NESTED",
+        }
+    }
+}
+
+// Parse an ordering, ignoring case: ring, or nested (or nest)
+impl FromStr for HealpixOrdering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HealpixOrdering, String> {
+        match s.trim().to_lowercase().as_str() {
+            "ring" => Ok(HealpixOrdering::Ring),
+            "nested" | "nest" => Ok(HealpixOrdering::Nested),
+            _ => Err(format!("unknown HEALPix ordering '{}', expected ring or nested", s)),
+        }
+    }
+}
 
 
 
 /*
 *   Elevation and Azimuth (in radians)
 */
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ElAz {
     pub el: f64,
     pub az: f64
@@ -47,8 +99,8 @@ pub struct HpAngle {
 impl LonLat {
     pub fn new(lon: f64,  lat: f64) -> LonLat {
         LonLat {
-            lon: lon,
-            lat: lat
+            lon,
+            lat
         }
     }
     #[allow(dead_code)]
@@ -66,8 +118,8 @@ impl LonLat {
 impl HpAngle {
     pub fn new(theta: f64,  phi: f64) -> HpAngle {
         HpAngle {
-            theta: theta,
-            phi: phi
+            theta,
+            phi
         }
     }
     
@@ -95,11 +147,31 @@ impl HpAngle {
 impl ElAz {
     pub fn new(el: f64, az: f64) -> ElAz {
         ElAz {
-            el: el,
-            az: az
+            el,
+            az
         }
     }
     
+    /*
+    *   Named constructors for the public boundary. ElAz is always stored in radians;
+    *   anything in degrees (tart_api::Source, catalogues, the CLI) goes through from_degrees.
+    */
+    pub fn from_radians(el_rad: f64, az_rad: f64) -> ElAz {
+        ElAz::new(el_rad, az_rad)
+    }
+
+    pub fn from_degrees(el_deg: f64, az_deg: f64) -> ElAz {
+        ElAz::new(el_deg.to_radians(), az_deg.to_radians())
+    }
+
+    pub fn el_degrees(&self) -> f64 {
+        self.el.to_degrees()
+    }
+
+    pub fn az_degrees(&self) -> f64 {
+        self.az.to_degrees()
+    }
+    
     pub fn from_hp(hp: &HpAngle) -> ElAz {
         let el = PI_OVER_2 - hp.theta;
         let az = -hp.phi;
@@ -114,17 +186,83 @@ impl ElAz {
         let l = self.az.sin()*self.el.cos();
         let m = self.az.cos()*self.el.cos();
         let n = self.el.sin(); // Often written in this weird way... np.sqrt(1.0 - l**2 - m**2)
-        return (l, m, n)
+        (l, m, n)
     }
+
+    /*
+    *   Angle (radians) between two directions, from the unit vectors given by to_lmn.
+    *   atan2 of the cross and dot products stays accurate for tiny and near-antipodal separations.
+    */
+    pub fn angular_separation(&self, other: &ElAz) -> f64 {
+        let (l1, m1, n1) = self.to_lmn();
+        let (l2, m2, n2) = other.to_lmn();
+
+        let dot = l1*l2 + m1*m2 + n1*n2;
+        let cx = m1*n2 - n1*m2;
+        let cy = n1*l2 - l1*n2;
+        let cz = l1*m2 - m1*l2;
+        let cross = (cx*cx + cy*cy + cz*cz).sqrt();
+        cross.atan2(dot)
+    }
+}
+
+
+/*
+*   A visible pixel of a Hemisphere, as returned by Hemisphere::iter_pixels.
+*   el and az are in radians.
+*/
+#[derive(Debug)]
+pub struct PixelView {
+    pub healpix_index: u64,
+    pub el: f64,
+    pub az: f64,
+    pub value: f64
+}
+
+
+/*
+*   Summary statistics of the visible pixel values, leaving out unseen and masked (NaN)
+*   pixels. n_s is the number of pixels counted.
+*/
+#[derive(Debug, Clone, Serialize)]
+pub struct HemisphereStats {
+    pub n_s: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub sdev: f64,
+    pub median: f64,
+    pub mad: f64,   // median absolute deviation
+    pub valid: bool, // false when no pixels were counted, and the other fields are NaN
+}
+
+
+/*
+*   How much of the sky a Hemisphere samples. Every HEALPix pixel has the same
+*   solid angle, 4*pi/total_pixels steradians.
+*/
+#[derive(Debug, Clone, Serialize)]
+pub struct Coverage {
+    pub visible_pixels: usize,
+    pub total_pixels: u64,
+    pub solid_angle: f64,   // steradians covered by the visible pixels
+    pub fraction: f64,      // of the 2*pi steradian hemisphere above the horizon
 }
 
 
+/*
+*   The HEALPix pixels above the horizon and their values. visible_indices is strictly
+*   ascending (RING order), and the same for every hemisphere of the same nside, however
+*   it was built (new, from_binary). visible_pix, l, m, n and the pixel views are in
+*   the same order, so arrays from two hemispheres of one nside can be zipped, and a
+*   RING index found by binary search.
+*/
+#[derive(Clone)]
 pub struct Hemisphere {
     pub nside: u32,
     pub npix: usize,
     pub visible_pix: VectorReal,
     pub visible_indices: Vec::<u64>,
-    #[allow(dead_code)]
     elaz: Vec::<ElAz>,
     pub l: VectorReal,
     pub m: VectorReal,
@@ -153,7 +291,7 @@ impl Hemisphere {
             
             // Find only the visible pixels (above the horizon)
             if hp.theta < PI_OVER_2 {
-                visible_pixels.push(0.0 as f64);
+                visible_pixels.push(0.0_f64);
                 visible_indices.push(pix);
                 
                 let elaz = ElAz::from_hp(&hp);
@@ -168,19 +306,435 @@ impl Hemisphere {
         }
 
         Hemisphere {
-            nside: nside,
+            nside,
             npix: visible_pixels.len(),
             visible_pix: VectorReal::from_vec(visible_pixels),
-            visible_indices: visible_indices,
+            visible_indices,
+            elaz: elaz_arr,
+            l: VectorReal::from_vec(l_arr),
+            m: VectorReal::from_vec(m_arr),
+            n: VectorReal::from_vec(n_arr)
+        }
+    }
+
+    pub fn stats(&self) -> HemisphereStats {
+        let pixels: Vec<f64> = self.visible_pix.iter().cloned().filter(|p| !p.is_nan()).collect();
+        let med = match median(&pixels) {
+            Some(med) => med,
+            None => return HemisphereStats {
+                n_s: 0,
+                min: f64::NAN,
+                max: f64::NAN,
+                mean: f64::NAN,
+                sdev: f64::NAN,
+                median: f64::NAN,
+                mad: f64::NAN,
+                valid: false,
+            },
+        };
+
+        let mut max_p: f64 = -1e99;
+        let mut min_p: f64 = 1e99;
+        let mut mean_p: f64 = 0.0;
+        
+        for p in pixels.iter() {
+            max_p = p.max(max_p);
+            min_p = p.min(min_p);
+            mean_p += p;
+        }
+        mean_p /= pixels.len() as f64;
+        
+        let mut sdev_p: f64 = 0.0;
+        for p in pixels.iter() {
+            sdev_p += (p - mean_p) * (p - mean_p);
+        }
+        sdev_p = (sdev_p / (pixels.len() as f64)).sqrt();
+
+        let deviation: Vec<f64> = pixels.iter().map(|p| (p - med).abs()).collect();
+        let mad_p: f64 = median(&deviation).unwrap_or(f64::NAN);
+
+        HemisphereStats {
+            n_s: pixels.len(),
+            min: min_p,
+            max: max_p,
+            mean: mean_p,
+            sdev: sdev_p,
+            median: med,
+            mad: mad_p,
+            valid: true,
+        }
+    }
+
+    /*
+    *   Check nside can be used with the NESTED scheme, which needs a power of two: for
+    *   smooth and NESTED maps. Imaging (RING) works at any nside.
+    */
+    pub fn check_nside(nside: u32) -> Result<(), ProcessingError> {
+        if nside.is_power_of_two() {
+            Ok(())
+        } else {
+            Err(ProcessingError::InvalidFormat(format!(
+                "nside {} is not a power of two, as the NESTED scheme requires", nside)))
+        }
+    }
+
+    // The value of the pixel containing elaz, or None if it is not above the horizon
+    pub fn value_at(&self, elaz: &ElAz) -> Option<f64> {
+        let hp = elaz.to_hp();
+        let pixel = hash(self.nside, hp.phi, PI_OVER_2 - hp.theta);
+        self.visible_indices.binary_search(&pixel).ok().map(|i| self.visible_pix[i])
+    }
+
+    /*
+    *   Nearest-neighbour resample onto the visible pixels of another nside: each new
+    *   pixel takes the value of the pixel here containing its centre. New pixels whose
+    *   centre falls in a pixel at or below the horizon here are unseen, and set to NaN.
+    */
+    pub fn resample_to(&self, nside: u32) -> Hemisphere {
+        let mut sph = Hemisphere::new(nside);
+        for (p, elaz) in sph.visible_pix.iter_mut().zip(sph.elaz.iter()) {
+            *p = self.value_at(elaz).unwrap_or(f64::NAN);
+        }
+        sph
+    }
+
+    /*
+    *   Smooth with a Gaussian of full width at half maximum fwhm_deg. Each pixel becomes
+    *   the mean of the visible pixels within about three sigma, found by growing rings
+    *   of HEALPix neighbours, weighted by their angular distance. This approximates a
+    *   convolution on the sphere without a spherical harmonic transform; weights are
+    *   normalized, so a flat map stays flat. NaN (unseen) pixels are left out and stay
+    *   NaN. The neighbours come from the NESTED scheme, so nside must pass check_nside.
+    */
+    pub fn smooth(&self, fwhm_deg: f32) -> Result<Hemisphere, ProcessingError> {
+        let mut sph = self.clone();
+        if fwhm_deg.is_nan() || fwhm_deg <= 0.0 || self.npix == 0 {
+            return Ok(sph);
+        }
+        Hemisphere::check_nside(self.nside)?;
+        let sigma = (fwhm_deg as f64).to_radians() / (8.0 * 2.0f64.ln()).sqrt();
+        let rings = ((3.0 * sigma) / Hemisphere::pixel_scale(self.nside)).ceil() as usize;
+
+        // The visible neighbours of each visible pixel, as indices into visible_pix
+        let layer = nested::get(self.nside.trailing_zeros() as u8);
+        let adjacent: Vec<Vec<usize>> = self.visible_indices.iter().map(|&pix| {
+            layer.neighbours(layer.from_ring(pix), false).values_vec().iter()
+                .filter_map(|&n| self.visible_indices.binary_search(&layer.to_ring(n)).ok())
+                .collect()
+        }).collect();
+
+        let mut seen = vec![usize::MAX; self.npix];
+        for i in 0..self.npix {
+            if self.visible_pix[i].is_nan() {
+                continue;
+            }
+            seen[i] = i;
+            let mut ring = vec![i];
+            let (mut sum, mut weights) = (0.0, 0.0);
+            for step in 0..=rings {
+                for &j in &ring {
+                    let value = self.visible_pix[j];
+                    if value.is_nan() {
+                        continue;
+                    }
+                    let d = self.elaz[i].angular_separation(&self.elaz[j]);
+                    let weight = (-0.5 * (d / sigma).powi(2)).exp();
+                    sum += weight * value;
+                    weights += weight;
+                }
+                if step == rings {
+                    break;
+                }
+                let mut next = Vec::new();
+                for &j in &ring {
+                    for &k in &adjacent[j] {
+                        if seen[k] != i {
+                            seen[k] = i;
+                            next.push(k);
+                        }
+                    }
+                }
+                ring = next;
+            }
+            sph.visible_pix[i] = sum / weights;
+        }
+        Ok(sph)
+    }
+
+    /*
+    *   Count the visible pixels in bins equal-width bins spanning [min, max]. Values
+    *   outside the range go in the end bins (as they saturate the colormap) and NaNs
+    *   are skipped.
+    */
+    pub fn histogram(&self, bins: usize, min: f64, max: f64) -> Vec<usize> {
+        let mut counts = vec![0; bins];
+        if bins == 0 {
+            return counts;
+        }
+        let range = max - min;
+        for p in self.visible_pix.iter().filter(|p| !p.is_nan()) {
+            let bin = if range > 0.0 {
+                (((p - min) / range) * (bins as f64)).floor().max(0.0) as usize
+            } else {
+                bins / 2
+            };
+            counts[bin.min(bins - 1)] += 1;
+        }
+        counts
+    }
+
+    pub fn coverage(&self) -> Coverage {
+        let total_pixels = n_hash(self.nside);
+        let solid_angle = (self.npix as f64) * 4.0 * PI / (total_pixels as f64);
+        Coverage {
+            visible_pixels: self.npix,
+            total_pixels,
+            solid_angle,
+            fraction: solid_angle / (2.0 * PI),
+        }
+    }
+
+    // Side of an equal-area pixel (radians): the square root of 4*pi/(12*nside^2)
+    pub fn pixel_scale(nside: u32) -> f64 {
+        (PI / 3.0).sqrt() / (nside as f64)
+    }
+
+    /*
+    *   The number of pixels strictly above the horizon for a given nside: the
+    *   nside-1 polar cap rings plus nside rings of the equatorial belt.
+    */
+    pub fn visible_pixel_count(nside: u32) -> usize {
+        let n = nside as usize;
+        6*n*n - 2*n
+    }
+
+    /*
+    *   The full sky map (12*nside^2 values) in ordering, with pixels below the horizon
+    *   and unseen (NaN) pixels set to HEALPIX_UNSEEN. NESTED needs nside to pass check_nside.
+    */
+    pub fn to_healpix_map(&self, ordering: HealpixOrdering) -> Result<Vec<f64>, ProcessingError> {
+        if ordering == HealpixOrdering::Nested {
+            Hemisphere::check_nside(self.nside)?;
+        }
+        let mut map = vec![HEALPIX_UNSEEN; n_hash(self.nside) as usize];
+        let layer = nested::get(self.nside.trailing_zeros() as u8);
+        for (&pixel, &value) in self.visible_indices.iter().zip(self.visible_pix.iter()) {
+            let index = match ordering {
+                HealpixOrdering::Ring => pixel,
+                HealpixOrdering::Nested => layer.from_ring(pixel),
+            };
+            map[index as usize] = if value.is_nan() { HEALPIX_UNSEEN } else { value };
+        }
+        Ok(map)
+    }
+
+    /*
+    *   The hemisphere above the horizon of a full sky map in ordering. HEALPIX_UNSEEN
+    *   pixels become NaN. NESTED needs nside to pass check_nside.
+    */
+    pub fn from_healpix_map(nside: u32, map: &[f64], ordering: HealpixOrdering) -> Result<Hemisphere, ProcessingError> {
+        if ordering == HealpixOrdering::Nested {
+            Hemisphere::check_nside(nside)?;
+        }
+        if map.len() as u64 != n_hash(nside) {
+            return Err(ProcessingError::InvalidFormat(format!(
+                "{} values is not a full sky map with nside {}", map.len(), nside)));
+        }
+        let mut sph = Hemisphere::new(nside);
+        let layer = nested::get(nside.trailing_zeros() as u8);
+        for (p, &pixel) in sph.visible_pix.iter_mut().zip(sph.visible_indices.iter()) {
+            let index = match ordering {
+                HealpixOrdering::Ring => pixel,
+                HealpixOrdering::Nested => layer.from_ring(pixel),
+            };
+            let value = map[index as usize];
+            *p = if value == HEALPIX_UNSEEN { f64::NAN } else { value };
+        }
+        Ok(sph)
+    }
+
+    // The full sky map as a HEALPix FITS file in ordering
+    pub fn to_fits(&self, ordering: HealpixOrdering) -> Result<Vec<u8>, ProcessingError> {
+        Ok(fits::write_healpix_map(&self.to_healpix_map(ordering)?, self.nside, ordering))
+    }
+
+    // The hemisphere of a HEALPix FITS map, in whichever ordering the file declares
+    pub fn from_fits(bytes: &[u8]) -> Result<Hemisphere, ProcessingError> {
+        let (nside, ordering, map) = fits::read_healpix_map(bytes)?;
+        Hemisphere::from_healpix_map(nside, &map, ordering)
+    }
+
+    /*
+    *   Serialize the geometry (not the pixel values) so it can be computed once and
+    *   shipped to a client. Little endian: the magic "GLHS", nside (u32), the number
+    *   of visible pixels (u32), then for each pixel its index (u64) and el, az, l, m, n (f64).
+    */
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(GEOMETRY_HEADER + GEOMETRY_PIXEL*self.npix);
+        bytes.extend_from_slice(GEOMETRY_MAGIC);
+        bytes.extend_from_slice(&self.nside.to_le_bytes());
+        bytes.extend_from_slice(&(self.npix as u32).to_le_bytes());
+        for i in 0..self.npix {
+            bytes.extend_from_slice(&self.visible_indices[i].to_le_bytes());
+            for x in &[self.elaz[i].el, self.elaz[i].az, self.l[i], self.m[i], self.n[i]] {
+                bytes.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /*
+    *   Rebuild a hemisphere from the output of to_binary. The pixel values are zero.
+    *   The pixels must be in the order of Hemisphere::new (strictly ascending indices).
+    */
+    pub fn from_binary(bytes: &[u8]) -> Result<Hemisphere, ProcessingError> {
+        let invalid = |msg: String| ProcessingError::InvalidFormat(format!("hemisphere geometry: {}", msg));
+
+        if bytes.len() < GEOMETRY_HEADER || &bytes[0..4] != GEOMETRY_MAGIC {
+            return Err(invalid("missing header".to_string()));
+        }
+        let nside = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let npix = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+
+        if nside == 0 || nside > MAX_GEOMETRY_NSIDE {
+            return Err(invalid(format!("nside {} is out of range", nside)));
+        }
+        if npix != Hemisphere::visible_pixel_count(nside) {
+            return Err(invalid(format!("{} pixels is not a hemisphere with nside {}", npix, nside)));
+        }
+        let body = bytes.len() - GEOMETRY_HEADER;
+        if npix.checked_mul(GEOMETRY_PIXEL) != Some(body) {
+            return Err(invalid(format!("expected {} pixels of {} bytes, got {} bytes", npix, GEOMETRY_PIXEL, body)));
+        }
+
+        let total = n_hash(nside);
+        let mut visible_indices = Vec::with_capacity(npix);
+        let mut elaz_arr = Vec::with_capacity(npix);
+        let mut l_arr = Vec::with_capacity(npix);
+        let mut m_arr = Vec::with_capacity(npix);
+        let mut n_arr = Vec::with_capacity(npix);
+
+        for chunk in bytes[GEOMETRY_HEADER..].chunks(GEOMETRY_PIXEL) {
+            let index = u64::from_le_bytes(chunk[0..8].try_into().unwrap());
+            if index >= total {
+                return Err(invalid(format!("pixel index {} out of range for nside {}", index, nside)));
+            }
+            if visible_indices.last().is_some_and(|&last| index <= last) {
+                return Err(invalid(format!("pixel index {} is out of order", index)));
+            }
+            let x: Vec<f64> = (0..5).map(|k| f64::from_le_bytes(chunk[8 + 8*k..16 + 8*k].try_into().unwrap())).collect();
+
+            visible_indices.push(index);
+            elaz_arr.push(ElAz::new(x[0], x[1]));
+            l_arr.push(x[2]);
+            m_arr.push(x[3]);
+            n_arr.push(x[4]);
+        }
+
+        Ok(Hemisphere {
+            nside,
+            npix,
+            visible_pix: VectorReal::zeros(npix),
+            visible_indices,
             elaz: elaz_arr,
             l: VectorReal::from_vec(l_arr),
             m: VectorReal::from_vec(m_arr),
             n: VectorReal::from_vec(n_arr)
+        })
+    }
+
+    // Mark the pixels whose (l, m, n) direction is at or below the horizon unseen
+    // (NaN), e.g. after imaging onto a sky from astro::rotate_sky_to_reference
+    pub fn hide_below_horizon(&mut self) {
+        for i in 0..self.npix {
+            if self.n[i] <= 0.0 {
+                self.visible_pix[i] = f64::NAN;
+            }
+        }
+    }
+
+    /*
+    *   Masking: mark the pixels whose value is below threshold, above it, or outside
+    *   [lo, hi] unseen (NaN), keeping them in place. Masked pixels are left out of
+    *   stats(), peak() and the colour scale, are not drawn in the SVG or raster (or show
+    *   the theme's unseen_color), and are transparent in color_bytes_rgba. Each returns
+    *   the number of pixels newly masked.
+    */
+    pub fn mask_below(&mut self, threshold: f64) -> usize {
+        self.mask_where(|p| p < threshold)
+    }
+
+    pub fn mask_above(&mut self, threshold: f64) -> usize {
+        self.mask_where(|p| p > threshold)
+    }
+
+    pub fn mask_outside(&mut self, lo: f64, hi: f64) -> usize {
+        self.mask_where(|p| p < lo || p > hi)
+    }
+
+    fn mask_where(&mut self, masked: impl Fn(f64) -> bool) -> usize {
+        let mut count = 0;
+        for p in self.visible_pix.iter_mut() {
+            if !p.is_nan() && masked(*p) {
+                *p = f64::NAN;
+                count += 1;
+            }
         }
+        count
+    }
+
+    // The brightest visible pixel, or None if every pixel is unseen
+    pub fn peak(&self) -> Option<PixelView> {
+        self.iter_pixels()
+            .filter(|p| !p.value.is_nan())
+            .max_by(|a, b| a.value.total_cmp(&b.value))
+    }
+
+    /*
+    *   Zero the pixel values in place, keeping the geometry. Imaging with
+    *   gridless::image_visibilities overwrites visible_pix anyway; this is for
+    *   accumulating into a reused hemisphere.
+    */
+    pub fn clear_values(&mut self) {
+        self.visible_pix.fill(0.0);
+    }
+
+    /// Iterate over the visible pixels together with their sky coordinates.
+    ///
+    /// ```
+    /// use gridlesslib::sphere::Hemisphere;
+    ///
+    /// let sky = Hemisphere::new(4);
+    /// for p in sky.iter_pixels() {
+    ///     assert!(p.el > 0.0);
+    ///     println!("{} el={} az={} value={}", p.healpix_index, p.el, p.az, p.value);
+    /// }
+    /// assert_eq!(sky.iter_pixels().count(), sky.npix);
+    /// ```
+    pub fn iter_pixels(&self) -> impl Iterator<Item = PixelView> + '_ {
+        self.visible_indices.iter()
+            .zip(self.elaz.iter())
+            .zip(self.visible_pix.iter())
+            .map(|((&index, elaz), &value)| PixelView {
+                healpix_index: index,
+                el: elaz.el,
+                az: elaz.az,
+                value
+            })
     }
 
 }
 
+#[cfg(test)]
+impl Hemisphere {
+    // A hemisphere with a linear ramp of pixel values from 0 to 1, for tests
+    pub fn ramp(nside: u32) -> Hemisphere {
+        let mut sky = Hemisphere::new(nside);
+        let npix = sky.npix;
+        sky.visible_pix = VectorReal::from_shape_fn(npix, |i| (i as f64) / ((npix - 1) as f64));
+        sky
+    }
+}
 
 
 #[cfg(test)]
@@ -188,6 +742,34 @@ mod tests {
     
     use rand::Rng;
     use super::*;
+    use utils::PI;
+
+    #[test]
+    fn test_degrees_radians() {
+        let elaz = ElAz::from_degrees(30.0, 90.0);
+        assert!((elaz.el - PI/6.0).abs() < 1.0e-12);
+        assert!((elaz.az - PI/2.0).abs() < 1.0e-12);
+        assert!((elaz.el_degrees() - 30.0).abs() < 1.0e-12);
+        assert!((elaz.az_degrees() - 90.0).abs() < 1.0e-12);
+
+        let same = ElAz::from_radians(PI/6.0, PI/2.0);
+        assert!(elaz.angular_separation(&same) < 1.0e-12);
+    }
+
+    #[test]
+    fn test_peak() {
+        let mut sky = Hemisphere::new(4);
+        assert!(sky.peak().is_some());
+        sky.visible_pix.fill(f64::NAN);
+        assert!(sky.peak().is_none());
+
+        sky.visible_pix[7] = 2.0;
+        sky.visible_pix[30] = 5.0;
+        let peak = sky.peak().unwrap();
+        assert_eq!(peak.healpix_index, sky.visible_indices[30]);
+        assert_eq!(peak.value, 5.0);
+        assert_eq!((peak.el, peak.az), (sky.elaz[30].el, sky.elaz[30].az));
+    }
 
     #[test]
     fn test_nside() {
@@ -199,7 +781,7 @@ mod tests {
     #[test]
     fn test_angles() {
         let mut rng = rand::thread_rng();
-        for i in 0..1000 {
+        for _ in 0..1000 {
             let lon : f64 = rng.gen_range(0.0, PI*2.0);
             let lat : f64 = rng.gen_range(-PI_OVER_2, PI_OVER_2);
             
@@ -229,24 +811,305 @@ mod tests {
             let sph = Hemisphere::new(nside);
             // find the zenith pixel
             
-            let pix = &sph.get_pix(&hp);
-            assert_eq!(pix, &3);
+            let pix = hash(sph.nside, ll.lon, ll.lat);
+            assert_eq!(pix, 3);
             
-            let ll2 = LonLat::from_pix(nside, *pix);
-            assert_eq!(ll.lat, ll2.lat);
+            // The zenith is a pixel corner, so the pixel center is within one pixel of it.
+            let ll2 = LonLat::from_pix(nside, pix);
+            let pixel_size = (4.0*PI / (12*nside*nside) as f64).sqrt();
+            assert!((ll.lat - ll2.lat).abs() < pixel_size);
         }
     }
 
     #[test]
-    fn test_horizon() {
+    fn test_angular_separation() {
+        let zenith = ElAz::from_degrees(90.0, 0.0);
+        let north = ElAz::from_degrees(0.0, 0.0);
+        let east = ElAz::from_degrees(0.0, 90.0);
+        let south = ElAz::from_degrees(0.0, 180.0);
+
+        assert!((zenith.angular_separation(&north).to_degrees() - 90.0).abs() < 1.0e-9);
+        assert!((north.angular_separation(&east).to_degrees() - 90.0).abs() < 1.0e-9);
+        assert!((north.angular_separation(&south).to_degrees() - 180.0).abs() < 1.0e-9);
+        assert!(east.angular_separation(&east).abs() < 1.0e-12);
+
+        let a = ElAz::from_degrees(30.0, 10.0);
+        let b = ElAz::from_degrees(40.0, 10.0);
+        assert!((a.angular_separation(&b).to_degrees() - 10.0).abs() < 1.0e-9);
+        assert!((a.angular_separation(&b) - b.angular_separation(&a)).abs() < 1.0e-15);
+    }
+
+    #[test]
+    fn test_check_nside() {
+        assert!(Hemisphere::check_nside(1).is_ok());
+        assert!(Hemisphere::check_nside(64).is_ok());
+        assert!(Hemisphere::check_nside(0).is_err());
+        assert!(Hemisphere::check_nside(12).is_err());
+    }
+
+    #[test]
+    fn test_visible_pixel_count() {
+        for nside in &[1, 2, 4, 8, 16] {
+            assert_eq!(Hemisphere::new(*nside).npix, Hemisphere::visible_pixel_count(*nside));
+        }
+    }
+
+    #[test]
+    fn test_value_at() {
+        let mut sph = Hemisphere::new(4);
+        let npix = sph.npix;
+        sph.visible_pix = VectorReal::from_shape_fn(npix, |i| i as f64);
+        for (i, elaz) in sph.elaz.iter().enumerate() {
+            assert_eq!(sph.value_at(elaz), Some(i as f64));
+        }
+        assert_eq!(sph.value_at(&ElAz::from_degrees(-30.0, 45.0)), None);
+    }
+
+    #[test]
+    fn test_healpix_map_ordering() {
+        let mut sky = Hemisphere::new(8);
+        for i in 0..sky.npix {
+            sky.visible_pix[i] = (i as f64).sin();
+        }
+        sky.visible_pix[5] = f64::NAN;
+
+        let ring = sky.to_healpix_map(HealpixOrdering::Ring).unwrap();
+        let nest = sky.to_healpix_map(HealpixOrdering::Nested).unwrap();
+        assert_eq!(ring.len(), 768);
+        let layer = nested::get(3);
+        for p in 0..768u64 {
+            assert_eq!(nest[layer.from_ring(p) as usize], ring[p as usize]);
+        }
+        assert_eq!(ring[sky.visible_indices[5] as usize], HEALPIX_UNSEEN);
+        assert_eq!(ring[767], HEALPIX_UNSEEN);
+
+        // A round trip through a NESTED FITS file preserves every value
+        let fits = sky.to_fits(HealpixOrdering::Nested).unwrap();
+        let back = Hemisphere::from_fits(&fits).unwrap();
+        assert_eq!(back.nside, 8);
+        assert!(back.visible_pix[5].is_nan());
+        for i in (0..sky.npix).filter(|&i| i != 5) {
+            assert_eq!(back.visible_pix[i], sky.visible_pix[i]);
+        }
+
+        assert!(Hemisphere::from_healpix_map(8, &nest[1..], HealpixOrdering::Nested).is_err());
+
+        // RING maps work at any nside, NESTED ones need a power of two
+        let sky = Hemisphere::new(6);
+        let ring = sky.to_healpix_map(HealpixOrdering::Ring).unwrap();
+        assert!(Hemisphere::from_healpix_map(6, &ring, HealpixOrdering::Ring).is_ok());
+        assert!(sky.to_healpix_map(HealpixOrdering::Nested).is_err());
+        assert!(sky.to_fits(HealpixOrdering::Nested).is_err());
+        assert!(Hemisphere::from_healpix_map(6, &ring, HealpixOrdering::Nested).is_err());
+        assert_eq!("NEST".parse(), Ok(HealpixOrdering::Nested));
+        assert!("zigzag".parse::<HealpixOrdering>().is_err());
+    }
+
+    #[test]
+    fn test_resample_to() {
+        let mut sph = Hemisphere::new(8);
+        let npix = sph.npix;
+        sph.visible_pix = VectorReal::from_shape_fn(npix, |i| i as f64);
+        assert_eq!(sph.resample_to(8).visible_pix, sph.visible_pix);
+
+        // Finer pixels near the horizon have centres in coarse pixels on the horizon
+        let fine = sph.resample_to(16);
+        assert_eq!(fine.npix, Hemisphere::visible_pixel_count(16));
+        assert!(fine.visible_pix.iter().any(|p| p.is_nan()));
+        for (elaz, &p) in fine.elaz.iter().zip(fine.visible_pix.iter()) {
+            assert!(p.is_nan() || sph.value_at(elaz) == Some(p));
+        }
+
+        // Each fine pixel nests inside a coarse one, so going back down is exact
+        assert_eq!(fine.resample_to(8).visible_pix, sph.visible_pix);
+    }
+
+    #[test]
+    fn test_smooth() {
+        let mut sph = Hemisphere::new(8);
+        sph.visible_pix.fill(3.0);
+        sph.visible_pix[40] = f64::NAN;
+        let flat = sph.smooth(20.0).unwrap();
+        assert!(flat.visible_pix[40].is_nan());
+        assert!(flat.visible_pix.iter().filter(|p| !p.is_nan()).all(|p| (p - 3.0).abs() < 1.0e-12));
+
+        // A single bright pixel spreads out, keeping its peak where it was
+        let mut spike = Hemisphere::new(8);
+        spike.visible_pix[100] = 1.0;
+        let smoothed = spike.smooth(20.0).unwrap();
+        assert!(smoothed.visible_pix[100] < 1.0);
+        assert!(smoothed.visible_pix.iter().all(|&p| p <= smoothed.visible_pix[100]));
+        assert!(smoothed.visible_pix.iter().filter(|&&p| p > 0.0).count() > 9);
+        let far = spike.elaz.iter().position(|e| e.angular_separation(&spike.elaz[100]) > 1.0).unwrap();
+        assert_eq!(smoothed.visible_pix[far], 0.0);
+
+        assert_eq!(spike.smooth(0.0).unwrap().visible_pix, spike.visible_pix);
+        assert!(Hemisphere::new(6).smooth(20.0).is_err());
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut sph = Hemisphere::ramp(2);
+        let npix = sph.npix;
+        sph.visible_pix[0] = -5.0;
+        sph.visible_pix[1] = f64::NAN;
+
+        let counts = sph.histogram(4, 0.0, 1.0);
+        assert_eq!(counts.len(), 4);
+        assert_eq!(counts.iter().sum::<usize>(), npix - 1);
+        assert!(counts[0] >= 1 && counts[3] >= 1);
+        assert!(sph.histogram(0, 0.0, 1.0).is_empty());
+    }
+
+    #[test]
+    fn test_coverage() {
+        let cov = Hemisphere::new(4).coverage();
+        assert_eq!(cov.visible_pixels, Hemisphere::visible_pixel_count(4));
+        assert_eq!(cov.total_pixels, 192);
+        // The 88 pixels strictly above the horizon are 88/96 of the hemisphere
+        assert!((cov.fraction - 88.0/96.0).abs() < 1.0e-12);
+        assert!((cov.solid_angle - 2.0*PI*88.0/96.0).abs() < 1.0e-12);
+
+        // Pixels straddling the horizon are excluded, so coverage tends to 1 with nside
+        assert!(Hemisphere::new(16).coverage().fraction > cov.fraction);
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
         let sph = Hemisphere::new(4);
-        
+        let copy = Hemisphere::from_binary(&sph.to_binary()).unwrap();
+        assert_eq!(copy.nside, sph.nside);
+        assert_eq!(copy.npix, sph.npix);
+        assert_eq!(copy.visible_indices, sph.visible_indices);
+        assert_eq!(copy.l, sph.l);
+        assert_eq!(copy.m, sph.m);
+        assert_eq!(copy.n, sph.n);
+    }
+
+    #[test]
+    fn test_binary_invalid() {
+        let bytes = Hemisphere::new(4).to_binary();
+        assert!(Hemisphere::from_binary(&bytes[0..bytes.len() - 1]).is_err());
+        assert!(Hemisphere::from_binary(&bytes[0..8]).is_err());
+
+        // Claim a different nside for the same pixels
+        let mut wrong = bytes.clone();
+        wrong[4] = 8;
+        assert!(Hemisphere::from_binary(&wrong).is_err());
+
+        // An nside whose pixel count would overflow
+        let mut huge = bytes.clone();
+        huge[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        match Hemisphere::from_binary(&huge) {
+            Err(ProcessingError::InvalidFormat(msg)) => assert!(msg.contains("out of range")),
+            _ => panic!("Expected an InvalidFormat error"),
+        }
+
+        // Swap the first two pixels
+        let mut swapped = bytes.clone();
+        let first = GEOMETRY_HEADER..GEOMETRY_HEADER + GEOMETRY_PIXEL;
+        let pixel: Vec<u8> = swapped[first.clone()].to_vec();
+        swapped.copy_within(first.end..first.end + GEOMETRY_PIXEL, first.start);
+        swapped[first.end..first.end + GEOMETRY_PIXEL].copy_from_slice(&pixel);
+        match Hemisphere::from_binary(&swapped) {
+            Err(ProcessingError::InvalidFormat(msg)) => assert!(msg.contains("out of order")),
+            _ => panic!("Expected an InvalidFormat error"),
+        }
+    }
+
+    #[test]
+    fn test_visible_indices_order() {
+        for &nside in &[1, 2, 4, 16] {
+            let sph = Hemisphere::new(nside);
+            assert!(sph.visible_indices.windows(2).all(|w| w[0] < w[1]));
+            assert_eq!(sph.visible_indices, Hemisphere::new(nside).visible_indices);
+            let copy = Hemisphere::from_binary(&sph.to_binary()).unwrap();
+            assert_eq!(copy.visible_indices, sph.visible_indices);
+
+            // The parallel arrays follow it
+            let indices: Vec<u64> = sph.iter_pixels().map(|p| p.healpix_index).collect();
+            assert_eq!(indices, sph.visible_indices);
+            assert_eq!((sph.visible_pix.len(), sph.l.len(), sph.n.len()), (sph.npix, sph.npix, sph.npix));
+        }
+    }
+
+    #[test]
+    fn test_stats_no_pixels() {
+        let mut sph = Hemisphere::new(2);
+        assert!(sph.stats().valid);
+
+        sph.npix = 0;
+        sph.visible_pix = VectorReal::zeros(0);
+        let stats = sph.stats();
+        assert!(!stats.valid);
+        assert_eq!(stats.n_s, 0);
+        assert!(stats.mean.is_nan() && stats.median.is_nan());
+    }
+
+    #[test]
+    fn test_masks() {
+        let mut sph = Hemisphere::new(4);
+        let npix = sph.npix;
+        sph.visible_pix = VectorReal::from_shape_fn(npix, |i| i as f64);
+
+        assert_eq!(sph.mask_below(10.0), 10);
+        assert_eq!(sph.mask_above((npix - 11) as f64), 10);
+        assert_eq!(sph.mask_below(10.0), 0);
+        assert_eq!(sph.visible_pix.len(), npix);
+
+        // Only the pixels left count: 10 to npix - 11
+        let stats = sph.stats();
+        assert_eq!(stats.n_s, npix - 20);
+        assert_eq!((stats.min, stats.max), (10.0, (npix - 11) as f64));
+        assert_eq!(stats.mean, (npix - 1) as f64 / 2.0);
+        assert_eq!(sph.peak().unwrap().value, (npix - 11) as f64);
+
+        assert_eq!(sph.mask_outside(20.0, 30.0), npix - 20 - 11);
+        let stats = sph.stats();
+        assert_eq!((stats.n_s, stats.min, stats.max, stats.mean), (11, 20.0, 30.0, 25.0));
+        assert!(sph.visible_pix.iter().filter(|p| !p.is_nan()).all(|&p| (20.0..=30.0).contains(&p)));
+
+        sph.mask_above(0.0);
+        assert!(!sph.stats().valid);
+    }
+
+    #[test]
+    fn test_clear_values() {
+        let mut sph = Hemisphere::new(4);
+        let l = sph.l.clone();
+        sph.visible_pix.fill(3.0);
+
+        sph.clear_values();
+        assert_eq!(sph.visible_pix.len(), sph.npix);
+        assert!(sph.visible_pix.iter().all(|&p| p == 0.0));
+        assert_eq!(sph.l, l);
+    }
+
+    #[test]
+    fn test_iter_pixels() {
+        let mut sph = Hemisphere::new(4);
+        sph.visible_pix[5] = 1.0;
+
+        let pixels: Vec<PixelView> = sph.iter_pixels().collect();
+        assert_eq!(pixels.len(), sph.npix);
+        assert_eq!(pixels[5].healpix_index, sph.visible_indices[5]);
+        assert_eq!(pixels[5].value, 1.0);
+
+        let ll = LonLat::from_pix(4, pixels[5].healpix_index);
+        let elaz = ElAz::from_hp(&HpAngle::from_lonlat(&ll));
+        assert_eq!(pixels[5].el, elaz.el);
+        assert_eq!(pixels[5].az, elaz.az);
+    }
+
+    #[test]
+    fn test_horizon() {
         let hp = HpAngle::from_elaz(0.0, 0.0);
         assert_eq!(hp.theta, PI_OVER_2);
 
+        // North is at the top of the plot (negative y in SVG coordinates)
         let (x,y) = hp.proj();
         assert_eq!(x, 0.0);
-        assert_eq!(y, 1.0);
+        assert_eq!(y, -1.0);
 
         let ll = LonLat::from_hp(&hp);
         assert_eq!(ll.lat, 0.0);
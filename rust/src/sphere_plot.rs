@@ -3,22 +3,32 @@
 //
 
 use svg::SVG;
+use num::clamp;
 
 use cdshealpix::ring::{vertices, hash};
-use utils::{PI_OVER_2, VectorReal, median};
+use utils::{PI, PI_OVER_2, percentile};
 
 use sphere::{Hemisphere, LonLat, HpAngle, ElAz};
 
 use tart_api::{Source};
+use config::{Colorbar, ProcessingConfig, PixelStyle};
+use colormap::{AutoScale, dither_offset};
+use logging;
+use error::ProcessingError;
 
+/*
+*   Projected coordinates to SVG user units. Every conversion rounds to the nearest
+*   unit (never truncates), so all drawing paths agree on the same integer position.
+*/
 struct PlotCoords {
-    #[allow(dead_code)]
     w: i32,
     center: i32,
     scale: f64,
     line_size: u32
 }
 
+// from_* convert plot units to pixels, so they take self
+#[allow(clippy::wrong_self_convention)]
 impl PlotCoords {
 
     pub fn new(w: i32) -> PlotCoords {
@@ -28,10 +38,10 @@ impl PlotCoords {
         let line_size = (w / 400) as u32;
 
         PlotCoords {
-            w: w,
-            center: center,
-            scale: scale,
-            line_size: line_size
+            w,
+            center,
+            scale,
+            line_size
         }
     }
     fn from_d(&self, d: f64) -> u32 {
@@ -50,6 +60,194 @@ impl PlotCoords {
         let (x,y) = hp.proj();
         (self.from_x(x), self.from_y(y))
     }
+
+    // Projected length of an angle of deg degrees at the zenith
+    fn scalebar_length(&self, deg: f64) -> u32 {
+        self.from_d(deg.to_radians().sin())
+    }
+
+    // The point at projected radius r (0 at the zenith, 1 on the horizon) along azimuth az (radians)
+    fn from_az_r(&self, az: f64, r: f64) -> (i32, i32) {
+        let hp = HpAngle::new(r.asin(), -az);
+        let (x,y) = hp.proj();
+        (self.from_x(x), self.from_y(y))
+    }
+}
+
+/*
+*   The pixel values at the bottom and top of the colormap. For AutoScale::HistEq, cdf
+*   holds the fraction of pixels below each edge of HISTEQ_BINS equal-width bins
+*   spanning [min, max] (so it runs from 0 to 1), and values are mapped through it.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScaleBounds {
+    pub min: f64,
+    pub max: f64,
+    pub cdf: Option<Vec<f64>>,
+}
+
+impl ScaleBounds {
+    // Map a pixel value into [0, 1], clamping values outside the bounds
+    pub fn normalize(&self, value: f64) -> f64 {
+        let range = self.max - self.min;
+        if range > 0.0 {
+            self.equalize(clamp((value - self.min) / range, 0.0, 1.0))
+        } else {
+            0.5
+        }
+    }
+
+    // Map a fraction of the way from min to max through cdf, interpolating linearly between bin edges
+    pub fn equalize(&self, fract: f64) -> f64 {
+        match self.cdf {
+            Some(ref cdf) => {
+                let bins = cdf.len() - 1;
+                let x = fract*(bins as f64);
+                let k = (x.floor() as usize).min(bins - 1);
+                cdf[k] + (x - k as f64)*(cdf[k + 1] - cdf[k])
+            },
+            None => fract,
+        }
+    }
+}
+
+// Bins of the cumulative histogram used by AutoScale::HistEq
+const HISTEQ_BINS: usize = 256;
+
+// Pixels drawn between flushes when streaming an SVG with write_svg
+const SVG_FLUSH_POLYGONS: usize = 1024;
+
+// Number of colored cells in the colorbar, which is also the number of histogram bins
+const COLORBAR_STEPS: i32 = 32;
+
+// The " font-family=..." attribute for the theme, or nothing
+fn font_attrib(config: &ProcessingConfig) -> String {
+    match config.theme.font_family {
+        Some(ref family) => format!(" font-family={}", family),
+        None => String::new(),
+    }
+}
+
+/*
+*   A caller's mapping from a value scaled to [0, 1] to a colour.
+*/
+pub type Shader<'a> = &'a dyn Fn(f32) -> (u8, u8, u8);
+
+/*
+*   Where the colour of each pixel comes from when drawing.
+*/
+#[derive(Clone, Copy)]
+enum Fill<'a> {
+    Colormap,
+    Shader(Shader<'a>),
+    // One per visible pixel, with no scale (so no threshold or colorbar)
+    Colors(&'a [(u8, u8, u8)]),
+}
+
+// The colour bytes of visible pixel i at fract of the scale, dithered by its index if configured
+fn pixel_rgb(i: usize, fract: f64, config: &ProcessingConfig) -> (u8, u8, u8) {
+    if config.dither {
+        config.colormap.rgb_u8_dithered(fract, dither_offset(i, i / 4))
+    } else {
+        config.colormap.rgb_u8(fract)
+    }
+}
+
+/*
+*   The colour cells of a colorbar with its top left corner at origin, the min and max
+*   of the scale under its ends, and its label under the middle. The cells are evenly
+*   spaced in value, so with a histogram equalized scale their colours are not evenly
+*   spaced. They are coloured by shader if given, otherwise by config.colormap.
+*/
+#[allow(clippy::too_many_arguments)]
+fn draw_colorbar(image: &mut SVG,
+                 (x0, y0): (i32, i32),
+                 step: i32,
+                 height: i32,
+                 font_size: i32,
+                 scale: &ScaleBounds,
+                 colorbar: &Colorbar,
+                 shader: Option<Shader>,
+                 config: &ProcessingConfig) {
+    for i in 0..COLORBAR_STEPS {
+        let fract = scale.equalize((i as f64 + 0.5) / (COLORBAR_STEPS as f64));
+        let fill = match shader {
+            Some(shader) => {
+                let (r, g, b) = shader(fract as f32);
+                format!("fill=rgb({},{},{})", r, g, b)
+            },
+            None => {
+                let (r, g, b) = config.colormap.rgb(fract);
+                format!("fill=rgb({:.1},{:.1},{:.1})", r, g, b)
+            },
+        };
+        image.rect(x0 + i*step, y0, step, height, &fill);
+    }
+
+    let font = font_attrib(config);
+    let attrib_label = |anchor| format!("font-size={} fill={} text-anchor={} dominant-baseline=hanging{}",
+        font_size, config.theme.text_color, anchor, font);
+    let y = y0 + height + font_size/4;
+    image.text(x0, y, &format!("{:.3e}", scale.min), &attrib_label("start"));
+    image.text(x0 + COLORBAR_STEPS*step, y, &format!("{:.3e}", scale.max), &attrib_label("end"));
+    if let Some(ref label) = colorbar.label {
+        image.text(x0 + COLORBAR_STEPS*step/2, y, label, &attrib_label("middle"));
+    }
+}
+
+/*
+*   A colorbar on its own, for a legend shared by several images drawn with the same
+*   scale (min, max) and config. Uses config.colorbar for the label, and the theme's
+*   colours and background. There is no data, so there is no histogram.
+*/
+pub fn colorbar_svg(min: f64, max: f64, config: &ProcessingConfig) -> SVG {
+    let (step, height, font_size, margin) = (25, 40, 50, 25);
+    let w = 2*margin + COLORBAR_STEPS*step;
+    let h = 2*margin + height + font_size/4 + (font_size*5)/4;
+
+    let mut image = SVG::new(12, 2);
+    image.view_box(0, 0, w, h);
+    if let Some(ref background) = config.theme.background {
+        image.rect(0, 0, w, h, &format!("fill={}", background));
+    }
+    let colorbar = config.colorbar.clone().unwrap_or_default();
+    let scale = ScaleBounds { min, max, cdf: None };
+    draw_colorbar(&mut image, (margin, margin), step, height, font_size, &scale, &colorbar, None, config);
+    image
+}
+
+/*
+*   Two images drawn by to_svg_with_config (e.g. the same sky with two configs) side by
+*   side in one SVG, each with its title above it. The background and the title colour
+*   and font come from config's theme.
+*/
+pub fn side_by_side_svg(left: &SVG, right: &SVG, titles: (&str, &str), config: &ProcessingConfig) -> SVG {
+    let (w, gap, font_size) = (4000, 200, 120); // w is the view drawn by draw_svg
+    let top = 2*font_size;
+    let (width, height) = (2*w + gap, w + top);
+
+    let mut image = SVG::new(24, 13);
+    image.view_box(0, 0, width, height);
+    if let Some(ref background) = config.theme.background {
+        image.rect(0, 0, width, height, &format!("fill={}", background));
+    }
+    let attrib_title = format!("font-size={} fill={} text-anchor=middle class=title{}",
+        font_size, config.theme.text_color, font_attrib(config));
+    for (n, (panel, title)) in [(left, titles.0), (right, titles.1)].iter().enumerate() {
+        let x = (n as i32)*(w + gap);
+        image.text(x + w/2, (3*font_size)/2, title, &attrib_title);
+        image.embed(panel, x, top);
+    }
+    image
+}
+
+// An SVG stroke-opacity attribute (with leading space), or nothing when fully opaque.
+fn stroke_opacity(opacity: f32) -> String {
+    if opacity < 1.0 {
+        format!(" stroke-opacity={:.3}", clamp(opacity, 0.0, 1.0))
+    } else {
+        String::new()
+    }
 }
 
 impl Hemisphere {
@@ -57,18 +255,229 @@ impl Hemisphere {
     fn corners(&self, pixel: u64) -> [(f64, f64); 4] {
         vertices(self.nside, pixel)
     }
+
+    /*
+    *   The (lon, lat) outline of a pixel: its four corners with edge_subdivisions extra
+    *   points linearly interpolated in lon/lat along each edge.
+    */
+    fn outline(&self, pixel: u64, edge_subdivisions: u32) -> Vec<(f64, f64)> {
+        let corners = self.corners(pixel);
+        if edge_subdivisions == 0 {
+            return corners.to_vec();
+        }
+
+        let steps = edge_subdivisions + 1;
+        let mut points = Vec::with_capacity(4*steps as usize);
+        for k in 0..4 {
+            let (lon0, lat0) = corners[k];
+            let (lon1, lat1) = corners[(k + 1) % 4];
+
+            // The longitude of a pole is arbitrary, so follow the other end of the edge.
+            let lon0 = if lat0.abs() >= PI_OVER_2 { lon1 } else { lon0 };
+            let lon1 = if lat1.abs() >= PI_OVER_2 { lon0 } else { lon1 };
+
+            // Interpolate the short way round
+            let mut d_lon = lon1 - lon0;
+            if d_lon > PI {
+                d_lon -= 2.0*PI;
+            } else if d_lon < -PI {
+                d_lon += 2.0*PI;
+            }
+
+            for s in 0..steps {
+                let t = (s as f64) / (steps as f64);
+                points.push((lon0 + t*d_lon, lat0 + t*(lat1 - lat0)));
+            }
+        }
+        points
+    }
     
+    /*
+    *   The four projected corners of each visible pixel (in visible_pix order) as
+    *   x, y pairs, 8 values per pixel, independent of any canvas size. The horizon is
+    *   the circle inscribed in the unit square: (0, 0) is the top left, x increases to
+    *   the right (towards the west) and y downwards (towards the south), so north is
+    *   up, east is left, and the zenith is at (0.5, 0.5), as in the SVG.
+    */
+    pub fn corners_normalized(&self) -> Vec<f32> {
+        self.drawn_corners_normalized(f64::NEG_INFINITY)
+    }
+
+    /*
+    *   As corners_normalized, with NaN for every coordinate of the pixels that
+    *   is_drawn(.., min_lat) leaves out, so the rest still line up with visible_pix.
+    */
+    pub fn drawn_corners_normalized(&self, min_lat: f64) -> Vec<f32> {
+        let mut coords = Vec::with_capacity(8*self.npix);
+        for &pixel in &self.visible_indices {
+            let corners = self.corners(pixel);
+            let drawn = Hemisphere::is_drawn(&corners, min_lat);
+            for &(lon, lat) in corners.iter() {
+                let (x, y) = HpAngle::from_lonlat(&LonLat::new(lon, lat)).proj();
+                if drawn {
+                    coords.push(((x + 1.0)/2.0) as f32);
+                    coords.push(((y + 1.0)/2.0) as f32);
+                } else {
+                    coords.extend_from_slice(&[f32::NAN, f32::NAN]);
+                }
+            }
+        }
+        coords
+    }
+
+    // Whether a pixel with this (lon, lat) outline is drawn: some point of it is above min_lat
+    pub fn is_drawn(outline: &[(f64, f64)], min_lat: f64) -> bool {
+        outline.iter().any(|p| p.1 > min_lat)
+    }
+
     #[allow(dead_code)]
     fn get_pix(&self, hp: &HpAngle) -> u64 {
-        let lonlat = LonLat::from_hp(&hp);
+        let lonlat = LonLat::from_hp(hp);
         hash(self.nside, lonlat.lon, lonlat.lat)
     }
 
     pub fn to_svg(&self,
             show_grid: bool,
             sources: Option<&Vec<Source>>) ->SVG {
-    
-        
+        let mut config = ProcessingConfig::default();
+        config.set_show_grid(show_grid);
+        self.to_svg_with_config(&config, sources)
+    }
+
+    pub fn to_svg_with_config(&self,
+            config: &ProcessingConfig,
+            sources: Option<&Vec<Source>>) ->SVG {
+        match self.draw_svg(config, sources, Fill::Colormap, &mut |_| Ok(())) {
+            Ok(image) => image,
+            Err(_) => unreachable!("drawing to memory does not do I/O"),
+        }
+    }
+
+    /*
+    *   As to_svg_with_config, with shader mapping each pixel's value, scaled to [0, 1]
+    *   by config.auto_scale, to its colour in place of config.colormap. The colorbar
+    *   (if any) uses the shader too.
+    */
+    pub fn to_svg_with_shader(&self,
+            config: &ProcessingConfig,
+            sources: Option<&Vec<Source>>,
+            shader: impl Fn(f32) -> (u8, u8, u8)) -> SVG {
+        match self.draw_svg(config, sources, Fill::Shader(&shader), &mut |_| Ok(())) {
+            Ok(image) => image,
+            Err(_) => unreachable!("drawing to memory does not do I/O"),
+        }
+    }
+
+    /*
+    *   A false colour SVG of two or three images of the same sky (e.g. two frequency
+    *   bands): each is scaled to [0, 1] independently, by config.auto_scale, and drives
+    *   one colour channel. Without green the result is red/blue. Unseen (NaN) pixels
+    *   add nothing to their channel, and pixels unseen in every image are not drawn.
+    *   The images must share a geometry (the same nside).
+    */
+    pub fn compose_rgb(red: &Hemisphere,
+                       green: Option<&Hemisphere>,
+                       blue: &Hemisphere,
+                       config: &ProcessingConfig) -> Result<SVG, ProcessingError> {
+        let channels = [Some(red), green, Some(blue)];
+        for sky in channels.iter().flatten() {
+            if sky.nside != red.nside || sky.visible_indices != red.visible_indices {
+                return Err(ProcessingError::InvalidFormat(format!(
+                    "cannot compose images with nside {} and {}", red.nside, sky.nside)));
+            }
+        }
+
+        // Each channel as bytes, after clipping and scaling it on its own
+        let bytes: Vec<Vec<u8>> = channels.iter().map(|channel| match channel {
+            Some(sky) => {
+                let clipped = sky.clipped(config);
+                let sky = clipped.as_ref().unwrap_or(sky);
+                let scale = sky.scale_bounds(config.auto_scale);
+                sky.visible_pix.iter()
+                    .map(|&p| if p.is_nan() { 0 } else { (255.0*scale.normalize(p)).round() as u8 })
+                    .collect()
+            },
+            None => vec![0; red.npix],
+        }).collect();
+        let colors: Vec<(u8, u8, u8)> = (0..red.npix).map(|i| (bytes[0][i], bytes[1][i], bytes[2][i])).collect();
+
+        // The shape of the sky to draw: NaN only where every image is unseen
+        let mut mask = red.clone();
+        for i in 0..red.npix {
+            let seen = channels.iter().flatten().any(|sky| !sky.visible_pix[i].is_nan());
+            mask.visible_pix[i] = if seen { 0.0 } else { f64::NAN };
+        }
+        // The mask's own values mean nothing, so nothing is drawn from them
+        let config = ProcessingConfig {
+            clip_negatives: false,
+            auto_scale: AutoScale::MinMax,
+            stats_overlay: None,
+            ..config.clone()
+        };
+        match mask.draw_svg(&config, None, Fill::Colors(&colors), &mut |_| Ok(())) {
+            Ok(image) => Ok(image),
+            Err(_) => unreachable!("drawing to memory does not do I/O"),
+        }
+    }
+
+    /*
+    *   Write the SVG to output as it is drawn, so that the whole document (several MB
+    *   at nside 128) is never held in memory. The output is the same as
+    *   to_svg_with_config with the title set.
+    */
+    pub fn write_svg(&self,
+            config: &ProcessingConfig,
+            sources: Option<&Vec<Source>>,
+            title: Option<&str>,
+            output: &mut dyn std::io::Write) -> std::io::Result<()> {
+        let mut started = false;
+        let mut flush = |image: &mut SVG| -> std::io::Result<()> {
+            if !started {
+                if let Some(title) = title {
+                    image.title(title);
+                }
+                image.write_head(output)?;
+                started = true;
+            }
+            image.flush_content(output)
+        };
+        let mut image = self.draw_svg(config, sources, Fill::Colormap, &mut flush)?;
+        flush(&mut image)?;
+        image.write_tail(output)
+    }
+
+    /*
+    *   The order to draw the visible pixels in (indices into visible_pix). With
+    *   config.deterministic_pixel_order they are sorted top to bottom, then left to
+    *   right, by projected centre, so where neighbouring outlines overlap the lower one
+    *   is always on top and seams look the same everywhere. Otherwise visible_indices order.
+    */
+    fn draw_order(&self, config: &ProcessingConfig) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.npix).collect();
+        if config.deterministic_pixel_order {
+            let centers: Vec<(f64, f64)> = self.visible_indices.iter()
+                .map(|&pixel| HpAngle::from_lonlat(&LonLat::from_pix(self.nside, pixel)).proj())
+                .collect();
+            order.sort_by(|&a, &b| centers[a].1.total_cmp(&centers[b].1)
+                .then(centers[a].0.total_cmp(&centers[b].0)));
+        }
+        order
+    }
+
+    /*
+    *   Draw the sky and decorations. flush is called every SVG_FLUSH_POLYGONS pixels
+    *   with the document so far, and may write out and clear its content. fill gives
+    *   the pixel colours.
+    */
+    fn draw_svg(&self,
+            config: &ProcessingConfig,
+            sources: Option<&Vec<Source>>,
+            fill: Fill,
+            flush: &mut dyn FnMut(&mut SVG) -> std::io::Result<()>) -> std::io::Result<SVG> {
+        if let Some(sky) = self.clipped(config) {
+            return sky.draw_svg(config, sources, fill, flush);
+        }
+
         let mut image = SVG::new(12, 12);
 
         let w = 4000;
@@ -78,164 +487,963 @@ impl Hemisphere {
         let line_size = pc.line_size;
         
         image.desc("Gridless imaging from visibilities.");
-        
-        let mut max_p: f64 = -1e99;
-        let mut min_p: f64 = 1e99;
-        let mut mean_p: f64 = 0.0;
-        
-        for p in self.visible_pix.iter() {
-            max_p = p.max(max_p);
-            min_p = p.min(min_p);
-            mean_p += p;
-        }
-        mean_p = mean_p / (self.npix as f64);
-        
-        let mut sdev_p: f64 = 0.0;
-        for p in self.visible_pix.iter() {
-            sdev_p += (p - mean_p) * (p - mean_p);
-        }
-        sdev_p = (sdev_p / (self.npix as f64)).sqrt();
 
-        print!("'N_s':{}, 'S/N': {}, 'min': {}, 'max': {}, 'mean': {}, 'sdev': {}", self.npix, (max_p/sdev_p), min_p, max_p, mean_p, sdev_p);
-        
-//         let mean2 = mean(&self.visible_pix.as_slice().expect("")).expect("Fail");
-        let med = median(&self.visible_pix.as_slice().expect("")).expect("Fail");
-        
-        
-        
-        let mut deviation : VectorReal = VectorReal::zeros(self.visible_pix.raw_dim());
-        deviation = deviation + &self.visible_pix - med;
-        deviation.mapv_inplace(f64::abs);
-        let mad_p: f64 = median(&deviation.as_slice().expect("")).expect("Fail");
+        let theme = &config.theme;
+        if let Some(ref background) = theme.background {
+            image.rect(0, 0, w, w, &format!("fill={}", background));
+        }
+        let font = font_attrib(config);
         
+        let stats = self.stats();
+        logging::log(&format!("'N_s':{}, 'S/N': {}, 'min': {}, 'max': {}, 'mean': {}, 'sdev': {}, 'R_mad': {}, 'MAD': {}, 'median': {}",
+            stats.n_s, (stats.max/stats.sdev), stats.min, stats.max, stats.mean, stats.sdev, (stats.max/stats.mad), stats.mad, stats.median));
 
-        println!(", 'R_mad': {}, 'MAD': {}, 'median': {}", (max_p/mad_p), mad_p, med);
+        let scale = self.scale_bounds(config.auto_scale);
 
+        if let Some(ref unseen) = theme.unseen_color {
+            image.circle(pc.from_x(0.0), pc.from_y(0.0), pc.from_d(1.0), &format!("fill={} stroke=none", unseen));
+        }
         {
             let base_poly_attrib = "stroke-width=2 stroke-linejoin=round stroke-opacity=1.0";
-            image.g_attribs(&base_poly_attrib);
+            image.g_attribs(base_poly_attrib);
         }
-        for i in 0..self.npix {
+        for (n, &i) in self.draw_order(config).iter().enumerate() {
             let pixel = self.visible_indices[i];
-            let corners = self.corners(pixel); // lon lat
             let value = self.visible_pix[i];
-            
-            let mut poly = Vec::new();
-            
-            let mut max_lat = 0.0;
-            let mut min_lat = PI_OVER_2;
-
-            for p in &corners {
-                max_lat = f64::max(max_lat, p.1);
-                min_lat = f64::min(min_lat, p.1);
-                
-                let ll = LonLat::new(p.0, p.1);
-                let hp = HpAngle::from_lonlat(&ll);
-                
-                if hp.theta > PI_OVER_2 {
-                    panic!("colatitude {} < PI_OVER_2", hp.theta);
+            if value.is_nan() {
+                continue;   // unseen
+            }
+
+            let color = if let Fill::Colors(colors) = fill {
+                let (r, g, b) = colors[i];
+                format!("rgb({},{},{})", r, g, b)
+            } else {
+                let fract = scale.normalize(value);
+                if let Some(threshold) = config.render_threshold {
+                    if fract < threshold as f64 {
+                        continue;
+                    }
+                }
+                if let Fill::Shader(shader) = fill {
+                    let (r, g, b) = shader(fract as f32);
+                    format!("rgb({},{},{})", r, g, b)
+                } else if config.dither {
+                    let (r, g, b) = config.colormap.rgb_u8_dithered(fract, dither_offset(i, i / 4));
+                    format!("rgb({},{},{})", r, g, b)
+                } else {
+                    let (r, g, b) = config.colormap.rgb(fract);
+                    format!("rgb({:.1},{:.1},{:.1})", r, g, b)
                 }
-                let (x,y) = hp.proj();
-            
-                poly.push((pc.from_x(x), pc.from_y(y)));
-                
+            };
+
+            let stroke = theme.polygon_stroke.as_ref().unwrap_or(&color);
+            let mut attrib = format!("fill={} stroke={}", color, stroke);
+            if let Some(opacity) = config.pixel_opacity.as_ref().and_then(|o| o.get(i)) {
+                let alpha = clamp(*opacity, 0.0, 1.0);
+                attrib.push_str(&format!(" fill-opacity={:.3} stroke-opacity={:.3}", alpha, alpha));
             }
 
+            match config.pixel_style {
+                PixelStyle::Polygon => {
+                    let corners = self.outline(pixel, config.edge_subdivisions); // lon lat
+
+                    let mut poly = Vec::new();
 
-            let (r, g, b) = cmap((value - min_p) / ( max_p - min_p));
-            let color = format!("rgb({:.1},{:.1},{:.1})", r, g, b);
+                    for p in &corners {
+                        let ll = LonLat::new(p.0, p.1);
+                        let hp = HpAngle::from_lonlat(&ll);
 
-            let attrib = format!("fill={} stroke={}",color, color);
-            //let attrib = format!("fill={}",color);
-            if max_lat > 0.07 {
-                image.polygon(&poly, &attrib);
+                        if hp.theta > PI_OVER_2 {
+                            panic!("colatitude {} < PI_OVER_2", hp.theta);
+                        }
+                        let (x,y) = hp.proj();
+
+                        poly.push((pc.from_x(x), pc.from_y(y)));
+                    }
+
+                    if Hemisphere::is_drawn(&corners, config.min_draw_lat) {
+                        image.polygon(&poly, &attrib);
+                    }
+                },
+                PixelStyle::Point { radius } => {
+                    let hp = HpAngle::from_lonlat(&LonLat::from_pix(self.nside, pixel));
+                    let (x, y) = hp.proj();
+                    image.circle(pc.from_x(x), pc.from_y(y), radius, &attrib);
+                },
+            }
+            if (n + 1) % SVG_FLUSH_POLYGONS == 0 {
+                flush(&mut image)?;
             }
         }
         image.g_end(); // end the attribute group for polygons
 
-        if show_grid {
-            let attrib_grid = format!("fill=none stroke=white stroke-width={} stroke-linejoin=round stroke-dasharray={},{}", line_size, 5*line_size, 10*line_size);
+        let grid_width = (theme.grid_width*(line_size as f32)).round() as u32;
+        let mut attrib_grid = format!("fill=none stroke={} stroke-width={} stroke-linejoin=round",
+            theme.grid_color, grid_width);
+        if let Some((dash, gap)) = theme.grid_dash {
+            attrib_grid.push_str(&format!(" stroke-dasharray={},{}", dash*line_size, gap*line_size));
+        }
+        attrib_grid.push_str(&stroke_opacity(config.grid_opacity));
+        if config.show_elevation_circles {
             for angle in &[10, 30, 60, 90] {
                 let rad = (*angle as f64).to_radians();
                 let radius = pc.from_d(rad.sin());
                 image.circle(pc.from_x(0.0), pc.from_y(0.0), radius, &attrib_grid );
             }
-            
+        }
+
+        if config.show_azimuth_lines {
             let rad0 = 10_f64.to_radians();
             let radius0 = rad0.sin();
 
             for angle in (0..360).step_by(30) {
                 let rad = (angle as f64).to_radians();
-                let x = rad.sin();
-                let y = rad.cos();
-                let x0 = radius0*x;
-                let y0 = radius0*y;
-                image.line(pc.from_x(x0), pc.from_y(y0), pc.from_x(x), pc.from_y(y), &attrib_grid );
+                let (x0, y0) = pc.from_az_r(rad, radius0);
+                let (x, y) = pc.from_az_r(rad, 1.0);
+                image.line(x0, y0, x, y, &attrib_grid );
+            }
+        }
+
+        if config.show_azimuth_ticks {
+            // Radial ticks just inside the horizon with the azimuth in degrees
+            let font_size = pc.w / 50;
+            let attrib_tick = format!("stroke={} stroke-width={}", theme.text_color, 2*line_size);
+            let attrib_label = format!("font-size={} fill={} text-anchor=middle dominant-baseline=middle{}", font_size, theme.text_color, font);
+
+            for angle in (0..360).step_by(30) {
+                let rad = (angle as f64).to_radians();
+                let (x0, y0) = pc.from_az_r(rad, 0.97);
+                let (x, y) = pc.from_az_r(rad, 1.0);
+                image.line(x0, y0, x, y, &attrib_tick );
+
+                let (xt, yt) = pc.from_az_r(rad, 0.93);
+                image.text(xt, yt, &format!("{}°", angle), &attrib_label);
+            }
+        }
+
+        if config.show_scalebar {
+            let font_size = pc.w / 50;
+            let length = pc.scalebar_length(config.scalebar_deg as f64) as i32;
+            let x0 = pc.w / 40;
+            let y0 = pc.w - pc.w / 40;
+            let tick = pc.w / 200;
+            let attrib_bar = format!("stroke={} stroke-width={}", theme.text_color, 2*line_size);
+            let attrib_label = format!("font-size={} fill={} text-anchor=middle{}", font_size, theme.text_color, font);
+
+            image.line(x0, y0, x0 + length, y0, &attrib_bar);
+            image.line(x0, y0 - tick, x0, y0 + tick, &attrib_bar);
+            image.line(x0 + length, y0 - tick, x0 + length, y0 + tick, &attrib_bar);
+            image.text(x0 + length/2, y0 - 2*tick, &format!("{}°", config.scalebar_deg), &attrib_label);
+        }
+
+        let colorbar = match fill {
+            Fill::Colors(_) => None,
+            _ => config.colorbar.as_ref(),
+        };
+        if let Some(colorbar) = colorbar {
+            let font_size = pc.w / 80;
+            let x0 = pc.w - pc.w / 40 - COLORBAR_STEPS*(pc.w / 160);
+            let step = pc.w / 160;
+            let height = pc.w / 100;
+            let y0 = pc.w - pc.w / 40 - height;
+
+            draw_colorbar(&mut image, (x0, y0), step, height, font_size, &scale, colorbar,
+                if let Fill::Shader(shader) = fill { Some(shader) } else { None }, config);
+
+            if colorbar.show_histogram {
+                let counts = self.histogram(COLORBAR_STEPS as usize, scale.min, scale.max);
+                let max_count = counts.iter().cloned().max().unwrap_or(0).max(1);
+                let max_height = pc.w / 40;
+                for (i, count) in counts.iter().enumerate() {
+                    let bar = ((*count as f64) / (max_count as f64) * (max_height as f64)).round() as i32;
+                    if bar > 0 {
+                        image.rect(x0 + (i as i32)*step, y0 - bar, step, bar, &format!("fill={}", theme.text_color));
+                    }
+                }
+            }
+        }
+
+        if let Some(ref overlay) = config.stats_overlay {
+            let lines = [
+                format!("N {}", stats.n_s),
+                format!("max {:.3e}", stats.max),
+                format!("min {:.3e}", stats.min),
+                format!("mean {:.3e}", stats.mean),
+                format!("sdev {:.3e}", stats.sdev),
+                format!("S/N {:.1}", stats.max/stats.sdev),
+            ];
+            let font_size = pc.w / 80;
+            let line_height = (font_size*5)/4;
+            let pad = font_size/2;
+            let x0 = (overlay.x*(pc.w as f32)).round() as i32;
+            let y0 = (overlay.y*(pc.w as f32)).round() as i32;
+            let height = 2*pad + line_height*(lines.len() as i32);
+
+            let fill = theme.background.as_ref().map_or("rgb(0,0,0)", |b| b.as_str());
+            image.rect(x0, y0, pc.w / 6, height, &format!("fill={} fill-opacity=0.7 stroke={} stroke-width={}",
+                fill, theme.text_color, line_size));
+            let attrib_text = format!("font-size={} fill={} dominant-baseline=hanging{}", font_size, theme.text_color, font);
+            for (k, line) in lines.iter().enumerate() {
+                image.text(x0 + pad, y0 + pad + (k as i32)*line_height, line, &attrib_text);
             }
         }
 
         match sources {
             Some(src) => {
-                let angular_size = (2.0 as f64).to_radians();
+                let angular_size = 2.0_f64.to_radians();
                 // let text_attrib_source = format!("font-size={} fill=rgb(255,20,50) stroke=rgb(200,200,200) stroke-width={}", 150, 5);
                         
                 for s in src {
                     if s.el > 20.0 {
 
-                        let el = s.el.to_radians();
-                        let az = s.az.to_radians();
-                        let elaz = ElAz::new(el, az);
-                        
-                        let (x,y) = pc.from_elaz(&elaz);
+                        let (x,y) = pc.from_elaz(&s.elaz());
 
-                        let attrib_source = format!("fill=none stroke=red stroke-width={} el={} az={} name={}", line_size, s.el, s.az, s.name.replace(" ", ""));
+                        let attrib_source = format!("fill=none stroke={} stroke-width={}{} el={} az={} name={}",
+                            theme.source_color, line_size, stroke_opacity(config.source_opacity), s.el, s.az, s.name.replace(" ", ""));
 
                         let radius = pc.from_d(angular_size);
                         //    let minor_axis = major_axis*el.sin();
                         //    let transform = image.transform();
                         //println!("circle({}, {}, {} {})", x,y,radius, &attrib_source);
 
+                        if config.source_outline {
+                            let attrib_outline = format!("fill=none stroke=rgb(0,0,0) stroke-width={}{}",
+                                3*line_size, stroke_opacity(config.source_opacity));
+                            image.circle(x, y, radius, &attrib_outline);
+                        }
                         image.circle(x, y, radius, &attrib_source );
                     }
                 }
             },
             None    => {/* nothing to do */}
         }
-        
-        //let mut output = BufWriter::new(File::create(fname).unwrap());
-       // image.finalize(&mut output).expect("Writing SVG image failed");
-        return image
+
+        if let (true, Fill::Colormap | Fill::Shader(_), Some(peak)) = (config.annotate_peak, fill, self.peak()) {
+            let (x, y) = pc.from_elaz(&ElAz::from_radians(peak.el, peak.az));
+            let size = pc.w / 100;
+            let attrib_marker = format!("stroke={} stroke-width={}", theme.text_color, 2*line_size);
+            image.line(x - size, y, x + size, y, &attrib_marker);
+            image.line(x, y - size, x, y + size, &attrib_marker);
+
+            let font_size = pc.w / 80;
+            // Label to the left of peaks on the right half, so it stays in the image
+            let (dx, anchor) = if x > pc.w / 2 { (-size, "end") } else { (size, "start") };
+            let attrib_label = format!("font-size={} fill={} text-anchor={} class=peak{}",
+                font_size, theme.text_color, anchor, font_attrib(config));
+            let label = format!("{:.3e} el {:.1}° az {:.1}°",
+                peak.value, peak.el.to_degrees(), peak.az.to_degrees());
+            image.text(x + dx, y - size, &label, &attrib_label);
+        }
+
+        Ok(image)
+    }
+
+    /*
+    *   The pixel values mapped to the ends of the colormap.
+    */
+    pub fn scale_bounds(&self, auto_scale: AutoScale) -> ScaleBounds {
+        let (min, max) = match auto_scale {
+            AutoScale::HistEq => {
+                let bounds = self.scale_bounds(AutoScale::MinMax);
+                let counts = self.histogram(HISTEQ_BINS, bounds.min, bounds.max);
+                let total: usize = counts.iter().sum();
+                if total == 0 {
+                    return bounds;
+                }
+                let mut cdf = Vec::with_capacity(HISTEQ_BINS + 1);
+                let mut below = 0;
+                cdf.push(0.0);
+                for count in counts {
+                    below += count;
+                    cdf.push((below as f64) / (total as f64));
+                }
+                return ScaleBounds { cdf: Some(cdf), ..bounds };
+            },
+            AutoScale::MinMax => {
+                let mut max_p: f64 = -1e99;
+                let mut min_p: f64 = 1e99;
+                for p in self.visible_pix.iter() {
+                    max_p = p.max(max_p);
+                    min_p = p.min(min_p);
+                }
+                (min_p, max_p)
+            },
+            AutoScale::Percentile(lo, hi) => {
+                let pix: Vec<f64> = self.visible_pix.iter().cloned().filter(|p| !p.is_nan()).collect();
+                (percentile(&pix, lo).unwrap_or(0.0), percentile(&pix, hi).unwrap_or(1.0))
+            }
+        };
+        ScaleBounds { min, max, cdf: None }
+    }
+
+    /*
+    *   A copy with negative pixels set to zero, when config.clip_negatives asks for it
+    *   and there are any. The renderers draw the copy instead.
+    */
+    pub fn clipped(&self, config: &ProcessingConfig) -> Option<Hemisphere> {
+        if config.clip_negatives && self.visible_pix.iter().any(|p| *p < 0.0) {
+            let mut sky = self.clone();
+            sky.visible_pix.mapv_inplace(|p| p.max(0.0));
+            Some(sky)
+        } else {
+            None
+        }
+    }
+
+    /*
+    *   RGB triplets for each visible pixel (in visible_pix order), scaled
+    *   as chosen by config.auto_scale.
+    */
+    pub fn color_bytes(&self, config: &ProcessingConfig) -> Vec<u8> {
+        if let Some(sky) = self.clipped(config) {
+            return sky.color_bytes(config);
+        }
+        let scale = self.scale_bounds(config.auto_scale);
+
+        let mut bytes = Vec::with_capacity(3*self.npix);
+        for (i, p) in self.visible_pix.iter().enumerate() {
+            let (r, g, b) = pixel_rgb(i, scale.normalize(*p), config);
+            bytes.push(r);
+            bytes.push(g);
+            bytes.push(b);
+        }
+        bytes
+    }
+
+    /*
+    *   RGBA for each visible pixel, 4 bytes per pixel in visible_pix order (that is,
+    *   ascending RING index, as visible_indices), for compositing over other layers.
+    *   The pixels the SVG leaves out are fully transparent (0, 0, 0, 0): unseen (NaN),
+    *   below config.render_threshold, or not reaching config.min_draw_lat. The rest
+    *   take their config.pixel_opacity as alpha, or are opaque.
+    */
+    pub fn color_bytes_rgba(&self, config: &ProcessingConfig) -> Vec<u8> {
+        if let Some(sky) = self.clipped(config) {
+            return sky.color_bytes_rgba(config);
+        }
+        let scale = self.scale_bounds(config.auto_scale);
+
+        let mut bytes = Vec::with_capacity(4*self.npix);
+        for (i, &p) in self.visible_pix.iter().enumerate() {
+            let fract = scale.normalize(p);
+            let hidden = p.is_nan()
+                || config.render_threshold.is_some_and(|t| fract < t as f64)
+                || !Hemisphere::is_drawn(&self.corners(self.visible_indices[i]), config.min_draw_lat);
+            if hidden {
+                bytes.extend_from_slice(&[0, 0, 0, 0]);
+                continue;
+            }
+            let (r, g, b) = pixel_rgb(i, fract, config);
+            let alpha = config.pixel_opacity.as_ref().and_then(|o| o.get(i)).map_or(1.0, |&a| clamp(a, 0.0, 1.0));
+            bytes.extend_from_slice(&[r, g, b, (alpha*255.0).round() as u8]);
+        }
+        bytes
     }
     
 }
 
-use num::clamp;
+#[cfg(test)]
+mod tests {
 
-/* Generate a colour Map
-// http://inversed.ru/Blog_2.htm
-// THese are CUbeHelix values
-*/
-fn cmap(fract: f64) -> (f64, f64, f64) {
-    let start = 1.0;
-    let rot = -1.5;
-    let sat = 1.5; 
-    let _gamma = 1.0;
-    
-    let pi = 3.14159265;
-    
-    let angle = 2.0 * pi * (start / 3.0 + rot * fract + 1.);
+    use super::*;
+    use theme::Theme;
+    use config::{Colorbar, StatsOverlay};
+
+    #[test]
+    fn test_compose_rgb() {
+        let red = Hemisphere::ramp(4);
+        let mut blue = Hemisphere::ramp(4);
+        blue.visible_pix.mapv_inplace(|p| 10.0*(1.0 - p));
+        let config = ProcessingConfig::default();
+
+        // Each channel is scaled on its own, so blue peaks where red is lowest
+        let svg = Hemisphere::compose_rgb(&red, None, &blue, &config).unwrap().to_string();
+        assert_eq!(svg.matches("<polygon").count(), red.to_svg_with_config(&config, None).to_string().matches("<polygon").count());
+        assert!(svg.contains("fill=\"rgb(0,0,255)\""));
+        assert!(svg.contains("fill=\"rgb(255,0,0)\""));
+
+        let svg = Hemisphere::compose_rgb(&red, Some(&red), &blue, &config).unwrap().to_string();
+        assert!(svg.contains("fill=\"rgb(255,255,0)\""));
 
-    let amp = sat * fract * (1. - fract) / 2.;
+        // Pixels unseen in every image are left out
+        let mut unseen_red = red.clone();
+        let mut unseen_blue = blue.clone();
+        unseen_red.visible_pix[40] = f64::NAN;
+        unseen_blue.visible_pix[40] = f64::NAN;
+        unseen_blue.visible_pix[41] = f64::NAN;
+        let partial = Hemisphere::compose_rgb(&unseen_red, None, &unseen_blue, &config).unwrap().to_string();
+        assert_eq!(partial.matches("<polygon").count() + 1, svg.matches("<polygon").count());
 
-    // compute the RGB vectors according to main equations
-    let mut red = fract + amp * (-0.14861 * angle.cos() + 1.78277 * angle.sin());
-    let mut grn = fract + amp * (-0.29227 * angle.cos() - 0.90649 * angle.sin());
-    let mut blu = fract + amp * (1.97294 * angle.cos());
+        match Hemisphere::compose_rgb(&red, None, &Hemisphere::ramp(8), &config) {
+            Err(ProcessingError::InvalidFormat(msg)) => assert!(msg.contains("nside 4 and 8")),
+            _ => panic!("Expected an InvalidFormat error"),
+        }
+    }
+
+    #[test]
+    fn test_to_svg_with_shader() {
+        let sky = Hemisphere::ramp(4);
+        let config = ProcessingConfig {
+            colorbar: Some(Colorbar::default()),
+            ..ProcessingConfig::default()
+        };
+
+        // Highlight one band of values, grey elsewhere
+        let band = |x: f32| if (0.4..0.6).contains(&x) { (255, 0, 0) } else { (128, 128, 128) };
+        let svg = sky.to_svg_with_shader(&config, None, band).to_string();
+        let highlighted = sky.visible_pix.iter().filter(|&&p| (0.4..0.6).contains(&(p as f32))).count();
+        let polygons = svg.matches("fill=\"rgb(255,0,0)\" stroke=\"rgb(255,0,0)\"").count();
+        assert!(highlighted > 0);
+        assert_eq!(polygons, highlighted);
+        assert_eq!(svg.matches("<polygon").count(), sky.npix);
+        // The colorbar cells come from the shader
+        assert_eq!((svg.matches("fill=\"rgb(255,0,0)\"").count() - polygons) as i32, COLORBAR_STEPS/5);
+
+        // The colormap as a shader draws the same image, up to rounding
+        let colormap = |x: f32| config.colormap.rgb_u8(x as f64);
+        let shaded = sky.to_svg_with_shader(&config, None, colormap).to_string();
+        assert_eq!(shaded.matches("<polygon").count(), svg.matches("<polygon").count());
+    }
+
+    #[test]
+    fn test_corners_normalized() {
+        let sky = Hemisphere::new(4);
+        let coords = sky.corners_normalized();
+        assert_eq!(coords.len(), 8*sky.npix);
+
+        // Inside the horizon circle inscribed in the unit square
+        for xy in coords.chunks(2) {
+            let (dx, dy) = (xy[0] - 0.5, xy[1] - 0.5);
+            assert!(dx*dx + dy*dy <= 0.25 + 1.0e-6);
+        }
+
+        // The same positions as the SVG, scaled from its plot coordinates
+        let pc = PlotCoords::new(4000);
+        let pixel = sky.visible_indices[30];
+        let (lon, lat) = sky.corners(pixel)[0];
+        let (x, y) = HpAngle::from_lonlat(&LonLat::new(lon, lat)).proj();
+        let to_unit = |p: i32| (((p - pc.center) as f64)/pc.scale + 1.0)/2.0;
+        assert!((to_unit(pc.from_x(x)) - coords[8*30] as f64).abs() < 1.0e-3);
+        assert!((to_unit(pc.from_y(y)) - coords[8*30 + 1] as f64).abs() < 1.0e-3);
+
+        // North is up: a pixel near az 0 is above the zenith
+        let north = sky.iter_pixels().position(|p| p.az.abs() < 0.2 && p.el < 0.5).unwrap();
+        assert!(coords[8*north + 1] < 0.5);
+    }
+
+    #[test]
+    fn test_min_draw_lat() {
+        let sky = Hemisphere::ramp(8);
+        // The SVG draws exactly the pixels the corner binding keeps
+        for &min_lat in &[0.0, 0.07, 0.2] {
+            let config = ProcessingConfig { min_draw_lat: min_lat, ..ProcessingConfig::default() };
+            let polygons = sky.to_svg_with_config(&config, None).to_string().matches("<polygon").count();
+            let corners = sky.drawn_corners_normalized(min_lat);
+            assert_eq!(corners.len(), 8*sky.npix);
+            assert_eq!(corners.chunks(8).filter(|c| !c[0].is_nan()).count(), polygons);
+        }
+        let count = |min_lat| sky.drawn_corners_normalized(min_lat).chunks(8).filter(|c| !c[0].is_nan()).count();
+        assert!(count(0.0) >= count(0.07) && count(0.07) > count(0.2));
+        assert_eq!(count(f64::NEG_INFINITY), sky.npix);
+    }
+
+    #[test]
+    fn test_annotate_peak() {
+        let mut sky = Hemisphere::ramp(4);
+        sky.visible_pix[20] = 3.0;
+        let peak = sky.peak().unwrap();
+
+        let plain = sky.to_svg_with_config(&ProcessingConfig::default(), None).to_string();
+        assert!(!plain.contains("class=\"peak\""));
+
+        let config = ProcessingConfig { annotate_peak: true, ..ProcessingConfig::default() };
+        let svg = sky.to_svg_with_config(&config, None).to_string();
+        assert_eq!(svg.matches("class=\"peak\"").count(), 1);
+        assert!(svg.contains(&format!("3.000e0 el {:.1}° az {:.1}°", peak.el.to_degrees(), peak.az.to_degrees())));
+        assert_eq!(svg.matches("<line").count(), plain.matches("<line").count() + 2);
+
+        // No peak to mark when nothing is seen
+        sky.visible_pix.fill(f64::NAN);
+        assert!(!sky.to_svg_with_config(&config, None).to_string().contains("class=\"peak\""));
+    }
+
+    #[test]
+    fn test_percentile_scale_ignores_outlier() {
+        let mut sky = Hemisphere::ramp(8);
+        sky.visible_pix[10] = 1.0e6;
+
+        let minmax = sky.scale_bounds(AutoScale::MinMax);
+        assert_eq!(minmax.max, 1.0e6);
+
+        let pct = sky.scale_bounds(AutoScale::Percentile(1.0, 99.0));
+        assert!(pct.max < 1.0);
+        assert!(pct.min > 0.0);
+        assert_eq!(pct.normalize(1.0e6), 1.0);
+        assert_eq!(pct.normalize(-1.0), 0.0);
+    }
+
+    #[test]
+    fn test_histeq() {
+        // Most of a power law is faint: a linear scale puts much of it at the bottom
+        let mut sky = Hemisphere::ramp(16);
+        sky.visible_pix.mapv_inplace(|p| p.powi(2));
+        let deciles = |scale: &ScaleBounds| {
+            let mut counts = [0; 10];
+            for p in sky.visible_pix.iter() {
+                counts[((10.0*scale.normalize(*p)) as usize).min(9)] += 1;
+            }
+            counts
+        };
+        let expected = sky.npix/10;
+        assert!(deciles(&sky.scale_bounds(AutoScale::MinMax))[0] > 3*expected);
+
+        // Equalized, the output is flat
+        let histeq = sky.scale_bounds(AutoScale::HistEq);
+        assert_eq!((histeq.min, histeq.max), (0.0, 1.0));
+        for count in deciles(&histeq).iter() {
+            assert!((*count as f64 - expected as f64).abs() < 0.1*(expected as f64), "{}", count);
+        }
+        assert_eq!(histeq.normalize(0.0), 0.0);
+        assert_eq!(histeq.normalize(1.0), 1.0);
+
+        // The colorbar shows the equalized colours: a quarter of the way up by its second cell
+        let config = ProcessingConfig {
+            auto_scale: AutoScale::HistEq,
+            colorbar: Some(Colorbar::default()),
+            ..ProcessingConfig::default()
+        };
+        let svg = sky.to_svg_with_config(&config, None).to_string();
+        let cell = |n: usize| svg.rsplit("<rect").nth(COLORBAR_STEPS as usize - 1 - n).unwrap().to_string();
+        let (r, g, b) = config.colormap.rgb(histeq.equalize(1.5/(COLORBAR_STEPS as f64)));
+        assert!(cell(1).contains(&format!("fill=\"rgb({:.1},{:.1},{:.1})\"", r, g, b)));
+        assert!(histeq.equalize(1.5/(COLORBAR_STEPS as f64)) > 0.2);
+    }
+
+    #[test]
+    fn test_render_threshold() {
+        let sky = Hemisphere::ramp(8);
+
+        let all = sky.to_svg_with_config(&ProcessingConfig::default(), None).to_string();
+        let config = ProcessingConfig {
+            render_threshold: Some(0.5),
+            ..ProcessingConfig::default()
+        };
+        let bright = sky.to_svg_with_config(&config, None).to_string();
+
+        let n_all = all.matches("<polygon").count();
+        let n_bright = bright.matches("<polygon").count();
+        assert!(n_bright > 0);
+        assert!(n_bright < n_all);
+        assert!(n_bright <= sky.npix/2 + 1);
+    }
+
+    #[test]
+    fn test_pixel_opacity() {
+        let sky = Hemisphere::ramp(4);
+        let mut opacity = vec![1.0; sky.npix];
+        opacity[0] = 0.25;
+        opacity[1] = 2.0;
+        let config = ProcessingConfig {
+            pixel_opacity: Some(opacity),
+            ..ProcessingConfig::default()
+        };
+        let svg = sky.to_svg_with_config(&config, None).to_string();
+        assert!(svg.contains("fill-opacity=\"0.250\""));
+        assert!(!svg.contains("fill-opacity=\"2.000\""));
+
+        let plain = sky.to_svg_with_config(&ProcessingConfig::default(), None).to_string();
+        assert!(!plain.contains("fill-opacity"));
+    }
 
-    // find where RBB are outside the range [0,1], clip
-    red = clamp(red, 0.0, 1.0);
-    grn = clamp(grn, 0.0, 1.0);
-    blu = clamp(blu, 0.0, 1.0);
+    #[test]
+    fn test_edge_subdivisions() {
+        let sky = Hemisphere::ramp(4);
+        for pixel in &sky.visible_indices {
+            assert_eq!(sky.outline(*pixel, 0).len(), 4);
 
-    (red*255.0, grn*255.0, blu*255.0)
+            let outline = sky.outline(*pixel, 2);
+            assert_eq!(outline.len(), 12);
+            for (lon, lat) in outline {
+                assert!((0.0..=PI_OVER_2).contains(&lat));
+                assert!(lon.is_finite());
+            }
+        }
+
+        let config = ProcessingConfig {
+            edge_subdivisions: 2,
+            ..ProcessingConfig::default()
+        };
+        let svg = sky.to_svg_with_config(&config, None).to_string();
+        let first = svg.split("<polygon points=\"").nth(1).unwrap();
+        let points = &first[..first.find('"').unwrap()];
+        assert_eq!(points.split_whitespace().count(), 12);
+    }
+
+    #[test]
+    fn test_azimuth_ticks() {
+        let sky = Hemisphere::ramp(4);
+        let config = ProcessingConfig {
+            show_azimuth_ticks: true,
+            ..ProcessingConfig::default()
+        };
+        let svg = sky.to_svg_with_config(&config, None).to_string();
+        assert_eq!(svg.matches("<text").count(), 12);
+        assert!(svg.contains(">90°</text>"));
+
+        let plain = sky.to_svg_with_config(&ProcessingConfig::default(), None).to_string();
+        assert_eq!(plain.matches("<text").count(), 0);
+    }
+
+    #[test]
+    fn test_scalebar() {
+        // A full 90 degree bar spans the projected radius from zenith to horizon
+        let pc = PlotCoords::new(4000);
+        let radius = pc.from_x(1.0) - pc.from_x(0.0);
+        assert!((pc.scalebar_length(90.0) as i32 - radius).abs() <= 1);
+        assert!(pc.scalebar_length(10.0) < pc.scalebar_length(20.0));
+
+        let sky = Hemisphere::ramp(4);
+        let config = ProcessingConfig {
+            show_scalebar: true,
+            scalebar_deg: 15.0,
+            ..ProcessingConfig::default()
+        };
+        let svg = sky.to_svg_with_config(&config, None).to_string();
+        assert!(svg.contains(">15°</text>"));
+        let plain = sky.to_svg_with_config(&ProcessingConfig::default(), None).to_string();
+        assert!(!plain.contains("°</text>"));
+    }
+
+    #[test]
+    fn test_theme() {
+        let sky = Hemisphere::ramp(4);
+        let plain = sky.to_svg_with_config(&ProcessingConfig::default(), None).to_string();
+        assert!(plain.contains("stroke=\"white\""));
+        assert!(!plain.contains("<rect"));
+
+        let config = ProcessingConfig {
+            theme: Theme::light(),
+            ..ProcessingConfig::default()
+        };
+        let svg = sky.to_svg_with_config(&config, None).to_string();
+        assert!(svg.contains("<rect"));
+        assert!(svg.contains("stroke=\"#555555\""));
+
+        let custom = Theme {
+            grid_color: "#123456".to_string(),
+            grid_dash: None,
+            polygon_stroke: Some("black".to_string()),
+            ..Theme::astronomy()
+        };
+        let config = ProcessingConfig {
+            theme: custom,
+            ..ProcessingConfig::default()
+        };
+        let svg = sky.to_svg_with_config(&config, None).to_string();
+        assert_eq!(svg.matches("stroke=\"#123456\"").count(), 16);
+        assert_eq!(svg.matches("stroke=\"black\"").count(), svg.matches("<polygon").count());
+        assert!(!svg.contains("stroke-dasharray"));
+    }
+
+    #[test]
+    fn test_unseen_color() {
+        let mut sky = Hemisphere::ramp(4);
+        sky.visible_pix[3] = f64::NAN;
+        let plain = sky.to_svg_with_config(&ProcessingConfig::default(), None).to_string();
+        assert_eq!(plain.matches("<polygon").count(), sky.npix - 1);
+
+        let config = ProcessingConfig {
+            theme: Theme { unseen_color: Some("#202020".to_string()), ..Theme::astronomy() },
+            ..ProcessingConfig::default()
+        };
+        let svg = sky.to_svg_with_config(&config, None).to_string();
+        assert_eq!(svg.matches("fill=\"#202020\"").count(), 1);
+        // Drawn first, behind the data
+        assert!(svg.find("#202020").unwrap() < svg.find("<polygon").unwrap());
+        assert_eq!(svg.matches("<circle").count(), plain.matches("<circle").count() + 1);
+    }
+
+    #[test]
+    fn test_grid_flags() {
+        let sky = Hemisphere::ramp(4);
+        let count = |config: &ProcessingConfig| {
+            let svg = sky.to_svg_with_config(config, None).to_string();
+            (svg.matches("<circle").count(), svg.matches("<line").count())
+        };
+
+        assert_eq!(count(&ProcessingConfig::default()), (4, 12));
+
+        let spokes_only = ProcessingConfig {
+            show_elevation_circles: false,
+            ..ProcessingConfig::default()
+        };
+        assert_eq!(count(&spokes_only), (0, 12));
+
+        let circles_only = ProcessingConfig {
+            show_azimuth_lines: false,
+            ..ProcessingConfig::default()
+        };
+        assert_eq!(count(&circles_only), (4, 0));
+
+        let mut none = ProcessingConfig::default();
+        none.set_show_grid(false);
+        assert_eq!(count(&none), (0, 0));
+    }
+
+    #[test]
+    fn test_grid_and_source_opacity() {
+        let sky = Hemisphere::ramp(4);
+        let sources = vec![Source { az: 90.0, el: 45.0, jy: 1.0, name: "Test".to_string(), r: 0.0 }];
+
+        let plain = sky.to_svg_with_config(&ProcessingConfig::default(), Some(&sources)).to_string();
+        assert!(!plain.contains("stroke-opacity=\"0"));
+
+        let config = ProcessingConfig {
+            grid_opacity: 0.4,
+            source_opacity: 0.25,
+            ..ProcessingConfig::default()
+        };
+        let svg = sky.to_svg_with_config(&config, Some(&sources)).to_string();
+        // Four circles and twelve spokes
+        assert_eq!(svg.matches("stroke-opacity=\"0.400\"").count(), 16);
+        assert_eq!(svg.matches("stroke-opacity=\"0.250\"").count(), 1);
+    }
+
+    #[test]
+    fn test_coords_round() {
+        let pc = PlotCoords::new(4000);
+        for k in -200..=200 {
+            // Includes negative offsets, where truncation would move towards the centre
+            let x = (k as f64) * 0.00513;
+            assert_eq!(pc.from_x(x), (x*pc.scale).round() as i32 + pc.center);
+            assert_eq!(pc.from_y(x), (x*pc.scale).round() as i32 + pc.center);
+        }
+
+        // The grid (from_az_r) and source (from_elaz) paths land on the same point
+        for az_deg in (0..360).step_by(15) {
+            let az = (az_deg as f64).to_radians();
+            for el_deg in [5.0_f64, 30.0, 60.0, 85.0] {
+                let el = el_deg.to_radians();
+                assert_eq!(pc.from_elaz(&ElAz::new(el, az)), pc.from_az_r(az, el.cos()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_stats_overlay() {
+        let sky = Hemisphere::ramp(4);
+        let plain = sky.to_svg_with_config(&ProcessingConfig::default(), None).to_string();
+        assert!(!plain.contains("S/N"));
+
+        let config = ProcessingConfig {
+            stats_overlay: Some(StatsOverlay { x: 0.5, y: 0.25 }),
+            ..ProcessingConfig::default()
+        };
+        let svg = sky.to_svg_with_config(&config, None).to_string();
+        assert!(svg.contains("S/N"));
+        // Placed and sized relative to the 4000 unit view
+        assert!(svg.contains("x=\"2000\"") && svg.contains("y=\"1000\""));
+        assert!(svg.contains("width=\"666\""));
+        assert!(svg.contains("font-size=\"50\""));
+    }
+
+    #[test]
+    fn test_write_svg_matches() {
+        let sky = Hemisphere::ramp(16);
+        let sources = vec![Source { az: 90.0, el: 45.0, jy: 1.0, name: "Test".to_string(), r: 0.0 }];
+        let config = ProcessingConfig::default();
+
+        let mut svg = sky.to_svg_with_config(&config, Some(&sources));
+        svg.title("Observed now");
+        let expected = svg.to_string();
+
+        let mut streamed = Vec::new();
+        sky.write_svg(&config, Some(&sources), Some("Observed now"), &mut streamed).unwrap();
+        assert!(sky.npix > SVG_FLUSH_POLYGONS);
+        assert_eq!(String::from_utf8(streamed).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_dither() {
+        let sky = Hemisphere::ramp(16);
+        let config = ProcessingConfig { dither: true, ..ProcessingConfig::default() };
+        let plain = sky.color_bytes(&ProcessingConfig::default());
+        let dithered = sky.color_bytes(&config);
+        assert_eq!(dithered, sky.color_bytes(&config));
+        assert_ne!(dithered, plain);
+        assert!(plain.iter().zip(dithered.iter()).all(|(a, b)| (*a as i32 - *b as i32).abs() <= 1));
+
+        // The SVG gets the same whole-number colours
+        let svg = sky.to_svg_with_config(&config, None).to_string();
+        let first = format!("fill=\"rgb({},{},{})\"", dithered[0], dithered[1], dithered[2]);
+        assert!(svg.contains(&first));
+        assert_eq!(svg, sky.to_svg_with_config(&config, None).to_string());
+    }
+
+    #[test]
+    fn test_color_bytes_rgba() {
+        let mut sky = Hemisphere::ramp(8);
+        sky.visible_pix[3] = f64::NAN;
+        let config = ProcessingConfig {
+            render_threshold: Some(0.25),
+            min_draw_lat: 0.2,
+            pixel_opacity: Some(vec![0.5; sky.npix]),
+            ..ProcessingConfig::default()
+        };
+        let rgb = sky.color_bytes(&config);
+        let rgba = sky.color_bytes_rgba(&config);
+        assert_eq!(rgba.len(), 4*sky.npix);
+
+        // Transparent exactly where the SVG draws nothing, otherwise the RGB colour
+        let drawn = rgba.chunks(4).filter(|p| p[3] > 0).count();
+        let svg = sky.to_svg_with_config(&config, None).to_string();
+        assert_eq!(drawn, svg.matches("<polygon").count());
+        for i in 0..sky.npix {
+            let pixel = &rgba[4*i..4*i + 4];
+            if pixel[3] == 0 {
+                assert_eq!(pixel, &[0, 0, 0, 0]);
+            } else {
+                assert_eq!(&pixel[0..3], &rgb[3*i..3*i + 3]);
+                assert_eq!(pixel[3], 128);
+            }
+        }
+        assert_eq!(&rgba[12..16], &[0, 0, 0, 0]);
+        assert!(drawn > 0 && drawn < sky.npix - 1);
+
+        // With nothing hidden every pixel is opaque
+        let opaque = ProcessingConfig { min_draw_lat: f64::NEG_INFINITY, ..ProcessingConfig::default() };
+        assert!(Hemisphere::ramp(8).color_bytes_rgba(&opaque).chunks(4).all(|p| p[3] == 255));
+    }
+
+    #[test]
+    fn test_clip_negatives() {
+        let mut sky = Hemisphere::ramp(4);
+        sky.visible_pix.mapv_inplace(|p| p - 0.5);
+        let config = ProcessingConfig {
+            clip_negatives: true,
+            ..ProcessingConfig::default()
+        };
+        assert!(sky.clipped(&ProcessingConfig::default()).is_none());
+
+        let clipped = sky.clipped(&config).unwrap();
+        assert_eq!(clipped.scale_bounds(AutoScale::MinMax).min, 0.0);
+        assert!(clipped.clipped(&config).is_none());
+
+        // Every negative pixel takes the colour of zero, the bottom of the colormap
+        let bytes = sky.color_bytes(&config);
+        let low = config.colormap.rgb_u8(0.0);
+        for i in 0..sky.npix {
+            if sky.visible_pix[i] <= 0.0 {
+                assert_eq!((bytes[3*i], bytes[3*i + 1], bytes[3*i + 2]), low);
+            }
+        }
+        assert_ne!(sky.color_bytes(&config), sky.color_bytes(&ProcessingConfig::default()));
+        assert_eq!(sky.to_svg_with_config(&config, None).to_string(),
+                   clipped.to_svg_with_config(&config, None).to_string());
+    }
+
+    #[test]
+    fn test_point_style() {
+        let sky = Hemisphere::ramp(8);
+        let polygons = sky.to_svg_with_config(&ProcessingConfig::default(), None).to_string();
+
+        let config = ProcessingConfig {
+            pixel_style: PixelStyle::Point { radius: 7 },
+            ..ProcessingConfig::default()
+        };
+        let points = sky.to_svg_with_config(&config, None).to_string();
+        assert!(!points.contains("<polygon"));
+        assert_eq!(points.matches("r=\"7\"").count(), sky.npix);
+        assert!(points.len() < polygons.len());
+    }
+
+    #[test]
+    fn test_deterministic_pixel_order() {
+        let sky = Hemisphere::ramp(8);
+        let plain = sky.to_svg_with_config(&ProcessingConfig::default(), None).to_string();
+        let config = ProcessingConfig {
+            deterministic_pixel_order: true,
+            ..ProcessingConfig::default()
+        };
+        let sorted = sky.to_svg_with_config(&config, None).to_string();
+        assert_ne!(sorted, plain);
+        assert_eq!(sorted.matches("<polygon").count(), plain.matches("<polygon").count());
+
+        // Pixel centres are drawn top to bottom
+        let points = ProcessingConfig {
+            pixel_style: PixelStyle::Point { radius: 7 },
+            ..config
+        };
+        let svg = sky.to_svg_with_config(&points, None).to_string();
+        let cy: Vec<i64> = svg.split("<circle").skip(1)
+            .filter(|c| c.contains("r=\"7\""))
+            .map(|c| c.split("cy=\"").nth(1).unwrap().split('"').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(cy.len(), sky.npix);
+        assert!(cy.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn test_colorbar() {
+        let sky = Hemisphere::ramp(4);
+        let plain = sky.to_svg_with_config(&ProcessingConfig::default(), None).to_string();
+
+        let bar = ProcessingConfig {
+            colorbar: Some(Colorbar::default()),
+            ..ProcessingConfig::default()
+        };
+        let with_bar = sky.to_svg_with_config(&bar, None).to_string();
+        assert_eq!(with_bar.matches("<rect").count(), plain.matches("<rect").count() + COLORBAR_STEPS as usize);
+
+        let hist = ProcessingConfig {
+            colorbar: Some(Colorbar { show_histogram: true, ..Colorbar::default() }),
+            ..ProcessingConfig::default()
+        };
+        // A ramp fills every bin, so each gets a histogram bar
+        let with_hist = sky.to_svg_with_config(&hist, None).to_string();
+        assert_eq!(with_hist.matches("<rect").count(), plain.matches("<rect").count() + 2*COLORBAR_STEPS as usize);
+    }
+
+    #[test]
+    fn test_colorbar_svg() {
+        let config = ProcessingConfig {
+            colorbar: Some(Colorbar { label: Some("Jy/beam".to_string()), ..Colorbar::default() }),
+            ..ProcessingConfig::default()
+        };
+        let legend = colorbar_svg(-0.5, 2.0, &config).to_string();
+        assert!(legend.contains("<svg"));
+        assert_eq!(legend.matches("<rect").count(), COLORBAR_STEPS as usize);
+        assert!(legend.contains(">-5.000e-1<") && legend.contains(">2.000e0<") && legend.contains(">Jy/beam<"));
+        assert!(!legend.contains("<polygon") && !legend.contains("<circle"));
+
+        // The same cells as the colorbar drawn on an image with that config
+        let sky = Hemisphere::ramp(4);
+        let image = sky.to_svg_with_config(&config, None).to_string();
+        assert!(image.contains(">Jy/beam<"));
+        let first_cell = |svg: &str| svg.split("<rect").nth(1).unwrap().split("fill=").nth(1).unwrap().split(' ').next().unwrap().to_string();
+        assert_eq!(first_cell(&legend), first_cell(&image));
+
+        // A background is drawn behind it
+        let light = ProcessingConfig { theme: Theme::light(), ..ProcessingConfig::default() };
+        assert_eq!(colorbar_svg(0.0, 1.0, &light).to_string().matches("<rect").count(), COLORBAR_STEPS as usize + 1);
+    }
+
+    #[test]
+    fn test_source_outline() {
+        let sky = Hemisphere::ramp(4);
+        let sources = vec![Source { az: 90.0, el: 45.0, jy: 1.0, name: "Test".to_string(), r: 0.0 }];
+
+        let plain = sky.to_svg_with_config(&ProcessingConfig::default(), Some(&sources)).to_string();
+
+        let config = ProcessingConfig {
+            source_outline: true,
+            ..ProcessingConfig::default()
+        };
+        let svg = sky.to_svg_with_config(&config, Some(&sources)).to_string();
+        assert_eq!(svg.matches("<circle").count(), plain.matches("<circle").count() + 1);
+        // The dark backing comes first so the coloured marker is drawn on top
+        let outline = svg.find("stroke=\"rgb(0,0,0)\"").unwrap();
+        let marker = svg.find("name=\"Test\"").unwrap();
+        assert!(outline < marker);
+    }
 }
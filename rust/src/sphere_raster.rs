@@ -0,0 +1,587 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+use num::clamp;
+use png::{Encoder, ColorType, BitDepth};
+
+use cdshealpix::ring::hash;
+use utils::PI_OVER_2;
+
+use sphere::{Hemisphere, ElAz};
+use config::ProcessingConfig;
+use theme::parse_rgb;
+use colormap::dither_offset;
+use error::ProcessingError;
+
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::prelude::*;
+
+// Rows of output rendered together, one band per task when rendering in parallel
+const RASTER_BAND_ROWS: u32 = 32;
+// Deepest web map zoom level rendered, 2^16 tiles along each side
+const MAX_TILE_ZOOM: u32 = 16;
+
+impl Hemisphere {
+
+    /*
+    *   The index into visible_pix of the pixel seen at projected position (x, y),
+    *   using the same zenith-up projection as the SVG. None outside the horizon.
+    */
+    fn pixel_at(&self, x: f64, y: f64) -> Option<usize> {
+        let r = (x*x + y*y).sqrt();
+        if r >= 1.0 {
+            return None;
+        }
+        let theta = r.asin();
+        let phi = x.atan2(-y);
+        let pixel = hash(self.nside, phi, PI_OVER_2 - theta);
+        self.visible_indices.binary_search(&pixel).ok()
+    }
+
+    /*
+    *   Render the sky as size x size straight (non premultiplied) RGBA, framed like the
+    *   SVG. Pixels outside the horizon, or below config.render_threshold, are transparent,
+    *   or take the theme's unseen_color within the horizon.
+    */
+    pub fn to_rgba(&self, size: u32, config: &ProcessingConfig) -> Result<Vec<u8>, ProcessingError> {
+        self.region_rgba(size, 0, 0, size, config)
+    }
+
+    // The number of tiles along each side of the web map pyramid at zoom z
+    pub fn tiles_per_side(z: u32) -> Result<u32, ProcessingError> {
+        if z > MAX_TILE_ZOOM {
+            return Err(ProcessingError::InvalidFormat(format!("zoom {} is above the maximum of {}", z, MAX_TILE_ZOOM)));
+        }
+        Ok(1 << z)
+    }
+
+    /*
+    *   One square tile of a web map (slippy map) pyramid. At zoom z the whole image is
+    *   2^z x 2^z tiles of tile_size pixels, and (x, y) counts tiles from the top left.
+    *   Tiles entirely beyond the horizon are fully transparent.
+    */
+    pub fn to_tile(&self, z: u32, x: u32, y: u32, tile_size: u32,
+                   config: &ProcessingConfig) -> Result<Vec<u8>, ProcessingError> {
+        let n = Hemisphere::tiles_per_side(z)?;
+        if x >= n || y >= n {
+            return Err(ProcessingError::InvalidFormat(format!("tile ({}, {}) is outside zoom {}", x, y, z)));
+        }
+        let full_size = tile_size.checked_mul(n).ok_or_else(|| {
+            ProcessingError::InvalidFormat(format!("{} pixel tiles at zoom {} are too large", tile_size, z))
+        })?;
+        self.region_rgba(full_size, x*tile_size, y*tile_size, tile_size, config)
+    }
+
+    // The tile as a PNG, or None if it is fully transparent (map viewers leave missing tiles blank)
+    pub fn to_tile_png(&self, z: u32, x: u32, y: u32, tile_size: u32,
+                       config: &ProcessingConfig) -> Result<Option<Vec<u8>>, ProcessingError> {
+        let rgba = self.to_tile(z, x, y, tile_size, config)?;
+        if rgba.chunks(4).all(|p| p[3] == 0) {
+            Ok(None)
+        } else {
+            encode_png(&rgba, tile_size, tile_size).map(Some)
+        }
+    }
+
+    /*
+    *   The size x size square at (x0, y0) of the image rendered at full_size, with
+    *   config.supersample applied.
+    */
+    fn region_rgba(&self, full_size: u32, x0: u32, y0: u32, size: u32,
+                   config: &ProcessingConfig) -> Result<Vec<u8>, ProcessingError> {
+        config.check_supersample()?;
+        let n = config.supersample;
+        let too_large = || ProcessingError::InvalidFormat(format!(
+            "{} pixels supersampled {} times is too large", full_size, n));
+        let big_full = full_size.checked_mul(n).ok_or_else(too_large)?;
+        let big_size = size.checked_mul(n).ok_or_else(too_large)?;
+        // The region lies within the image, so its corner scales without overflow
+        let big = self.render_rgba(big_full, x0*n, y0*n, big_size, config);
+        if n == 1 {
+            return Ok(big);
+        }
+
+        // Box filter each n x n block, averaging colour weighted by alpha so that
+        // transparent samples don't darken the edges.
+        let big_w = big_size as usize;
+        let size = size as usize;
+        let n = n as usize;
+        let mut rgba = vec![0u8; 4*size*size];
+        for py in 0..size {
+            for px in 0..size {
+                let mut sum = [0.0f64; 4];
+                for sy in 0..n {
+                    for sx in 0..n {
+                        let k = 4*((py*n + sy)*big_w + px*n + sx);
+                        let a = big[k + 3] as f64;
+                        for c in 0..3 {
+                            sum[c] += (big[k + c] as f64)*a;
+                        }
+                        sum[3] += a;
+                    }
+                }
+                let k = 4*(py*size + px);
+                if sum[3] > 0.0 {
+                    for c in 0..3 {
+                        rgba[k + c] = (sum[c]/sum[3]).round() as u8;
+                    }
+                }
+                rgba[k + 3] = (sum[3]/((n*n) as f64)).round() as u8;
+            }
+        }
+        Ok(rgba)
+    }
+
+    fn render_rgba(&self, full_size: u32, x0: u32, y0: u32, size: u32, config: &ProcessingConfig) -> Vec<u8> {
+        self.render_rgba_banded(full_size, x0, y0, size, RASTER_BAND_ROWS, config)
+    }
+
+    /*
+    *   render_rgba in horizontal bands of band_rows rows, each filling only its own
+    *   rows of the output, rendered in parallel where there are threads. Every output
+    *   pixel depends only on its own position, so the result is independent of band_rows.
+    */
+    fn render_rgba_banded(&self, full_size: u32, x0: u32, y0: u32, size: u32, band_rows: u32, config: &ProcessingConfig) -> Vec<u8> {
+        if let Some(sky) = self.clipped(config) {
+            return sky.render_rgba_banded(full_size, x0, y0, size, band_rows, config);
+        }
+        let scale = self.scale_bounds(config.auto_scale);
+        let unseen = config.theme.unseen_color.as_deref().and_then(parse_rgb);
+        let plot_scale = (full_size as f64)/2.1;
+        let center = (full_size as f64)/2.0;
+
+        let mut rgba = vec![0u8; 4*(size as usize)*(size as usize)];
+        let band_rows = band_rows.max(1);
+        let band_len = 4*(size as usize)*(band_rows as usize);
+
+        // Fill one band, whose first row is row py0 of the output
+        let render_band = |(band, rgba): (usize, &mut [u8])| {
+            let py0 = (band as u32)*band_rows;
+            for k in (0..rgba.len()).step_by(4) {
+                let px = ((k/4) as u32) % size;
+                let py = py0 + ((k/4) as u32) / size;
+                let x = ((x0 + px) as f64 + 0.5 - center)/plot_scale;
+                let y = ((y0 + py) as f64 + 0.5 - center)/plot_scale;
+
+                // The unseen colour fills the horizon, behind the data
+                let background = match unseen {
+                    Some(rgb) if x*x + y*y <= 1.0 => {
+                        rgba[k..k + 4].copy_from_slice(&[rgb.0, rgb.1, rgb.2, 255]);
+                        Some(rgb)
+                    },
+                    _ => None,
+                };
+
+                let i = match self.pixel_at(x, y) {
+                    Some(i) if !self.visible_pix[i].is_nan() => i,
+                    _ => continue,
+                };
+
+                let fract = scale.normalize(self.visible_pix[i]);
+                if let Some(threshold) = config.render_threshold {
+                    if fract < threshold as f64 {
+                        continue;
+                    }
+                }
+                let alpha = match config.pixel_opacity.as_ref().and_then(|o| o.get(i)) {
+                    Some(opacity) => clamp(*opacity, 0.0, 1.0),
+                    None => 1.0,
+                };
+
+                let (r, g, b) = if config.dither {
+                    // Keyed on the position in the whole image, so tiles match it
+                    config.colormap.rgb_u8_dithered(fract, dither_offset((x0 + px) as usize, (y0 + py) as usize))
+                } else {
+                    config.colormap.rgb_u8(fract)
+                };
+                match background {
+                    Some((br, bg, bb)) => {
+                        let over = |c: u8, under: u8| ((c as f32)*alpha + (under as f32)*(1.0 - alpha)).round() as u8;
+                        rgba[k..k + 3].copy_from_slice(&[over(r, br), over(g, bg), over(b, bb)]);
+                    },
+                    None => {
+                        rgba[k..k + 4].copy_from_slice(&[r, g, b, (alpha*255.0).round() as u8]);
+                    },
+                }
+            }
+        };
+
+        if band_len > 0 {
+            #[cfg(not(target_arch = "wasm32"))]
+            rgba.par_chunks_mut(band_len).enumerate().for_each(render_band);
+            #[cfg(target_arch = "wasm32")]
+            rgba.chunks_mut(band_len).enumerate().for_each(render_band);
+        }
+        rgba
+    }
+
+    /*
+    *   The sky as an encoded PNG, size x size pixels: colour for display, or with
+    *   config.png_bit_depth 16 the data product from to_png16.
+    */
+    pub fn to_png(&self, size: u32, config: &ProcessingConfig) -> Result<Vec<u8>, ProcessingError> {
+        match config.png_bit_depth {
+            16 => self.to_png16(size, config),
+            _ => encode_png(&self.to_rgba(size, config)?, size, size),
+        }
+    }
+
+    /*
+    *   A data product rather than a display image: a size x size 16 bit grayscale PNG,
+    *   framed like to_rgba, of the pixel values scaled to [0, 65535] by config.auto_scale
+    *   (so colormap, theme and supersampling are ignored). Outside the horizon and unseen
+    *   pixels have zero alpha, the rest are opaque. The scale's ends are recorded in
+    *   tEXt chunks ("Scale min", "Scale max"), so that with a linear scale a level L
+    *   is the value min + (max - min)*L/65535.
+    */
+    pub fn to_png16(&self, size: u32, config: &ProcessingConfig) -> Result<Vec<u8>, ProcessingError> {
+        if let Some(sky) = self.clipped(config) {
+            return sky.to_png16(size, config);
+        }
+        let scale = self.scale_bounds(config.auto_scale);
+        let plot_scale = (size as f64)/2.1;
+        let center = (size as f64)/2.0;
+
+        // Big endian gray, alpha pairs
+        let mut data = Vec::with_capacity(4*(size as usize)*(size as usize));
+        for py in 0..size {
+            for px in 0..size {
+                let x = (px as f64 + 0.5 - center)/plot_scale;
+                let y = (py as f64 + 0.5 - center)/plot_scale;
+                match self.pixel_at(x, y) {
+                    Some(i) if !self.visible_pix[i].is_nan() => {
+                        let level = (65535.0*scale.normalize(self.visible_pix[i])).round() as u16;
+                        data.extend_from_slice(&level.to_be_bytes());
+                        data.extend_from_slice(&[255, 255]);
+                    },
+                    _ => data.extend_from_slice(&[0; 4]),
+                }
+            }
+        }
+        let text = [
+            ("Scale min", format!("{:e}", scale.min)),
+            ("Scale max", format!("{:e}", scale.max)),
+            ("Scaling", format!("{:?}", config.auto_scale)),
+        ];
+        encode_png_with(&data, size, size, ColorType::GrayscaleAlpha, BitDepth::Sixteen, &text)
+    }
+
+    /*
+    *   The sky on a width x height equirectangular grid, row major, for texture mapping
+    *   onto a dome. x runs over azimuth [0, 360) degrees and y down from elevation 90
+    *   (the top row) to 0, each cell taking the value of the pixel containing its centre.
+    *   Unseen (NaN) pixels stay NaN.
+    */
+    pub fn to_equirectangular(&self, width: u32, height: u32) -> Vec<f64> {
+        let mut grid = Vec::with_capacity((width as usize)*(height as usize));
+        for y in 0..height {
+            let el = 90.0*(1.0 - (y as f64 + 0.5)/(height as f64));
+            for x in 0..width {
+                let az = 360.0*(x as f64 + 0.5)/(width as f64);
+                grid.push(self.value_at(&ElAz::from_degrees(el, az)).unwrap_or(f64::NAN));
+            }
+        }
+        grid
+    }
+
+    /*
+    *   to_equirectangular coloured as straight RGBA, scaled as for to_rgba. Unseen cells,
+    *   and those below config.render_threshold, take the theme's unseen_color, or are
+    *   transparent without one.
+    */
+    pub fn to_equirectangular_rgba(&self, width: u32, height: u32, config: &ProcessingConfig) -> Vec<u8> {
+        if let Some(sky) = self.clipped(config) {
+            return sky.to_equirectangular_rgba(width, height, config);
+        }
+        let scale = self.scale_bounds(config.auto_scale);
+        let unseen = match config.theme.unseen_color.as_deref().and_then(parse_rgb) {
+            Some((r, g, b)) => [r, g, b, 255],
+            None => [0, 0, 0, 0],
+        };
+
+        let grid = self.to_equirectangular(width, height);
+        let mut rgba = vec![0u8; 4*grid.len()];
+        for (k, value) in grid.iter().enumerate() {
+            let fract = scale.normalize(*value);
+            let shown = !value.is_nan() && config.render_threshold.is_none_or(|t| fract >= t as f64);
+            let color = if !shown {
+                unseen
+            } else {
+                let (r, g, b) = if config.dither {
+                    let width = width as usize;
+                    config.colormap.rgb_u8_dithered(fract, dither_offset(k % width, k / width))
+                } else {
+                    config.colormap.rgb_u8(fract)
+                };
+                [r, g, b, 255]
+            };
+            rgba[4*k..4*k + 4].copy_from_slice(&color);
+        }
+        rgba
+    }
+
+    // The equirectangular texture as an encoded PNG
+    pub fn to_equirectangular_png(&self, width: u32, height: u32,
+                                  config: &ProcessingConfig) -> Result<Vec<u8>, ProcessingError> {
+        encode_png(&self.to_equirectangular_rgba(width, height, config), width, height)
+    }
+}
+
+fn encode_png(rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>, ProcessingError> {
+    encode_png_with(rgba, width, height, ColorType::Rgba, BitDepth::Eight, &[])
+}
+
+/*
+*   Encode data of the given colour type and depth, adding a tEXt chunk for each
+*   (keyword, text). Fails for an empty (zero width or height) image.
+*/
+fn encode_png_with(data: &[u8], width: u32, height: u32, color: ColorType, depth: BitDepth,
+                   text: &[(&str, String)]) -> Result<Vec<u8>, ProcessingError> {
+    let failed = |e: png::EncodingError| {
+        ProcessingError::InvalidFormat(format!("can't encode a {} x {} PNG: {}", width, height, e))
+    };
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = Encoder::new(&mut bytes, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        for (keyword, value) in text {
+            encoder.add_text_chunk(keyword.to_string(), value.clone()).map_err(failed)?;
+        }
+        let mut writer = encoder.write_header().map_err(failed)?;
+        writer.write_image_data(data).map_err(failed)?;
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use sphere::{LonLat, HpAngle};
+    use theme::Theme;
+    use colormap::AutoScale;
+    use config::MAX_SUPERSAMPLE;
+
+    #[test]
+    fn test_pixel_at_centers() {
+        let sky = Hemisphere::new(8);
+        for i in 0..sky.npix {
+            let ll = LonLat::from_pix(sky.nside, sky.visible_indices[i]);
+            let (x, y) = HpAngle::from_lonlat(&ll).proj();
+            assert_eq!(sky.pixel_at(x, y), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_rgba_frame() {
+        let sky = Hemisphere::ramp(4);
+        let rgba = sky.to_rgba(64, &ProcessingConfig::default()).unwrap();
+        assert_eq!(rgba.len(), 4*64*64);
+        // Corners are beyond the horizon, the center is the zenith
+        assert_eq!(rgba[3], 0);
+        let k = 4*(32*64 + 32);
+        assert_eq!(rgba[k + 3], 255);
+    }
+
+    #[test]
+    fn test_supersample_softens_edges() {
+        let sky = Hemisphere::ramp(4);
+        let coarse = sky.to_rgba(64, &ProcessingConfig::default()).unwrap();
+        let config = ProcessingConfig { supersample: 4, ..ProcessingConfig::default() };
+        let fine = sky.to_rgba(64, &config).unwrap();
+        assert_eq!(fine.len(), coarse.len());
+
+        // Only fully opaque or transparent without supersampling; partial alpha
+        // appears around the horizon with it.
+        let partial = |rgba: &Vec<u8>| rgba.chunks(4).filter(|p| p[3] > 0 && p[3] < 255).count();
+        assert_eq!(partial(&coarse), 0);
+        assert!(partial(&fine) > 0);
+    }
+
+    #[test]
+    fn test_supersample_bounds() {
+        let sky = Hemisphere::ramp(4);
+        for &n in &[0, MAX_SUPERSAMPLE + 1, u32::MAX] {
+            let config = ProcessingConfig { supersample: n, ..ProcessingConfig::default() };
+            match sky.to_png(64, &config) {
+                Err(ProcessingError::InvalidFormat(_)) => (),
+                _ => panic!("Expected an InvalidFormat error for supersample {}", n),
+            }
+        }
+
+        // An allowed factor that overflows the full image size is an error, not a wrap
+        let config = ProcessingConfig { supersample: MAX_SUPERSAMPLE, ..ProcessingConfig::default() };
+        assert!(sky.region_rgba(u32::MAX, 0, 0, 1, &config).is_err());
+        assert!(sky.to_png(8, &config).is_ok());
+    }
+
+    #[test]
+    fn test_tiles_match_whole_image() {
+        let sky = Hemisphere::ramp(4);
+        let config = ProcessingConfig { supersample: 2, ..ProcessingConfig::default() };
+        let whole = sky.to_rgba(64, &config).unwrap();
+
+        // Zoom 2 is a 4 x 4 grid of 16 pixel tiles over the same 64 pixel image
+        for ty in 0..4 {
+            for tx in 0..4 {
+                let tile = sky.to_tile(2, tx, ty, 16, &config).unwrap();
+                assert_eq!(tile.len(), 4*16*16);
+                for row in 0..16 {
+                    let k = 4*(((ty*16 + row)*64 + tx*16) as usize);
+                    assert_eq!(&tile[64*(row as usize)..64*(row as usize + 1)], &whole[k..k + 64]);
+                }
+            }
+        }
+        assert_eq!(sky.to_tile(0, 0, 0, 64, &config).unwrap(), whole);
+        assert!(sky.to_tile(2, 4, 0, 16, &config).is_err());
+        assert!(sky.to_tile(32, 0, 0, 16, &config).is_err());
+        // 256 << 24 does not fit a u32
+        assert!(sky.to_tile(16, 0, 0, 1 << 16, &config).is_err());
+
+        // At zoom 3 the corner tile lies beyond the horizon
+        assert!(sky.to_tile_png(3, 0, 0, 8, &config).unwrap().is_none());
+        assert!(sky.to_tile_png(3, 3, 3, 8, &config).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_unseen_color() {
+        let mut sky = Hemisphere::ramp(4);
+        // The sample at the centre of raster pixel (32, 32)
+        let d = 0.5/(64.0/2.1);
+        let zenith = sky.pixel_at(d, d).unwrap();
+        sky.visible_pix[zenith] = f64::NAN;
+        let k = 4*(32*64 + 32);
+        assert_eq!(sky.to_rgba(64, &ProcessingConfig::default()).unwrap()[k + 3], 0);
+
+        let mut config = ProcessingConfig::default();
+        config.theme.unseen_color = Some("rgb(1,2,3)".to_string());
+        let rgba = sky.to_rgba(64, &config).unwrap();
+        assert_eq!(&rgba[k..k + 4], &[1, 2, 3, 255]);
+        // Beyond the horizon stays transparent
+        assert_eq!(rgba[3], 0);
+    }
+
+    #[test]
+    fn test_dither_tiles() {
+        let sky = Hemisphere::ramp(8);
+        let config = ProcessingConfig { dither: true, ..ProcessingConfig::default() };
+        let whole = sky.to_rgba(64, &config).unwrap();
+        assert_ne!(whole, sky.to_rgba(64, &ProcessingConfig::default()).unwrap());
+        let tile = sky.to_tile(1, 1, 1, 32, &config).unwrap();
+        for row in 0..32 {
+            let k = 4*((32 + row)*64 + 32);
+            assert_eq!(&tile[4*32*row..4*32*(row + 1)], &whole[k..k + 4*32]);
+        }
+    }
+
+    #[test]
+    fn test_equirectangular() {
+        let mut sky = Hemisphere::ramp(8);
+        let grid = sky.to_equirectangular(72, 18);
+        assert_eq!(grid.len(), 72*18);
+
+        // Each cell takes the pixel at its centre: row 9 is elevation 42.5, column 4 azimuth 22.5
+        let expected = sky.value_at(&ElAz::from_degrees(42.5, 22.5)).unwrap();
+        assert_eq!(grid[9*72 + 4], expected);
+        // Everything is sampled away from the horizon, where cells can fall in pixels
+        // whose centres are below it
+        assert!(grid[..16*72].iter().all(|v| !v.is_nan()));
+
+        // Unseen pixels get the unseen colour, or are transparent
+        // The four pixels of the polar cap hold the whole top row
+        for pixel in 0..4 {
+            let i = sky.visible_indices.binary_search(&pixel).unwrap();
+            sky.visible_pix[i] = f64::NAN;
+        }
+        let plain = sky.to_equirectangular_rgba(72, 18, &ProcessingConfig::default());
+        assert_eq!(plain.len(), 4*72*18);
+        assert_eq!(plain[3], 0);
+        assert_eq!(plain[4*(9*72) + 3], 255);
+
+        let mut config = ProcessingConfig::default();
+        config.theme.unseen_color = Some("#010203".to_string());
+        let rgba = sky.to_equirectangular_rgba(72, 18, &config);
+        assert_eq!(&rgba[0..4], &[1, 2, 3, 255]);
+        assert_eq!(&rgba[4*(9*72)..4*(9*72) + 4], &plain[4*(9*72)..4*(9*72) + 4]);
+
+        let png = sky.to_equirectangular_png(72, 18, &config).unwrap();
+        assert_eq!(&png[0..8], &[137, 80, 78, 71, 13, 10, 26, 10]);
+    }
+
+    #[test]
+    fn test_bands_match_single_band() {
+        let mut sky = Hemisphere::ramp(8);
+        sky.visible_pix[5] = f64::NAN;
+        let config = ProcessingConfig {
+            dither: true,
+            theme: Theme { unseen_color: Some("rgb(20,30,40)".to_string()), ..Theme::default() },
+            ..ProcessingConfig::default()
+        };
+
+        // An odd size, so the last band is short
+        let size = 203;
+        let reference = sky.render_rgba_banded(size, 0, 0, size, size, &config);
+        for &band_rows in &[1, 7, RASTER_BAND_ROWS, 202] {
+            assert!(sky.render_rgba_banded(size, 0, 0, size, band_rows, &config) == reference,
+                "bands of {} rows differ", band_rows);
+        }
+        assert!(sky.to_rgba(size, &config).unwrap() == reference);
+
+        // And within a tile
+        let tile = sky.render_rgba_banded(4*size, size, 2*size, size, size, &config);
+        assert!(sky.render_rgba_banded(4*size, size, 2*size, size, 5, &config) == tile);
+    }
+
+    #[test]
+    fn test_png_signature() {
+        let png = Hemisphere::ramp(2).to_png(16, &ProcessingConfig::default()).unwrap();
+        assert_eq!(&png[0..8], &[137, 80, 78, 71, 13, 10, 26, 10]);
+        // An empty image is an error, not a panic
+        match Hemisphere::ramp(2).to_png(0, &ProcessingConfig::default()) {
+            Err(ProcessingError::InvalidFormat(_)) => (),
+            _ => panic!("Expected an InvalidFormat error"),
+        }
+    }
+
+    #[test]
+    fn test_png16() {
+        let sky = Hemisphere::ramp(8);
+        let config = ProcessingConfig {
+            png_bit_depth: 16,
+            auto_scale: AutoScale::MinMax,
+            ..ProcessingConfig::default()
+        };
+        let size = 64;
+        let png = sky.to_png(size, &config).unwrap();
+        assert_eq!(png, sky.to_png16(size, &config).unwrap());
+
+        let decoder = png::Decoder::new(&png[..]);
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut data).unwrap();
+        assert_eq!((frame.color_type, frame.bit_depth), (ColorType::GrayscaleAlpha, BitDepth::Sixteen));
+        let text = &reader.info().uncompressed_latin1_text;
+        assert!(text.iter().any(|t| t.keyword == "Scale max" && t.text == "1e0"));
+
+        // Every level of the ramp's pixels, finer than 8 bits can hold
+        let (plot_scale, center) = ((size as f64)/2.1, (size as f64)/2.0);
+        for py in 0..size {
+            for px in 0..size {
+                let k = 4*(py*size + px) as usize;
+                let level = u16::from_be_bytes([data[k], data[k + 1]]);
+                let alpha = u16::from_be_bytes([data[k + 2], data[k + 3]]);
+                let x = (px as f64 + 0.5 - center)/plot_scale;
+                let y = (py as f64 + 0.5 - center)/plot_scale;
+                match sky.pixel_at(x, y) {
+                    Some(i) => {
+                        assert_eq!(alpha, 65535);
+                        assert_eq!(level, (65535.0*sky.visible_pix[i]).round() as u16);
+                    },
+                    None => assert_eq!(alpha, 0),
+                }
+            }
+        }
+    }
+}
@@ -19,12 +19,12 @@
 // IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use num::Integer;
 
 use super::transform::Transform;
 
-pub fn insert_attribs(mut o: String, attribs: &HashMap<String, String>) -> String {
+pub fn insert_attribs(mut o: String, attribs: &BTreeMap<String, String>) -> String {
     for (at, value) in attribs.iter() {
         o.push_str(format!(" {}=\"{}\"", *at, *value).as_str())
     }
@@ -25,7 +25,7 @@ pub use self::shapes::{Circle, Rect, RoundedRect, Ellipse, Line, PolyLine, Polyg
 pub use self::text::Text;
 pub use self::transform::Transform;
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use num::Integer;
 use std::fmt::Debug;
 
@@ -34,12 +34,12 @@ mod text;
 mod transform;
 mod common;
 
-static DOC_TYPE: &'static str = "<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" \
+static DOC_TYPE: &str = "<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" \
 \"http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd\">\n";
-static XMLNS: &'static str = "version=\"1.1\" xmlns=\"http://www.w3.org/2000/svg\" \
+static XMLNS: &str = "version=\"1.1\" xmlns=\"http://www.w3.org/2000/svg\" \
 xmlns:xlink=\"http://www.w3.org/1999/xlink\">\n";
-static STANDALONE_YES: &'static str = "<?xml version=\"1.0\" standalone=\"yes\"?>\n";
-static STANDALONE_NO: &'static str = "<?xml version=\"1.0\" standalone=\"no\"?>\n";
+static STANDALONE_YES: &str = "<?xml version=\"1.0\" standalone=\"yes\"?>\n";
+static STANDALONE_NO: &str = "<?xml version=\"1.0\" standalone=\"no\"?>\n";
 
 pub trait SVGEntity {
     fn gen_output(&self) -> String;
@@ -58,8 +58,8 @@ impl Head {
     pub fn new(width: i32, height: i32) -> Head {
         Head {
             standalone: false,
-            width: width,
-            height: height,
+            width,
+            height,
             view_box: None,
             desc: None,
             title: None
@@ -68,6 +68,7 @@ impl Head {
 }
 
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct SVG {
     head: Head,
     content: String
@@ -75,13 +76,13 @@ pub struct SVG {
 
         //h.insert(t[0].to_string(), t[1].to_string());
 
-fn make_attribs(attribs: &str) -> HashMap<String, String>{
-    let mut h = HashMap::new();
+fn make_attribs(attribs: &str) -> BTreeMap<String, String>{
+    let mut h = BTreeMap::new();
     for s in attribs.split(' ') {
         //println!("make_attribs s={}",s);
         let t: Vec<&str> = s.split('=').collect();
 //         h.insert(String::from(t[0]), String::from(t[1]));
-        h.insert(format!("{}",t[0]), format!("{}",t[1]));
+        h.insert(t[0].to_string(), t[1].to_string());
     }
     h
 }
@@ -124,9 +125,9 @@ impl SVG {
                   radius: u32,
                   attribs: &str) {
         self.content.push_str(Circle {
-            x: x,
-            y: y,
-            radius: radius,
+            x,
+            y,
+            radius,
             attribs: make_attribs(attribs),
             transform: None
         }.gen_output().as_str())
@@ -139,15 +140,16 @@ impl SVG {
                 height: i32,
                 attribs: &str) {
         self.content.push_str(Rect {
-            x: x,
-            y: y,
-            width: width,
-            height: height,
+            x,
+            y,
+            width,
+            height,
             attribs: make_attribs(attribs),
             transform: None
         }.gen_output().as_str())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn rounded_rect(&mut self,
                         x: i32,
                         y: i32,
@@ -157,12 +159,12 @@ impl SVG {
                         y_round: u32,
                         attribs: &str) {
         self.content.push_str(RoundedRect {
-            x: x,
-            y: y,
-            width: width,
-            height: height,
-            x_round: x_round,
-            y_round: y_round,
+            x,
+            y,
+            width,
+            height,
+            x_round,
+            y_round,
             attribs: make_attribs(attribs),
             transform: None
         }.gen_output().as_str())
@@ -175,10 +177,10 @@ impl SVG {
                    y_radius: u32,
                    attribs: &str) {
         self.content.push_str(Ellipse {
-            x: x,
-            y: y,
-            x_radius: x_radius,
-            y_radius: y_radius,
+            x,
+            y,
+            x_radius,
+            y_radius,
             attribs: make_attribs(attribs),
             transform: None
         }.gen_output().as_str())
@@ -191,30 +193,30 @@ impl SVG {
                 y2: i32,
                 attribs: &str) {
         self.content.push_str(Line {
-            x1: x1,
-            y1: y1,
-            x2: x2,
-            y2: y2,
+            x1,
+            y1,
+            x2,
+            y2,
             attribs: make_attribs(attribs),
             transform: None
         }.gen_output().as_str())
     }
 
     pub fn polyline<T: Integer + Debug + Clone>(&mut self,
-                                           points: &Vec<(T, T)>,
+                                           points: &[(T, T)],
                                            attribs: &str) {
         self.content.push_str(PolyLine {
-            points: points.clone(),
+            points: points.to_vec(),
             attribs: make_attribs(attribs),
             transform: None
         }.gen_output().as_str())
     }
 
     pub fn polygon<T: Integer + Debug + Clone>(&mut self,
-                                          points: &Vec<(T, T)>,
+                                          points: &[(T, T)],
                                           attribs: &str) {
         self.content.push_str(Polygon {
-            points: points.clone(),
+            points: points.to_vec(),
             attribs: make_attribs(attribs),
             transform: None
         }.gen_output().as_str())
@@ -226,8 +228,8 @@ impl SVG {
                 text: &str,
                 attribs: &str) {
         self.content.push_str(Text {
-            x: x,
-            y: y,
+            x,
+            y,
             text: String::from(text),
             attribs: make_attribs(attribs),
             transform: None
@@ -237,7 +239,7 @@ impl SVG {
     pub fn g_begin(&mut self,
                    id: Option<&str>,
                    transform: Option<&Transform>,
-                   attribs: Option<&HashMap<String, String>>) {
+                   attribs: Option<&BTreeMap<String, String>>) {
         self.content.push_str("<g ");
         match id {
             Some(i) => self.content.push_str(format!("id=\"{:?}\" ", i).as_str()),
@@ -301,40 +303,67 @@ impl SVG {
         self.content.push_str("</g>\n");
     }
 
+    // Add the content of other, moved by (x, y). Its head (view box, title) is dropped.
+    pub fn embed(&mut self, other: &SVG, x: i32, y: i32) {
+        self.content.push_str(&format!("<g transform=\"translate({}, {})\">\n", x, y));
+        self.content.push_str(&other.content);
+        self.g_end();
+    }
+
+    #[allow(clippy::inherent_to_string, clippy::wrong_self_convention)]
     pub fn to_string(&mut self) -> String {
-        let mut o = String::new();
-        // Head
+        let mut o = Vec::with_capacity(self.content.len() + 1024);
+        self.write_to(&mut o).expect("Writing to memory failed");
+        String::from_utf8(o).expect("SVG output is UTF-8")
+    }
+
+    /*
+    *   Write the document without first assembling it in a String. For streaming,
+    *   write_head, then flush_content as the content grows, then write_tail.
+    */
+    pub fn write_to(&self, output: &mut dyn std::io::Write) -> std::io::Result<()> {
+        self.write_head(output)?;
+        output.write_all(self.content.as_bytes())?;
+        self.write_tail(output)
+    }
+
+    pub fn write_head(&self, output: &mut dyn std::io::Write) -> std::io::Result<()> {
         match self.head.standalone {
-            true    => o.push_str(STANDALONE_YES),
-            false   => o.push_str(STANDALONE_NO)
+            true    => output.write_all(STANDALONE_YES.as_bytes())?,
+            false   => output.write_all(STANDALONE_NO.as_bytes())?
         };
-        o.push_str(DOC_TYPE);
-        o.push_str(format!("<svg width=\"{:?}cm\" height=\"{:?}cm\" ",
-                           self.head.width, self.head.height).as_str());
+        output.write_all(DOC_TYPE.as_bytes())?;
+        write!(output, "<svg width=\"{:?}cm\" height=\"{:?}cm\" ", self.head.width, self.head.height)?;
         match self.head.view_box {
             Some((x, y, width, height)) => {
-                o.push_str(format!("viewBox=\"{:?} {:?} {:?} {:?}\" ", x, y, width, height).as_str())
+                write!(output, "viewBox=\"{:?} {:?} {:?} {:?}\" ", x, y, width, height)?
             },
             None                        => {/* nothing to do */}
         }
-        o.push_str(XMLNS);
+        output.write_all(XMLNS.as_bytes())?;
         match self.head.title {
-            Some(ref t) => o.push_str(format!("<title>{:?}</title>\n", *t).as_str()),
+            Some(ref t) => writeln!(output, "<title>{:?}</title>", *t)?,
             None    => {/* nothing to do */}
         }
         match self.head.desc {
-            Some(ref d) => o.push_str(format!("<desc>{:?}</desc>\n", *d).as_str()),
+            Some(ref d) => writeln!(output, "<desc>{:?}</desc>", *d)?,
             None    => {/* nothing to do */}
         }
-        // Body
-        o.push_str(self.content.clone().as_str());
-        // Close
-        o.push_str("</svg>\n");
-        return o;
+        Ok(())
     }
-    
+
+    // Write out the content added so far and clear it, keeping the buffer for reuse
+    pub fn flush_content(&mut self, output: &mut dyn std::io::Write) -> std::io::Result<()> {
+        output.write_all(self.content.as_bytes())?;
+        self.content.clear();
+        Ok(())
+    }
+
+    pub fn write_tail(&self, output: &mut dyn std::io::Write) -> std::io::Result<()> {
+        output.write_all(b"</svg>\n")
+    }
+
     pub fn finalize(&mut self, output: &mut dyn std::io::Write) -> std::result::Result<(), std::io::Error> {
-        let o = self.to_string();
-        output.write_all(o.as_bytes())
+        self.write_to(output)
     }
 }
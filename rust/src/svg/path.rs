@@ -20,7 +20,7 @@
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use std::fmt::Debug;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use super::common::{insert_attribs, insert_transform, finalize};
 use super::transform::Transform;
@@ -36,7 +36,7 @@ pub struct EllipticalArc {
     pub large_arc: bool,
     pub sweep: bool,
     pub close: bool,
-    pub attribs: HashMap<~str, ~str>,
+    pub attribs: BTreeMap<~str, ~str>,
     pub transform: Option<Transform>
 }
 
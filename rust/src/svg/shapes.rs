@@ -21,7 +21,7 @@
 
 use std::fmt::Debug;
 use std::vec::Vec;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use num::Integer;
 
@@ -34,7 +34,7 @@ pub struct Circle {
     pub x: i32,
     pub y: i32,
     pub radius: u32,
-    pub attribs: HashMap<String, String>,
+    pub attribs: BTreeMap<String, String>,
     pub transform: Option<Transform>
 }
 
@@ -44,7 +44,7 @@ pub struct Ellipse {
     pub y: i32,
     pub x_radius: u32,
     pub y_radius: u32,
-    pub attribs: HashMap<String, String>,
+    pub attribs: BTreeMap<String, String>,
     pub transform: Option<Transform>
 }
 
@@ -54,7 +54,7 @@ pub struct Line {
     pub y1: i32,
     pub x2: i32,
     pub y2: i32,
-    pub attribs: HashMap<String, String>,
+    pub attribs: BTreeMap<String, String>,
     pub transform: Option<Transform>
 }
 
@@ -66,7 +66,7 @@ pub struct RoundedRect {
     pub height: i32,
     pub x_round: u32,
     pub y_round: u32,
-    pub attribs: HashMap<String, String>,
+    pub attribs: BTreeMap<String, String>,
     pub transform: Option<Transform>
 }
 
@@ -76,21 +76,21 @@ pub struct Rect {
     pub y: i32,
     pub width: i32,
     pub height: i32,
-    pub attribs: HashMap<String, String>,
+    pub attribs: BTreeMap<String, String>,
     pub transform: Option<Transform>
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct PolyLine<T> {
     pub points: Vec<(T, T)>,
-    pub attribs: HashMap<String, String>,
+    pub attribs: BTreeMap<String, String>,
     pub transform: Option<Transform>
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Polygon<T> {
     pub points: Vec<(T, T)>,
-    pub attribs: HashMap<String, String>,
+    pub attribs: BTreeMap<String, String>,
     pub transform: Option<Transform>
 }
 
@@ -106,12 +106,12 @@ impl<T: Integer + Debug> Polygon<T> {
     }
 }
 
-fn get_points<T: Integer + Debug>(points: &Vec<(T, T)>) -> String {
+fn get_points<T: Integer + Debug>(points: &[(T, T)]) -> String {
     let mut p: String = String::from("points=\"");
-    for &(ref x, ref y) in points.iter() {
+    for (x, y) in points.iter() {
         p.push_str(format!("{:?},{:?} ", x, y).as_str())
     }
-    p.push_str("\"");
+    p.push('"');
     p
 }
 
@@ -19,7 +19,7 @@
 // IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use super::common::{insert_attribs, insert_transform};
 use super::transform::Transform;
@@ -30,7 +30,7 @@ pub struct Text {
     pub x: i32,
     pub y: i32,
     pub text: String,
-    pub attribs: HashMap<String, String>,
+    pub attribs: BTreeMap<String, String>,
     pub transform: Option<Transform>
 }
 
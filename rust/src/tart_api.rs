@@ -2,7 +2,8 @@
 // Copyright (c) 2019-2021 Tim Molteno tim@elec.ac.nz
 //
 
-use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::de::{self, DeserializeOwned, Deserializer};
 
 // use reqwest::Error;
 // fn api_get<T: DeserializeOwned>(route: &str) -> T {
@@ -17,8 +18,17 @@ use serde::de::DeserializeOwned;
 use std::fs::File;
 use std::io::prelude::*;
 
+use chrono::{DateTime, Utc};
+
+use astro;
+use error::ProcessingError;
+use gridless;
+use img::{self, AntPositionUnit};
+use sphere::ElAz;
+use utils::VectorReal;
+
 fn api_parse_json<T: DeserializeOwned>(contents: &String) -> T {
-    serde_json::from_str(&contents).expect(&format!("Failed to get data from string {}", contents))
+    serde_json::from_str(contents).unwrap_or_else(|_| panic!("Failed to get data from string {}", contents))
 }
 
 fn api_parse<T: DeserializeOwned>(fname: &str) -> T {
@@ -53,12 +63,97 @@ pub struct VisData {
     pub timestamp: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Polarization {
+    XX, YY, XY, YX,
+    RR, LL, RL, LR,
+}
+
+// Parse a polarization product such as "XX" or "rl", ignoring case
+impl std::str::FromStr for Polarization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Polarization, String> {
+        match s.trim().to_uppercase().as_str() {
+            "XX" => Ok(Polarization::XX),
+            "YY" => Ok(Polarization::YY),
+            "XY" => Ok(Polarization::XY),
+            "YX" => Ok(Polarization::YX),
+            "RR" => Ok(Polarization::RR),
+            "LL" => Ok(Polarization::LL),
+            "RL" => Ok(Polarization::RL),
+            "LR" => Ok(Polarization::LR),
+            _ => Err(format!("unknown polarization '{}', expected one of XX, YY, XY, YX, RR, LL, RL, LR", s)),
+        }
+    }
+}
+
+impl Polarization {
+    // The parallel hands, which sum to Stokes I
+    pub fn is_parallel(&self) -> bool {
+        matches!(*self, Polarization::XX | Polarization::YY | Polarization::RR | Polarization::LL)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct VisEntry {
+    // Some exporters write antenna indices as 0.0 or "0"
+    #[serde(deserialize_with = "antenna_index")]
     pub i: u32,
+    #[serde(deserialize_with = "antenna_index")]
     pub j: u32,
     pub im: f64,
-    pub re: f64
+    pub re: f64,
+    // Inverse variance weight from the correlator, 1 when not given
+    #[serde(default = "one")]
+    pub weight: f64,
+    // Single polarization datasets leave this out
+    #[serde(default)]
+    pub pol: Option<Polarization>,
+    // Set by an RFI flagger. This is the only way to flag a visibility: Observation::new
+    // leaves flagged entries out, so they never reach the imaging functions
+    #[serde(default, alias = "flags")]
+    pub flagged: bool,
+    // Baseline coordinates [u, v, w] in wavelengths, for exporters (e.g. from a
+    // measurement set) that have already computed them
+    #[serde(default)]
+    pub uvw: Option<[f64; 3]>,
+}
+
+impl VisEntry {
+    // An unflagged, single polarization visibility of weight 1 on baseline (i, j)
+    pub fn new(i: u32, j: u32, re: f64, im: f64) -> VisEntry {
+        VisEntry { i, j, im, re, weight: 1.0, pol: None, flagged: false, uvw: None }
+    }
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LooseIndex {
+    Integer(u64),
+    Float(f64),
+    Text(String),
+}
+
+/*
+*   An antenna index given as an integer, a float with no fractional part, or a string
+*   holding either.
+*/
+fn antenna_index<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let value = match LooseIndex::deserialize(deserializer)? {
+        LooseIndex::Integer(n) => n as f64,
+        LooseIndex::Float(x) => x,
+        LooseIndex::Text(s) => s.trim().parse::<f64>()
+            .map_err(|_| de::Error::custom(format!("antenna index '{}' is not a number", s)))?,
+    };
+    if value.fract() != 0.0 || value < 0.0 || value > u32::MAX as f64 {
+        return Err(de::Error::custom(format!("antenna index {} is not a non-negative integer", value)));
+    }
+    Ok(value as u32)
 }
 
 #[allow(dead_code)]
@@ -71,17 +166,29 @@ pub fn visibilities() -> VisData  {
 /************************************* Info ***************************************/
 #[derive(Deserialize, Debug)]
 pub struct TARTinfo {
-    #[allow(dead_code)]
     info: TARTdetail
 }
 
+impl TARTinfo {
+    pub fn location(&self) -> Option<&Location> {
+        self.info.location.as_ref()
+    }
+}
+
 #[allow(non_snake_case)]
 #[derive(Deserialize, Debug)]
 pub struct TARTdetail {
+    #[serde(default)]
+    location: Option<Location>,
 }
 
-#[derive(Deserialize, Debug)]
+// Site position: lat and lon in degrees (lon east positive), alt in metres
+#[derive(Deserialize, Debug, Clone)]
 pub struct Location {
+    pub lat: f64,
+    pub lon: f64,
+    #[serde(default)]
+    pub alt: f64,
 }
 
 #[allow(dead_code)]
@@ -121,6 +228,39 @@ pub struct Source {
     pub r: f64, //"r": 38458365.1
 }
 
+impl Source {
+    // The one place a Source's degrees become an ElAz (radians)
+    pub fn elaz(&self) -> ElAz {
+        ElAz::from_degrees(self.el, self.az)
+    }
+}
+
+/*
+*   A catalog source in equatorial coordinates (degrees, J2000-ish: no precession
+*   is applied). to_source gives its position on the sky for a site and time.
+*/
+#[derive(Deserialize, Debug, Clone)]
+pub struct RaDecSource {
+    pub ra: f64,
+    pub dec: f64,
+    pub name: String,
+    pub jy: f64,
+}
+
+impl RaDecSource {
+    pub fn to_source(&self, location: &Location, utc: &DateTime<Utc>) -> Source {
+        let elaz = astro::equatorial_to_horizontal(self.ra.to_radians(), self.dec.to_radians(),
+                                                   location.lat.to_radians(), location.lon.to_radians(), utc);
+        Source {
+            az: elaz.az_degrees(),
+            el: elaz.el_degrees(),
+            jy: self.jy,
+            name: self.name.clone(),
+            r: 0.0,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct VisSource {
     pub data: VisData, 
@@ -137,9 +277,302 @@ pub struct FullDataset {
 
 
 pub fn file_to_dataset(fname: &str) -> FullDataset  {
-    api_parse::<FullDataset>(&fname)
+    api_parse::<FullDataset>(fname)
 }
 
 pub fn json_to_dataset(json: &String) -> FullDataset  {
-    api_parse_json::<FullDataset>(&json)
+    api_parse_json::<FullDataset>(json)
+}
+
+/*
+*   Read one endpoint's JSON from a file, naming the file in any error.
+*/
+fn read_endpoint<T: DeserializeOwned>(fname: &str) -> Result<T, ProcessingError> {
+    let json = std::fs::read_to_string(fname)
+        .map_err(|e| ProcessingError::Io(format!("{}: {}", fname, e)))?;
+    serde_json::from_str::<T>(&json)
+        .map_err(|e| ProcessingError::Parse(format!("{}: {}", fname, e)))
+}
+
+/*
+*   Assemble a FullDataset from the separate vis, gains, antenna position and info
+*   JSON files, as served by the individual TART API endpoints. The vis endpoint
+*   carries no catalog, so the snapshot has no sources.
+*/
+pub fn assemble_dataset(vis_path: &str, gains_path: &str, ant_path: &str, info_path: &str) -> Result<FullDataset, ProcessingError> {
+    Ok(FullDataset {
+        info: read_endpoint::<TARTinfo>(info_path)?,
+        ant_pos: read_endpoint::<Vec<AntPosition>>(ant_path)?,
+        gains: read_endpoint::<Gains>(gains_path)?,
+        data: vec![VisSource {
+            data: read_endpoint::<VisData>(vis_path)?,
+            sources: Vec::new(),
+        }],
+    })
+}
+
+/// A tiny valid dataset for examples and doctests: three antennas, three baselines,
+/// and the noiseless visibilities of one 1 Jy source at el 60°, az 30° (the one entry
+/// in its catalog). Fixed, with no files or randomness, so always the same.
+///
+/// ```
+/// use gridlesslib::tart_api::minimal_dataset;
+/// use gridlesslib::prelude::*;
+///
+/// let data = minimal_dataset();
+/// let (sky, _timestamp) = gridlesslib::image_dataset(&data, 4, &ProcessingConfig::default()).unwrap();
+/// assert_eq!(sky.nside, 4);
+/// ```
+pub fn minimal_dataset() -> FullDataset {
+    let ant_pos = vec![
+        AntPosition { x: 0.0, y: 0.0, z: 0.0 },
+        AntPosition { x: 0.3, y: 0.1, z: 0.0 },
+        AntPosition { x: -0.1, y: 0.3, z: 0.02 },
+    ];
+    let source = Source { az: 30.0, el: 60.0, jy: 1.0, name: "minimal".to_string(), r: 0.0 };
+
+    let baselines = vec![(0, 1), (0, 2), (1, 2)];
+    let position = |f: fn(&AntPosition) -> f64| VectorReal::from_iter(ant_pos.iter().map(f));
+    let (u, v, w) = img::get_uvw_in(&baselines, &position(|a| a.x), &position(|a| a.y), &position(|a| a.z),
+                                    AntPositionUnit::Meters);
+    let elaz = source.elaz();
+    let vis = gridless::point_source_vis(&u, &v, &w, elaz.el, elaz.az, source.jy);
+
+    FullDataset {
+        info: TARTinfo {
+            info: TARTdetail { location: Some(Location { lat: -45.85, lon: 170.54, alt: 0.0 }) },
+        },
+        ant_pos,
+        gains: Gains { gain: vec![1.0; 3], phase_offset: vec![0.0; 3] },
+        data: vec![VisSource {
+            data: VisData {
+                data: baselines.iter().zip(vis.iter()).map(|(&(i, j), v)| VisEntry::new(i, j, v.re, v.im)).collect(),
+                timestamp: "2024-01-01T00:00:00+00:00".to_string(),
+            },
+            sources: vec![source],
+        }],
+    }
+}
+
+/*
+*   As json_to_dataset, returning a parse error instead of panicking.
+*/
+pub fn parse_dataset(json: &str) -> Result<FullDataset, ProcessingError> {
+    serde_json::from_str::<FullDataset>(json)
+        .map_err(|e| ProcessingError::Parse(e.to_string()))
+}
+
+/*
+*   Bounds on untrusted input. The byte limit is checked before parsing, which caps
+*   the memory serde can allocate (and serde_json limits nesting depth to 128). The
+*   array lengths are checked after parsing, before any imaging work.
+*/
+#[derive(Debug, Clone)]
+pub struct InputLimits {
+    pub max_bytes: usize,
+    pub max_antennas: usize,
+    pub max_baselines: usize,
+    pub max_snapshots: usize,
+    pub max_sources: usize,
+}
+
+impl Default for InputLimits {
+    fn default() -> InputLimits {
+        InputLimits {
+            max_bytes: 16*1024*1024,
+            max_antennas: 256,
+            max_baselines: 256*255,  // including both polarizations of every baseline
+            max_snapshots: 16,
+            max_sources: 1024,
+        }
+    }
+}
+
+fn check_length(what: &str, len: usize, max: usize) -> Result<(), ProcessingError> {
+    if len > max {
+        return Err(ProcessingError::InvalidFormat(format!("{} {} exceeds the limit of {}", len, what, max)));
+    }
+    Ok(())
+}
+
+/*
+*   As parse_dataset, for untrusted input: enforces limits before and after parsing.
+*/
+pub fn parse_dataset_limited(json: &str, limits: &InputLimits) -> Result<FullDataset, ProcessingError> {
+    check_length("bytes of input", json.len(), limits.max_bytes)?;
+    let data = parse_dataset(json)?;
+
+    check_length("antenna positions", data.ant_pos.len(), limits.max_antennas)?;
+    check_length("gains", data.gains.gain.len(), limits.max_antennas)?;
+    check_length("phase offsets", data.gains.phase_offset.len(), limits.max_antennas)?;
+    check_length("snapshots", data.data.len(), limits.max_snapshots)?;
+    for snapshot in &data.data {
+        check_length("visibilities", snapshot.data.data.len(), limits.max_baselines)?;
+        check_length("sources", snapshot.sources.len(), limits.max_sources)?;
+    }
+    Ok(data)
+}
+
+/*
+*   Lazily parse a JSON lines stream with one FullDataset per line. Blank lines are
+*   skipped, and a malformed line yields an error item without ending the iteration.
+*/
+pub fn datasets_from_jsonl<R: BufRead>(reader: R) -> impl Iterator<Item = Result<FullDataset, ProcessingError>> {
+    reader.lines()
+        .enumerate()
+        .filter(|(_, line)| match line {
+            Ok(l) => !l.trim().is_empty(),
+            Err(_) => true,
+        })
+        .map(|(n, line)| {
+            let line = line.map_err(|e| ProcessingError::Io(format!("line {}: {}", n + 1, e)))?;
+            serde_json::from_str::<FullDataset>(&line)
+                .map_err(|e| ProcessingError::Parse(format!("line {}: {}", n + 1, e)))
+        })
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_location_and_radec() {
+        let data = file_to_dataset("data.json");
+        let location = data.info.location().unwrap();
+        assert_eq!(location.lat, -45.85177);
+
+        // The south celestial pole sits due south at an elevation of -lat
+        let pole = RaDecSource { ra: 0.0, dec: -90.0, name: "SCP".to_string(), jy: 1.0 };
+        let src = pole.to_source(location, &Utc::now());
+        assert!((src.el - 45.85177).abs() < 1.0e-6);
+        assert!((src.az - 180.0).abs() < 1.0e-6);
+
+        let elaz = src.elaz();
+        assert!((elaz.el - 45.85177f64.to_radians()).abs() < 1.0e-9);
+        assert!((elaz.az - ::utils::PI).abs() < 1.0e-9);
+    }
+
+    #[test]
+    fn test_polarization_from_str() {
+        assert_eq!("xx".parse(), Ok(Polarization::XX));
+        assert_eq!(" Rl ".parse(), Ok(Polarization::RL));
+        assert!("I".parse::<Polarization>().unwrap_err().contains("XX, YY"));
+    }
+
+    #[test]
+    fn test_antenna_index_forms() {
+        let entry = |i: &str| serde_json::from_str::<VisEntry>(
+            &format!("{{\"i\": {}, \"j\": 3, \"re\": 1.0, \"im\": 0.0}}", i));
+
+        assert_eq!(entry("0").unwrap().i, 0);
+        assert_eq!(entry("7").unwrap().i, 7);
+        assert_eq!(entry("7.0").unwrap().i, 7);
+        assert_eq!(entry("\"7\"").unwrap().i, 7);
+        assert_eq!(entry("\"7.0\"").unwrap().i, 7);
+        assert_eq!(entry("\" 12 \"").unwrap().i, 12);
+        assert_eq!(entry("\"0\"").unwrap().j, 3);
+
+        assert!(entry("7.5").is_err());
+        assert!(entry("-1").is_err());
+        assert!(entry("\"seven\"").is_err());
+        assert!(entry("\"7.5\"").is_err());
+        assert!(entry("null").is_err());
+        assert!(entry("1e12").is_err());
+    }
+
+    #[test]
+    fn test_parse_dataset_limited() {
+        let json = std::fs::read_to_string("data.json").unwrap();
+        assert!(parse_dataset_limited(&json, &InputLimits::default()).is_ok());
+
+        let tiny = InputLimits { max_bytes: 100, ..InputLimits::default() };
+        match parse_dataset_limited(&json, &tiny) {
+            Err(ProcessingError::InvalidFormat(msg)) => assert!(msg.contains("bytes")),
+            _ => panic!("Expected the byte limit to be enforced"),
+        }
+
+        let few = InputLimits { max_antennas: 8, ..InputLimits::default() };
+        match parse_dataset_limited(&json, &few) {
+            Err(ProcessingError::InvalidFormat(msg)) => assert!(msg.contains("antenna positions")),
+            _ => panic!("Expected the antenna limit to be enforced"),
+        }
+
+        // Deep nesting is a parse error, not a stack overflow
+        let nested = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+        assert!(parse_dataset_limited(&nested, &InputLimits::default()).is_err());
+    }
+
+    #[test]
+    fn test_minimal_dataset() {
+        use tart_obs;
+        use sphere::Hemisphere;
+
+        let data = minimal_dataset();
+        let obs = tart_obs::get_full(&data).unwrap();
+        assert_eq!(obs.baselines.len(), 3);
+        assert!(obs.warnings.is_empty());
+        assert_eq!(minimal_dataset().data[0].data.data[2].re, data.data[0].data.data[2].re);
+
+        // Every baseline is in phase at the source, so it is at the maximum (which
+        // with only three baselines is reached elsewhere too)
+        let (u, v, w) = obs.get_uvw(AntPositionUnit::Meters);
+        let mut sky = Hemisphere::new(16);
+        gridless::image_visibilities(&obs.vis_arr, &u, &v, &w, &mut sky, false);
+        let source = &tart_obs::get_sources(&data)[0];
+        let at_source = sky.value_at(&source.elaz()).unwrap();
+        assert!(at_source > 0.99*sky.peak().unwrap().value);
+    }
+
+    #[test]
+    fn test_assemble_dataset() {
+        let json = std::fs::read_to_string("data.json").unwrap();
+        let full: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("gridless_assemble_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, value: &serde_json::Value| {
+            let fname = dir.join(name);
+            std::fs::write(&fname, value.to_string()).unwrap();
+            fname.to_str().unwrap().to_string()
+        };
+        let vis = write("vis.json", &full["data"][0][0]);
+        let gains = write("gains.json", &full["gains"]);
+        let ant = write("antenna_positions.json", &full["ant_pos"]);
+        let info = write("info.json", &full["info"]);
+
+        let data = assemble_dataset(&vis, &gains, &ant, &info).unwrap();
+        let expected = parse_dataset(&json).unwrap();
+        assert_eq!(data.ant_pos.len(), expected.ant_pos.len());
+        assert_eq!(data.gains.gain, expected.gains.gain);
+        assert_eq!(data.data[0].data.data.len(), expected.data[0].data.data.len());
+        assert_eq!(data.data[0].data.timestamp, expected.data[0].data.timestamp);
+        assert!(data.data[0].sources.is_empty());
+        assert_eq!(data.info.location().unwrap().lat, -45.85177);
+
+        // A bad file is named in the error
+        match assemble_dataset(&vis, &ant, &ant, &info) {
+            Err(ProcessingError::Parse(msg)) => assert!(msg.contains("antenna_positions.json")),
+            _ => panic!("Expected a parse error"),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_datasets_from_jsonl() {
+        let json = std::fs::read_to_string("data.json").unwrap();
+        let line = serde_json::to_string(&serde_json::from_str::<serde_json::Value>(&json).unwrap()).unwrap();
+        let jsonl = format!("{}\n\n{{\"not\": \"a dataset\"}}\n{}\n", line, line);
+
+        let results: Vec<Result<FullDataset, ProcessingError>> = datasets_from_jsonl(Cursor::new(jsonl)).collect();
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        match results[1] {
+            Err(ProcessingError::Parse(ref msg)) => assert!(msg.starts_with("line 3")),
+            _ => panic!("Expected a parse error"),
+        }
+        assert!(results[2].is_ok());
+    }
 }
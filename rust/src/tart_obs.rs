@@ -2,18 +2,31 @@
 // Copyright (c) 2019-2021 Tim Molteno tim@elec.ac.nz
 //
 
+use std::collections::HashMap;
+
 use tart_api;
-use tart_api::{Gains, VisData, Source, TARTinfo, AntPosition, FullDataset};
+use astro;
+use gridless;
+use img::{self, AntPositionUnit};
+use logging;
+use tart_api::{Gains, VisData, VisEntry, Source, TARTinfo, AntPosition, FullDataset, Location};
 use utils::{VectorReal, VectorComplex, C64};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use error::{ProcessingError, Warning};
+use sphere::{ElAz, Hemisphere};
 
 pub struct Observation {
     pub timestamp: DateTime<Utc>,
-    pub vis_arr: VectorComplex,
+    pub vis_arr: VectorComplex, // calibrated, and scaled by the visibility weights
     pub ant_x: VectorReal,
     pub ant_y: VectorReal,
     pub ant_z: VectorReal,
-    pub baselines: Vec<(u32, u32)>
+    pub baselines: Vec<(u32, u32)>,
+    pub location: Option<Location>, // of the site, when the dataset gives it
+    pub warnings: Vec<Warning>,     // about data left out or suspect while building it
+    // Baseline coordinates (wavelengths) given with the data, used instead of
+    // computing them from the antenna positions
+    pub uvw: Option<(VectorReal, VectorReal, VectorReal)>,
 }
 
 
@@ -21,13 +34,19 @@ pub struct Observation {
 impl Observation {
     pub fn new(cal_data: &Gains, 
                 vis: &VisData,
-                _info: &TARTinfo, 
-                ant_positions: &Vec<AntPosition>) -> Observation {
+                info: &TARTinfo, 
+                ant_positions: &[AntPosition]) -> Result<Observation, ProcessingError> {
         
-        let rfc3339 = DateTime::parse_from_rfc3339(&vis.timestamp).expect("Couldn't parse timestamp");
-        println!("{}", rfc3339);
+        let timestamp = parse_timestamp(&vis.timestamp)?;
+        logging::log(&format!("{}", timestamp));
 
         let num_antenna = ant_positions.len();
+
+        if cal_data.gain.len() < num_antenna || cal_data.phase_offset.len() < num_antenna {
+            return Err(ProcessingError::InvalidFormat(format!(
+                "{} gains and {} phase offsets for {} antennas",
+                cal_data.gain.len(), cal_data.phase_offset.len(), num_antenna)));
+        }
         
         let mut vis_vec = Vec::<C64>::new();
         let mut baselines = Vec::new();
@@ -35,35 +54,304 @@ impl Observation {
         let mut ant_y = Vec::new();
         let mut ant_z = Vec::new();
         
-        for i in 0..num_antenna {
-            ant_x.push(ant_positions[i].x);
-            ant_y.push(ant_positions[i].y);
-            ant_z.push(ant_positions[i].z);
+        for a in ant_positions {
+            ant_x.push(a.x);
+            ant_y.push(a.y);
+            ant_z.push(a.z);
+        }
+
+        let mut warnings = Vec::new();
+        let unflagged: Vec<VisEntry> = vis.data.iter().filter(|v| !v.flagged).cloned().collect();
+        if unflagged.len() < vis.data.len() {
+            warnings.push(Warning::FlaggedVisibilities { count: vis.data.len() - unflagged.len() });
+        }
+        let cross = unflagged.iter().filter(|v| v.pol.is_some_and(|p| !p.is_parallel())).count();
+        if cross > 0 {
+            warnings.push(Warning::CrossHandsDropped { count: cross });
         }
 
-        for v in &vis.data {
-            vis_vec.push(C64::new(v.re, v.im));
-            baselines.push((v.i as u32,v.j as u32));
+        let entries = stokes_i(&unflagged);
+        for v in &entries {
+            for &a in &[v.i, v.j] {
+                if a as usize >= num_antenna {
+                    return Err(ProcessingError::InvalidFormat(format!(
+                        "baseline ({}, {}) references antenna {} but there are only {} antennas",
+                        v.i, v.j, a, num_antenna)));
+                }
+            }
+            if !(v.re.is_finite() && v.im.is_finite()) {
+                return Err(ProcessingError::InvalidFormat(format!(
+                    "baseline ({}, {}) has a non-finite visibility", v.i, v.j)));
+            }
+            if !(v.weight.is_finite() && v.weight >= 0.0) {
+                return Err(ProcessingError::InvalidFormat(format!(
+                    "baseline ({}, {}) has an invalid weight {}", v.i, v.j, v.weight)));
+            }
+            vis_vec.push(C64::new(v.re, v.im)*v.weight);
+            baselines.push((v.i,v.j));
         }
 
-        let cal_vis = apply_gains(&baselines, &vis_vec, &cal_data);
+        let cal_vis = apply_gains(&baselines, &vis_vec, cal_data);
+
+        let given: Vec<[f64; 3]> = entries.iter().filter_map(|v| v.uvw).collect();
+        let uvw = if given.is_empty() {
+            None
+        } else if given.len() == entries.len() {
+            Some((VectorReal::from_shape_fn(given.len(), |k| given[k][0]),
+                  VectorReal::from_shape_fn(given.len(), |k| given[k][1]),
+                  VectorReal::from_shape_fn(given.len(), |k| given[k][2])))
+        } else {
+            return Err(ProcessingError::InvalidFormat(format!(
+                "uvw given for {} of {} visibilities", given.len(), entries.len())));
+        };
+
+        let zero_weight = entries.iter().filter(|v| v.weight == 0.0).count();
+        if zero_weight > 0 {
+            warnings.push(Warning::ZeroWeight { count: zero_weight });
+        }
+        let mut seen = std::collections::BTreeSet::new();
+        let mut duplicates = std::collections::BTreeSet::new();
+        for &(i, j) in &baselines {
+            let pair = (i.min(j), i.max(j));
+            if !seen.insert(pair) {
+                duplicates.insert(pair);
+            }
+        }
+        if !duplicates.is_empty() {
+            warnings.push(Warning::DuplicateBaselines { baselines: duplicates.into_iter().collect() });
+        }
         
-        Observation {
-            timestamp: rfc3339.with_timezone(&Utc),
+        Ok(Observation {
+            timestamp,
             ant_x: VectorReal::from_vec(ant_x),
             ant_y: VectorReal::from_vec(ant_y),
             ant_z: VectorReal::from_vec(ant_z),
             vis_arr: VectorComplex::from_vec(cal_vis),
-            baselines: baselines
+            baselines,
+            location: info.location().cloned(),
+            warnings,
+            uvw,
+        })
+    }
+
+    /*
+    *   An observation from calibrated visibilities with their baseline coordinates
+    *   (in wavelengths) already known, e.g. from a measurement set. There are no
+    *   antenna positions, so the uvw are always used as given.
+    */
+    pub fn from_uvw(timestamp: DateTime<Utc>,
+                    vis_arr: VectorComplex,
+                    baselines: Vec<(u32, u32)>,
+                    u: VectorReal,
+                    v: VectorReal,
+                    w: VectorReal) -> Result<Observation, ProcessingError> {
+        let n = vis_arr.len();
+        if baselines.len() != n || u.len() != n || v.len() != n || w.len() != n {
+            return Err(ProcessingError::InvalidFormat(format!(
+                "{} visibilities with {} baselines and {}, {}, {} u, v, w",
+                n, baselines.len(), u.len(), v.len(), w.len())));
+        }
+        Ok(Observation {
+            timestamp,
+            vis_arr,
+            ant_x: VectorReal::zeros(0),
+            ant_y: VectorReal::zeros(0),
+            ant_z: VectorReal::zeros(0),
+            baselines,
+            location: None,
+            warnings: Vec::new(),
+            uvw: Some((u, v, w)),
+        })
+    }
+
+    /*
+    *   The sub-array of the given antennas: only the baselines with both ends among
+    *   them. Antennas are counted from the positions, or for an observation built from
+    *   uvw, from the highest antenna in any baseline.
+    */
+    pub fn with_antennas(&self, antennas: &[u32]) -> Result<Observation, ProcessingError> {
+        let num_antenna = if self.ant_x.is_empty() {
+            self.baselines.iter().map(|&(i, j)| i.max(j) as usize + 1).max().unwrap_or(0)
+        } else {
+            self.ant_x.len()
+        };
+        if let Some(&a) = antennas.iter().find(|&&a| a as usize >= num_antenna) {
+            return Err(ProcessingError::InvalidFormat(format!(
+                "antenna {} is not in the array, which has {} antennas", a, num_antenna)));
+        }
+
+        let keep: Vec<usize> = (0..self.baselines.len())
+            .filter(|&k| antennas.contains(&self.baselines[k].0) && antennas.contains(&self.baselines[k].1))
+            .collect();
+        if keep.is_empty() {
+            return Err(ProcessingError::InvalidFormat(format!(
+                "no baselines between antennas {:?}", antennas)));
+        }
+        let select = |x: &VectorReal| VectorReal::from_shape_fn(keep.len(), |k| x[keep[k]]);
+        Ok(Observation {
+            timestamp: self.timestamp,
+            vis_arr: VectorComplex::from_shape_fn(keep.len(), |k| self.vis_arr[keep[k]]),
+            ant_x: self.ant_x.clone(),
+            ant_y: self.ant_y.clone(),
+            ant_z: self.ant_z.clone(),
+            baselines: keep.iter().map(|&k| self.baselines[k]).collect(),
+            location: self.location.clone(),
+            warnings: self.warnings.clone(),
+            uvw: self.uvw.as_ref().map(|(u, v, w)| (select(u), select(v), select(w))),
+        })
+    }
+
+    /*
+    *   Baseline coordinates (wavelengths): those given with the data if any, otherwise
+    *   computed from the antenna positions, which are in unit.
+    */
+    pub fn get_uvw(&self, unit: AntPositionUnit) -> (VectorReal, VectorReal, VectorReal) {
+        match self.uvw {
+            Some(ref uvw) => uvw.clone(),
+            None => img::get_uvw_in(&self.baselines, &self.ant_x, &self.ant_y, &self.ant_z, unit),
+        }
+    }
+
+    /*
+    *   Local sidereal time in degrees (divide by 15 for hours) at the observation
+    *   time, for a site at east longitude lon_deg.
+    */
+    pub fn lst(&self, lon_deg: f32) -> f32 {
+        astro::local_sidereal_time((lon_deg as f64).to_radians(), &self.timestamp).to_degrees() as f32
+    }
+
+    // LST (degrees) at the dataset's own site, if it has a location
+    pub fn site_lst(&self) -> Option<f32> {
+        self.location.as_ref().map(|loc| self.lst(loc.lon as f32))
+    }
+
+    // Longest projected (uv plane) baseline, in wavelengths, with antenna positions in unit
+    pub fn max_baseline_wavelengths(&self, unit: AntPositionUnit) -> f64 {
+        let (u, v, _) = self.get_uvw(unit);
+        u.iter().zip(v.iter())
+            .map(|(u, v)| (u*u + v*v).sqrt())
+            .fold(0.0, f64::max)
+    }
+
+    // A one line description of when the observation was made, for image metadata
+    pub fn describe(&self) -> String {
+        let utc = self.timestamp.to_rfc3339();
+        match self.site_lst() {
+            Some(lst) => format!("Observed {} (LST {:.4} deg)", utc, lst),
+            None => format!("Observed {}", utc),
+        }
+    }
+
+}
+
+
+/*
+*   Parse an observation timestamp. Accepts RFC 3339, the same with a space instead
+*   of the 'T' (with or without a UTC offset), and seconds since the Unix epoch.
+*   Times without an offset are taken as UTC.
+*/
+pub fn parse_timestamp(s: &str) -> Result<DateTime<Utc>, ProcessingError> {
+    let s = s.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    for format in &["%Y-%m-%d %H:%M:%S%.f%:z", "%Y-%m-%d %H:%M:%S%.f%z"] {
+        if let Ok(t) = DateTime::parse_from_str(s, format) {
+            return Ok(t.with_timezone(&Utc));
+        }
+    }
+    for format in &["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, format) {
+            return Ok(t.and_utc());
+        }
+    }
+    if let Ok(secs) = s.parse::<f64>() {
+        if secs.is_finite() {
+            let whole = secs.floor();
+            let nanos = ((secs - whole) * 1.0e9).round().min(999_999_999.0) as u32;
+            if let Some(t) = DateTime::from_timestamp(whole as i64, nanos) {
+                return Ok(t);
+            }
+        }
+    }
+    Err(ProcessingError::Parse(format!("timestamp '{}' is not RFC 3339, 'YYYY-MM-DD HH:MM:SS', or epoch seconds", s)))
+}
+
+
+/*
+*   Largest nside suggest_nside will return, whatever the baselines.
+*/
+pub const MAX_SUGGESTED_NSIDE: u32 = 1024;
+
+/*
+*   Choose an nside that samples the synthesized beam: the smallest power of two whose
+*   pixel scale is at most a third of the beam FWHM, taken as 1/max_baseline radians.
+*   The antenna positions are read in unit, as they will be when imaging.
+*/
+pub fn suggest_nside(obs: &Observation, unit: AntPositionUnit) -> u32 {
+    let max_baseline = obs.max_baseline_wavelengths(unit);
+    if max_baseline <= 0.0 {
+        return 1;
+    }
+    let fwhm = 1.0 / max_baseline;
+
+    let mut nside = 1;
+    while Hemisphere::pixel_scale(nside) > fwhm / 3.0 && nside < MAX_SUGGESTED_NSIDE {
+        nside *= 2;
+    }
+    nside
+}
+
+
+/*
+*   Combine dual polarization visibilities into Stokes I: on each baseline the parallel
+*   hands (XX and YY, or RR and LL) are averaged, weighted by their weights, and cross
+*   hands are dropped. The combined weight is the sum of the hands' inverse variance
+*   weights. Entries without a polarization pass through unchanged, so single
+*   polarization data is untouched. Baselines keep their first-seen order.
+*/
+pub fn stokes_i(entries: &[VisEntry]) -> Vec<VisEntry> {
+    if entries.iter().all(|v| v.pol.is_none()) {
+        return entries.to_vec();
+    }
+
+    // (entry, sum of weighted re, sum of weighted im, sum of weights)
+    let mut combined: Vec<(VisEntry, f64, f64, f64)> = Vec::new();
+    // Index into combined of each baseline's parallel hands
+    let mut parallel: HashMap<(u32, u32), usize> = HashMap::new();
+    for v in entries {
+        match v.pol {
+            None => combined.push((v.clone(), 0.0, 0.0, 0.0)),
+            Some(p) if !p.is_parallel() => {},
+            Some(_) => {
+                let k = *parallel.entry((v.i, v.j)).or_insert_with(|| {
+                    combined.push((v.clone(), 0.0, 0.0, 0.0));
+                    combined.len() - 1
+                });
+                let c = &mut combined[k];
+                c.1 += v.weight*v.re;
+                c.2 += v.weight*v.im;
+                c.3 += v.weight;
+            }
         }
     }
 
+    combined.into_iter().map(|(mut v, re, im, weight)| {
+        if v.pol.is_some() {
+            if weight > 0.0 {
+                v.re = re/weight;
+                v.im = im/weight;
+            }
+            v.weight = weight;
+        }
+        v
+    }).collect()
 }
 
 
 pub fn apply_gains(
-                baselines: &Vec::<(u32, u32)>,
-                vis_arr: &Vec::<C64>,
+                baselines: &[(u32, u32)],
+                vis_arr: &[C64],
                 cal: &tart_api::Gains) -> Vec<C64> 
 {
     let mut cal_vis = Vec::<C64>::new();
@@ -87,18 +375,89 @@ pub fn get_sources(data: &FullDataset) -> &Vec<Source> {
 }
 
     
-pub fn get_full(data: &FullDataset) -> Observation {
+pub fn get_full(data: &FullDataset) -> Result<Observation, ProcessingError> {
+    if data.data.is_empty() {
+        return Err(ProcessingError::InvalidFormat("no visibility data".to_string()));
+    }
     let cal_data = &data.gains;
     let vis = &data.data[0].data;
     let info = &data.info;
     let ant_positions = &data.ant_pos;
 
-    Observation::new(&cal_data, &vis, &info, &ant_positions)
+    Observation::new(cal_data, vis, info, ant_positions)
+}
+
+
+/*
+*   Add simulated point sources to the (calibrated) visibilities of an observation.
+*   Each source is (el, az, jy) with el and az in degrees, like tart_api::Source.
+*/
+pub fn inject_sources(obs: &mut Observation, sources: &[(f64, f64, f64)]) {
+    let model = model_visibilities(obs, sources);
+    obs.vis_arr = &obs.vis_arr + &model;
+}
+
+
+// Visibilities of a set of (el, az, jy) point sources, el and az in degrees
+fn model_visibilities(obs: &Observation, sources: &[(f64, f64, f64)]) -> VectorComplex {
+    let (u, v, w) = obs.get_uvw(AntPositionUnit::Meters);
+
+    let mut model = VectorComplex::zeros(obs.baselines.len());
+    for &(el, az, jy) in sources {
+        let elaz = ElAz::from_degrees(el, az);
+        model = model + gridless::point_source_vis(&u, &v, &w, elaz.el, elaz.az, jy);
+    }
+    model
+}
+
+
+/*
+*   The visibilities left after subtracting a point source model, one per baseline
+*   in the order of obs.baselines. Large residuals on the baselines of one antenna
+*   point to a calibration problem with it.
+*/
+pub fn residual_visibilities(obs: &Observation, model_sources: &[Source]) -> Vec<C64> {
+    let sources: Vec<(f64, f64, f64)> = model_sources.iter().map(|s| (s.el, s.az, s.jy)).collect();
+    let model = model_visibilities(obs, &sources);
+    (&obs.vis_arr - &model).to_vec()
+}
+
+
+/*
+*   Residual of one baseline (between antennas i and j), for reports.
+*/
+#[derive(Debug, Clone, Serialize)]
+pub struct BaselineResidual {
+    pub i: u32,
+    pub j: u32,
+    pub re: f64,
+    pub im: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ResidualReport {
+    pub baselines: Vec<BaselineResidual>,
+    pub rms: f64,   // root mean square of the residual amplitudes
+}
+
+pub fn residual_report(obs: &Observation, model_sources: &[Source]) -> ResidualReport {
+    let residuals = residual_visibilities(obs, model_sources);
+    let rms = if residuals.is_empty() {
+        0.0
+    } else {
+        (residuals.iter().map(|r| r.norm_sqr()).sum::<f64>() / (residuals.len() as f64)).sqrt()
+    };
+    ResidualReport {
+        baselines: obs.baselines.iter().zip(residuals.iter())
+            .map(|(&(i, j), r)| BaselineResidual { i, j, re: r.re, im: r.im })
+            .collect(),
+        rms,
+    }
 }
 
 
 #[allow(dead_code)]
-pub fn get() -> Observation {
+pub fn get() -> Result<Observation, ProcessingError> {
     let cal_data = tart_api::gains();
     let vis = tart_api::visibilities();
     let info = tart_api::info();
@@ -106,3 +465,429 @@ pub fn get() -> Observation {
     
     Observation::new(&cal_data, &vis, &info, &ant_positions)
 }
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use tart_api::Polarization;
+    use sphere::{LonLat, HpAngle};
+
+    const TIMESTAMP: &str = "2021-11-04T09:31:05.123+00:00";
+
+    fn unit_gains(num_ant: usize) -> Gains {
+        Gains { gain: vec![1.0; num_ant], phase_offset: vec![0.0; num_ant] }
+    }
+
+    fn empty_info() -> TARTinfo {
+        serde_json::from_str("{\"info\": {}}").unwrap()
+    }
+
+    // Antennas 1 m apart along x
+    fn line_of_antennas(num_ant: usize) -> Vec<AntPosition> {
+        (0..num_ant).map(|i| AntPosition { x: i as f64, y: 0.0, z: 0.0 }).collect()
+    }
+
+    // Three antennas in an L, with unit gains
+    fn three_antennas() -> (Gains, Vec<AntPosition>, TARTinfo) {
+        let ant_pos = vec![
+            AntPosition { x: 0.0, y: 0.0, z: 0.0 },
+            AntPosition { x: 1.0, y: 0.0, z: 0.0 },
+            AntPosition { x: 0.0, y: 1.5, z: 0.0 },
+        ];
+        (unit_gains(3), ant_pos, empty_info())
+    }
+
+    // A small ring of antennas with an empty sky.
+    fn empty_obs() -> Observation {
+        let num_ant = 8;
+        let mut ant_x = Vec::new();
+        let mut ant_y = Vec::new();
+        let mut baselines = Vec::new();
+        for i in 0..num_ant {
+            let angle = 2.0*std::f64::consts::PI*(i as f64)/(num_ant as f64);
+            let r = 0.5 + 0.1*(i as f64);
+            ant_x.push(r*angle.cos());
+            ant_y.push(r*angle.sin());
+            for j in (i+1)..num_ant {
+                baselines.push((i as u32, j as u32));
+            }
+        }
+        Observation {
+            timestamp: Utc::now(),
+            vis_arr: VectorComplex::zeros(baselines.len()),
+            ant_x: VectorReal::from_vec(ant_x),
+            ant_y: VectorReal::from_vec(ant_y),
+            ant_z: VectorReal::zeros(num_ant),
+            baselines,
+            location: None,
+            warnings: Vec::new(),
+            uvw: None,
+        }
+    }
+
+    fn peak(obs: &Observation, nside: u32) -> (ElAz, f64) {
+        let (u, v, w) = obs.get_uvw(AntPositionUnit::Meters);
+        let mut sky = Hemisphere::new(nside);
+        gridless::image_visibilities(&obs.vis_arr, &u, &v, &w, &mut sky, false);
+
+        let mut i_max = 0;
+        for i in 0..sky.npix {
+            if sky.visible_pix[i] > sky.visible_pix[i_max] {
+                i_max = i;
+            }
+        }
+        let ll = LonLat::from_pix(nside, sky.visible_indices[i_max]);
+        (ElAz::from_hp(&HpAngle::from_lonlat(&ll)), sky.visible_pix[i_max])
+    }
+
+    #[test]
+    fn test_baseline_out_of_range() {
+        let gains = unit_gains(3);
+        let ant_pos = line_of_antennas(3);
+        let vis = VisData {
+            data: vec![
+                VisEntry::new(0, 1, 1.0, 0.0),
+                VisEntry::new(1, 7, 1.0, 0.0),
+            ],
+            timestamp: TIMESTAMP.to_string(),
+        };
+        let info = empty_info();
+
+        match Observation::new(&gains, &vis, &info, &ant_pos) {
+            Err(ProcessingError::InvalidFormat(msg)) => assert!(msg.contains("antenna 7")),
+            _ => panic!("Expected an InvalidFormat error"),
+        }
+    }
+
+    #[test]
+    fn test_short_gains_and_bad_vis() {
+        let ant_pos = line_of_antennas(3);
+        let info = empty_info();
+        let mut vis = VisData {
+            data: vec![VisEntry::new(0, 1, 1.0, 0.0)],
+            timestamp: TIMESTAMP.to_string(),
+        };
+
+        let short = Gains { gain: vec![1.0; 2], phase_offset: vec![0.0; 3] };
+        match Observation::new(&short, &vis, &info, &ant_pos) {
+            Err(ProcessingError::InvalidFormat(msg)) => assert!(msg.contains("2 gains")),
+            _ => panic!("Expected an InvalidFormat error"),
+        }
+
+        let gains = unit_gains(3);
+        vis.data[0].im = f64::NAN;
+        assert!(Observation::new(&gains, &vis, &info, &ant_pos).is_err());
+
+        vis.data[0].im = 0.0;
+        vis.timestamp = "yesterday".to_string();
+        match Observation::new(&gains, &vis, &info, &ant_pos) {
+            Err(ProcessingError::Parse(msg)) => assert!(msg.contains("yesterday")),
+            _ => panic!("Expected a Parse error"),
+        }
+    }
+
+    #[test]
+    fn test_given_uvw() {
+        let gains = unit_gains(3);
+        let ant_pos: Vec<AntPosition> = vec![
+            AntPosition { x: 0.0, y: 0.0, z: 0.0 },
+            AntPosition { x: 1.0, y: 0.2, z: 0.0 },
+            AntPosition { x: -0.3, y: 1.5, z: 0.1 },
+        ];
+        let info = empty_info();
+        let mut vis = VisData {
+            data: vec![
+                VisEntry::new(0, 1, 1.0, 0.5),
+                VisEntry::new(0, 2, 0.3, -0.2),
+                VisEntry::new(1, 2, -0.4, 0.1),
+            ],
+            timestamp: TIMESTAMP.to_string(),
+        };
+        let image = |obs: &Observation| {
+            let (u, v, w) = obs.get_uvw(AntPositionUnit::Meters);
+            let mut sky = Hemisphere::new(4);
+            gridless::image_visibilities(&obs.vis_arr, &u, &v, &w, &mut sky, false);
+            sky.visible_pix
+        };
+
+        let computed = Observation::new(&gains, &vis, &info, &ant_pos).unwrap();
+        assert!(computed.uvw.is_none());
+        let (u, v, w) = computed.get_uvw(AntPositionUnit::Meters);
+
+        // The same uvw given with the data image identically
+        for (k, entry) in vis.data.iter_mut().enumerate() {
+            entry.uvw = Some([u[k], v[k], w[k]]);
+        }
+        let given = Observation::new(&gains, &vis, &info, &ant_pos).unwrap();
+        assert_eq!(image(&given), image(&computed));
+
+        // As does an observation built from them directly, with no antenna positions
+        let direct = Observation::from_uvw(computed.timestamp, computed.vis_arr.clone(),
+                                           computed.baselines.clone(), u.clone(), v.clone(), w.clone()).unwrap();
+        assert_eq!(image(&direct), image(&computed));
+        assert!(Observation::from_uvw(computed.timestamp, computed.vis_arr.clone(),
+                                      computed.baselines.clone(), u, v, VectorReal::zeros(1)).is_err());
+
+        // The uvw must be given for every visibility or none
+        vis.data[1].uvw = None;
+        match Observation::new(&gains, &vis, &info, &ant_pos) {
+            Err(ProcessingError::InvalidFormat(msg)) => assert!(msg.contains("2 of 3")),
+            _ => panic!("Expected an InvalidFormat error"),
+        }
+
+        // and is read from the JSON
+        let entry: VisEntry = serde_json::from_str("{\"i\": 0, \"j\": 1, \"re\": 1.0, \"im\": 0.0, \"uvw\": [1.5, -2.0, 0.25]}").unwrap();
+        assert_eq!(entry.uvw, Some([1.5, -2.0, 0.25]));
+    }
+
+    #[test]
+    fn test_with_antennas() {
+        let obs = get_full(&tart_api::file_to_dataset("data.json")).unwrap();
+        let num_antenna = obs.ant_x.len() as u32;
+        let inner: Vec<u32> = (0..num_antenna/2).collect();
+
+        let sub = obs.with_antennas(&inner).unwrap();
+        assert!(sub.baselines.len() < obs.baselines.len());
+        assert!(sub.baselines.iter().all(|&(i, j)| inner.contains(&i) && inner.contains(&j)));
+        assert_eq!(sub.vis_arr.len(), sub.baselines.len());
+
+        // Each kept baseline keeps its visibility and uvw
+        let (u, _, _) = obs.get_uvw(AntPositionUnit::Meters);
+        let (sub_u, _, _) = sub.get_uvw(AntPositionUnit::Meters);
+        for (k, b) in sub.baselines.iter().enumerate() {
+            let n = obs.baselines.iter().position(|o| o == b).unwrap();
+            assert_eq!(sub.vis_arr[k], obs.vis_arr[n]);
+            assert_eq!(sub_u[k], u[n]);
+        }
+
+        let all: Vec<u32> = (0..num_antenna).collect();
+        assert_eq!(obs.with_antennas(&all).unwrap().baselines, obs.baselines);
+
+        match obs.with_antennas(&[0, 1, num_antenna]) {
+            Err(ProcessingError::InvalidFormat(msg)) => assert!(msg.contains(&format!("antenna {} is not in the array", num_antenna))),
+            _ => panic!("Expected an InvalidFormat error"),
+        }
+        assert!(obs.with_antennas(&[3]).is_err());
+    }
+
+    #[test]
+    fn test_zero_weight_ignored() {
+        let (gains, ant_pos, info) = three_antennas();
+        let timestamp = TIMESTAMP.to_string();
+
+        let vis = VisData {
+            data: vec![
+                VisEntry::new(0, 1, 1.0, 0.5),
+                VisEntry { weight: 2.0, ..VisEntry::new(0, 2, 0.3, -0.2) },
+            ],
+            timestamp: timestamp.clone(),
+        };
+        let mut noisy = VisData {
+            data: vec![
+                VisEntry::new(0, 1, 1.0, 0.5),
+                VisEntry { weight: 2.0, ..VisEntry::new(0, 2, 0.3, -0.2) },
+                VisEntry { weight: 0.0, ..VisEntry::new(1, 2, 50.0, 50.0) },
+            ],
+            timestamp,
+        };
+
+        let image = |data: &VisData| {
+            let obs = Observation::new(&gains, data, &info, &ant_pos).unwrap();
+            let (u, v, w) = obs.get_uvw(AntPositionUnit::Meters);
+            let mut sky = Hemisphere::new(4);
+            gridless::image_visibilities(&obs.vis_arr, &u, &v, &w, &mut sky, false);
+            sky.visible_pix
+        };
+        assert_eq!(image(&vis), image(&noisy));
+
+        noisy.data[2].weight = -1.0;
+        assert!(Observation::new(&gains, &noisy, &info, &ant_pos).is_err());
+
+        // Weights default to one
+        let entry: VisEntry = serde_json::from_str("{\"i\": 0, \"j\": 1, \"re\": 1.0, \"im\": 0.0}").unwrap();
+        assert_eq!(entry.weight, 1.0);
+    }
+
+    #[test]
+    fn test_flagged_ignored() {
+        let (gains, ant_pos, info) = three_antennas();
+        let json = |extra: &str| format!("{{\"timestamp\": \"2021-11-04T09:31:05+00:00\", \"data\": [
+            {{\"i\": 0, \"j\": 1, \"re\": 1.0, \"im\": 0.5}},
+            {{\"i\": 0, \"j\": 2, \"re\": 0.3, \"im\": -0.2}}{}]}}", extra);
+
+        let clean: VisData = serde_json::from_str(&json("")).unwrap();
+        let rfi: VisData = serde_json::from_str(&json(",
+            {\"i\": 1, \"j\": 2, \"re\": 80.0, \"im\": 80.0, \"flags\": true},
+            {\"i\": 0, \"j\": 1, \"re\": -9.0, \"im\": 9.0, \"pol\": \"XX\", \"flagged\": true}")).unwrap();
+        assert!(rfi.data[2].flagged && rfi.data[3].flagged && !rfi.data[0].flagged);
+
+        let a = Observation::new(&gains, &clean, &info, &ant_pos).unwrap();
+        let b = Observation::new(&gains, &rfi, &info, &ant_pos).unwrap();
+        assert_eq!(a.baselines, b.baselines);
+        assert_eq!(a.vis_arr, b.vis_arr);
+        assert!(a.warnings.is_empty());
+        assert_eq!(b.warnings, vec![Warning::FlaggedVisibilities { count: 2 }]);
+    }
+
+    #[test]
+    fn test_warnings() {
+        let (gains, ant_pos, info) = three_antennas();
+        let vis: VisData = serde_json::from_str("{\"timestamp\": \"2021-11-04T09:31:05+00:00\", \"data\": [
+            {\"i\": 0, \"j\": 1, \"re\": 1.0, \"im\": 0.5, \"pol\": \"XX\"},
+            {\"i\": 0, \"j\": 1, \"re\": 1.0, \"im\": 0.5, \"pol\": \"XY\"},
+            {\"i\": 1, \"j\": 0, \"re\": 1.0, \"im\": -0.5},
+            {\"i\": 0, \"j\": 2, \"re\": 0.3, \"im\": -0.2, \"weight\": 0.0},
+            {\"i\": 1, \"j\": 2, \"re\": 0.3, \"im\": -0.2, \"flagged\": true}]}").unwrap();
+
+        let obs = Observation::new(&gains, &vis, &info, &ant_pos).unwrap();
+        assert_eq!(obs.warnings, vec![
+            Warning::FlaggedVisibilities { count: 1 },
+            Warning::CrossHandsDropped { count: 1 },
+            Warning::ZeroWeight { count: 1 },
+            Warning::DuplicateBaselines { baselines: vec![(0, 1)] },
+        ]);
+        assert_eq!(obs.warnings[3].to_string(), "baselines given more than once: (0, 1)");
+        assert_eq!(serde_json::to_string(&obs.warnings[0]).unwrap(), "{\"kind\":\"FlaggedVisibilities\",\"count\":1}");
+    }
+
+    #[test]
+    fn test_stokes_i() {
+        let entry = |i, j, re, im, pol| VisEntry { pol, ..VisEntry::new(i, j, re, im) };
+        let dual = vec![
+            entry(0, 1, 1.0, 0.5, Some(Polarization::XX)),
+            entry(0, 2, 0.4, 0.0, Some(Polarization::XX)),
+            entry(0, 1, 3.0, -0.5, Some(Polarization::YY)),
+            entry(0, 1, 9.0, 9.0, Some(Polarization::XY)),
+            entry(0, 2, 0.2, 0.2, Some(Polarization::YY)),
+        ];
+        let combined = stokes_i(&dual);
+        assert_eq!(combined.len(), 2);
+        assert_eq!((combined[0].i, combined[0].j, combined[0].re, combined[0].im), (0, 1, 2.0, 0.0));
+        assert_eq!((combined[1].i, combined[1].j, combined[1].re, combined[1].im), (0, 2, 0.30000000000000004, 0.1));
+        // Inverse variance weights add
+        assert_eq!(combined[0].weight, 2.0);
+        assert_eq!(combined[1].weight, 2.0);
+
+        let mut unequal = dual.clone();
+        unequal[0].weight = 3.0;
+        unequal[2].weight = 1.0;
+        let combined = stokes_i(&unequal);
+        assert_eq!((combined[0].re, combined[0].im, combined[0].weight), (1.5, 0.25, 4.0));
+        assert_eq!(combined[1].weight, 2.0);
+
+        // The Stokes I image is the image of the averaged visibilities, with their summed weights
+        let (gains, ant_pos, info) = three_antennas();
+        let timestamp = TIMESTAMP.to_string();
+        let mut averaged = vec![entry(0, 1, 2.0, 0.0, None), entry(0, 2, 0.30000000000000004, 0.1, None)];
+        for v in averaged.iter_mut() {
+            v.weight = 2.0;
+        }
+
+        let obs_dual = Observation::new(&gains, &VisData { data: dual, timestamp: timestamp.clone() }, &info, &ant_pos).unwrap();
+        let obs_avg = Observation::new(&gains, &VisData { data: averaged, timestamp }, &info, &ant_pos).unwrap();
+        assert_eq!(obs_dual.vis_arr, obs_avg.vis_arr);
+        assert_eq!(obs_dual.baselines, obs_avg.baselines);
+    }
+
+    #[test]
+    fn test_suggest_nside() {
+        let mut obs = empty_obs();
+        let max_baseline = obs.max_baseline_wavelengths(AntPositionUnit::Meters);
+        assert!(max_baseline > 0.0);
+
+        let nside = suggest_nside(&obs, AntPositionUnit::Meters);
+        assert!(nside.is_power_of_two());
+        assert!(Hemisphere::pixel_scale(nside) <= 1.0 / max_baseline / 3.0);
+        assert!(nside == 1 || Hemisphere::pixel_scale(nside / 2) > 1.0 / max_baseline / 3.0);
+
+        // Doubling the array doubles the resolution, so doubles nside
+        obs.ant_x = &obs.ant_x * 2.0;
+        obs.ant_y = &obs.ant_y * 2.0;
+        assert_eq!(suggest_nside(&obs, AntPositionUnit::Meters), 2*nside);
+    }
+
+    #[test]
+    fn test_suggest_nside_unit() {
+        // Positions already in wavelengths are not scaled up by 1/lambda, so the
+        // baselines are shorter and the beam wider than when they are read as metres
+        let obs = empty_obs();
+        let meters = obs.max_baseline_wavelengths(AntPositionUnit::Meters);
+        let wavelengths = obs.max_baseline_wavelengths(AntPositionUnit::Wavelengths);
+        assert!(wavelengths < meters);
+        assert!(suggest_nside(&obs, AntPositionUnit::Wavelengths) < suggest_nside(&obs, AntPositionUnit::Meters));
+    }
+
+    #[test]
+    fn test_residuals() {
+        let mut obs = empty_obs();
+        inject_sources(&mut obs, &[(60.0, 45.0, 2.0)]);
+
+        let model = vec![Source { az: 45.0, el: 60.0, jy: 2.0, name: "A".to_string(), r: 0.0 }];
+        let report = residual_report(&obs, &model);
+        assert_eq!(report.baselines.len(), obs.baselines.len());
+        assert_eq!((report.baselines[0].i, report.baselines[0].j), obs.baselines[0]);
+        assert!(report.rms < 1.0e-9);
+
+        // With no model the residual is the data, of amplitude 2 on every baseline
+        let report = residual_report(&obs, &[]);
+        assert!((report.rms - 2.0).abs() < 1.0e-9);
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let expected = DateTime::parse_from_rfc3339("2021-11-04T09:31:05.5Z").unwrap().with_timezone(&Utc);
+        for s in &["2021-11-04T09:31:05.5+00:00",
+                   "2021-11-04T11:31:05.5+02:00",
+                   "2021-11-04 09:31:05.5",
+                   "2021-11-04 09:31:05.500+00:00",
+                   "2021-11-04 10:31:05.5 +0100",
+                   "2021-11-04T09:31:05.5",
+                   "1636018265.5"] {
+            assert_eq!(parse_timestamp(s), Ok(expected), "{}", s);
+        }
+        assert_eq!(parse_timestamp("2021-11-04 09:31:05").unwrap().timestamp(), 1636018265);
+        match parse_timestamp("yesterday") {
+            Err(ProcessingError::Parse(msg)) => assert!(msg.contains("yesterday")),
+            _ => panic!("Expected a parse error"),
+        }
+        assert!(parse_timestamp("NaN").is_err());
+    }
+
+    #[test]
+    fn test_lst() {
+        // LST 304.80762 deg at longitude -1.9167 on 1998-08-10 23:10 UT
+        // (http://www.stargazing.net/kepler/altaz.html)
+        let mut obs = empty_obs();
+        obs.timestamp = DateTime::parse_from_rfc3339("1998-08-10T23:10:00Z").unwrap().with_timezone(&Utc);
+        assert!((obs.lst(-1.9166667) - 304.80762).abs() < 0.01);
+
+        assert_eq!(obs.site_lst(), None);
+        assert!(!obs.describe().contains("LST"));
+        obs.location = Some(Location { lat: 52.5, lon: -1.9166667, alt: 0.0 });
+        assert!(obs.describe().contains("LST 304.8"));
+    }
+
+    #[test]
+    fn test_inject_source_position() {
+        let mut obs = empty_obs();
+        inject_sources(&mut obs, &[(60.0, 45.0, 1.0)]);
+
+        let (elaz, _) = peak(&obs, 16);
+        assert!((elaz.el.to_degrees() - 60.0).abs() < 5.0);
+        assert!((elaz.az.to_degrees().rem_euclid(360.0) - 45.0).abs() < 7.0);
+    }
+
+    #[test]
+    fn test_inject_source_brightness() {
+        let mut obs1 = empty_obs();
+        inject_sources(&mut obs1, &[(60.0, 45.0, 1.0)]);
+        let mut obs2 = empty_obs();
+        inject_sources(&mut obs2, &[(60.0, 45.0, 2.0)]);
+
+        let (_, p1) = peak(&obs1, 16);
+        let (_, p2) = peak(&obs2, 16);
+        assert!((p2/p1 - 2.0).abs() < 1.0e-6);
+    }
+}
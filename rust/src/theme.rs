@@ -0,0 +1,111 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+
+/*
+*   Colours and line styles of the SVG decorations. Colours are any SVG colour without
+*   spaces, e.g. "white", "#1a2b3c" or "rgb(10,20,30)". Widths and dashes are in
+*   multiples of the plot's base line width.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Theme {
+    pub background: Option<String>,     // None leaves the background transparent
+    pub grid_color: String,
+    pub grid_width: f32,
+    pub grid_dash: Option<(u32, u32)>,  // (dash, gap), None for solid grid lines
+    pub polygon_stroke: Option<String>, // None outlines each pixel in its own colour
+    pub text_color: String,
+    pub font_family: Option<String>,
+    pub source_color: String,
+    // Flat colour behind the data, showing through masked, thresholded and NaN pixels.
+    // None leaves them transparent. PNG output understands #rrggbb, #rgb and rgb(r,g,b).
+    #[serde(default)]
+    pub unseen_color: Option<String>,
+}
+
+impl Theme {
+    // Light decorations for the usual dark sky image
+    pub fn astronomy() -> Theme {
+        Theme {
+            background: None,
+            grid_color: "white".to_string(),
+            grid_width: 1.0,
+            grid_dash: Some((5, 10)),
+            polygon_stroke: None,
+            text_color: "white".to_string(),
+            font_family: None,
+            source_color: "red".to_string(),
+            unseen_color: None,
+        }
+    }
+
+    // Dark decorations on a white page, for print
+    pub fn light() -> Theme {
+        Theme {
+            background: Some("white".to_string()),
+            grid_color: "#555555".to_string(),
+            grid_width: 1.0,
+            grid_dash: Some((5, 10)),
+            polygon_stroke: None,
+            text_color: "black".to_string(),
+            font_family: Some("sans-serif".to_string()),
+            source_color: "#d00000".to_string(),
+            unseen_color: None,
+        }
+    }
+}
+
+/*
+*   The (r, g, b) of a colour given as #rrggbb, #rgb or rgb(r,g,b), or None for
+*   anything else, such as SVG colour names.
+*/
+pub fn parse_rgb(color: &str) -> Option<(u8, u8, u8)> {
+    let color = color.trim();
+    if let Some(hex) = color.strip_prefix('#') {
+        let digit = |i: usize, n: usize| u8::from_str_radix(hex.get(i..i + n)?, 16).ok();
+        return match hex.len() {
+            6 => Some((digit(0, 2)?, digit(2, 2)?, digit(4, 2)?)),
+            3 => Some((digit(0, 1)?*17, digit(1, 1)?*17, digit(2, 1)?*17)),
+            _ => None,
+        };
+    }
+    let args = color.strip_prefix("rgb(")?.strip_suffix(')')?;
+    let rgb: Vec<u8> = args.split(',').map(|c| c.trim().parse().ok()).collect::<Option<_>>()?;
+    match rgb[..] {
+        [r, g, b] => Some((r, g, b)),
+        _ => None,
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::astronomy()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_rgb() {
+        assert_eq!(parse_rgb("#1a2b3c"), Some((0x1a, 0x2b, 0x3c)));
+        assert_eq!(parse_rgb("#fff"), Some((255, 255, 255)));
+        assert_eq!(parse_rgb("rgb(10, 20,30)"), Some((10, 20, 30)));
+        assert_eq!(parse_rgb("rgb(10,20)"), None);
+        assert_eq!(parse_rgb("rgb(10,20,300)"), None);
+        assert_eq!(parse_rgb("#12345"), None);
+        assert_eq!(parse_rgb("navy"), None);
+    }
+
+    #[test]
+    fn test_unseen_color_optional() {
+        // Theme files written before unseen_color still load
+        let mut json = serde_json::to_value(Theme::light()).unwrap();
+        json.as_object_mut().unwrap().remove("unseen_color");
+        let theme: Theme = serde_json::from_value(json).unwrap();
+        assert_eq!(theme, Theme::light());
+    }
+}
@@ -24,18 +24,50 @@ pub const PI_OVER_2: f64 = PI/2.0;
 #[allow(dead_code)]
 pub fn mean(data: &[f64]) -> Option<f64> {
 
-    let sum = data.iter().sum::<f64>() as f64;
+    let sum = data.iter().sum::<f64>();
     let count = data.len();
 
-    let mean = match count {
+    match count {
        positive if positive > 0 => Some(sum  / count as f64),
        _ => None
-    };
-    mean
+    }
+}
+
+/*
+*   The p-th percentile (0 to 100) of data, interpolating between ranks.
+*/
+pub fn percentile(data: &[f64], p: f64) -> Option<f64> {
+    if data.is_empty() {
+        return None;
+    }
+    let mut sorted = data.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+    let rank = (num::clamp(p, 0.0, 100.0) / 100.0) * ((sorted.len() - 1) as f64);
+    let lo = rank.floor() as usize;
+    let hi = rank.ceil() as usize;
+    let t = rank - (lo as f64);
+    Some(sorted[lo]*(1.0 - t) + sorted[hi]*t)
 }
 
 use std::cmp::Ordering;
 
+/*
+*   Split a textual option such as "percentile:1,99" into its lower-cased name and
+*   numeric arguments, for the FromStr implementations of the option enums.
+*/
+pub fn parse_spec(s: &str) -> Result<(String, Vec<f64>), String> {
+    let mut parts = s.trim().splitn(2, ':');
+    let name = parts.next().unwrap_or("").trim().to_lowercase();
+    let args = match parts.next() {
+        Some(args) => args.split(',')
+            .map(|a| a.trim().parse::<f64>().map_err(|_| format!("'{}' is not a number in '{}'", a.trim(), s)))
+            .collect::<Result<Vec<f64>, String>>()?,
+        None => Vec::new(),
+    };
+    Ok((name, args))
+}
+
 fn partition(data: &[f64]) -> Option<(Vec<f64>, f64, Vec<f64>)> {
     match data.len() {
         0 => None,
@@ -81,15 +113,78 @@ pub fn median(data: &[f64]) -> Option<f64> {
     let size = data.len();
 
     match size {
+        0 => None,
         even if even % 2 == 0 => {
             let fst_med = select(data, (even / 2) - 1);
             let snd_med = select(data, even / 2);
 
             match (fst_med, snd_med) {
-                (Some(fst), Some(snd)) => Some((fst + snd) as f64 / 2.0),
+                (Some(fst), Some(snd)) => Some((fst + snd) / 2.0),
                 _ => None
             }
         },
-        odd => select(data, odd / 2).map(|x| x as f64)
+        odd => select(data, odd / 2)
+    }
+}
+
+/*
+*   Standard (RFC 4648) base64 with padding, for data URLs.
+*/
+pub fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(4*bytes.len().div_ceil(3));
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | (b[2] as usize);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6*i)) & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let data: Vec<f64> = (0..101).map(|x| x as f64).collect();
+        assert_eq!(percentile(&data, 0.0), Some(0.0));
+        assert_eq!(percentile(&data, 50.0), Some(50.0));
+        assert_eq!(percentile(&data, 99.0), Some(99.0));
+        assert_eq!(percentile(&data, 100.0), Some(100.0));
+        assert_eq!(percentile(&[1.0, 2.0], 50.0), Some(1.5));
+        assert_eq!(percentile(&[], 50.0), None);
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(parse_spec(" Percentile:1, 99"), Ok(("percentile".to_string(), vec![1.0, 99.0])));
+        assert_eq!(parse_spec("MinMax"), Ok(("minmax".to_string(), vec![])));
+        assert!(parse_spec("airy:x,1").unwrap_err().contains("'x'"));
+    }
+
+    #[test]
+    fn test_base64_encode() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(&[0xff, 0xfe, 0x00]), "//4A");
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&[3.0, 1.0, 2.0]), Some(2.0));
+        assert_eq!(median(&[4.0, 1.0, 2.0, 3.0]), Some(2.5));
+        assert_eq!(median(&[]), None);
     }
 }
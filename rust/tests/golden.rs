@@ -0,0 +1,65 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+// Compare rendered SVGs of a small committed dataset against checked-in golden files,
+// to catch accidental changes to the output. After an intended change, regenerate with
+//
+//     UPDATE_GOLDEN=1 cargo test --test golden
+//
+extern crate gridlesslib;
+
+use gridlesslib::config::ProcessingConfig;
+use gridlesslib::tart_api;
+
+use std::fs;
+use std::path::Path;
+
+// Blank out the parts of the SVG that legitimately vary: the title holds the observation
+// time, and the version comment (if any) changes with every release.
+fn normalize(svg: &str) -> String {
+    let mut out = String::new();
+    for line in svg.lines() {
+        if line.contains("<title>") {
+            out.push_str("<title/>");
+        } else if line.starts_with("<!--") && line.contains("version") {
+            continue;
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn check_golden(name: &str, config: &ProcessingConfig, nside: u32, show_sources: bool) {
+    let json = fs::read_to_string("tests/data/synthetic.json").unwrap();
+    let data = tart_api::parse_dataset(&json).unwrap();
+    let (svg, _timestamp) = gridlesslib::dataset_to_svg(&data, nside, show_sources, config).unwrap();
+    let svg = normalize(&svg);
+
+    let golden = Path::new("tests/golden").join(format!("{}.svg", name));
+    if std::env::var("UPDATE_GOLDEN").is_ok() {
+        fs::write(&golden, &svg).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&golden)
+        .expect("Missing golden file, run with UPDATE_GOLDEN=1 to create it");
+    assert!(svg == expected, "{} differs from {}, run with UPDATE_GOLDEN=1 if this is intended",
+            name, golden.display());
+}
+
+#[test]
+fn golden_default() {
+    check_golden("synthetic_nside4", &ProcessingConfig::default(), 4, true);
+}
+
+#[test]
+fn golden_decorated() {
+    let config = ProcessingConfig {
+        show_azimuth_ticks: true,
+        show_scalebar: true,
+        render_threshold: Some(0.2),
+        ..ProcessingConfig::default()
+    };
+    check_golden("synthetic_nside4_decorated", &config, 4, false);
+}
@@ -0,0 +1,63 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+// The library has several routes to the same dirty image: direct summation, stored
+// harmonics, the caching Imager, and the config and dataset wrappers. Check they agree
+// on a small committed dataset so that an optimisation of one path can't drift from
+// the others. The WASM bindings call these same functions, so native agreement
+// covers them too.
+//
+extern crate gridlesslib;
+
+use gridlesslib::gridless;
+use gridlesslib::prelude::*;
+use gridlesslib::tart_api;
+
+use std::fs;
+
+const NSIDE: u32 = 8;
+
+fn assert_close(name: &str, a: &VectorReal, b: &VectorReal) {
+    assert_eq!(a.len(), b.len(), "{}", name);
+    let scale = b.iter().fold(0.0f64, |m, x| m.max(x.abs()));
+    for (x, y) in a.iter().zip(b.iter()) {
+        assert!((x - y).abs() <= 1.0e-9*scale, "{}: {} != {}", name, x, y);
+    }
+}
+
+#[test]
+fn reconstruction_paths_agree() {
+    let json = fs::read_to_string("tests/data/synthetic.json").unwrap();
+    let data = tart_api::parse_dataset(&json).unwrap();
+    let obs = gridlesslib::get_obs_from_dataset(&data).unwrap();
+    let (u, v, w) = gridlesslib::get_uvw_from_obs(&obs);
+    let vis = &obs.vis_arr;
+
+    for &real_only in &[false, true] {
+        let mut reference = Hemisphere::new(NSIDE);
+        gridless::image_visibilities(vis, &u, &v, &w, &mut reference, real_only);
+
+        let mut sky = Hemisphere::new(NSIDE);
+        let harmonics = gridless::get_harmonics(&sky, &u, &v, &w);
+        gridless::image_with_harmonics(vis, &harmonics, &mut sky, real_only);
+        assert_close("harmonics", &sky.visible_pix, &reference.visible_pix);
+
+        let direct = gridless::image_at_directions(vis, &u, &v, &w, &sky.l, &sky.m, &sky.n, real_only, true);
+        assert_close("directions", &direct, &reference.visible_pix);
+    }
+
+    let mut reference = Hemisphere::new(NSIDE);
+    gridless::image_visibilities(vis, &u, &v, &w, &mut reference, false);
+
+    let mut sky = Hemisphere::new(NSIDE);
+    gridless::image_visibilities_with_config(vis, &u, &v, &w, &mut sky, &ProcessingConfig::default());
+    assert_close("config", &sky.visible_pix, &reference.visible_pix);
+
+    let mut imager = Imager::new(NSIDE, ProcessingConfig::default()).with_cache();
+    for _ in 0..2 {
+        assert_close("cached imager", imager.image(&obs), &reference.visible_pix);
+    }
+
+    let (sky, _) = gridlesslib::image_dataset(&data, NSIDE, &ProcessingConfig::default()).unwrap();
+    assert_close("dataset", &sky.visible_pix, &reference.visible_pix);
+}
@@ -0,0 +1,61 @@
+//
+// Copyright (c) 2019-2024 Tim Molteno tim@elec.ac.nz
+//
+// The reconstruction parity check of tests/parity.rs, run as WebAssembly under Node so
+// that the code the browser actually executes is covered, including the simd128 build:
+//
+//     wasm-pack test --node
+//     RUSTFLAGS='-C target-feature=+simd128' wasm-pack test --node
+//
+#![cfg(target_arch = "wasm32")]
+
+extern crate gridlesslib;
+extern crate wasm_bindgen_test;
+
+use gridlesslib::gridless;
+use gridlesslib::prelude::*;
+use gridlesslib::tart_api;
+
+use wasm_bindgen_test::*;
+
+const NSIDE: u32 = 8;
+
+// There is no filesystem under Node, so the dataset is compiled in
+const SYNTHETIC: &str = include_str!("data/synthetic.json");
+
+fn assert_close(name: &str, a: &VectorReal, b: &VectorReal) {
+    assert_eq!(a.len(), b.len(), "{}", name);
+    let scale = b.iter().fold(0.0f64, |m, x| m.max(x.abs()));
+    for (x, y) in a.iter().zip(b.iter()) {
+        assert!((x - y).abs() <= 1.0e-9*scale, "{}: {} != {}", name, x, y);
+    }
+}
+
+#[wasm_bindgen_test]
+fn reconstruction_paths_agree() {
+    let data = tart_api::parse_dataset(SYNTHETIC).unwrap();
+    let obs = gridlesslib::get_obs_from_dataset(&data).unwrap();
+    let (u, v, w) = gridlesslib::get_uvw_from_obs(&obs);
+    let vis = &obs.vis_arr;
+
+    let mut reference = Hemisphere::new(NSIDE);
+    gridless::image_visibilities(vis, &u, &v, &w, &mut reference, false);
+
+    let mut sky = Hemisphere::new(NSIDE);
+    let harmonics = gridless::get_harmonics(&sky, &u, &v, &w);
+    gridless::image_with_harmonics(vis, &harmonics, &mut sky, false);
+    assert_close("harmonics", &sky.visible_pix, &reference.visible_pix);
+
+    let direct = gridless::image_at_directions(vis, &u, &v, &w, &sky.l, &sky.m, &sky.n, false, true);
+    assert_close("directions", &direct, &reference.visible_pix);
+
+    let (sky, _) = gridlesslib::image_dataset(&data, NSIDE, &ProcessingConfig::default()).unwrap();
+    assert_close("dataset", &sky.visible_pix, &reference.visible_pix);
+}
+
+#[wasm_bindgen_test]
+fn bindings_image_the_dataset() {
+    let png = gridlesslib::json_to_png(SYNTHETIC.to_string(), NSIDE, 64).unwrap();
+    assert_eq!(&png[0..8], &[137, 80, 78, 71, 13, 10, 26, 10]);
+    assert!(gridlesslib::json_to_png(SYNTHETIC.to_string(), NSIDE, 0).is_err());
+}